@@ -97,21 +97,66 @@ pub fn middleware_fn(attr: TokenStream, item: TokenStream) -> TokenStream {
             Span2::call_site(),
         );
         let new_rbf_type = Ident::new(&format!("__ReusableBoxFuture_{}", name), Span2::call_site());
-        let crate_path = match attr.to_string().as_str() {
-            "_internal" => quote! {
-                crate::{core::{ MiddlewareReturnValue as #new_return_type }, ReusableBoxFuture as #new_rbf_type }
-            },
-            _ => quote! {
-                thruster::{ MiddlewareReturnValue as #new_return_type, ReusableBoxFuture as #new_rbf_type }
-            },
+        let (crate_path, extractors_path) = match attr.to_string().as_str() {
+            "_internal" => (
+                quote! {
+                    crate::{core::{ MiddlewareReturnValue as #new_return_type }, ReusableBoxFuture as #new_rbf_type }
+                },
+                quote! { crate::core::extractors },
+            ),
+            _ => (
+                quote! {
+                    thruster::{ MiddlewareReturnValue as #new_return_type, ReusableBoxFuture as #new_rbf_type }
+                },
+                quote! { thruster::extractors },
+            ),
         };
 
-        let gen = quote! {
-            #function_item
+        // Any arguments between the context and `next` are extractors, which are pulled out of
+        // the context before the function is called.
+        let extractor_types: Vec<&syn::Type> = arguments
+            .iter()
+            .skip(1)
+            .take(arguments.len().saturating_sub(2))
+            .map(|arg| match arg {
+                syn::FnArg::Captured(cap) => &cap.ty,
+                _ => panic!("Expected extractor arguments to have a type"),
+            })
+            .collect();
+
+        let gen = if extractor_types.is_empty() {
+            quote! {
+                #function_item
 
-            use #crate_path;
-            #visibility fn #name#generics(ctx: #context_type, next: MiddlewareNext<#context_type>) -> #new_return_type<#context_type> {
-                #new_rbf_type::new(#new_name(ctx, next))
+                use #crate_path;
+                #visibility fn #name#generics(ctx: #context_type, next: MiddlewareNext<#context_type>) -> #new_return_type<#context_type> {
+                    #new_rbf_type::new(#new_name(ctx, next))
+                }
+            }
+        } else {
+            let extracted: Vec<Ident> = (0..extractor_types.len())
+                .map(|i| Ident::new(&format!("__extracted_{}", i), Span2::call_site()))
+                .collect();
+            let extractions = extracted.iter().zip(extractor_types).map(|(extracted, ty)| {
+                quote! {
+                    let #extracted = match <#ty as #extractors_path::FromContext<#context_type>>::from_context(&mut ctx).await {
+                        Ok(extracted) => extracted,
+                        Err(e) => return Err(e.into_thruster_error(ctx)),
+                    };
+                }
+            });
+
+            quote! {
+                #function_item
+
+                use #crate_path;
+                #visibility fn #name#generics(mut ctx: #context_type, next: MiddlewareNext<#context_type>) -> #new_return_type<#context_type> {
+                    #new_rbf_type::new(async move {
+                        #(#extractions)*
+
+                        #new_name(ctx, #(#extracted),*, next).await
+                    })
+                }
             }
         };
 
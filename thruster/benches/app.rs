@@ -72,7 +72,7 @@ async fn test_params_1(
         .unwrap()
         .get("hello")
         .unwrap()
-        .clone();
+        .to_owned();
     context.body(body);
     Ok(context)
 }
@@ -111,7 +111,7 @@ async fn test_query_params_1(
         .unwrap()
         .get("hello")
         .unwrap()
-        .clone();
+        .to_owned();
     context.body(body);
     Ok(context)
 }
@@ -0,0 +1,32 @@
+use futures::FutureExt;
+use log::info;
+use std::time::Duration;
+use thruster::{m, middleware_fn};
+use thruster::{App, BasicContext as Ctx, Request, Server, ThrusterServer};
+use thruster::{MiddlewareNext, MiddlewareResult};
+
+#[middleware_fn]
+async fn slow(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    context.body("Finished, even though the server was asked to stop!");
+    Ok(context)
+}
+
+fn main() {
+    env_logger::init();
+    info!("Starting server...");
+
+    let mut app = App::<Request, Ctx, ()>::new_basic();
+
+    app.get("/slow", m![slow]);
+
+    let mut server = Server::new(app);
+    server.drain_timeout(Duration::from_secs(10));
+
+    // Hit /slow and then press ctrl-c; the in-flight request still completes.
+    server.start_with_shutdown(
+        "0.0.0.0",
+        4321,
+        tokio::signal::ctrl_c().map(|_| info!("Shutting down...")),
+    );
+}
@@ -1,31 +1,38 @@
 ///
-/// Hey! This program is better with a few extra env vars. Try
-/// RUST_HOST_DIR=./examples/static_file/ RUST_ROOT_DIR=/ \
+/// Serves the files in ./examples/static_file/ at /static, and its
+/// index.html at /. Try
 ///   cargo run --example static_file --features="hyper_server file"
 ///
-/// RUST_HOST_DIR is where to serve the content from on your local
-/// filesystem
+/// and then visit http://localhost:4321/static/ for the directory
+/// listing.
 ///
-/// RUST_ROOT_DIR is the root of where content should be requested
-/// from. In otherwords, RUST_ROOT_DIR will attempt to replace the
-/// first piece of a path with the RUST_HOST_DIR.
-///
-use hyper::Body;
+use lazy_static::lazy_static;
 use log::info;
 use thruster::context::basic_hyper_context::{
-    generate_context, to_owned_request, BasicHyperContext as Ctx, HyperRequest,
+    generate_context, BasicHyperContext as Ctx, HyperRequest,
 };
 use thruster::hyper_server::HyperServer;
-use thruster::middleware::file::{file, get_file};
-use thruster::{async_middleware, middleware_fn};
+use thruster::middleware::file::StaticFiles;
+use thruster::{m, middleware_fn};
 use thruster::{App, ThrusterServer};
 use thruster::{MiddlewareNext, MiddlewareResult};
 
+lazy_static! {
+    static ref STATIC_FILES: StaticFiles = StaticFiles::new("/static", "examples/static_file")
+        .index(None)
+        .directory_listing(true)
+        .cache_control("public, max-age=60");
+    static ref INDEX: StaticFiles = StaticFiles::new("/", "examples/static_file");
+}
+
+#[middleware_fn]
+async fn static_files(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    STATIC_FILES.handle(context, next).await
+}
+
 #[middleware_fn]
-async fn index(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
-    let content = get_file("examples/static_file/index.html").unwrap();
-    context.body = Body::from(content);
-    Ok(context)
+async fn index(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+    INDEX.handle(context, next).await
 }
 
 fn main() {
@@ -33,8 +40,8 @@ fn main() {
     info!("Starting server...");
 
     let mut app = App::<HyperRequest, Ctx, ()>::create(generate_context, ());
-    app.get("/", async_middleware!(Ctx, [index]));
-    app.get("/*", async_middleware!(Ctx, [to_owned_request, file]));
+    app.get("/", m![index]);
+    app.get("/static/*", m![static_files]);
     let server = HyperServer::new(app);
     server.start("0.0.0.0", 4321);
 }
@@ -0,0 +1,38 @@
+use futures::{SinkExt, StreamExt};
+use log::info;
+use thruster::context::basic_hyper_context::{
+    generate_context, BasicHyperContext as Ctx, HyperRequest,
+};
+use thruster::hyper_server::HyperServer;
+use thruster::middleware::websocket::WebSocketUpgrade;
+use thruster::{m, middleware_fn};
+use thruster::{App, ThrusterServer};
+use thruster::{MiddlewareNext, MiddlewareResult};
+
+#[middleware_fn]
+async fn echo(
+    mut context: Ctx,
+    ws: WebSocketUpgrade,
+    _next: MiddlewareNext<Ctx>,
+) -> MiddlewareResult<Ctx> {
+    ws.on_upgrade(&mut context, |mut socket| async move {
+        while let Some(Ok(message)) = socket.next().await {
+            if (message.is_text() || message.is_binary()) && socket.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(context)
+}
+
+fn main() {
+    env_logger::init();
+    info!("Starting server...");
+
+    let mut app = App::<HyperRequest, Ctx, ()>::create(generate_context, ());
+    app.get("/echo", m![echo]);
+
+    let server = HyperServer::new(app);
+    server.start("0.0.0.0", 4321);
+}
@@ -1,12 +1,16 @@
 mod thruster_app;
 
-#[cfg(not(feature = "hyper_server"))]
+// The basic helpers are also used by the crate's own tests, whichever server is enabled
+#[cfg(any(test, not(feature = "hyper_server")))]
+#[cfg_attr(feature = "hyper_server", allow(dead_code))]
 pub mod testing_async;
 
 #[cfg(feature = "hyper_server")]
-mod testing_hyper_async;
+#[cfg_attr(test, allow(dead_code))]
+pub mod testing_hyper_async;
 
-#[cfg(feature = "hyper_server")]
+// Kept for code that imports the hyper helpers from where they used to be
+#[cfg(all(not(test), feature = "hyper_server"))]
 pub mod testing_async {
     pub use super::testing_hyper_async::*;
 }
@@ -32,8 +32,14 @@ pub async fn request<T: Context<Response = Response> + Clone + Send + Sync, S: '
     bytes.put(body.as_bytes());
 
     let request = decode(&mut bytes).unwrap().unwrap();
-    let matched_route = app.resolve_from_method_and_path("GET", route.to_owned());
-    let response = app.resolve(request, matched_route).await.unwrap();
+    let matched_route = app.resolve_from_method_and_path(method, route.to_owned());
+    let mut response = app.resolve(request, matched_route).await.unwrap();
+
+    // Servers don't send the body of responses to HEADs
+    if method == "HEAD" {
+        response.response.clear();
+        response.body_stream = None;
+    }
 
     TestResponse::new(response)
 }
@@ -156,7 +162,7 @@ impl TestResponse {
 
         for header_pair in header_string.split("\r\n") {
             if !header_pair.is_empty() {
-                let mut split = header_pair.split(':');
+                let mut split = header_pair.splitn(2, ':');
                 let key = split.next().unwrap().trim().to_owned();
                 let value = split.next().unwrap().trim().to_owned();
 
@@ -1,12 +1,20 @@
 use crate::ReusableBoxFuture;
+use fnv::FnvHashMap;
 use futures::FutureExt;
 
 use std::io;
-
+use std::sync::Arc;
 
 use crate::core::context::Context;
+use crate::core::errors::{response_status, ThrusterError};
+use crate::core::guard::Guard;
 use crate::core::request::Request;
-use crate::parser::{middleware_traits::MiddlewareTuple, tree::Node, tree::NodeOutput};
+use crate::middleware::cors::Cors;
+use crate::parser::{
+    middleware_traits::{MiddlewareTuple, NextFn},
+    tree::Node,
+    tree::NodeOutput,
+};
 use crate::{
     context::basic_context::{generate_context, BasicContext},
     core::request::ThrusterRequest,
@@ -26,6 +34,8 @@ pub trait Middleware<CIn: Send, COut: Send, CErr: Send>:
 // type ReturnValue<T> = Result<T, ThrusterError<T>>;
 type ReturnValue<T> = T;
 
+type ErrorHandler<T> = dyn Fn(ThrusterError<T>) -> T + Send + Sync;
+
 /// App, the main component of Thruster. The App is the entry point for your application
 /// and handles all incomming requests. Apps are also composeable, that is, via the `subapp`
 /// method, you can use all of the methods and middlewares contained within an app as a subset
@@ -58,6 +68,11 @@ type ReturnValue<T> = T;
 ///
 /// In the above example, the route `/test/some-id` will return `some-id` in the body of the response.
 ///
+/// Route params can be constrained, either with a regex, as in `/:id(\d+)`, or with a type, as in
+/// `/:id<u64>`, and `/:rest*` captures the rest of the path. When a param's constraint doesn't
+/// match, the other routes at that point are tried instead, constrained params before
+/// unconstrained ones.
+///
 /// The provided start methods are great places to start, but you can also simply use Thruster as a router
 /// and create your own version of an HTTP server by directly calling `App.resolve` with a Request object.
 /// It will then return a future with the Response object that corresponds to the request. This can be
@@ -70,6 +85,25 @@ pub struct App<R: ThrusterRequest + Send, T: 'static + Context + Clone + Send +
     pub post_root: Node<ReturnValue<T>>,
     pub put_root: Node<ReturnValue<T>>,
     pub patch_root: Node<ReturnValue<T>>,
+    /// Roots for any other methods that have had routes added, such as `HEAD`, `CONNECT`, `TRACE`
+    /// or extension methods like `PROPFIND` and `PURGE`, keyed by method name.
+    pub custom_roots: FnvHashMap<String, Node<ReturnValue<T>>>,
+    /// Root used for methods without any routes. It only holds method-agnostic middleware and
+    /// the 404 handler.
+    fallback_root: Node<ReturnValue<T>>,
+    /// Root used for the responses that the app prepares itself, i.e. `405`s and automatic
+    /// `OPTIONS` responses. It holds the method-agnostic middleware, ending in a route that sends
+    /// the prepared response as it is. Built on commit.
+    respond_root: Node<ReturnValue<T>>,
+    /// Method-agnostic middleware, kept around so that it can be applied to custom roots that are
+    /// created after the fact.
+    method_agnostic_middleware: Vec<(String, MiddlewareTuple<ReturnValue<T>>)>,
+    /// Method-agnostic guards, kept around for the same reason.
+    method_agnostic_guards: Vec<(String, Guard<ReturnValue<T>>)>,
+    not_found: Option<MiddlewareTuple<ReturnValue<T>>>,
+    cors: Option<Cors>,
+    /// Renders the errors that come back from the middleware, if set.
+    error_handler: Option<Arc<ErrorHandler<T>>>,
     /// Generate context is common to all `App`s. It's the function that's called upon receiving a request
     /// that translates an acutal `Request` struct to your custom Context type. It should be noted that
     /// the context_generator should be as fast as possible as this is called with every request, including
@@ -98,6 +132,14 @@ impl<R: 'static + ThrusterRequest + Send, T: Context + Clone + Send + Sync, S: '
             post_root: Node::default(),
             put_root: Node::default(),
             patch_root: Node::default(),
+            custom_roots: FnvHashMap::default(),
+            fallback_root: Node::default(),
+            respond_root: Node::default(),
+            method_agnostic_middleware: Vec::new(),
+            method_agnostic_guards: Vec::new(),
+            not_found: None,
+            cors: None,
+            error_handler: None,
             context_generator: generate_context,
             state: std::sync::Arc::new(state),
         }
@@ -124,7 +166,15 @@ impl<R: 'static + ThrusterRequest + Send, T: Context + Clone + Send + Sync, S: '
         self.delete_root
             .add_non_leaf_value_at_path(path, middlewares.clone());
         self.patch_root
-            .add_non_leaf_value_at_path(path, middlewares);
+            .add_non_leaf_value_at_path(path, middlewares.clone());
+        self.fallback_root
+            .add_non_leaf_value_at_path(path, middlewares.clone());
+        for root in self.custom_roots.values_mut() {
+            root.add_non_leaf_value_at_path(path, middlewares.clone());
+        }
+
+        self.method_agnostic_middleware
+            .push((path.to_owned(), middlewares));
 
         self
     }
@@ -139,6 +189,71 @@ impl<R: 'static + ThrusterRequest + Send, T: Context + Clone + Send + Sync, S: '
         self.put_root.add_node_at_path(prefix, app.put_root);
         self.delete_root.add_node_at_path(prefix, app.delete_root);
         self.patch_root.add_node_at_path(prefix, app.patch_root);
+        self.fallback_root
+            .add_node_at_path(prefix, app.fallback_root);
+
+        for (method, node) in app.custom_roots {
+            self.custom_root_mut(&method).add_node_at_path(prefix, node);
+        }
+
+        for (path, middlewares) in app.method_agnostic_middleware {
+            self.method_agnostic_middleware
+                .push((format!("{}{}", prefix, path), middlewares));
+        }
+
+        for (path, guard) in app.method_agnostic_guards {
+            self.method_agnostic_guards
+                .push((format!("{}{}", prefix, path), guard));
+        }
+
+        self
+    }
+
+    /// Add a guard to every route at or below a path, for all methods. Guards are checked after
+    /// all of the method-agnostic middleware for the route has run, so after things like
+    /// authentication, and before the route's own middleware. If any of them fail, the request is
+    /// answered with a `403 Forbidden` instead.
+    ///
+    /// ```rust, ignore
+    /// app.use_middleware("/", m![authenticate]);
+    /// app.get("/admin/users", m![list_users]);
+    /// app.guard("/admin", has_role::<User, _>("admin"));
+    /// ```
+    pub fn guard(&mut self, path: &str, guard: Guard<ReturnValue<T>>) -> &mut App<R, T, S> {
+        self.get_root.add_guard_at_path(path, guard.clone());
+        self.options_root.add_guard_at_path(path, guard.clone());
+        self.post_root.add_guard_at_path(path, guard.clone());
+        self.put_root.add_guard_at_path(path, guard.clone());
+        self.delete_root.add_guard_at_path(path, guard.clone());
+        self.patch_root.add_guard_at_path(path, guard.clone());
+        self.fallback_root.add_guard_at_path(path, guard.clone());
+        for root in self.custom_roots.values_mut() {
+            root.add_guard_at_path(path, guard.clone());
+        }
+
+        self.method_agnostic_guards.push((path.to_owned(), guard));
+
+        self
+    }
+
+    /// Add a guard to every route at or below a path for a single method, e.g. to only guard
+    /// the `DELETE`s of a resource. Checked the same way as guards added with `guard`.
+    pub fn guard_method(
+        &mut self,
+        method: &str,
+        path: &str,
+        guard: Guard<ReturnValue<T>>,
+    ) -> &mut App<R, T, S> {
+        let root = match method {
+            "GET" => &mut self.get_root,
+            "OPTIONS" => &mut self.options_root,
+            "POST" => &mut self.post_root,
+            "PUT" => &mut self.put_root,
+            "DELETE" => &mut self.delete_root,
+            "PATCH" => &mut self.patch_root,
+            _ => self.custom_root_mut(method),
+        };
+        root.add_guard_at_path(path, guard);
 
         self
     }
@@ -154,7 +269,7 @@ impl<R: 'static + ThrusterRequest + Send, T: Context + Clone + Send + Sync, S: '
         self
     }
 
-    /// Add a route that responds to `OPTION`s to a given path
+    /// Add a route that responds to `OPTIONS`s to a given path
     pub fn options(
         &mut self,
         path: &str,
@@ -209,6 +324,83 @@ impl<R: 'static + ThrusterRequest + Send, T: Context + Clone + Send + Sync, S: '
         self
     }
 
+    /// Add a route that responds to `HEAD`s to a given path. Without one, `HEAD` requests are
+    /// answered by the matching `GET` route, whose response is sent with its headers, including
+    /// `Content-Length`, but without its body.
+    pub fn head(
+        &mut self,
+        path: &str,
+        middlewares: MiddlewareTuple<ReturnValue<T>>,
+    ) -> &mut App<R, T, S> {
+        self.method("HEAD", path, middlewares)
+    }
+
+    /// Add a route that responds to `CONNECT`s to a given path
+    pub fn connect(
+        &mut self,
+        path: &str,
+        middlewares: MiddlewareTuple<ReturnValue<T>>,
+    ) -> &mut App<R, T, S> {
+        self.method("CONNECT", path, middlewares)
+    }
+
+    /// Add a route that responds to `TRACE`s to a given path
+    pub fn trace(
+        &mut self,
+        path: &str,
+        middlewares: MiddlewareTuple<ReturnValue<T>>,
+    ) -> &mut App<R, T, S> {
+        self.method("TRACE", path, middlewares)
+    }
+
+    /// Add a route that responds to an arbitrary method at a given path, for example `PROPFIND`
+    /// or `PURGE`. Method names are case sensitive.
+    pub fn method(
+        &mut self,
+        method: &str,
+        path: &str,
+        middlewares: MiddlewareTuple<ReturnValue<T>>,
+    ) -> &mut App<R, T, S> {
+        match method {
+            "GET" => self.get(path, middlewares),
+            "OPTIONS" => self.options(path, middlewares),
+            "POST" => self.post(path, middlewares),
+            "PUT" => self.put(path, middlewares),
+            "DELETE" => self.delete(path, middlewares),
+            "PATCH" => self.patch(path, middlewares),
+            _ => {
+                self.custom_root_mut(method)
+                    .add_value_at_path(path, middlewares);
+
+                self
+            }
+        }
+    }
+
+    /// Gets the root for a custom method, creating it with all of the method-agnostic middleware,
+    /// guards and the 404 handler added so far if it doesn't exist yet.
+    fn custom_root_mut(&mut self, method: &str) -> &mut Node<ReturnValue<T>> {
+        if !self.custom_roots.contains_key(method) {
+            let mut root = Node::default();
+
+            for (path, middlewares) in self.method_agnostic_middleware.iter() {
+                root.add_non_leaf_value_at_path(path, middlewares.clone());
+            }
+
+            for (path, guard) in self.method_agnostic_guards.iter() {
+                root.add_guard_at_path(path, guard.clone());
+            }
+
+            if let Some(not_found) = self.not_found.as_ref() {
+                root.add_value_at_path("/*", not_found.clone());
+            }
+
+            self.custom_roots.insert(method.to_owned(), root);
+        }
+
+        self.custom_roots.get_mut(method).unwrap()
+    }
+
     /// Sets the middleware if no route is successfully matched. Note, that due to type restrictions,
     /// we Context needs to implement Clone in order to call this function, even though _clone will
     /// never be called._
@@ -223,7 +415,39 @@ impl<R: 'static + ThrusterRequest + Send, T: Context + Clone + Send + Sync, S: '
         self.put_root.add_value_at_path("/*", middlewares.clone());
         self.delete_root
             .add_value_at_path("/*", middlewares.clone());
-        self.patch_root.add_value_at_path("/*", middlewares);
+        self.patch_root.add_value_at_path("/*", middlewares.clone());
+        self.fallback_root
+            .add_value_at_path("/*", middlewares.clone());
+        for root in self.custom_roots.values_mut() {
+            root.add_value_at_path("/*", middlewares.clone());
+        }
+
+        self.not_found = Some(middlewares);
+
+        self
+    }
+
+    /// Sets the function that turns the errors returned by middleware into responses, such as
+    /// `errors::problem_json`. It's given the whole error, including its context, and returns the
+    /// context to respond with. Without one, the error's context is sent with the error's status,
+    /// or a `500` if that isn't a valid one, and whatever body the middleware set on it before
+    /// failing.
+    ///
+    /// ```rust, ignore
+    /// app.set_error_handler(|error: ThrusterError<Ctx>| {
+    ///     let mut context = error.context;
+    ///
+    ///     context.status(error.status);
+    ///     context.body(&error.message);
+    ///
+    ///     context
+    /// });
+    /// ```
+    pub fn set_error_handler(
+        &mut self,
+        error_handler: impl Fn(ThrusterError<T>) -> T + Send + Sync + 'static,
+    ) -> &mut App<R, T, S> {
+        self.error_handler = Some(Arc::new(error_handler));
 
         self
     }
@@ -236,74 +460,198 @@ impl<R: 'static + ThrusterRequest + Send, T: Context + Clone + Send + Sync, S: '
         self.put_root = self.put_root.commit();
         self.delete_root = self.delete_root.commit();
         self.patch_root = self.patch_root.commit();
+        self.fallback_root = self.fallback_root.commit();
+        self.custom_roots = self
+            .custom_roots
+            .into_iter()
+            .map(|(method, root)| (method, root.commit()))
+            .collect();
+
+        let mut respond_root = Node::default();
+        add_responder(&mut respond_root, "/");
+        for (path, middlewares) in self.method_agnostic_middleware.iter() {
+            respond_root.add_non_leaf_value_at_path(path, middlewares.clone());
+            add_responder(&mut respond_root, path);
+        }
+        self.respond_root = respond_root.commit();
 
         self
     }
 
+    fn root_for_method(&self, method: &str) -> &Node<ReturnValue<T>> {
+        match method {
+            "GET" => &self.get_root,
+            "OPTIONS" => &self.options_root,
+            "POST" => &self.post_root,
+            "PUT" => &self.put_root,
+            "DELETE" => &self.delete_root,
+            "PATCH" => &self.patch_root,
+            _ => self.custom_roots.get(method).unwrap_or(&self.fallback_root),
+        }
+    }
+
     pub fn resolve_from_method_and_path<'m>(
         &'m self,
         method: &str,
         path: String,
     ) -> NodeOutput<'m, ReturnValue<T>> {
-        match method {
-            "OPTION" => self.options_root.get_value_at_path(path),
-            "POST" => self.post_root.get_value_at_path(path),
-            "PUT" => self.put_root.get_value_at_path(path),
-            "DELETE" => self.delete_root.get_value_at_path(path),
-            "PATCH" => self.patch_root.get_value_at_path(path),
-            // default get
-            _ => self.get_root.get_value_at_path(path),
+        if method == "HEAD" {
+            if let Some(head_root) = self.custom_roots.get(method) {
+                let matched = head_root.get_value_at_path(path.clone());
+
+                if matched.exact_match {
+                    return matched;
+                }
+            }
+
+            return self.get_root.get_value_at_path(path);
+        }
+
+        self.root_for_method(method).get_value_at_path(path)
+    }
+
+    /// Lists the methods that have a route registered for the given path, suitable for use as an
+    /// `Allow` header. `HEAD` is included whenever `GET` is, and `OPTIONS` whenever any method is,
+    /// since both are answered automatically.
+    pub fn allowed_methods(&self, path: &str) -> Vec<String> {
+        let mut custom_methods: Vec<&String> = self.custom_roots.keys().collect();
+        custom_methods.sort();
+
+        let mut allowed = vec![];
+        for method in ["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]
+            .iter()
+            .copied()
+            .chain(custom_methods.into_iter().map(|m| m.as_str()))
+        {
+            if method != "OPTIONS"
+                && self
+                    .root_for_method(method)
+                    .get_value_at_path(path.to_owned())
+                    .exact_match
+            {
+                allowed.push(method.to_owned());
+            }
+        }
+
+        if allowed.iter().any(|m| m == "GET") && !allowed.iter().any(|m| m == "HEAD") {
+            allowed.insert(1, "HEAD".to_owned());
+        }
+
+        if !allowed.is_empty()
+            || self
+                .options_root
+                .get_value_at_path(path.to_owned())
+                .exact_match
+        {
+            allowed.push("OPTIONS".to_owned());
         }
+
+        allowed
+    }
+
+    /// Sets the CORS config for the app. Once set, the CORS headers are added to the responses
+    /// of all cross-origin requests, and preflight requests are answered automatically based on
+    /// the methods that have routes for the requested path.
+    pub fn set_cors(&mut self, cors: Cors) -> &mut App<R, T, S> {
+        self.cors = Some(cors);
+
+        self
+    }
+
+    /// Generates the context for a matched route, or prepares the response if the request can be
+    /// answered without running one, i.e. `OPTIONS` requests without a route and requests with a
+    /// method that the path has no routes for. Those still go through the method-agnostic
+    /// middleware. CORS preflights are answered straight away, as they're sent without any
+    /// credentials that middleware like authentication would look for.
+    fn dispatch<'m>(
+        &'m self,
+        request: R,
+        matched_route: &NodeOutput<'m, ReturnValue<T>>,
+    ) -> Dispatch<'m, T> {
+        let is_options = request.method() == "OPTIONS";
+        let origin = request.get_header("origin").into_iter().next();
+
+        if let (Some(cors), Some(origin)) = (self.cors.as_ref(), origin.as_ref()) {
+            let is_preflight = is_options
+                && !request
+                    .get_header("access-control-request-method")
+                    .is_empty();
+
+            if is_preflight {
+                let allowed = self.allowed_methods(&request.path());
+
+                if !allowed.is_empty() {
+                    let request_headers = request
+                        .get_header("access-control-request-headers")
+                        .join(", ");
+                    let mut context = (self.context_generator)(request, &self.state, "");
+
+                    cors.preflight(&mut context, origin, &request_headers, &allowed);
+
+                    return Dispatch::Respond(context.get_response());
+                }
+            }
+        }
+
+        let (mut context, value) = if matched_route.exact_match {
+            (
+                (self.context_generator)(request, &self.state, &matched_route.path),
+                matched_route.value,
+            )
+        } else {
+            let path = request.path();
+            let allowed = self.allowed_methods(&path);
+
+            if allowed.is_empty() {
+                (
+                    (self.context_generator)(request, &self.state, &matched_route.path),
+                    matched_route.value,
+                )
+            } else {
+                let mut context = (self.context_generator)(request, &self.state, "");
+
+                context.status(if is_options { 204 } else { 405 });
+                context.set("Allow", &allowed.join(", "));
+
+                (context, self.respond_root.get_value_at_path(path).value)
+            }
+        };
+
+        if let Some(cors) = self.cors.as_ref() {
+            cors.apply(&mut context, origin.as_deref());
+        }
+
+        Dispatch::Run(context, value)
     }
 
     // pub async fn match_and_resolve<'m>(&'m self, request: R) -> Result<T::Response, io::Error> {
     pub fn match_and_resolve<'m>(
         &'m self,
-        request: R,
+        mut request: R,
     ) -> ReusableBoxFuture<Result<T::Response, io::Error>> {
-        let method = request.method();
-        let path = request.path();
-
-        let node = match method {
-            "OPTION" => self.options_root.get_value_at_path(path),
-            "POST" => self.post_root.get_value_at_path(path),
-            "PUT" => self.put_root.get_value_at_path(path),
-            "DELETE" => self.delete_root.get_value_at_path(path),
-            "PATCH" => self.patch_root.get_value_at_path(path),
-            // default get
-            _ => self.get_root.get_value_at_path(path),
+        let mut node = self.resolve_from_method_and_path(request.method(), request.path());
+        request.set_params(std::mem::take(&mut node.params));
+        if !node.path.is_empty() {
+            request.set_route(&node.path);
+        }
+
+        let (context, value) = match self.dispatch(request, &node) {
+            Dispatch::Run(context, value) => (context, value),
+            Dispatch::Respond(response) => {
+                return ReusableBoxFuture::new(futures::future::ready(Ok(response)))
+            }
         };
 
-        let context = (self.context_generator)(request, &self.state, &node.path);
+        let error_handler = self.error_handler.clone();
 
-        ReusableBoxFuture::new((node.value)(context).map(|ctx| {
+        ReusableBoxFuture::new((value)(context).map(move |ctx| {
             let ctx = match ctx {
                 Ok(val) => val,
-                Err(e) => e.context,
+                Err(e) => handle_error(error_handler.as_deref(), e),
             };
 
             Ok(ctx.get_response())
         }))
-
-        // let copy = node.value;
-        // let ctx = (copy)(context).await;
-
-        // let ctx = match ctx {
-        //     Ok(val) => val,
-        //     Err(e) => e.context,
-        // };
-
-        // Ok(ctx.get_response())
-
-        // let copy = node.value;
-        // let ctx = (copy)(context).await;
-
-        // let ctx = match ctx {
-        //     Ok(val) => val,
-        //     Err(e) => e.context,
-        // };
-
-        // Ok(ctx.get_response())
     }
 
     pub async fn resolve<'m>(
@@ -316,19 +664,403 @@ impl<R: 'static + ThrusterRequest + Send, T: Context + Clone + Send + Sync, S: '
 
     async fn _resolve<'m>(
         &self,
-        request: R,
-        matched_route: NodeOutput<'m, T>,
+        mut request: R,
+        mut matched_route: NodeOutput<'m, T>,
     ) -> Result<T::Response, io::Error> {
-        let context = (self.context_generator)(request, &self.state, &matched_route.path);
+        request.set_params(std::mem::take(&mut matched_route.params));
+        if !matched_route.path.is_empty() {
+            request.set_route(&matched_route.path);
+        }
 
-        let copy = matched_route.value;
-        let ctx = (copy)(context).await;
+        let (context, value) = match self.dispatch(request, &matched_route) {
+            Dispatch::Run(context, value) => (context, value),
+            Dispatch::Respond(response) => return Ok(response),
+        };
+
+        let ctx = (value)(context).await;
 
         let ctx = match ctx {
             Ok(val) => val,
-            Err(e) => e.context,
+            Err(e) => handle_error(self.error_handler.as_deref(), e),
         };
 
         Ok(ctx.get_response())
     }
 }
+
+fn handle_error<T: Context>(error_handler: Option<&ErrorHandler<T>>, error: ThrusterError<T>) -> T {
+    match error_handler {
+        Some(error_handler) => error_handler(error),
+        None => {
+            let mut context = error.context;
+            context.status(response_status(error.status));
+
+            context
+        }
+    }
+}
+
+// Adds the routes to a respond root that send the response as it was prepared, at a path and
+// anything below it, so that the middleware for the path runs first.
+fn add_responder<T: 'static + Context + Clone + Send>(root: &mut Node<T>, path: &str) {
+    fn respond<T: 'static + Send>(
+        context: T,
+        _next: NextFn<T>,
+    ) -> ReusableBoxFuture<Result<T, ThrusterError<T>>> {
+        ReusableBoxFuture::new(async move { Ok(context) })
+    }
+
+    let path = path.trim_end_matches('/');
+
+    root.add_value_at_path(
+        if path.is_empty() { "/" } else { path },
+        MiddlewareTuple::A(respond),
+    );
+    root.add_value_at_path(&format!("{}/:rest*", path), MiddlewareTuple::A(respond));
+}
+
+enum Dispatch<'m, T: Context> {
+    Run(
+        T,
+        &'m Box<dyn Fn(T) -> ReusableBoxFuture<Result<T, ThrusterError<T>>> + Send + Sync>,
+    ),
+    Respond(T::Response),
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::app::testing_async as testing;
+    use crate::core::errors::{problem_json, HttpError};
+    use crate::core::request::decode;
+    use crate::core::{MiddlewareNext, MiddlewareResult};
+    use crate::middleware_fn;
+
+    #[middleware_fn(_internal)]
+    async fn hello(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body("hello");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn tag(
+        mut context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set("X-Root", "seen");
+        next(context).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn tag_posts(
+        mut context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set("X-Posts", "seen");
+        next(context).await
+    }
+
+    fn app_with_middleware() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(tag));
+        app.use_middleware("/posts", MiddlewareTuple::A(tag_posts));
+        app.get("/posts/:id", MiddlewareTuple::A(hello));
+        app.get("/users", MiddlewareTuple::A(hello));
+        app.set_cors(Cors::new());
+        app.commit()
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.get("/posts/:id", MiddlewareTuple::A(hello));
+        app.delete("/posts/:id", MiddlewareTuple::A(hello));
+        app.method("PROPFIND", "/posts/:id", MiddlewareTuple::A(hello));
+        app.post("/posts", MiddlewareTuple::A(hello));
+        app.commit()
+    }
+
+    #[test]
+    fn it_should_list_the_allowed_methods_of_a_path() {
+        let app = app();
+
+        assert_eq!(
+            app.allowed_methods("/posts/1"),
+            vec!["GET", "HEAD", "DELETE", "PROPFIND", "OPTIONS"]
+        );
+        assert_eq!(app.allowed_methods("/posts"), vec!["POST", "OPTIONS"]);
+        assert!(app.allowed_methods("/users").is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_methods_without_a_route_with_a_405() {
+        let response = testing::request(&app(), "PUT", "/posts/1", &[], "").await;
+
+        assert_eq!(response.status.1, 405);
+        assert_eq!(
+            response.headers.get("Allow").unwrap(),
+            "GET, HEAD, DELETE, PROPFIND, OPTIONS"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_options_automatically() {
+        let response = testing::request(&app(), "OPTIONS", "/posts", &[], "").await;
+
+        assert_eq!(response.status.1, 204);
+        assert_eq!(response.headers.get("Allow").unwrap(), "POST, OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn it_should_run_the_middleware_of_the_path_for_405s() {
+        let app = app_with_middleware();
+
+        let response = testing::request(&app, "POST", "/posts/1", &[], "").await;
+
+        assert_eq!(response.status.1, 405);
+        assert_eq!(response.headers.get("X-Root").unwrap(), "seen");
+        assert_eq!(response.headers.get("X-Posts").unwrap(), "seen");
+
+        let response = testing::request(&app, "POST", "/users", &[], "").await;
+
+        assert_eq!(response.status.1, 405);
+        assert_eq!(response.headers.get("X-Root").unwrap(), "seen");
+        assert!(!response.headers.contains_key("X-Posts"));
+    }
+
+    #[tokio::test]
+    async fn it_should_run_the_middleware_of_the_path_for_automatic_options() {
+        let response =
+            testing::request(&app_with_middleware(), "OPTIONS", "/posts/1", &[], "").await;
+
+        assert_eq!(response.status.1, 204);
+        assert_eq!(response.headers.get("Allow").unwrap(), "GET, HEAD, OPTIONS");
+        assert_eq!(response.headers.get("X-Posts").unwrap(), "seen");
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_preflights_without_running_middleware() {
+        let response = testing::request(
+            &app_with_middleware(),
+            "OPTIONS",
+            "/posts/1",
+            &[
+                ("Origin", "https://app.example.com"),
+                ("Access-Control-Request-Method", "GET"),
+            ],
+            "",
+        )
+        .await;
+
+        assert_eq!(response.status.1, 204);
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Allow-Methods")
+                .unwrap(),
+            "GET, HEAD, OPTIONS"
+        );
+        assert!(!response.headers.contains_key("X-Root"));
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_paths_without_any_routes_with_a_404() {
+        let response = testing::request(&app(), "PUT", "/users", &[], "").await;
+
+        assert_eq!(response.status.1, 404);
+        assert!(!response.headers.contains_key("Allow"));
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_head_with_the_get_route_and_keep_its_length() {
+        let app = app();
+        let request = decode(&mut BytesMut::from(
+            "HEAD /posts/1 HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        ))
+        .unwrap()
+        .unwrap();
+        let matched = app.resolve_from_method_and_path("HEAD", "/posts/1".to_owned());
+        let response = app.resolve(request, matched).await.unwrap();
+
+        assert_eq!(response.response, b"hello".to_vec());
+
+        let response = testing::request(&app, "HEAD", "/posts/1", &[], "").await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "");
+    }
+
+    #[derive(Debug)]
+    enum AccountError {
+        Locked,
+        Unreachable,
+    }
+
+    impl std::fmt::Display for AccountError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                AccountError::Locked => write!(f, "The account is locked"),
+                AccountError::Unreachable => write!(f, "The accounts database is unreachable"),
+            }
+        }
+    }
+
+    impl std::error::Error for AccountError {}
+
+    impl HttpError for AccountError {
+        fn status(&self) -> u32 {
+            match self {
+                AccountError::Locked => 423,
+                AccountError::Unreachable => 503,
+            }
+        }
+    }
+
+    // Fails with the status in the path, leaving the context's status as it was
+    #[middleware_fn(_internal)]
+    async fn fail(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let status = context.params.as_ref().unwrap()["status"].parse().unwrap();
+        context.body("failed");
+
+        Err(ThrusterError::new(context, status, "Something went wrong"))
+    }
+
+    #[middleware_fn(_internal)]
+    async fn locked(
+        context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        Err(ThrusterError::from_error(context, AccountError::Locked))
+    }
+
+    #[middleware_fn(_internal)]
+    async fn unreachable(
+        context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        Err(ThrusterError::from_error(
+            context,
+            AccountError::Unreachable,
+        ))
+    }
+
+    fn failing_app(
+        error_handler: Option<fn(ThrusterError<BasicContext>) -> BasicContext>,
+    ) -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.get("/fail/:status", MiddlewareTuple::A(fail));
+        app.get("/locked", MiddlewareTuple::A(locked));
+        app.get("/unreachable", MiddlewareTuple::A(unreachable));
+        if let Some(error_handler) = error_handler {
+            app.set_error_handler(error_handler);
+        }
+        app.commit()
+    }
+
+    fn problem(response: &testing::TestResponse) -> serde_json::Value {
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "application/problem+json"
+        );
+
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_errors_with_their_status_without_an_error_handler() {
+        let app = failing_app(None);
+
+        let response = testing::get(&app, "/fail/418").await;
+
+        assert_eq!(response.status.1, 418);
+        assert_eq!(response.body, "failed");
+
+        let response = testing::get(&app, "/locked").await;
+
+        assert_eq!(response.status.1, 423);
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_errors_with_invalid_statuses_with_a_500() {
+        let app = failing_app(None);
+
+        assert_eq!(testing::get(&app, "/fail/0").await.status.1, 500);
+        assert_eq!(testing::get(&app, "/fail/99").await.status.1, 500);
+        assert_eq!(testing::get(&app, "/fail/600").await.status.1, 500);
+        assert_eq!(testing::get(&app, "/fail/599").await.status.1, 599);
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_errors_with_the_error_handler() {
+        let app = failing_app(Some(|error| {
+            let locked = error.downcast_cause::<AccountError>().is_some();
+            let mut context = error.context;
+
+            context.status(error.status);
+            context.set("X-Handled", if locked { "account" } else { "other" });
+            context.body(&error.message);
+
+            context
+        }));
+
+        let response = testing::get(&app, "/fail/409").await;
+
+        assert_eq!(response.status.1, 409);
+        assert_eq!(response.headers.get("X-Handled").unwrap(), "other");
+        assert_eq!(response.body, "Something went wrong");
+
+        let response = testing::get(&app, "/locked").await;
+
+        assert_eq!(response.status.1, 423);
+        assert_eq!(response.headers.get("X-Handled").unwrap(), "account");
+        assert_eq!(response.body, "The account is locked");
+    }
+
+    #[tokio::test]
+    async fn it_should_render_errors_as_problem_json() {
+        let app = failing_app(Some(problem_json));
+
+        let response = testing::get(&app, "/locked").await;
+
+        assert_eq!(response.status.1, 423);
+        assert_eq!(
+            problem(&response),
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Locked",
+                "status": 423,
+                "detail": "The account is locked",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_hide_the_detail_of_5xxs_in_problem_json() {
+        let app = failing_app(Some(problem_json));
+
+        let response = testing::get(&app, "/unreachable").await;
+
+        assert_eq!(response.status.1, 503);
+        assert_eq!(
+            problem(&response),
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Service Unavailable",
+                "status": 503,
+            })
+        );
+
+        let response = testing::get(&app, "/fail/1000").await;
+
+        assert_eq!(response.status.1, 500);
+        assert_eq!(problem(&response)["title"], "Internal Server Error");
+        assert!(problem(&response).get("detail").is_none());
+    }
+}
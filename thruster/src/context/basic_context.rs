@@ -1,15 +1,38 @@
+use async_trait::async_trait;
 use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
 use serde::Serialize;
 use serde_json::to_vec;
 use std::collections::HashMap;
-use std::str;
+use std::net::IpAddr;
+use std::{io, str};
 
 use crate::core::context::Context;
-use crate::core::request::Request;
-use crate::core::response::Response;
-
-use crate::middleware::cookies::{Cookie, CookieOptions, HasCookies, SameSite};
-use crate::middleware::query_params::HasQueryParams;
+use crate::core::extractors::{
+    HasClientIp, HasMatchedRoute, HasRequestBody, HasRequestHeaders, HasRequestId,
+    HasRequestMethod, HasRouteParams,
+};
+use crate::core::request::{Request, ThrusterRequest};
+use crate::core::response::{BodyStream, HasBodyStream, HasResponseBody, Response, ResponseBody};
+
+#[cfg(feature = "auth")]
+use crate::middleware::auth::HasPrincipal;
+#[cfg(feature = "cookie_jar")]
+use crate::middleware::cookie_jar::{seal_if_signed, CookieJar, HasCookieJar};
+use crate::middleware::cookies::{
+    set_cookie_header, Cookie, CookieOptions, HasCookies, HasResponseCookies,
+};
+#[cfg(feature = "csrf")]
+use crate::middleware::csrf::HasCsrfToken;
+use crate::middleware::form::HasForm;
+use crate::middleware::query_params::{HasQueryParams, QueryParams};
+#[cfg(feature = "security_headers")]
+use crate::middleware::security_headers::HasCspNonce;
+#[cfg(feature = "sessions")]
+use crate::middleware::sessions::{HasSession, Session};
+use crate::middleware::timeout::Detach;
+#[cfg(feature = "auth")]
+use std::any::Any;
 
 pub fn generate_context<S>(request: Request, _state: &S, _path: &str) -> BasicContext {
     let mut ctx = BasicContext::new();
@@ -24,10 +47,24 @@ pub struct BasicContext {
     response: Response,
     pub cookies: Vec<Cookie>,
     pub params: Option<HashMap<String, String>>,
-    pub query_params: Option<HashMap<String, String>>,
+    pub query_params: Option<QueryParams>,
+    pub form: Option<HashMap<String, String>>,
+    #[cfg(feature = "sessions")]
+    pub session: Option<Session>,
     pub request: Request,
     pub status: u32,
     pub headers: HashMap<String, String>,
+    request_body: Option<BodyStream>,
+    request_body_taken: bool,
+    request_id: Option<String>,
+    #[cfg(feature = "auth")]
+    principal: Option<Box<dyn Any + Send + Sync>>,
+    #[cfg(feature = "csrf")]
+    csrf_token: Option<String>,
+    #[cfg(feature = "security_headers")]
+    csp_nonce: Option<String>,
+    #[cfg(feature = "cookie_jar")]
+    cookie_jar: Option<CookieJar>,
 }
 
 impl Clone for BasicContext {
@@ -44,9 +81,23 @@ impl BasicContext {
             cookies: Vec::new(),
             params: None,
             query_params: None,
+            form: None,
+            #[cfg(feature = "sessions")]
+            session: None,
             request: Request::new(),
             headers: HashMap::new(),
             status: 200,
+            request_body: None,
+            request_body_taken: false,
+            request_id: None,
+            #[cfg(feature = "auth")]
+            principal: None,
+            #[cfg(feature = "csrf")]
+            csrf_token: None,
+            #[cfg(feature = "security_headers")]
+            csp_nonce: None,
+            #[cfg(feature = "cookie_jar")]
+            cookie_jar: None,
         };
 
         ctx.set("Server", "Thruster");
@@ -62,6 +113,17 @@ impl BasicContext {
             .body_bytes_from_vec(body_string.as_bytes().to_vec());
     }
 
+    ///
+    /// Set the body as a stream of bytes, which is sent to the client using chunked
+    /// transfer-encoding as it's produced
+    ///
+    pub fn body_stream<S>(&mut self, stream: S)
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        self.response.body_stream(stream);
+    }
+
     ///
     /// Set Generic Serialize as body and sets header Content-Type to application/json
     ///
@@ -118,48 +180,15 @@ impl BasicContext {
     }
 
     ///
-    /// Sets a cookie on the response
+    /// Sets a cookie on the response. Cookies with `signed` in their options are sealed by the
+    /// request's `CookieJar`.
     ///
     pub fn cookie(&mut self, name: &str, value: &str, options: &CookieOptions) {
-        let cookie_value = match self.headers.get("Set-Cookie") {
-            Some(val) => format!("{}, {}", val, self.cookify_options(name, value, &options)),
-            None => self.cookify_options(name, value, &options),
-        };
-
-        self.set("Set-Cookie", &cookie_value);
-    }
+        #[cfg(feature = "cookie_jar")]
+        let value = &*seal_if_signed(self.cookie_jar.as_ref(), name, value, options);
 
-    fn cookify_options(&self, name: &str, value: &str, options: &CookieOptions) -> String {
-        let mut pieces = vec![format!("Path={}", options.path)];
-
-        if options.expires > 0 {
-            pieces.push(format!("Expires={}", options.expires));
-        }
-
-        if options.max_age > 0 {
-            pieces.push(format!("Max-Age={}", options.max_age));
-        }
-
-        if !options.domain.is_empty() {
-            pieces.push(format!("Domain={}", options.domain));
-        }
-
-        if options.secure {
-            pieces.push("Secure".to_owned());
-        }
-
-        if options.http_only {
-            pieces.push("HttpOnly".to_owned());
-        }
-
-        if let Some(ref same_site) = options.same_site {
-            match same_site {
-                SameSite::Strict => pieces.push("SameSite=Strict".to_owned()),
-                SameSite::Lax => pieces.push("SameSite=Lax".to_owned()),
-            };
-        }
-
-        format!("{}={}; {}", name, value, pieces.join("; "))
+        self.response
+            .header("Set-Cookie", &set_cookie_header(name, value, options));
     }
 }
 
@@ -195,14 +224,47 @@ impl Context for BasicContext {
     fn remove(&mut self, key: &str) {
         self.headers.remove(key);
     }
+
+    fn status(&mut self, code: u32) {
+        self.status = code;
+    }
 }
 
 impl HasQueryParams for BasicContext {
-    fn set_query_params(&mut self, query_params: HashMap<String, String>) {
+    fn set_query_params(&mut self, query_params: QueryParams) {
         self.query_params = Some(query_params);
     }
 }
 
+impl HasForm for BasicContext {
+    fn set_form(&mut self, form: HashMap<String, String>) {
+        self.form = Some(form);
+    }
+}
+
+#[cfg(feature = "sessions")]
+impl HasSession for BasicContext {
+    fn set_session(&mut self, session: Session) {
+        self.session = Some(session);
+    }
+
+    fn take_session(&mut self) -> Option<Session> {
+        self.session.take()
+    }
+}
+
+impl HasBodyStream for BasicContext {
+    fn set_body_stream(&mut self, stream: BodyStream) {
+        self.response.body_stream(stream);
+    }
+}
+
+impl HasResponseCookies for BasicContext {
+    fn set_cookie(&mut self, name: &str, value: &str, options: &CookieOptions) {
+        self.cookie(name, value, options);
+    }
+}
+
 impl HasCookies for BasicContext {
     fn set_cookies(&mut self, cookies: Vec<Cookie>) {
         self.cookies = cookies;
@@ -211,7 +273,8 @@ impl HasCookies for BasicContext {
     fn get_cookies(&self) -> Vec<String> {
         self.request
             .headers()
-            .get("cookie").cloned()
+            .get("cookie")
+            .cloned()
             .unwrap_or_else(std::vec::Vec::new)
     }
 
@@ -222,3 +285,225 @@ impl HasCookies for BasicContext {
         }
     }
 }
+
+impl HasRequestHeaders for BasicContext {
+    fn request_header(&self, key: &str) -> Vec<String> {
+        ThrusterRequest::get_header(&self.request, key)
+    }
+}
+
+impl HasRequestMethod for BasicContext {
+    fn request_method(&self) -> &str {
+        self.request.method()
+    }
+}
+
+impl HasMatchedRoute for BasicContext {
+    fn matched_route(&self) -> Option<&str> {
+        self.request.route.as_deref()
+    }
+}
+
+impl HasClientIp for BasicContext {
+    fn client_ip(&self) -> Option<IpAddr> {
+        self.request.ip
+    }
+}
+
+impl HasRequestId for BasicContext {
+    fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    fn set_request_id(&mut self, id: String) {
+        self.request_id = Some(id);
+    }
+}
+
+#[cfg(feature = "auth")]
+impl HasPrincipal for BasicContext {
+    fn principal_any(&self) -> Option<&(dyn Any + Send + Sync)> {
+        self.principal.as_deref()
+    }
+
+    fn set_principal_any(&mut self, principal: Box<dyn Any + Send + Sync>) {
+        self.principal = Some(principal);
+    }
+}
+
+#[cfg(feature = "csrf")]
+impl HasCsrfToken for BasicContext {
+    fn csrf_token(&self) -> Option<&str> {
+        self.csrf_token.as_deref()
+    }
+
+    fn set_csrf_token(&mut self, token: String) {
+        self.csrf_token = Some(token);
+    }
+}
+
+#[cfg(feature = "security_headers")]
+impl HasCspNonce for BasicContext {
+    fn csp_nonce(&self) -> Option<&str> {
+        self.csp_nonce.as_deref()
+    }
+
+    fn set_csp_nonce(&mut self, nonce: String) {
+        self.csp_nonce = Some(nonce);
+    }
+}
+
+#[cfg(feature = "cookie_jar")]
+impl HasCookieJar for BasicContext {
+    fn cookie_jar(&self) -> Option<&CookieJar> {
+        self.cookie_jar.as_ref()
+    }
+
+    fn set_cookie_jar(&mut self, cookie_jar: CookieJar) {
+        self.cookie_jar = Some(cookie_jar);
+    }
+}
+
+impl Detach for BasicContext {
+    fn detach(&self) -> Self {
+        let mut response = Response::new();
+        response.header_raw = self.response.header_raw.clone();
+
+        BasicContext {
+            response,
+            cookies: self.cookies.clone(),
+            params: self.params.clone(),
+            query_params: self.query_params.clone(),
+            form: self.form.clone(),
+            request: self.request.without_body(),
+            status: self.status,
+            headers: self.headers.clone(),
+            request_id: self.request_id.clone(),
+            #[cfg(feature = "csrf")]
+            csrf_token: self.csrf_token.clone(),
+            #[cfg(feature = "security_headers")]
+            csp_nonce: self.csp_nonce.clone(),
+            #[cfg(feature = "cookie_jar")]
+            cookie_jar: self.cookie_jar.clone(),
+            ..BasicContext::default()
+        }
+    }
+}
+
+impl HasRouteParams for BasicContext {
+    fn route_params(&self) -> Option<&HashMap<String, String>> {
+        self.params.as_ref()
+    }
+}
+
+impl BasicContext {
+    // The body is handed out once, the raw body of the request unless it's been replaced
+    fn take_request_body(&mut self) -> Option<BodyStream> {
+        if self.request_body_taken {
+            return None;
+        }
+
+        self.request_body_taken = true;
+        Some(self.request_body.take().unwrap_or_else(|| {
+            let body = Bytes::copy_from_slice(self.request.raw_body());
+
+            BodyStream::new(futures::stream::once(async move { Ok(body) }))
+        }))
+    }
+}
+
+#[async_trait]
+impl HasRequestBody for BasicContext {
+    async fn request_body(&mut self) -> io::Result<Bytes> {
+        match self.take_request_body() {
+            Some(stream) => {
+                let chunks: Vec<Bytes> = stream.try_collect().await?;
+                Ok(Bytes::from(chunks.concat()))
+            }
+            None => Ok(Bytes::new()),
+        }
+    }
+
+    fn request_body_stream(&mut self) -> BodyStream {
+        self.take_request_body()
+            .unwrap_or_else(|| BodyStream::new(futures::stream::empty()))
+    }
+
+    fn set_request_body_stream(&mut self, stream: BodyStream) {
+        self.request_body = Some(stream);
+        self.request_body_taken = false;
+    }
+}
+
+#[async_trait]
+impl HasResponseBody for BasicContext {
+    fn response_status(&self) -> u16 {
+        self.status as u16
+    }
+
+    fn response_header(&self, key: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.clone())
+    }
+
+    fn response_size(&self) -> Option<u64> {
+        match self.response.body_stream {
+            Some(_) => None,
+            None => Some(self.response.response.len() as u64),
+        }
+    }
+
+    async fn take_response_body(&mut self) -> io::Result<ResponseBody> {
+        Ok(self.response.take_body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::core::request::decode;
+
+    fn posted(body: &str) -> BasicContext {
+        let request = format!(
+            "POST /posts HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let request = decode(&mut BytesMut::from(request.as_str()))
+            .unwrap()
+            .unwrap();
+
+        generate_context(request, &(), "/posts")
+    }
+
+    #[tokio::test]
+    async fn it_should_hand_out_the_request_body_once() {
+        let mut context = posted("hello");
+
+        assert_eq!(context.request_body().await.unwrap(), "hello");
+        assert_eq!(context.request_body().await.unwrap(), "");
+
+        let mut context = posted("hello");
+        let chunks: Vec<Bytes> = context.request_body_stream().try_collect().await.unwrap();
+
+        assert_eq!(chunks.concat(), b"hello");
+        assert_eq!(context.request_body().await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn it_should_hand_out_a_replaced_request_body_again() {
+        let mut context = posted("hello");
+        context.request_body().await.unwrap();
+
+        context.set_request_body_stream(BodyStream::new(futures::stream::once(async {
+            Ok(Bytes::from_static(b"decoded"))
+        })));
+
+        assert_eq!(context.request_body().await.unwrap(), "decoded");
+        assert_eq!(context.request_body().await.unwrap(), "");
+    }
+}
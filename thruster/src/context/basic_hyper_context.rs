@@ -1,62 +1,73 @@
+use async_trait::async_trait;
 use bytes::Bytes;
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
 use http::header::{HeaderMap, HeaderName, HeaderValue, SERVER};
 use http::request::Parts;
+use hyper::body::HttpBody;
 use hyper::{Body, Error, Response, StatusCode};
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::io;
+use std::net::IpAddr;
 use std::str;
 
-
+use crate::context::hyper_request::status_code;
 pub use crate::context::hyper_request::HyperRequest;
 use crate::core::context::Context;
-
-use crate::middleware::query_params::HasQueryParams;
+use crate::core::extractors::{
+    HasClientIp, HasMatchedRoute, HasRequestBody, HasRequestHeaders, HasRequestId,
+    HasRequestMethod, HasRouteParams,
+};
+use crate::core::response::{BodyStream, HasBodyStream, HasResponseBody, ResponseBody};
+
+#[cfg(feature = "auth")]
+use crate::middleware::auth::HasPrincipal;
+#[cfg(feature = "cookie_jar")]
+use crate::middleware::cookie_jar::{seal_if_signed, CookieJar, HasCookieJar};
+use crate::middleware::cookies::{set_cookie_header, HasResponseCookies};
+pub use crate::middleware::cookies::{CookieOptions, SameSite};
+#[cfg(feature = "csrf")]
+use crate::middleware::csrf::HasCsrfToken;
+use crate::middleware::form::HasForm;
+use crate::middleware::query_params::{HasQueryParams, QueryParams};
+#[cfg(feature = "security_headers")]
+use crate::middleware::security_headers::HasCspNonce;
+#[cfg(feature = "sessions")]
+use crate::middleware::sessions::{HasSession, Session};
+use crate::middleware::timeout::Detach;
+#[cfg(feature = "websocket")]
+use crate::middleware::websocket::HasUpgrade;
+#[cfg(feature = "websocket")]
+use hyper::upgrade::OnUpgrade;
+#[cfg(feature = "auth")]
+use std::any::Any;
 
 pub fn generate_context<S>(request: HyperRequest, _state: &S, _path: &str) -> BasicHyperContext {
     BasicHyperContext::new(request)
 }
 
-pub enum SameSite {
-    Strict,
-    Lax,
-}
-
-pub struct CookieOptions {
-    pub domain: String,
-    pub path: String,
-    pub expires: u64,
-    pub http_only: bool,
-    pub max_age: u64,
-    pub secure: bool,
-    pub signed: bool,
-    pub same_site: SameSite,
-}
-
-impl CookieOptions {
-    pub fn default() -> CookieOptions {
-        CookieOptions {
-            domain: "".to_owned(),
-            path: "/".to_owned(),
-            expires: 0,
-            http_only: false,
-            max_age: 0,
-            secure: false,
-            signed: false,
-            same_site: SameSite::Strict,
-        }
-    }
-}
-
 #[derive(Default)]
 pub struct BasicHyperContext {
     pub body: Body,
-    pub query_params: HashMap<String, String>,
+    pub query_params: QueryParams,
+    pub form: HashMap<String, String>,
+    #[cfg(feature = "sessions")]
+    pub session: Option<Session>,
     pub status: u16,
     pub params: Option<HashMap<String, String>>,
     pub hyper_request: Option<HyperRequest>,
     request_body: Option<Body>,
     request_parts: Option<Parts>,
+    ip: Option<IpAddr>,
+    route: Option<String>,
+    request_id: Option<String>,
+    #[cfg(feature = "auth")]
+    principal: Option<Box<dyn Any + Send + Sync>>,
+    #[cfg(feature = "csrf")]
+    csrf_token: Option<String>,
+    #[cfg(feature = "security_headers")]
+    csp_nonce: Option<String>,
+    #[cfg(feature = "cookie_jar")]
+    cookie_jar: Option<CookieJar>,
     http_version: hyper::Version,
     headers: HeaderMap,
 }
@@ -72,17 +83,33 @@ const SERVER_HEADER_NAME: HeaderName = SERVER;
 impl BasicHyperContext {
     pub fn new(req: HyperRequest) -> BasicHyperContext {
         let params = req.params.clone();
+        let ip = req.ip;
+        let route = req.route.clone();
         let mut headers = HeaderMap::new();
         headers.insert(SERVER_HEADER_NAME, HeaderValue::from_static("thruster"));
 
         BasicHyperContext {
             body: Body::empty(),
-            query_params: HashMap::new(),
+            query_params: QueryParams::default(),
+            form: HashMap::new(),
+            #[cfg(feature = "sessions")]
+            session: None,
             status: 200,
             params,
             hyper_request: Some(req),
             request_body: None,
             request_parts: None,
+            ip,
+            route,
+            request_id: None,
+            #[cfg(feature = "auth")]
+            principal: None,
+            #[cfg(feature = "csrf")]
+            csrf_token: None,
+            #[cfg(feature = "security_headers")]
+            csp_nonce: None,
+            #[cfg(feature = "cookie_jar")]
+            cookie_jar: None,
             http_version: hyper::Version::HTTP_11,
             headers,
         }
@@ -117,11 +144,25 @@ impl BasicHyperContext {
             BasicHyperContext {
                 body: ctx.body,
                 query_params: ctx.query_params,
+                form: ctx.form,
+                #[cfg(feature = "sessions")]
+                session: ctx.session,
                 status: ctx.status,
                 params: ctx.params,
                 hyper_request: ctx.hyper_request,
                 request_body: Some(Body::empty()),
                 request_parts: ctx.request_parts,
+                ip: ctx.ip,
+                route: ctx.route,
+                request_id: ctx.request_id,
+                #[cfg(feature = "auth")]
+                principal: ctx.principal,
+                #[cfg(feature = "csrf")]
+                csrf_token: ctx.csrf_token,
+                #[cfg(feature = "security_headers")]
+                csp_nonce: ctx.csp_nonce,
+                #[cfg(feature = "cookie_jar")]
+                cookie_jar: ctx.cookie_jar,
                 http_version: ctx.http_version,
                 headers: ctx.headers,
             },
@@ -143,21 +184,59 @@ impl BasicHyperContext {
         BasicHyperContext {
             body: self.body,
             query_params: self.query_params,
+            form: self.form,
+            #[cfg(feature = "sessions")]
+            session: self.session,
             status: self.status,
             params: hyper_request.params,
             hyper_request: None,
             request_body: Some(body),
             request_parts: Some(parts),
+            ip: self.ip,
+            route: self.route,
+            request_id: self.request_id,
+            #[cfg(feature = "auth")]
+            principal: self.principal,
+            #[cfg(feature = "csrf")]
+            csrf_token: self.csrf_token,
+            #[cfg(feature = "security_headers")]
+            csp_nonce: self.csp_nonce,
+            #[cfg(feature = "cookie_jar")]
+            cookie_jar: self.cookie_jar,
             http_version: self.http_version,
             headers: self.headers,
         }
     }
 
+    ///
+    /// Takes the request body, leaving an empty one in its place, while keeping the rest of
+    /// the request around.
+    ///
+    fn take_request_body(&mut self) -> Option<Body> {
+        if let Some(hyper_request) = self.hyper_request.take() {
+            let (parts, body) = hyper_request.request.into_parts();
+            self.request_parts = Some(parts);
+            self.request_body = Some(body);
+        }
+
+        self.request_body.replace(Body::empty())
+    }
+
+    ///
+    /// Set the body as a stream of bytes, which is sent to the client as it's produced
+    ///
+    pub fn body_stream<St>(&mut self, stream: St)
+    where
+        St: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        self.body = Body::wrap_stream(stream);
+    }
+
     ///
     /// Set the response status code
     ///
     pub fn status(&mut self, code: u32) {
-        self.status = code.try_into().unwrap();
+        self.status = status_code(code);
     }
 
     ///
@@ -187,50 +266,14 @@ impl BasicHyperContext {
     }
 
     ///
-    /// Sets a cookie on the response
+    /// Sets a cookie on the response. Cookies with `signed` in their options are sealed by the
+    /// request's `CookieJar`.
     ///
     pub fn cookie(&mut self, name: &str, value: &str, options: &CookieOptions) {
-        let cookie_value = match self.headers.get("Set-Cookie") {
-            Some(val) => format!(
-                "{}, {}",
-                val.to_str().unwrap_or(""),
-                self.cookify_options(name, value, &options)
-            ),
-            None => self.cookify_options(name, value, &options),
-        };
+        #[cfg(feature = "cookie_jar")]
+        let value = &*seal_if_signed(self.cookie_jar.as_ref(), name, value, options);
 
-        self.set("Set-Cookie", &cookie_value);
-    }
-
-    fn cookify_options(&self, name: &str, value: &str, options: &CookieOptions) -> String {
-        let mut pieces = vec![format!("Path={}", options.path)];
-
-        if options.expires > 0 {
-            pieces.push(format!("Expires={}", options.expires));
-        }
-
-        if options.max_age > 0 {
-            pieces.push(format!("Max-Age={}", options.max_age));
-        }
-
-        if !options.domain.is_empty() {
-            pieces.push(format!("Domain={}", options.domain));
-        }
-
-        if options.secure {
-            pieces.push("Secure".to_owned());
-        }
-
-        if options.http_only {
-            pieces.push("HttpOnly".to_owned());
-        }
-
-        match options.same_site {
-            SameSite::Strict => pieces.push("SameSite=Strict".to_owned()),
-            SameSite::Lax => pieces.push("SameSite=Lax".to_owned()),
-        };
-
-        format!("{}={}; {}", name, value, pieces.join("; "))
+        self.set("Set-Cookie", &set_cookie_header(name, value, options));
     }
 
     pub fn set_http2(&mut self) {
@@ -252,7 +295,8 @@ impl Context for BasicHyperContext {
     fn get_response(self) -> Self::Response {
         let mut response = Response::new(self.body);
 
-        *response.status_mut() = StatusCode::from_u16(self.status).unwrap();
+        *response.status_mut() =
+            StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
         *response.headers_mut() = self.headers;
         *response.version_mut() = self.http_version;
 
@@ -268,7 +312,12 @@ impl Context for BasicHyperContext {
     }
 
     fn route(&self) -> &str {
-        let uri = self.hyper_request.as_ref().unwrap().request.uri();
+        // Once the body has been taken, the rest of the request is kept in its parts
+        let uri = match (&self.hyper_request, &self.request_parts) {
+            (Some(hyper_request), _) => hyper_request.request.uri(),
+            (None, Some(parts)) => &parts.uri,
+            (None, None) => return "",
+        };
 
         match uri.path_and_query() {
             Some(val) => val.as_str(),
@@ -286,10 +335,315 @@ impl Context for BasicHyperContext {
     fn remove(&mut self, key: &str) {
         self.headers.remove(key);
     }
+
+    fn status(&mut self, code: u32) {
+        self.status = status_code(code);
+    }
 }
 
 impl HasQueryParams for BasicHyperContext {
-    fn set_query_params(&mut self, query_params: HashMap<String, String>) {
+    fn set_query_params(&mut self, query_params: QueryParams) {
         self.query_params = query_params;
     }
 }
+
+impl HasResponseCookies for BasicHyperContext {
+    fn set_cookie(&mut self, name: &str, value: &str, options: &CookieOptions) {
+        self.cookie(name, value, options);
+    }
+}
+
+impl HasForm for BasicHyperContext {
+    fn set_form(&mut self, form: HashMap<String, String>) {
+        self.form = form;
+    }
+}
+
+#[cfg(feature = "sessions")]
+impl HasSession for BasicHyperContext {
+    fn set_session(&mut self, session: Session) {
+        self.session = Some(session);
+    }
+
+    fn take_session(&mut self) -> Option<Session> {
+        self.session.take()
+    }
+}
+
+impl HasRequestHeaders for BasicHyperContext {
+    fn request_header(&self, key: &str) -> Vec<String> {
+        let headers = match (&self.hyper_request, &self.request_parts) {
+            (Some(hyper_request), _) => hyper_request.request.headers(),
+            (None, Some(parts)) => &parts.headers,
+            (None, None) => return Vec::new(),
+        };
+
+        headers
+            .get_all(key)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+            .collect()
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl HasUpgrade for BasicHyperContext {
+    fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        match (&mut self.hyper_request, &mut self.request_parts) {
+            (Some(hyper_request), _) => hyper_request.request.extensions_mut().remove(),
+            (None, Some(parts)) => parts.extensions.remove(),
+            (None, None) => None,
+        }
+    }
+}
+
+impl HasBodyStream for BasicHyperContext {
+    fn set_body_stream(&mut self, stream: BodyStream) {
+        self.body_stream(stream);
+    }
+}
+
+impl HasRequestMethod for BasicHyperContext {
+    fn request_method(&self) -> &str {
+        match (&self.hyper_request, &self.request_parts) {
+            (Some(hyper_request), _) => hyper_request.request.method().as_str(),
+            (None, Some(parts)) => parts.method.as_str(),
+            (None, None) => "",
+        }
+    }
+}
+
+impl HasMatchedRoute for BasicHyperContext {
+    fn matched_route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+}
+
+impl HasClientIp for BasicHyperContext {
+    fn client_ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+}
+
+impl HasRequestId for BasicHyperContext {
+    fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    fn set_request_id(&mut self, id: String) {
+        self.request_id = Some(id);
+    }
+}
+
+#[cfg(feature = "auth")]
+impl HasPrincipal for BasicHyperContext {
+    fn principal_any(&self) -> Option<&(dyn Any + Send + Sync)> {
+        self.principal.as_deref()
+    }
+
+    fn set_principal_any(&mut self, principal: Box<dyn Any + Send + Sync>) {
+        self.principal = Some(principal);
+    }
+}
+
+#[cfg(feature = "csrf")]
+impl HasCsrfToken for BasicHyperContext {
+    fn csrf_token(&self) -> Option<&str> {
+        self.csrf_token.as_deref()
+    }
+
+    fn set_csrf_token(&mut self, token: String) {
+        self.csrf_token = Some(token);
+    }
+}
+
+#[cfg(feature = "security_headers")]
+impl HasCspNonce for BasicHyperContext {
+    fn csp_nonce(&self) -> Option<&str> {
+        self.csp_nonce.as_deref()
+    }
+
+    fn set_csp_nonce(&mut self, nonce: String) {
+        self.csp_nonce = Some(nonce);
+    }
+}
+
+#[cfg(feature = "cookie_jar")]
+impl HasCookieJar for BasicHyperContext {
+    fn cookie_jar(&self) -> Option<&CookieJar> {
+        self.cookie_jar.as_ref()
+    }
+
+    fn set_cookie_jar(&mut self, cookie_jar: CookieJar) {
+        self.cookie_jar = Some(cookie_jar);
+    }
+}
+
+impl Detach for BasicHyperContext {
+    fn detach(&self) -> Self {
+        let mut hyper_request =
+            HyperRequest::head_of(self.hyper_request.as_ref(), self.request_parts.as_ref());
+        hyper_request.params = self.params.clone();
+        hyper_request.ip = self.ip;
+        hyper_request.route = self.route.clone();
+
+        BasicHyperContext {
+            query_params: self.query_params.clone(),
+            form: self.form.clone(),
+            status: self.status,
+            params: self.params.clone(),
+            hyper_request: Some(hyper_request),
+            ip: self.ip,
+            route: self.route.clone(),
+            request_id: self.request_id.clone(),
+            #[cfg(feature = "csrf")]
+            csrf_token: self.csrf_token.clone(),
+            #[cfg(feature = "security_headers")]
+            csp_nonce: self.csp_nonce.clone(),
+            #[cfg(feature = "cookie_jar")]
+            cookie_jar: self.cookie_jar.clone(),
+            http_version: self.http_version,
+            headers: self.headers.clone(),
+            ..BasicHyperContext::default()
+        }
+    }
+}
+
+impl HasRouteParams for BasicHyperContext {
+    fn route_params(&self) -> Option<&HashMap<String, String>> {
+        self.params.as_ref()
+    }
+}
+
+#[async_trait]
+impl HasRequestBody for BasicHyperContext {
+    async fn request_body(&mut self) -> io::Result<Bytes> {
+        match self.take_request_body() {
+            Some(body) => hyper::body::to_bytes(body).await.map_err(io::Error::other),
+            None => Ok(Bytes::new()),
+        }
+    }
+
+    fn request_body_stream(&mut self) -> BodyStream {
+        let body = self.take_request_body().unwrap_or_else(Body::empty);
+
+        BodyStream::new(body.map(|chunk| chunk.map_err(io::Error::other)))
+    }
+
+    fn set_request_body_stream(&mut self, stream: BodyStream) {
+        self.take_request_body();
+        self.request_body = Some(Body::wrap_stream(stream));
+    }
+}
+
+#[async_trait]
+impl HasResponseBody for BasicHyperContext {
+    fn response_status(&self) -> u16 {
+        self.status
+    }
+
+    fn response_header(&self, key: &str) -> Option<String> {
+        let values: Vec<&str> = self
+            .headers
+            .get_all(key)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    fn response_size(&self) -> Option<u64> {
+        HttpBody::size_hint(&self.body).exact()
+    }
+
+    async fn take_response_body(&mut self) -> io::Result<ResponseBody> {
+        let body = std::mem::take(&mut self.body);
+
+        // Bodies made from bytes know their exact length, and can be read without waiting
+        if HttpBody::size_hint(&body).exact().is_some() {
+            let bytes = hyper::body::to_bytes(body)
+                .await
+                .map_err(io::Error::other)?;
+
+            return Ok(ResponseBody::Bytes(bytes));
+        }
+
+        Ok(ResponseBody::Stream(BodyStream::new(
+            body.map(|chunk| chunk.map_err(io::Error::other)),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::extractors::{FromContext, Query};
+
+    fn context() -> BasicHyperContext {
+        let request = hyper::Request::post("/posts?page=2&tag=rust")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"title":"Hello"}"#))
+            .unwrap();
+
+        BasicHyperContext::new(HyperRequest::new(request))
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_the_request_once_its_body_is_read() {
+        let mut context = context();
+
+        assert_eq!(
+            context.request_body().await.unwrap(),
+            Bytes::from_static(br#"{"title":"Hello"}"#)
+        );
+        assert_eq!(context.route(), "/posts?page=2&tag=rust");
+        assert_eq!(context.request_method(), "POST");
+        assert_eq!(
+            context.request_header("content-type"),
+            vec!["application/json"]
+        );
+
+        let Query(query) = Query::<HashMap<String, String>>::from_context(&mut context)
+            .await
+            .unwrap();
+
+        assert_eq!(query["page"], "2");
+        assert_eq!(query["tag"], "rust");
+    }
+
+    #[test]
+    fn it_should_send_a_500_for_invalid_statuses() {
+        for (code, sent) in [
+            (404, 404),
+            (999, 999),
+            (99, 500),
+            (1000, 500),
+            (70_000, 500),
+        ] {
+            let mut context = context();
+            Context::status(&mut context, code);
+
+            assert_eq!(context.get_response().status().as_u16(), sent);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_the_request_once_its_body_is_streamed() {
+        let mut context = context();
+
+        let stream = context.request_body_stream();
+        context.set_request_body_stream(stream);
+
+        assert_eq!(context.route(), "/posts?page=2&tag=rust");
+        assert_eq!(
+            context.request_body().await.unwrap(),
+            Bytes::from_static(br#"{"title":"Hello"}"#)
+        );
+    }
+}
@@ -3,6 +3,7 @@ use http::header::{HeaderName, HeaderValue, SERVER};
 use hyper::{Body, Response, StatusCode};
 use std::str;
 
+use crate::context::hyper_request::status_code;
 pub use crate::context::hyper_request::HyperRequest;
 use crate::core::context::Context;
 
@@ -56,7 +57,8 @@ impl Context for FastHyperContext {
     fn get_response(self) -> Self::Response {
         let mut response = Response::new(self.body.unwrap());
 
-        *response.status_mut() = StatusCode::from_u16(self.status).unwrap();
+        *response.status_mut() =
+            StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
         *response.version_mut() = self.http_version;
         *response.headers_mut() = self.hyper_request.unwrap().request.into_parts().0.headers;
         response
@@ -103,4 +105,8 @@ impl Context for FastHyperContext {
             .headers_mut()
             .remove(key);
     }
+
+    fn status(&mut self, code: u32) {
+        self.status = status_code(code);
+    }
 }
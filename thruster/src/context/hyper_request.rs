@@ -1,10 +1,24 @@
 use http::request::Parts;
-use hyper::{Body, Request};
+use hyper::{Body, Request, StatusCode};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::net::IpAddr;
 
-// use crate::core::request::RequestWithParams;
-// use crate::parser::tree::Params;
+use crate::core::request::RequestWithParams;
+use crate::parser::tree::Params;
+
+///
+/// The status to send for `code`, which is a `500` if it isn't one that hyper can send.
+///
+pub(crate) fn status_code(code: u32) -> u16 {
+    match u16::try_from(code) {
+        Ok(code) if StatusCode::from_u16(code).is_ok() => code,
+        _ => {
+            warn!("Invalid status code {}, sending a 500 instead", code);
+            500
+        }
+    }
+}
 
 pub struct HyperRequest {
     pub request: Request<Body>,
@@ -12,6 +26,7 @@ pub struct HyperRequest {
     pub body: Option<Body>,
     pub params: Option<HashMap<String, String>>,
     pub ip: Option<IpAddr>,
+    pub route: Option<String>,
 }
 
 impl HyperRequest {
@@ -22,8 +37,34 @@ impl HyperRequest {
             body: None,
             params: None,
             ip: None,
+            route: None,
         }
     }
+
+    ///
+    /// A copy of the method, URI, version and headers of a request, from the request itself or
+    /// from its parts once its body has been taken, with an empty body.
+    ///
+    pub(crate) fn head_of(request: Option<&HyperRequest>, parts: Option<&Parts>) -> HyperRequest {
+        let (method, uri, version, headers) = match (request, parts) {
+            (Some(request), _) => (
+                request.request.method(),
+                request.request.uri(),
+                request.request.version(),
+                request.request.headers(),
+            ),
+            (None, Some(parts)) => (&parts.method, &parts.uri, parts.version, &parts.headers),
+            (None, None) => return HyperRequest::default(),
+        };
+
+        let mut copy = Request::new(Body::empty());
+        *copy.method_mut() = method.clone();
+        *copy.uri_mut() = uri.clone();
+        *copy.version_mut() = version;
+        *copy.headers_mut() = headers.clone();
+
+        HyperRequest::new(copy)
+    }
 }
 
 impl Default for HyperRequest {
@@ -31,3 +72,13 @@ impl Default for HyperRequest {
         HyperRequest::new(Request::default())
     }
 }
+
+impl RequestWithParams for HyperRequest {
+    fn set_params(&mut self, params: Params) {
+        self.params = Some(params.into());
+    }
+
+    fn set_route(&mut self, route: &str) {
+        self.route = Some(route.to_owned());
+    }
+}
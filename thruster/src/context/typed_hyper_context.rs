@@ -1,23 +1,53 @@
+use async_trait::async_trait;
 use bytes::Bytes;
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
 use http::header::{HeaderMap, HeaderName, HeaderValue};
 use http::request::Parts;
+use hyper::body::HttpBody;
 use hyper::{Body, Error, Response, StatusCode};
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::io;
+use std::net::IpAddr;
 use std::str;
 
-
-use crate::context::hyper_request::HyperRequest;
+use crate::context::hyper_request::{status_code, HyperRequest};
 use crate::core::context::Context;
-
-use crate::middleware::cookies::{Cookie, CookieOptions, HasCookies, SameSite};
-use crate::middleware::query_params::HasQueryParams;
+use crate::core::extractors::{
+    HasClientIp, HasMatchedRoute, HasRequestBody, HasRequestHeaders, HasRequestId,
+    HasRequestMethod, HasRouteParams,
+};
+use crate::core::response::{BodyStream, HasBodyStream, HasResponseBody, ResponseBody};
+
+#[cfg(feature = "auth")]
+use crate::middleware::auth::HasPrincipal;
+#[cfg(feature = "cookie_jar")]
+use crate::middleware::cookie_jar::{seal_if_signed, CookieJar, HasCookieJar};
+use crate::middleware::cookies::{
+    set_cookie_header, Cookie, CookieOptions, HasCookies, HasResponseCookies,
+};
+#[cfg(feature = "csrf")]
+use crate::middleware::csrf::HasCsrfToken;
+use crate::middleware::form::HasForm;
+use crate::middleware::query_params::{HasQueryParams, QueryParams};
+#[cfg(feature = "security_headers")]
+use crate::middleware::security_headers::HasCspNonce;
+#[cfg(feature = "sessions")]
+use crate::middleware::sessions::{HasSession, Session};
+use crate::middleware::timeout::Detach;
+#[cfg(feature = "websocket")]
+use crate::middleware::websocket::HasUpgrade;
+#[cfg(feature = "websocket")]
+use hyper::upgrade::OnUpgrade;
+#[cfg(feature = "auth")]
+use std::any::Any;
 
 #[derive(Default)]
 pub struct TypedHyperContext<S: 'static + Send> {
     pub body: Body,
-    pub query_params: HashMap<String, String>,
+    pub query_params: QueryParams,
+    pub form: HashMap<String, String>,
+    #[cfg(feature = "sessions")]
+    pub session: Option<Session>,
     pub status: u16,
     pub headers: HeaderMap,
     pub params: Option<HashMap<String, String>>,
@@ -27,6 +57,17 @@ pub struct TypedHyperContext<S: 'static + Send> {
     http_version: hyper::Version,
     request_body: Option<Body>,
     request_parts: Option<Parts>,
+    ip: Option<IpAddr>,
+    route: Option<String>,
+    request_id: Option<String>,
+    #[cfg(feature = "auth")]
+    principal: Option<Box<dyn Any + Send + Sync>>,
+    #[cfg(feature = "csrf")]
+    csrf_token: Option<String>,
+    #[cfg(feature = "security_headers")]
+    csp_nonce: Option<String>,
+    #[cfg(feature = "cookie_jar")]
+    cookie_jar: Option<CookieJar>,
 }
 
 impl<S: 'static + Send> Clone for TypedHyperContext<S> {
@@ -38,15 +79,31 @@ impl<S: 'static + Send> Clone for TypedHyperContext<S> {
 impl<S: 'static + Send> TypedHyperContext<S> {
     pub fn new(req: HyperRequest, extra: S) -> TypedHyperContext<S> {
         let params = req.params.clone();
+        let ip = req.ip;
+        let route = req.route.clone();
         let mut ctx = TypedHyperContext {
             body: Body::empty(),
-            query_params: HashMap::new(),
+            query_params: QueryParams::default(),
+            form: HashMap::new(),
+            #[cfg(feature = "sessions")]
+            session: None,
             headers: HeaderMap::new(),
             status: 200,
             params,
             hyper_request: Some(req),
             request_body: None,
             request_parts: None,
+            ip,
+            route,
+            request_id: None,
+            #[cfg(feature = "auth")]
+            principal: None,
+            #[cfg(feature = "csrf")]
+            csrf_token: None,
+            #[cfg(feature = "security_headers")]
+            csp_nonce: None,
+            #[cfg(feature = "cookie_jar")]
+            cookie_jar: None,
             extra,
             http_version: hyper::Version::HTTP_11,
             cookies: HashMap::new(),
@@ -61,13 +118,27 @@ impl<S: 'static + Send> TypedHyperContext<S> {
         let params = None;
         let mut ctx = TypedHyperContext {
             body: Body::empty(),
-            query_params: HashMap::new(),
+            query_params: QueryParams::default(),
+            form: HashMap::new(),
+            #[cfg(feature = "sessions")]
+            session: None,
             headers: HeaderMap::new(),
             status: 200,
             params,
             hyper_request: None,
             request_body: None,
             request_parts: None,
+            ip: None,
+            route: None,
+            request_id: None,
+            #[cfg(feature = "auth")]
+            principal: None,
+            #[cfg(feature = "csrf")]
+            csrf_token: None,
+            #[cfg(feature = "security_headers")]
+            csp_nonce: None,
+            #[cfg(feature = "cookie_jar")]
+            cookie_jar: None,
             extra,
             http_version: hyper::Version::HTTP_11,
             cookies: HashMap::new(),
@@ -107,12 +178,26 @@ impl<S: 'static + Send> TypedHyperContext<S> {
             TypedHyperContext {
                 body: ctx.body,
                 query_params: ctx.query_params,
+                form: ctx.form,
+                #[cfg(feature = "sessions")]
+                session: ctx.session,
                 headers: ctx.headers,
                 status: ctx.status,
                 params: ctx.params,
                 hyper_request: ctx.hyper_request,
                 request_body: Some(Body::empty()),
                 request_parts: ctx.request_parts,
+                ip: ctx.ip,
+                route: ctx.route,
+                request_id: ctx.request_id,
+                #[cfg(feature = "auth")]
+                principal: ctx.principal,
+                #[cfg(feature = "csrf")]
+                csrf_token: ctx.csrf_token,
+                #[cfg(feature = "security_headers")]
+                csp_nonce: ctx.csp_nonce,
+                #[cfg(feature = "cookie_jar")]
+                cookie_jar: ctx.cookie_jar,
                 extra: ctx.extra,
                 http_version: ctx.http_version,
                 cookies: ctx.cookies,
@@ -134,12 +219,26 @@ impl<S: 'static + Send> TypedHyperContext<S> {
                 TypedHyperContext {
                     body: self.body,
                     query_params: self.query_params,
+                    form: self.form,
+                    #[cfg(feature = "sessions")]
+                    session: self.session,
                     headers: self.headers,
                     status: self.status,
                     params: hyper_request.params,
                     hyper_request: None,
                     request_body: Some(body),
                     request_parts: Some(parts),
+                    ip: self.ip,
+                    route: self.route,
+                    request_id: self.request_id,
+                    #[cfg(feature = "auth")]
+                    principal: self.principal,
+                    #[cfg(feature = "csrf")]
+                    csrf_token: self.csrf_token,
+                    #[cfg(feature = "security_headers")]
+                    csp_nonce: self.csp_nonce,
+                    #[cfg(feature = "cookie_jar")]
+                    cookie_jar: self.cookie_jar,
                     extra: self.extra,
                     http_version: self.http_version,
                     cookies: self.cookies,
@@ -149,11 +248,35 @@ impl<S: 'static + Send> TypedHyperContext<S> {
         }
     }
 
+    ///
+    /// Takes the request body, leaving an empty one in its place, while keeping the rest of
+    /// the request around.
+    ///
+    fn take_request_body(&mut self) -> Option<Body> {
+        if let Some(hyper_request) = self.hyper_request.take() {
+            let (parts, body) = hyper_request.request.into_parts();
+            self.request_parts = Some(parts);
+            self.request_body = Some(body);
+        }
+
+        self.request_body.replace(Body::empty())
+    }
+
+    ///
+    /// Set the body as a stream of bytes, which is sent to the client as it's produced
+    ///
+    pub fn body_stream<St>(&mut self, stream: St)
+    where
+        St: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        self.body = Body::wrap_stream(stream);
+    }
+
     ///
     /// Set the response status code
     ///
     pub fn status(&mut self, code: u32) {
-        self.status = code.try_into().unwrap();
+        self.status = status_code(code);
     }
 
     ///
@@ -183,51 +306,14 @@ impl<S: 'static + Send> TypedHyperContext<S> {
     }
 
     ///
-    /// Sets a cookie on the response
+    /// Sets a cookie on the response. Cookies with `signed` in their options are sealed by the
+    /// request's `CookieJar`.
     ///
     pub fn cookie(&mut self, name: &str, value: &str, options: &CookieOptions) {
-        let cookie_value = match self.headers.get("Set-Cookie") {
-            Some(val) => format!(
-                "{}, {}",
-                val.to_str().unwrap_or(""),
-                self.cookify_options(name, value, &options)
-            ),
-            None => self.cookify_options(name, value, &options),
-        };
+        #[cfg(feature = "cookie_jar")]
+        let value = &*seal_if_signed(self.cookie_jar.as_ref(), name, value, options);
 
-        self.set("Set-Cookie", &cookie_value);
-    }
-
-    fn cookify_options(&self, name: &str, value: &str, options: &CookieOptions) -> String {
-        let mut pieces = vec![format!("Path={}", options.path)];
-
-        if options.expires > 0 {
-            pieces.push(format!("Expires={}", options.expires));
-        }
-
-        if options.max_age > 0 {
-            pieces.push(format!("Max-Age={}", options.max_age));
-        }
-
-        if !options.domain.is_empty() {
-            pieces.push(format!("Domain={}", options.domain));
-        }
-
-        if options.secure {
-            pieces.push("Secure".to_owned());
-        }
-
-        if options.http_only {
-            pieces.push("HttpOnly".to_owned());
-        }
-
-        match options.same_site {
-            Some(SameSite::Strict) => pieces.push("SameSite=Strict".to_owned()),
-            Some(SameSite::Lax) => pieces.push("SameSite=Lax".to_owned()),
-            None => (),
-        };
-
-        format!("{}={}; {}", name, value, pieces.join("; "))
+        self.set("Set-Cookie", &set_cookie_header(name, value, options));
     }
 
     pub fn set_http2(&mut self) {
@@ -249,7 +335,8 @@ impl<S: 'static + Send> Context for TypedHyperContext<S> {
     fn get_response(self) -> Self::Response {
         let mut response = Response::new(self.body);
 
-        *response.status_mut() = StatusCode::from_u16(self.status).unwrap();
+        *response.status_mut() =
+            StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
         *response.headers_mut() = self.headers;
         *response.version_mut() = self.http_version;
 
@@ -265,7 +352,12 @@ impl<S: 'static + Send> Context for TypedHyperContext<S> {
     }
 
     fn route(&self) -> &str {
-        let uri = self.hyper_request.as_ref().unwrap().request.uri();
+        // Once the body has been taken, the rest of the request is kept in its parts
+        let uri = match (&self.hyper_request, &self.request_parts) {
+            (Some(hyper_request), _) => hyper_request.request.uri(),
+            (None, Some(parts)) => &parts.uri,
+            (None, None) => return "",
+        };
 
         match uri.path_and_query() {
             Some(val) => val.as_str(),
@@ -283,14 +375,41 @@ impl<S: 'static + Send> Context for TypedHyperContext<S> {
     fn remove(&mut self, key: &str) {
         self.headers.remove(key);
     }
+
+    fn status(&mut self, code: u32) {
+        self.status = status_code(code);
+    }
 }
 
 impl<S: 'static + Send> HasQueryParams for TypedHyperContext<S> {
-    fn set_query_params(&mut self, query_params: HashMap<String, String>) {
+    fn set_query_params(&mut self, query_params: QueryParams) {
         self.query_params = query_params;
     }
 }
 
+impl<S: 'static + Send> HasForm for TypedHyperContext<S> {
+    fn set_form(&mut self, form: HashMap<String, String>) {
+        self.form = form;
+    }
+}
+
+#[cfg(feature = "sessions")]
+impl<S: 'static + Send> HasSession for TypedHyperContext<S> {
+    fn set_session(&mut self, session: Session) {
+        self.session = Some(session);
+    }
+
+    fn take_session(&mut self) -> Option<Session> {
+        self.session.take()
+    }
+}
+
+impl<S: 'static + Send> HasResponseCookies for TypedHyperContext<S> {
+    fn set_cookie(&mut self, name: &str, value: &str, options: &CookieOptions) {
+        self.cookie(name, value, options);
+    }
+}
+
 impl<S: 'static + Send> HasCookies for TypedHyperContext<S> {
     fn set_cookies(&mut self, cookies: Vec<Cookie>) {
         self.cookies.clear();
@@ -322,3 +441,253 @@ impl<S: 'static + Send> HasCookies for TypedHyperContext<S> {
             .collect()
     }
 }
+
+impl<S: 'static + Send> HasRequestHeaders for TypedHyperContext<S> {
+    fn request_header(&self, key: &str) -> Vec<String> {
+        let headers = match (&self.hyper_request, &self.request_parts) {
+            (Some(hyper_request), _) => hyper_request.request.headers(),
+            (None, Some(parts)) => &parts.headers,
+            (None, None) => return Vec::new(),
+        };
+
+        headers
+            .get_all(key)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+            .collect()
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl<S: 'static + Send> HasUpgrade for TypedHyperContext<S> {
+    fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        match (&mut self.hyper_request, &mut self.request_parts) {
+            (Some(hyper_request), _) => hyper_request.request.extensions_mut().remove(),
+            (None, Some(parts)) => parts.extensions.remove(),
+            (None, None) => None,
+        }
+    }
+}
+
+impl<S: 'static + Send> HasBodyStream for TypedHyperContext<S> {
+    fn set_body_stream(&mut self, stream: BodyStream) {
+        self.body_stream(stream);
+    }
+}
+
+impl<S: 'static + Send> HasRequestMethod for TypedHyperContext<S> {
+    fn request_method(&self) -> &str {
+        match (&self.hyper_request, &self.request_parts) {
+            (Some(hyper_request), _) => hyper_request.request.method().as_str(),
+            (None, Some(parts)) => parts.method.as_str(),
+            (None, None) => "",
+        }
+    }
+}
+
+impl<S: 'static + Send> HasMatchedRoute for TypedHyperContext<S> {
+    fn matched_route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+}
+
+impl<S: 'static + Send> HasClientIp for TypedHyperContext<S> {
+    fn client_ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+}
+
+impl<S: 'static + Send> HasRequestId for TypedHyperContext<S> {
+    fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    fn set_request_id(&mut self, id: String) {
+        self.request_id = Some(id);
+    }
+}
+
+#[cfg(feature = "auth")]
+impl<S: 'static + Send> HasPrincipal for TypedHyperContext<S> {
+    fn principal_any(&self) -> Option<&(dyn Any + Send + Sync)> {
+        self.principal.as_deref()
+    }
+
+    fn set_principal_any(&mut self, principal: Box<dyn Any + Send + Sync>) {
+        self.principal = Some(principal);
+    }
+}
+
+#[cfg(feature = "csrf")]
+impl<S: 'static + Send> HasCsrfToken for TypedHyperContext<S> {
+    fn csrf_token(&self) -> Option<&str> {
+        self.csrf_token.as_deref()
+    }
+
+    fn set_csrf_token(&mut self, token: String) {
+        self.csrf_token = Some(token);
+    }
+}
+
+#[cfg(feature = "security_headers")]
+impl<S: 'static + Send> HasCspNonce for TypedHyperContext<S> {
+    fn csp_nonce(&self) -> Option<&str> {
+        self.csp_nonce.as_deref()
+    }
+
+    fn set_csp_nonce(&mut self, nonce: String) {
+        self.csp_nonce = Some(nonce);
+    }
+}
+
+#[cfg(feature = "cookie_jar")]
+impl<S: 'static + Send> HasCookieJar for TypedHyperContext<S> {
+    fn cookie_jar(&self) -> Option<&CookieJar> {
+        self.cookie_jar.as_ref()
+    }
+
+    fn set_cookie_jar(&mut self, cookie_jar: CookieJar) {
+        self.cookie_jar = Some(cookie_jar);
+    }
+}
+
+impl<S: 'static + Send + Clone> Detach for TypedHyperContext<S> {
+    fn detach(&self) -> Self {
+        let mut hyper_request =
+            HyperRequest::head_of(self.hyper_request.as_ref(), self.request_parts.as_ref());
+        hyper_request.params = self.params.clone();
+        hyper_request.ip = self.ip;
+        hyper_request.route = self.route.clone();
+
+        TypedHyperContext {
+            body: Body::empty(),
+            query_params: self.query_params.clone(),
+            form: self.form.clone(),
+            #[cfg(feature = "sessions")]
+            session: None,
+            status: self.status,
+            headers: self.headers.clone(),
+            params: self.params.clone(),
+            hyper_request: Some(hyper_request),
+            extra: self.extra.clone(),
+            cookies: self.cookies.clone(),
+            http_version: self.http_version,
+            request_body: None,
+            request_parts: None,
+            ip: self.ip,
+            route: self.route.clone(),
+            request_id: self.request_id.clone(),
+            #[cfg(feature = "auth")]
+            principal: None,
+            #[cfg(feature = "csrf")]
+            csrf_token: self.csrf_token.clone(),
+            #[cfg(feature = "security_headers")]
+            csp_nonce: self.csp_nonce.clone(),
+            #[cfg(feature = "cookie_jar")]
+            cookie_jar: self.cookie_jar.clone(),
+        }
+    }
+}
+
+impl<S: 'static + Send> HasRouteParams for TypedHyperContext<S> {
+    fn route_params(&self) -> Option<&HashMap<String, String>> {
+        self.params.as_ref()
+    }
+}
+
+#[async_trait]
+impl<S: 'static + Send> HasRequestBody for TypedHyperContext<S> {
+    async fn request_body(&mut self) -> io::Result<Bytes> {
+        match self.take_request_body() {
+            Some(body) => hyper::body::to_bytes(body).await.map_err(io::Error::other),
+            None => Ok(Bytes::new()),
+        }
+    }
+
+    fn request_body_stream(&mut self) -> BodyStream {
+        let body = self.take_request_body().unwrap_or_else(Body::empty);
+
+        BodyStream::new(body.map(|chunk| chunk.map_err(io::Error::other)))
+    }
+
+    fn set_request_body_stream(&mut self, stream: BodyStream) {
+        self.take_request_body();
+        self.request_body = Some(Body::wrap_stream(stream));
+    }
+}
+
+#[async_trait]
+impl<S: 'static + Send> HasResponseBody for TypedHyperContext<S> {
+    fn response_status(&self) -> u16 {
+        self.status
+    }
+
+    fn response_header(&self, key: &str) -> Option<String> {
+        let values: Vec<&str> = self
+            .headers
+            .get_all(key)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    fn response_size(&self) -> Option<u64> {
+        HttpBody::size_hint(&self.body).exact()
+    }
+
+    async fn take_response_body(&mut self) -> io::Result<ResponseBody> {
+        let body = std::mem::take(&mut self.body);
+
+        // Bodies made from bytes know their exact length, and can be read without waiting
+        if HttpBody::size_hint(&body).exact().is_some() {
+            let bytes = hyper::body::to_bytes(body)
+                .await
+                .map_err(io::Error::other)?;
+
+            return Ok(ResponseBody::Bytes(bytes));
+        }
+
+        Ok(ResponseBody::Stream(BodyStream::new(
+            body.map(|chunk| chunk.map_err(io::Error::other)),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::extractors::{FromContext, Query};
+
+    #[tokio::test]
+    async fn it_should_keep_the_request_once_its_body_is_read() {
+        let request = hyper::Request::post("/posts?page=2")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"title":"Hello"}"#))
+            .unwrap();
+        let mut context = TypedHyperContext::new(HyperRequest::new(request), ());
+
+        assert_eq!(
+            context.request_body().await.unwrap(),
+            Bytes::from_static(br#"{"title":"Hello"}"#)
+        );
+        assert_eq!(context.route(), "/posts?page=2");
+        assert_eq!(context.request_method(), "POST");
+        assert_eq!(
+            context.request_header("content-type"),
+            vec!["application/json"]
+        );
+
+        let Query(query) = Query::<HashMap<String, String>>::from_context(&mut context)
+            .await
+            .unwrap();
+
+        assert_eq!(query["page"], "2");
+    }
+}
@@ -29,4 +29,8 @@ pub trait Context {
 
     /// remove is used to remove a header on the outgoing response.
     fn remove(&mut self, key: &str);
+
+    /// status is used to set the status code of the outgoing response. Contexts
+    /// that don't implement it ignore the status.
+    fn status(&mut self, _code: u32) {}
 }
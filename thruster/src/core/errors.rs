@@ -1,11 +1,118 @@
 use crate::core::context::Context;
 use std::error::Error as StdError;
 
+///
+/// The error that caused a `ThrusterError`. It's `Send` and `Sync` so that it can be held across
+/// awaits and handed to other tasks, like the rest of the error.
+///
+pub type Cause = Box<dyn StdError + Send + Sync>;
+
 pub struct ThrusterError<C> {
     pub context: C,
     pub message: String,
     pub status: u32,
-    pub cause: Option<Box<dyn StdError>>,
+    pub cause: Option<Cause>,
+}
+
+impl<C> ThrusterError<C> {
+    pub fn new(context: C, status: u32, message: impl Into<String>) -> ThrusterError<C> {
+        ThrusterError {
+            context,
+            message: message.into(),
+            status,
+            cause: None,
+        }
+    }
+
+    ///
+    /// Wraps a domain error, taking the status from it and the message from how it displays.
+    ///
+    /// ```rust, ignore
+    /// let account = map_try!(find_account(id), Err(e) => ThrusterError::from_error(context, e));
+    /// ```
+    ///
+    pub fn from_error<E: HttpError>(context: C, error: E) -> ThrusterError<C> {
+        ThrusterError {
+            context,
+            message: error.to_string(),
+            status: error.status(),
+            cause: Some(Box::new(error)),
+        }
+    }
+
+    ///
+    /// The cause of the error, if it's an `E`.
+    ///
+    pub fn downcast_cause<E: StdError + 'static>(&self) -> Option<&E> {
+        self.cause.as_ref().and_then(|cause| cause.downcast_ref())
+    }
+}
+
+///
+/// A domain error that knows the status it should be answered with.
+///
+/// ```rust, ignore
+/// #[derive(Debug)]
+/// enum AccountError {
+///     NotFound,
+///     Locked,
+/// }
+///
+/// impl HttpError for AccountError {
+///     fn status(&self) -> u32 {
+///         match self {
+///             AccountError::NotFound => 404,
+///             AccountError::Locked => 423,
+///         }
+///     }
+/// }
+/// ```
+///
+pub trait HttpError: StdError + Send + Sync + 'static {
+    fn status(&self) -> u32;
+}
+
+///
+/// Renders an error as `application/problem+json`, as described in RFC 7807, for use as an app's
+/// error handler. The message of the error is sent as the detail, except for `5xx`s, whose
+/// messages tend to be about the server's internals.
+///
+/// ```rust, ignore
+/// app.set_error_handler(errors::problem_json);
+/// ```
+///
+pub fn problem_json<C: Context>(error: ThrusterError<C>) -> C {
+    let mut context = error.context;
+    let status = response_status(error.status);
+
+    let title = http::StatusCode::from_u16(status as u16)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Error");
+
+    let mut problem = serde_json::json!({
+        "type": "about:blank",
+        "title": title,
+        "status": status,
+    });
+    if status < 500 {
+        problem["detail"] = error.message.into();
+    }
+
+    context.status(status);
+    context.remove("Content-Type");
+    context.set("Content-Type", "application/problem+json");
+    context.set_body(problem.to_string().into_bytes());
+
+    context
+}
+
+// The status to answer an error with, which is a `500` if the error's own isn't a valid one
+pub(crate) fn response_status(status: u32) -> u32 {
+    match status {
+        status @ 100..=599 => status,
+        _ => 500,
+    }
 }
 
 pub trait Error<C> {
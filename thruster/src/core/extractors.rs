@@ -0,0 +1,526 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::{fmt, io};
+
+use crate::core::context::Context;
+use crate::core::errors::ThrusterError;
+use crate::core::response::BodyStream;
+use crate::middleware::query_params::QueryParams;
+
+pub trait HasRouteParams {
+    /// The params captured from the route, e.g. `{ id: "1" }` for `/users/1` matching `/users/:id`.
+    fn route_params(&self) -> Option<&HashMap<String, String>>;
+}
+
+pub trait HasMatchedRoute {
+    /// The route that matched the request, as it was registered, e.g. `/users/:id`.
+    fn matched_route(&self) -> Option<&str>;
+}
+
+pub trait HasRequestHeaders {
+    /// All of the values of a request header. Header names are case insensitive.
+    fn request_header(&self, key: &str) -> Vec<String>;
+}
+
+pub trait HasRequestMethod {
+    /// The method of the request, e.g. `GET`.
+    fn request_method(&self) -> &str;
+}
+
+pub trait HasClientIp {
+    /// The IP address of the client, if the server knows it. Behind a proxy, that's the proxy's.
+    fn client_ip(&self) -> Option<IpAddr>;
+}
+
+pub trait HasRequestId {
+    /// The ID of the request, if one has been given to it, e.g. by the `RequestId` middleware.
+    fn request_id(&self) -> Option<&str>;
+
+    fn set_request_id(&mut self, id: String);
+}
+
+///
+/// Access to the body of the request. The body is taken by the first call to either
+/// `request_body` or `request_body_stream`, and every call after that gets an empty body,
+/// until another one is put in its place with `set_request_body_stream`.
+///
+#[async_trait]
+pub trait HasRequestBody {
+    /// Reads the whole body of the request.
+    async fn request_body(&mut self) -> io::Result<Bytes>;
+
+    /// Takes the body of the request as a stream of chunks, for bodies too large to read at once.
+    fn request_body_stream(&mut self) -> BodyStream;
+
+    /// Replaces the body of the request, for middleware that decodes it before it's read.
+    fn set_request_body_stream(&mut self, stream: BodyStream);
+}
+
+///
+/// The reason an extractor couldn't produce its value, along with the status that the
+/// request should be answered with.
+///
+#[derive(Debug)]
+pub struct ExtractionError {
+    pub status: u32,
+    pub message: String,
+}
+
+impl ExtractionError {
+    pub fn new(status: u32, message: impl Into<String>) -> ExtractionError {
+        ExtractionError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    ///
+    /// Turns this into a `ThrusterError`, setting the status and message on the context
+    /// so that the response is sensible even if no error handler picks it up.
+    ///
+    pub fn into_thruster_error<C: Context>(self, mut context: C) -> ThrusterError<C> {
+        context.status(self.status);
+        context.set_body(self.message.as_bytes().to_vec());
+
+        ThrusterError {
+            context,
+            message: self.message,
+            status: self.status,
+            cause: None,
+        }
+    }
+}
+
+impl fmt::Display for ExtractionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ExtractionError {}
+
+///
+/// Extractors pull typed values out of a context for handlers declared with `#[middleware_fn]`.
+///
+/// Any argument between a handler's context and its `next` is treated as an extractor, and the
+/// generated middleware runs `FromContext::from_context` for each of them, in order, before
+/// calling the handler. If one fails, the handler isn't called and the middleware returns a
+/// `ThrusterError` with the extractor's status.
+///
+/// ```rust, ignore
+/// #[derive(Deserialize)]
+/// struct Filter {
+///     active: bool,
+/// }
+///
+/// #[middleware_fn]
+/// async fn update_user(
+///     mut context: Ctx,
+///     Path(id): Path<u64>,
+///     Query(filter): Query<Filter>,
+///     Json(user): Json<User>,
+///     _next: MiddlewareNext<Ctx>,
+/// ) -> MiddlewareResult<Ctx> {
+///     ...
+/// }
+/// ```
+///
+#[async_trait]
+pub trait FromContext<C>: Sized {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError>;
+}
+
+///
+/// Extracts the route params. A single param can be extracted as any type that can be
+/// deserialized from a string, e.g. `Path<u64>`, while several need a struct with a field for
+/// each of them. Fails with a `400` if a param is missing or doesn't parse.
+///
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<C: HasRouteParams + Send, T: DeserializeOwned> FromContext<C> for Path<T> {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        let empty = HashMap::new();
+        let params = context.route_params().unwrap_or(&empty);
+
+        T::deserialize(ParamsDeserializer(params))
+            .map(Path)
+            .map_err(|e| ExtractionError::new(400, format!("Invalid route params: {}", e)))
+    }
+}
+
+///
+/// Extracts the query string, parsed as `QueryParams`. Fails with a `400` if it can't be
+/// deserialized into `T`.
+///
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<C: Context + Send, T: DeserializeOwned> FromContext<C> for Query<T> {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        let query = context
+            .route()
+            .split_once('?')
+            .map_or("", |(_, query)| query);
+
+        QueryParams::parse(query)
+            .deserialize()
+            .map(Query)
+            .map_err(|e| ExtractionError::new(400, format!("Invalid query string: {}", e)))
+    }
+}
+
+///
+/// Extracts the request body as JSON. Fails with a `400` if the body isn't valid JSON, and a
+/// `422` if it is but doesn't match `T`.
+///
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<C: HasRequestBody + Send, T: DeserializeOwned> FromContext<C> for Json<T> {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        let body = context.request_body().await.map_err(|e| {
+            ExtractionError::new(400, format!("Could not read request body: {}", e))
+        })?;
+
+        serde_json::from_slice(&body).map(Json).map_err(|e| {
+            let status = match e.classify() {
+                serde_json::error::Category::Data => 422,
+                _ => 400,
+            };
+
+            ExtractionError::new(status, format!("Invalid JSON body: {}", e))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ParamsError(pub(crate) String);
+
+impl fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParamsError {}
+
+impl de::Error for ParamsError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ParamsError(msg.to_string())
+    }
+}
+
+///
+/// Deserializes route params, either as a struct or map of all of them, or as the value of the
+/// only one.
+///
+struct ParamsDeserializer<'a>(&'a HashMap<String, String>);
+
+impl<'a> ParamsDeserializer<'a> {
+    fn single(&self) -> Result<ParamDeserializer<'a>, ParamsError> {
+        let mut values = self.0.values();
+
+        match (values.next(), values.next()) {
+            (Some(value), None) => Ok(ParamDeserializer(value)),
+            _ => Err(ParamsError(format!(
+                "expected a single route param, found {}",
+                self.0.len()
+            ))),
+        }
+    }
+}
+
+macro_rules! forward_to_single_param {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.single()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ParamsDeserializer<'a> {
+    type Error = ParamsError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(de::value::MapDeserializer::new(
+            self.0
+                .iter()
+                .map(|(key, value)| (key.as_str(), ParamDeserializer(value))),
+        ))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_single_param! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32
+        deserialize_f64 deserialize_char deserialize_str deserialize_string deserialize_option
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 bytes byte_buf unit unit_struct seq tuple tuple_struct identifier ignored_any
+    }
+}
+
+///
+/// Deserializes a single route or query param, parsing it into whichever primitive is asked for.
+///
+pub(crate) struct ParamDeserializer<'a>(pub(crate) &'a str);
+
+macro_rules! parse_param {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(self.0), &visitor)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ParamDeserializer<'a> {
+    type Error = ParamsError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_str(self.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    parse_param! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, ParamsError> for ParamDeserializer<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_derive::Deserialize;
+
+    use super::*;
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::core::{MiddlewareNext, MiddlewareResult};
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::{middleware_fn, App};
+
+    #[derive(Deserialize)]
+    struct Filter {
+        active: bool,
+        limit: Option<u32>,
+    }
+
+    #[derive(Deserialize)]
+    struct Ids {
+        user: u64,
+        post: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct Post {
+        title: String,
+    }
+
+    #[middleware_fn(_internal)]
+    async fn user(
+        mut context: BasicContext,
+        Path(id): Path<u64>,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body(&format!("user {}", id));
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn users(
+        mut context: BasicContext,
+        Query(filter): Query<Filter>,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body(&format!(
+            "active {} limit {:?}",
+            filter.active, filter.limit
+        ));
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn update_post(
+        mut context: BasicContext,
+        Path(ids): Path<Ids>,
+        Query(filter): Query<Filter>,
+        Json(post): Json<Post>,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body(&format!(
+            "post {} of user {} titled {} ({})",
+            ids.post, ids.user, post.title, filter.active
+        ));
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.get("/users", MiddlewareTuple::A(users));
+        app.get("/users/:id", MiddlewareTuple::A(user));
+        app.put("/users/:user/posts/:post", MiddlewareTuple::A(update_post));
+        app.commit()
+    }
+
+    #[tokio::test]
+    async fn it_should_extract_a_single_route_param() {
+        let response = testing::request(&app(), "GET", "/users/42", &[], "").await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "user 42");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_route_params_that_dont_parse() {
+        let response = testing::request(&app(), "GET", "/users/forty-two", &[], "").await;
+
+        assert_eq!(response.status.1, 400);
+        assert!(response.body.starts_with("Invalid route params: "));
+    }
+
+    #[tokio::test]
+    async fn it_should_extract_the_query_string() {
+        let app = app();
+
+        let response = testing::request(&app, "GET", "/users?active=true&limit=5", &[], "").await;
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "active true limit Some(5)");
+
+        let response = testing::request(&app, "GET", "/users?active=false", &[], "").await;
+        assert_eq!(response.body, "active false limit None");
+
+        let response = testing::request(&app, "GET", "/users?active=maybe", &[], "").await;
+        assert_eq!(response.status.1, 400);
+        assert!(response.body.starts_with("Invalid query string: "));
+
+        let response = testing::request(&app, "GET", "/users", &[], "").await;
+        assert_eq!(response.status.1, 400);
+    }
+
+    #[tokio::test]
+    async fn it_should_run_several_extractors_in_order() {
+        let response = testing::put(
+            &app(),
+            "/users/1/posts/2?active=true",
+            r#"{"title":"Hello"}"#,
+        )
+        .await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "post 2 of user 1 titled Hello (true)");
+
+        // The first extractor to fail decides the response
+        let response = testing::put(&app(), "/users/one/posts/2", "{").await;
+
+        assert_eq!(response.status.1, 400);
+        assert!(response.body.starts_with("Invalid route params: "));
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_bodies_that_arent_json_with_a_400() {
+        let response = testing::put(&app(), "/users/1/posts/2?active=true", r#"{"title":"#).await;
+
+        assert_eq!(response.status.1, 400);
+        assert!(response.body.starts_with("Invalid JSON body: "));
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_json_that_doesnt_match_with_a_422() {
+        let response = testing::put(
+            &app(),
+            "/users/1/posts/2?active=true",
+            r#"{"name":"Hello"}"#,
+        )
+        .await;
+
+        assert_eq!(response.status.1, 422);
+        assert!(response.body.starts_with("Invalid JSON body: "));
+    }
+}
@@ -0,0 +1,390 @@
+use std::fmt;
+use std::ops::Not;
+use std::sync::Arc;
+
+#[cfg(feature = "auth")]
+use crate::middleware::auth::{HasPrincipal, HasRoles};
+
+///
+/// A predicate over the context that decides whether a request can go on to a route. Guards are
+/// added to an `App` for a path, and when one fails the request is answered with a
+/// `403 Forbidden` without running the route's middleware.
+///
+/// Guards are composed with `and`, `or` and `!`, or with `all` and `any` for lists of them.
+///
+/// ```rust, ignore
+/// app.get("/admin/reports", m![list_reports]);
+/// app.delete("/posts/:id", m![delete_post]);
+///
+/// app.guard("/admin", has_role::<User, _>("admin"));
+/// app.guard_method(
+///     "DELETE",
+///     "/posts",
+///     has_role::<User, _>("moderator").or(has_role::<User, _>("admin")),
+/// );
+/// ```
+///
+pub struct Guard<T> {
+    check: Arc<dyn Fn(&T) -> bool + Send + Sync>,
+}
+
+impl<T> Clone for Guard<T> {
+    fn clone(&self) -> Self {
+        Guard {
+            check: self.check.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Guard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Guard").finish()
+    }
+}
+
+impl<T: 'static> Guard<T> {
+    pub fn new(check: impl Fn(&T) -> bool + Send + Sync + 'static) -> Guard<T> {
+        Guard {
+            check: Arc::new(check),
+        }
+    }
+
+    ///
+    /// A guard that passes if both this one and `other` do. `other` isn't checked if this one
+    /// fails.
+    ///
+    pub fn and(self, other: Guard<T>) -> Guard<T> {
+        Guard::new(move |context| self.check(context) && other.check(context))
+    }
+
+    ///
+    /// A guard that passes if either this one or `other` does. `other` isn't checked if this one
+    /// passes.
+    ///
+    pub fn or(self, other: Guard<T>) -> Guard<T> {
+        Guard::new(move |context| self.check(context) || other.check(context))
+    }
+
+    pub fn check(&self, context: &T) -> bool {
+        (self.check)(context)
+    }
+}
+
+impl<T: 'static> Not for Guard<T> {
+    type Output = Guard<T>;
+
+    fn not(self) -> Guard<T> {
+        Guard::new(move |context| !self.check(context))
+    }
+}
+
+///
+/// A guard that passes if all of `guards` do, checked in order.
+///
+pub fn all<T: 'static>(guards: Vec<Guard<T>>) -> Guard<T> {
+    Guard::new(move |context| guards.iter().all(|guard| guard.check(context)))
+}
+
+///
+/// A guard that passes if any of `guards` does, checked in order.
+///
+pub fn any<T: 'static>(guards: Vec<Guard<T>>) -> Guard<T> {
+    Guard::new(move |context| guards.iter().any(|guard| guard.check(context)))
+}
+
+///
+/// A guard that passes if the request has been authenticated by any of the auth middleware.
+///
+#[cfg(feature = "auth")]
+pub fn authenticated<T: HasPrincipal + 'static>() -> Guard<T> {
+    Guard::new(|context: &T| context.principal_any().is_some())
+}
+
+///
+/// A guard that passes if the request has been authenticated as a `P` that `check` passes.
+///
+#[cfg(feature = "auth")]
+pub fn principal<P, T>(check: impl Fn(&P) -> bool + Send + Sync + 'static) -> Guard<T>
+where
+    P: 'static,
+    T: HasPrincipal + 'static,
+{
+    Guard::new(move |context: &T| context.principal::<P>().is_some_and(&check))
+}
+
+///
+/// A guard that passes if the request has been authenticated as a `P` that has `role`. The type
+/// of the principal has to be given, e.g. `has_role::<User, _>("admin")`.
+///
+#[cfg(feature = "auth")]
+pub fn has_role<P, T>(role: &str) -> Guard<T>
+where
+    P: HasRoles + 'static,
+    T: HasPrincipal + 'static,
+{
+    let role = role.to_owned();
+
+    principal(move |principal: &P| principal.has_role(&role))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::context::Context;
+    use crate::core::extractors::HasRequestHeaders;
+    use crate::core::request::Request;
+    use crate::core::{MiddlewareNext, MiddlewareResult};
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::{middleware_fn, App};
+
+    fn even() -> Guard<u32> {
+        Guard::new(|n: &u32| n & 1 == 0)
+    }
+
+    fn big() -> Guard<u32> {
+        Guard::new(|n: &u32| *n > 10)
+    }
+
+    // A guard that counts how often it's checked
+    fn counted(passes: bool, checks: &Arc<AtomicUsize>) -> Guard<u32> {
+        let checks = checks.clone();
+
+        Guard::new(move |_: &u32| {
+            checks.fetch_add(1, Ordering::SeqCst);
+            passes
+        })
+    }
+
+    #[test]
+    fn it_should_combine_guards() {
+        let both = even().and(big());
+        let either = even().or(big());
+        let odd = !even();
+
+        assert!(both.check(&12));
+        assert!(!both.check(&4));
+        assert!(!both.check(&13));
+
+        assert!(either.check(&4));
+        assert!(either.check(&13));
+        assert!(!either.check(&3));
+
+        assert!(odd.check(&3));
+        assert!(!odd.check(&4));
+    }
+
+    #[test]
+    fn it_should_only_check_the_other_guard_when_needed() {
+        let checks = Arc::new(AtomicUsize::new(0));
+
+        assert!(!Guard::new(|_: &u32| false)
+            .and(counted(true, &checks))
+            .check(&0));
+        assert!(Guard::new(|_: &u32| true)
+            .or(counted(true, &checks))
+            .check(&0));
+        assert_eq!(checks.load(Ordering::SeqCst), 0);
+
+        assert!(Guard::new(|_: &u32| true)
+            .and(counted(true, &checks))
+            .check(&0));
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn it_should_check_lists_of_guards_in_order() {
+        let checks = Arc::new(AtomicUsize::new(0));
+
+        assert!(!all(vec![
+            even(),
+            counted(false, &checks),
+            counted(true, &checks)
+        ])
+        .check(&2));
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+        assert!(any(vec![big(), counted(true, &checks), counted(true, &checks)]).check(&2));
+        assert_eq!(checks.load(Ordering::SeqCst), 2);
+
+        assert!(all(vec![even(), big()]).check(&12));
+        assert!(!any(vec![even(), big()]).check(&3));
+    }
+
+    #[test]
+    fn it_should_treat_empty_lists_like_all_and_any() {
+        assert!(all::<u32>(vec![]).check(&0));
+        assert!(!any::<u32>(vec![]).check(&0));
+    }
+
+    fn has_header(name: &'static str) -> Guard<BasicContext> {
+        Guard::new(move |context: &BasicContext| !context.request_header(name).is_empty())
+    }
+
+    // Marks requests as trusted, to check that guards run after the method-agnostic middleware
+    #[middleware_fn(_internal)]
+    async fn trust(
+        mut context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set("X-Trusted", "yes");
+        next(context).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn ok(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body("ok");
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut admin = App::<Request, BasicContext, ()>::new_basic();
+        admin.get("/reports", MiddlewareTuple::A(ok));
+        admin.guard("/", has_header("X-Admin"));
+
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(trust));
+        app.get("/", MiddlewareTuple::A(ok));
+        app.get("/posts/:id", MiddlewareTuple::A(ok));
+        app.delete("/posts/:id", MiddlewareTuple::A(ok));
+        app.get("/private/notes", MiddlewareTuple::A(ok));
+        app.post("/private/notes", MiddlewareTuple::A(ok));
+        app.method("PURGE", "/private/cache", MiddlewareTuple::A(ok));
+        app.use_sub_app("/admin", admin);
+        app.guard("/private", has_header("X-User"));
+        app.guard_method("DELETE", "/posts", has_header("X-Moderator"));
+        app.commit()
+    }
+
+    async fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> testing::TestResponse {
+        testing::request(&app(), method, path, headers, "").await
+    }
+
+    #[tokio::test]
+    async fn it_should_forbid_requests_that_fail_a_guard() {
+        let response = request("GET", "/private/notes", &[]).await;
+
+        assert_eq!(response.status.1, 403);
+        assert_eq!(response.body, "Forbidden");
+        assert_eq!(response.headers["X-Trusted"], "yes");
+
+        let response = request("GET", "/private/notes", &[("X-User", "ada")]).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "ok");
+    }
+
+    #[tokio::test]
+    async fn it_should_guard_every_method_below_the_path() {
+        assert_eq!(request("POST", "/private/notes", &[]).await.status.1, 403);
+        assert_eq!(request("PURGE", "/private/cache", &[]).await.status.1, 403);
+        assert_eq!(
+            request("PURGE", "/private/cache", &[("X-User", "ada")])
+                .await
+                .status
+                .1,
+            200
+        );
+        assert_eq!(request("GET", "/", &[]).await.status.1, 200);
+    }
+
+    #[tokio::test]
+    async fn it_should_only_guard_the_given_method() {
+        assert_eq!(request("GET", "/posts/1", &[]).await.status.1, 200);
+        assert_eq!(request("DELETE", "/posts/1", &[]).await.status.1, 403);
+        assert_eq!(
+            request("DELETE", "/posts/1", &[("X-Moderator", "ada")])
+                .await
+                .status
+                .1,
+            200
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_the_guards_of_sub_apps() {
+        assert_eq!(request("GET", "/admin/reports", &[]).await.status.1, 403);
+        assert_eq!(
+            request("GET", "/admin/reports", &[("X-Admin", "ada")])
+                .await
+                .status
+                .1,
+            200
+        );
+    }
+
+    #[cfg(feature = "auth")]
+    mod auth {
+        use super::*;
+        use crate::middleware::auth::{HasPrincipal, HasRoles};
+
+        struct User {
+            name: &'static str,
+            roles: Vec<&'static str>,
+        }
+
+        impl HasRoles for User {
+            fn has_role(&self, role: &str) -> bool {
+                self.roles.contains(&role)
+            }
+        }
+
+        fn context_for(user: Option<User>) -> BasicContext {
+            let mut context = BasicContext::new();
+            if let Some(user) = user {
+                context.set_principal(user);
+            }
+            context
+        }
+
+        fn ada() -> Option<User> {
+            Some(User {
+                name: "ada",
+                roles: vec!["admin"],
+            })
+        }
+
+        #[test]
+        fn it_should_check_that_requests_are_authenticated() {
+            let guard = authenticated::<BasicContext>();
+
+            assert!(guard.check(&context_for(ada())));
+            assert!(!guard.check(&context_for(None)));
+        }
+
+        #[test]
+        fn it_should_check_the_principal() {
+            let guard = principal::<User, BasicContext>(|user| user.name == "ada");
+
+            assert!(guard.check(&context_for(ada())));
+            assert!(!guard.check(&context_for(Some(User {
+                name: "grace",
+                roles: vec![],
+            }))));
+            assert!(!guard.check(&context_for(None)));
+
+            // A principal of another type doesn't pass
+            let mut context = BasicContext::new();
+            context.set_principal("ada".to_owned());
+
+            assert!(!guard.check(&context));
+        }
+
+        #[test]
+        fn it_should_check_the_roles_of_the_principal() {
+            let admin = has_role::<User, BasicContext>("admin");
+            let moderator = has_role::<User, BasicContext>("moderator");
+
+            assert!(admin.check(&context_for(ada())));
+            assert!(!moderator.check(&context_for(ada())));
+            assert!(!admin.check(&context_for(None)));
+            assert!(admin.or(moderator).check(&context_for(ada())));
+        }
+    }
+}
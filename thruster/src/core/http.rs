@@ -1,18 +1,79 @@
-use bytes::BytesMut;
-use tokio_util::codec::{Decoder, Encoder};
+use bytes::{Bytes, BytesMut};
+use futures::future::poll_fn;
+use futures::{SinkExt, StreamExt};
+use std::future::Future;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
-use crate::core::request::{decode, Request};
-use crate::core::response::{encode, Response};
+use crate::core::request::{decode_resuming, ChunkedBody, DecodeError, Request, RequestLimits};
+use crate::core::response::{
+    encode, encode_chunk, encode_chunked_head, encode_without_body, Response,
+};
 use std::io;
 
-pub struct Http;
+#[derive(Default)]
+pub struct Http {
+    limits: RequestLimits,
+    head_started: Option<Instant>,
+    head_complete: bool,
+    chunked_body: ChunkedBody,
+}
+
+impl Http {
+    pub fn new(limits: RequestLimits) -> Http {
+        Http {
+            limits,
+            ..Http::default()
+        }
+    }
+
+    ///
+    /// When the first bytes of the current request's head arrived, if it's still arriving.
+    ///
+    fn reading_head_since(&self) -> Option<Instant> {
+        self.head_started.filter(|_| !self.head_complete)
+    }
+}
+
+/// A response to a `HEAD` request, sent with the headers it would have had for a `GET` but
+/// without its body.
+pub struct Bodiless(pub Response);
+
+/// The head of a response whose body is sent as a series of `Chunk`s.
+pub struct ChunkedHead(pub Response);
+
+/// A piece of a chunked response body. An empty chunk marks the end of the body.
+pub struct Chunk(pub Bytes);
 
 impl Decoder for Http {
     type Item = Request;
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
-        decode(buf)
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        self.head_started.get_or_insert_with(Instant::now);
+
+        let result = decode_resuming(buf, &self.limits, &mut self.chunked_body);
+        match result {
+            Ok(None) => {
+                // The head is bounded by the limits, so this doesn't scan far
+                self.head_complete =
+                    self.head_complete || buf.windows(4).any(|window| window == b"\r\n\r\n");
+            }
+            _ => {
+                self.head_started = None;
+                self.head_complete = false;
+                self.chunked_body = ChunkedBody::default();
+            }
+        }
+
+        result
     }
 }
 
@@ -25,3 +86,174 @@ impl Encoder<Response> for Http {
         Ok(())
     }
 }
+
+impl Encoder<Bodiless> for Http {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Bodiless, buf: &mut BytesMut) -> io::Result<()> {
+        encode_without_body(&msg.0, buf);
+
+        Ok(())
+    }
+}
+
+impl Encoder<ChunkedHead> for Http {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: ChunkedHead, buf: &mut BytesMut) -> io::Result<()> {
+        encode_chunked_head(&msg.0, buf);
+
+        Ok(())
+    }
+}
+
+impl Encoder<Chunk> for Http {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Chunk, buf: &mut BytesMut) -> io::Result<()> {
+        encode_chunk(&msg.0, buf);
+
+        Ok(())
+    }
+}
+
+///
+/// Writes a response to the connection. Responses with a body stream are sent using chunked
+/// transfer-encoding, one chunk per item of the stream. Responses to `HEAD` requests are sent
+/// without their body.
+///
+pub(crate) async fn send_response<T: AsyncWrite + Unpin>(
+    framed: &mut Framed<T, Http>,
+    mut response: Response,
+    head: bool,
+) -> io::Result<()> {
+    let mut body_stream = match (response.body_stream.take(), head) {
+        (Some(_), true) => return framed.send(ChunkedHead(response)).await,
+        (None, true) => return framed.send(Bodiless(response)).await,
+        (Some(body_stream), false) => body_stream,
+        (None, false) => return framed.send(response).await,
+    };
+
+    framed.send(ChunkedHead(response)).await?;
+
+    while let Some(chunk) = body_stream.next().await {
+        let chunk = chunk?;
+
+        // An empty chunk would end the body early
+        if !chunk.is_empty() {
+            framed.send(Chunk(chunk)).await?;
+        }
+    }
+
+    framed.send(Chunk(Bytes::new())).await
+}
+
+///
+/// Reads the next request from the connection, or `None` once it's closed. A connection that
+/// sits idle for `keep_alive` is closed, and one that takes longer than `header_read` to send
+/// the head of a request fails with a `DecodeError::Timeout`.
+///
+pub(crate) async fn next_request<T: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<T, Http>,
+    keep_alive: Duration,
+    header_read: Duration,
+) -> Option<io::Result<Request>> {
+    let idle_deadline = Instant::now() + keep_alive;
+    let timer = tokio::time::sleep_until(idle_deadline);
+    tokio::pin!(timer);
+
+    poll_fn(|cx| {
+        if let Poll::Ready(request) = framed.poll_next_unpin(cx) {
+            return Poll::Ready(request);
+        }
+
+        // Once the head has arrived, the body takes as long as it takes
+        let deadline = match framed.codec().reading_head_since() {
+            Some(started) => started + header_read,
+            None if framed.read_buffer().is_empty() => idle_deadline,
+            None => return Poll::Pending,
+        };
+
+        if timer.deadline() != deadline {
+            timer.as_mut().reset(deadline);
+        }
+
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) if deadline == idle_deadline => Poll::Ready(None),
+            Poll::Ready(()) => Poll::Ready(Some(Err(DecodeError::Timeout.into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+///
+/// The response to send back when a request couldn't be decoded, so that the client finds out
+/// why before the connection is closed. `None` if the error came from the connection itself.
+///
+pub(crate) fn decode_error_response(error: &io::Error) -> Option<Response> {
+    let (code, message) = error.get_ref()?.downcast_ref::<DecodeError>()?.status();
+
+    let mut response = Response::new();
+    response
+        .status_code(code, message)
+        .header("Connection", "close")
+        .body(message);
+
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn send(response: Response, head: bool) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut framed = Framed::new(server, Http::default());
+
+        send_response(&mut framed, response, head).await.unwrap();
+        drop(framed);
+
+        let mut sent = String::new();
+        client.read_to_string(&mut sent).await.unwrap();
+        sent
+    }
+
+    fn streamed() -> Response {
+        let mut response = Response::new();
+        response.body_stream(futures::stream::iter(vec![
+            Ok(Bytes::from("hello")),
+            Ok(Bytes::new()),
+            Ok(Bytes::from(", world")),
+        ]));
+        response
+    }
+
+    #[tokio::test]
+    async fn it_should_send_body_streams_as_chunks() {
+        let sent = send(streamed(), false).await;
+
+        assert!(sent.starts_with("HTTP/1.1 200"));
+        assert!(sent.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!sent.contains("Content-Length"));
+        assert!(sent.ends_with("\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn it_should_send_responses_to_heads_without_their_body() {
+        let mut response = Response::new();
+        response.body("hello");
+
+        let sent = send(response, true).await;
+
+        assert!(sent.contains("Content-Length: 5\r\n"));
+        assert!(sent.ends_with("\r\n\r\n"));
+
+        let sent = send(streamed(), true).await;
+
+        assert!(sent.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(sent.ends_with("\r\n\r\n"));
+    }
+}
@@ -1,6 +1,8 @@
 pub mod context;
 pub mod date;
 pub mod errors;
+pub mod extractors;
+pub mod guard;
 pub mod http;
 pub mod macros;
 pub mod request;
@@ -1,16 +1,23 @@
 use crate::parser::tree::Params;
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use smallvec::SmallVec;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
 use std::{fmt, io, str};
 
 pub trait RequestWithParams {
     fn set_params(&mut self, _: Params);
+
+    /// Sets the route that matched the request, as it was registered, e.g. `/users/:id`.
+    fn set_route(&mut self, _route: &str) {}
 }
 
-pub trait ThrusterRequest {
+pub trait ThrusterRequest: RequestWithParams {
     fn method(&self) -> &str;
     fn path(&self) -> String;
+    /// All of the values of a request header. Header names are case insensitive.
+    fn get_header(&self, key: &str) -> Vec<String>;
 }
 
 ///
@@ -27,6 +34,20 @@ pub struct Request {
     pub headers: SmallVec<[(Slice, Slice); 8]>,
     data: BytesMut,
     pub params: Option<HashMap<String, String>>,
+    /// The IP address of the peer the request came from, when the server knows it.
+    pub ip: Option<IpAddr>,
+    /// The route that matched the request, as it was registered, e.g. `/users/:id`.
+    pub route: Option<String>,
+}
+
+impl RequestWithParams for Request {
+    fn set_params(&mut self, params: Params) {
+        self.params = Some(params.into());
+    }
+
+    fn set_route(&mut self, route: &str) {
+        self.route = Some(route.to_owned());
+    }
 }
 
 impl ThrusterRequest for Request {
@@ -36,6 +57,14 @@ impl ThrusterRequest for Request {
     fn path(&self) -> String {
         self.path().to_owned()
     }
+    fn get_header(&self, key: &str) -> Vec<String> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| self.slice(k).eq_ignore_ascii_case(key.as_bytes()))
+            .filter_map(|(_, v)| str::from_utf8(self.slice(v)).ok())
+            .map(|v| v.to_owned())
+            .collect()
+    }
 }
 
 type Slice = (usize, usize);
@@ -53,6 +82,8 @@ impl Request {
             headers: SmallVec::new(),
             data: BytesMut::new(),
             params: None,
+            ip: None,
+            route: None,
         }
     }
 
@@ -113,7 +144,6 @@ impl Request {
                 }
                 None => {
                     header_map.insert(k, vec![v]);
-                    
                 }
             };
         }
@@ -155,6 +185,31 @@ impl Request {
     pub fn params(&self) -> &Option<HashMap<String, String>> {
         &self.params
     }
+
+    ///
+    /// A copy of the request line and headers, without the body.
+    ///
+    pub(crate) fn without_body(&self) -> Request {
+        let end = self
+            .headers
+            .iter()
+            .flat_map(|(key, value)| [key.1, value.1])
+            .chain([self.method.1, self.path.1])
+            .max()
+            .unwrap_or(0);
+
+        Request {
+            body: (end, end),
+            method: self.method,
+            path: self.path,
+            version: self.version,
+            headers: self.headers.clone(),
+            data: BytesMut::from(&self.data[..end]),
+            params: self.params.clone(),
+            ip: self.ip,
+            route: self.route.clone(),
+        }
+    }
 }
 
 impl fmt::Debug for Request {
@@ -163,40 +218,226 @@ impl fmt::Debug for Request {
     }
 }
 
+///
+/// Limits on the size of incoming requests. Requests going over them are answered with a `414`,
+/// `431` or `413` and the connection is closed, rather than being buffered.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLimits {
+    /// The maximum number of headers.
+    pub max_headers: usize,
+    /// The maximum size of the request line and headers, in bytes.
+    pub max_header_bytes: usize,
+    /// The maximum size of the body, in bytes, whether sent with `Content-Length` or chunked.
+    pub max_body_size: usize,
+    /// The maximum length of the request target, in bytes.
+    pub max_uri_length: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_headers: 64,
+            max_header_bytes: 16 * 1024,
+            max_body_size: 4 * 1024 * 1024,
+            max_uri_length: 8 * 1024,
+        }
+    }
+}
+
+///
+/// The reasons a request can't be decoded. These are wrapped in the `io::Error` returned by
+/// `decode`, and each maps to the status code of the response that the server sends back.
+///
+#[derive(Debug)]
+pub enum DecodeError {
+    Malformed(httparse::Error),
+    InvalidBody(&'static str),
+    TooManyHeaders,
+    HeadersTooLarge,
+    UriTooLong,
+    BodyTooLarge,
+    Timeout,
+}
+
+impl DecodeError {
+    /// The status code and reason phrase to respond with.
+    pub fn status(&self) -> (u32, &'static str) {
+        match self {
+            DecodeError::Malformed(_) | DecodeError::InvalidBody(_) => (400, "Bad Request"),
+            DecodeError::TooManyHeaders | DecodeError::HeadersTooLarge => {
+                (431, "Request Header Fields Too Large")
+            }
+            DecodeError::UriTooLong => (414, "URI Too Long"),
+            DecodeError::BodyTooLarge => (413, "Payload Too Large"),
+            DecodeError::Timeout => (408, "Request Timeout"),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Malformed(e) => write!(f, "failed to parse http request: {}", e),
+            DecodeError::InvalidBody(msg) => write!(f, "invalid request body: {}", msg),
+            DecodeError::Timeout => write!(f, "timed out reading the request head"),
+            _ => write!(f, "request over limit: {}", self.status().1),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> io::Error {
+        let kind = match e {
+            DecodeError::Timeout => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::InvalidData,
+        };
+
+        io::Error::new(kind, e)
+    }
+}
+
+///
+/// Decodes a request from the start of `buf` using the default `RequestLimits`.
+///
 pub fn decode(buf: &mut BytesMut) -> io::Result<Option<Request>> {
-    // TODO: we should grow this headers array if parsing fails and asks
-    //       for more headers
-    let (method, path, version, headers, amt, body_len) = {
-        let mut headers = [httparse::EMPTY_HEADER; 32];
-        let mut r = httparse::Request::new(&mut headers);
-        let mut body_len: usize = 0;
+    decode_with_limits(buf, &RequestLimits::default())
+}
+
+///
+/// Decodes a request from the start of `buf`, returning `None` if it hasn't fully arrived yet.
+///
+pub fn decode_with_limits(
+    buf: &mut BytesMut,
+    limits: &RequestLimits,
+) -> io::Result<Option<Request>> {
+    decode_resuming(buf, limits, &mut ChunkedBody::default())
+}
+
+///
+/// The progress made on a chunked body that hasn't fully arrived yet. It's kept between calls to
+/// `decode_resuming` so that each one picks up where the last one stopped, rather than decoding
+/// the chunks from the start again.
+///
+#[derive(Debug, Default)]
+pub(crate) struct ChunkedBody {
+    /// How far into the chunks decoding has got.
+    pos: usize,
+    /// The body decoded so far.
+    body: Vec<u8>,
+    /// Whether the last chunk has been read, leaving the trailers.
+    in_trailers: bool,
+    /// The size of the trailers read so far.
+    trailer_bytes: usize,
+}
+
+///
+/// Same as `decode_with_limits`, but a chunked body that hasn't fully arrived yet is decoded
+/// into `chunked_body`, which has to be passed in again with the same buffer until the request
+/// is returned or fails.
+///
+pub(crate) fn decode_resuming(
+    buf: &mut BytesMut,
+    limits: &RequestLimits,
+    chunked_body: &mut ChunkedBody,
+) -> io::Result<Option<Request>> {
+    // Most requests fit in the stack allocated headers, only fall back to the heap if they don't
+    // and the limits allow for more.
+    let mut stack_headers = [httparse::EMPTY_HEADER; 32];
+    let mut heap_headers;
+
+    let (method, path, version, headers, amt, body_len, chunked) = {
+        let max_stack_headers = limits.max_headers.min(stack_headers.len());
+        let mut r = httparse::Request::new(&mut stack_headers[..max_stack_headers]);
         let mut header_vec = SmallVec::new();
-        let status = r.parse(buf).map_err(|e| {
-            let msg = format!("failed to parse http request: {:?}", e);
-            eprintln!("msg: {}", msg);
-            eprintln!("dump: {:#?}", buf);
-            io::Error::new(io::ErrorKind::Other, msg)
-        })?;
+        let status = match r.parse(buf) {
+            Err(httparse::Error::TooManyHeaders) if limits.max_headers > max_stack_headers => {
+                heap_headers = vec![httparse::EMPTY_HEADER; limits.max_headers];
+                r = httparse::Request::new(&mut heap_headers);
+                r.parse(buf)
+            }
+            status => status,
+        };
         let amt = match status {
-            httparse::Status::Complete(amt) => amt,
-            httparse::Status::Partial => return Ok(None),
+            Ok(httparse::Status::Complete(amt)) => amt,
+            Ok(httparse::Status::Partial) => {
+                if partial_uri_len(buf) > limits.max_uri_length {
+                    return Err(DecodeError::UriTooLong.into());
+                }
+
+                if buf.len() > limits.max_header_bytes {
+                    return Err(DecodeError::HeadersTooLarge.into());
+                }
+
+                return Ok(None);
+            }
+            Err(httparse::Error::TooManyHeaders) => return Err(DecodeError::TooManyHeaders.into()),
+            Err(e) => return Err(DecodeError::Malformed(e).into()),
         };
+
+        if r.path.map(|path| path.len()).unwrap_or(0) > limits.max_uri_length {
+            return Err(DecodeError::UriTooLong.into());
+        }
+
+        if amt > limits.max_header_bytes {
+            return Err(DecodeError::HeadersTooLarge.into());
+        }
+
         let toslice = |a: &[u8]| {
             let start = a.as_ptr() as usize - buf.as_ptr() as usize;
             assert!(start < buf.len());
             (start, start + a.len())
         };
+
+        // A request whose body can be framed more than one way could be read differently by a
+        // proxy in front of us, so anything ambiguous is rejected, as per RFC 9112 section 6.3.
+        let mut content_length = None;
+        let mut transfer_encoding = None;
         for header in r.headers.iter() {
-            let header_name = httplib::header::CONTENT_LENGTH;
+            if header.name == httplib::header::CONTENT_LENGTH {
+                if content_length.is_some() {
+                    return Err(DecodeError::InvalidBody("duplicate Content-Length").into());
+                }
+
+                content_length = Some(
+                    str::from_utf8(header.value)
+                        .ok()
+                        .map(str::trim)
+                        .filter(|value| value.bytes().all(|b| b.is_ascii_digit()))
+                        .and_then(|value| value.parse::<usize>().ok())
+                        .ok_or(DecodeError::InvalidBody("invalid Content-Length"))?,
+                );
+            }
 
-            if header.name == header_name {
-                let value = str::from_utf8(header.value).unwrap_or("0");
-                body_len = value.parse::<usize>().unwrap_or(0);
+            // Chunked has to be the last transfer coding applied, across all of the headers
+            if header.name == httplib::header::TRANSFER_ENCODING {
+                transfer_encoding = Some(
+                    str::from_utf8(header.value)
+                        .ok()
+                        .and_then(|value| value.rsplit(',').next())
+                        .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+                        .unwrap_or(false),
+                );
             }
 
             header_vec.push((toslice(header.name.as_bytes()), toslice(header.value)));
         }
 
+        let (body_len, chunked) = match (transfer_encoding, content_length) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    DecodeError::InvalidBody("both Transfer-Encoding and Content-Length").into(),
+                )
+            }
+            (Some(false), None) => {
+                return Err(DecodeError::InvalidBody("Transfer-Encoding isn't chunked").into())
+            }
+            (Some(true), None) => (0, true),
+            (None, content_length) => (content_length.unwrap_or(0), false),
+        };
+
         (
             toslice(r.method.unwrap().as_bytes()),
             toslice(r.path.unwrap().as_bytes()),
@@ -204,10 +445,41 @@ pub fn decode(buf: &mut BytesMut) -> io::Result<Option<Request>> {
             header_vec,
             amt,
             body_len,
+            chunked,
         )
     };
 
-    if amt + body_len != buf.len() {
+    if chunked {
+        let chunked_len = match decode_chunked(&buf[amt..], limits, chunked_body)? {
+            Some(chunked_len) => chunked_len,
+            None => return Ok(None),
+        };
+        let body = std::mem::take(&mut chunked_body.body);
+
+        // The head stays where it is so that the slices above still line up, and the decoded
+        // body takes the place of the chunks right after it.
+        let mut data = buf.split_to(amt);
+        buf.advance(chunked_len);
+        data.extend_from_slice(&body);
+
+        return Ok(Some(Request {
+            method,
+            path,
+            version,
+            headers,
+            data,
+            body: (amt, amt + body.len()),
+            params: None,
+            ip: None,
+            route: None,
+        }));
+    }
+
+    if body_len > limits.max_body_size {
+        return Err(DecodeError::BodyTooLarge.into());
+    }
+
+    if buf.len() < amt + body_len {
         Ok(None)
     } else {
         Ok(Request {
@@ -218,7 +490,357 @@ pub fn decode(buf: &mut BytesMut) -> io::Result<Option<Request>> {
             data: buf.split_to(amt + body_len),
             body: (amt, amt + body_len),
             params: None,
+            ip: None,
+            route: None,
         }
         .into())
     }
 }
+
+///
+/// The length of the request target in a request line that hasn't fully arrived yet, so that
+/// overly long ones can be turned away before the rest of the line is buffered.
+///
+fn partial_uri_len(buf: &[u8]) -> usize {
+    let line = match buf.iter().position(|b| *b == b'\n') {
+        Some(end) => &buf[..end],
+        None => buf,
+    };
+
+    line.split(|b| *b == b' ')
+        .nth(1)
+        .map(|uri| uri.len())
+        .unwrap_or(0)
+}
+
+///
+/// Decodes a chunked body from the start of `buf`, carrying on from where `state` got to. Once
+/// the whole body, including any trailers, has arrived, returns how many bytes of `buf` it took
+/// up, leaving the decoded body in `state`. Trailers are discarded.
+///
+/// Chunk size lines and the trailers are limited to `max_header_bytes`, so that neither can be
+/// used to make the server buffer without end.
+///
+fn decode_chunked(
+    buf: &[u8],
+    limits: &RequestLimits,
+    state: &mut ChunkedBody,
+) -> io::Result<Option<usize>> {
+    while !state.in_trailers {
+        let rest = &buf[state.pos..];
+        let (size_len, size) = match httparse::parse_chunk_size(rest) {
+            Ok(httparse::Status::Complete(parsed)) => parsed,
+            Ok(httparse::Status::Partial) if rest.len() > limits.max_header_bytes => {
+                return Err(DecodeError::InvalidBody("chunk size line too long").into())
+            }
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(_) => return Err(DecodeError::InvalidBody("invalid chunk size").into()),
+        };
+
+        if size_len > limits.max_header_bytes {
+            return Err(DecodeError::InvalidBody("chunk size line too long").into());
+        }
+
+        let size = usize::try_from(size).map_err(|_| DecodeError::BodyTooLarge)?;
+
+        if size == 0 {
+            state.pos += size_len;
+            state.in_trailers = true;
+            break;
+        }
+
+        // Checked before the chunk is buffered, so that a client can't claim a huge chunk
+        if size > limits.max_body_size - state.body.len() {
+            return Err(DecodeError::BodyTooLarge.into());
+        }
+
+        // The chunk is only taken once all of it has arrived, its size line is read again then
+        if rest.len() - size_len < size + 2 {
+            return Ok(None);
+        }
+
+        let chunk = &rest[size_len..size_len + size];
+        if &rest[size_len + size..size_len + size + 2] != b"\r\n" {
+            return Err(DecodeError::InvalidBody("chunk missing trailing CRLF").into());
+        }
+
+        state.body.extend_from_slice(chunk);
+        state.pos += size_len + size + 2;
+    }
+
+    // The last chunk is followed by optional trailer fields, then an empty line
+    loop {
+        let rest = &buf[state.pos..];
+        let line_len = match rest.windows(2).position(|w| w == b"\r\n") {
+            Some(line_len) => line_len,
+            None if state.trailer_bytes + rest.len() > limits.max_header_bytes => {
+                return Err(DecodeError::HeadersTooLarge.into())
+            }
+            None => return Ok(None),
+        };
+
+        state.pos += line_len + 2;
+
+        if line_len == 0 {
+            return Ok(Some(state.pos));
+        }
+
+        state.trailer_bytes += line_len + 2;
+        if state.trailer_bytes > limits.max_header_bytes {
+            return Err(DecodeError::HeadersTooLarge.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+    use crate::core::http::Http;
+
+    const CHUNKED_HEAD: &str =
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    fn decode_str(request: &str, limits: &RequestLimits) -> io::Result<Option<Request>> {
+        decode_with_limits(&mut BytesMut::from(request), limits)
+    }
+
+    fn status_of(result: io::Result<Option<Request>>) -> u32 {
+        let error = result.expect_err("the request should have been rejected");
+
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<DecodeError>())
+            .expect("the error should be a DecodeError")
+            .status()
+            .0
+    }
+
+    #[test]
+    fn it_should_decode_a_chunked_body_and_drop_the_trailers() {
+        let request = format!(
+            "{}5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nExpires: never\r\n\r\nGET",
+            CHUNKED_HEAD
+        );
+        let mut buf = BytesMut::from(request.as_str());
+
+        let decoded = decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded.body(), "hello, world");
+        assert_eq!(decoded.headers()["host"], vec!["localhost"]);
+        assert_eq!(&buf[..], b"GET");
+    }
+
+    #[test]
+    fn it_should_wait_for_the_rest_of_a_chunked_body() {
+        let limits = RequestLimits::default();
+
+        for request in [
+            format!("{}5\r\nhel", CHUNKED_HEAD),
+            format!("{}5\r\nhello\r\n", CHUNKED_HEAD),
+            format!("{}5\r\nhello\r\n0\r\n", CHUNKED_HEAD),
+            format!("{}5\r\nhello\r\n0\r\nExpires: never\r\n", CHUNKED_HEAD),
+        ] {
+            assert!(
+                decode_str(&request, &limits).unwrap().is_none(),
+                "{:?}",
+                request
+            );
+        }
+    }
+
+    #[test]
+    fn it_should_decode_a_chunked_body_that_arrives_a_byte_at_a_time() {
+        let request = format!(
+            "{}5\r\nhello\r\n1;ext\r\n!\r\n0\r\nExpires: never\r\n\r\n",
+            CHUNKED_HEAD
+        );
+        let mut codec = Http::new(RequestLimits::default());
+        let mut buf = BytesMut::new();
+        let mut decoded = None;
+
+        for byte in request.as_bytes() {
+            assert!(decoded.is_none(), "decoded before the request was complete");
+
+            buf.extend_from_slice(&[*byte]);
+            decoded = codec.decode(&mut buf).unwrap();
+        }
+
+        assert_eq!(decoded.unwrap().body(), "hello!");
+        assert!(buf.is_empty());
+
+        // Nothing is carried over into the next request
+        buf.extend_from_slice(format!("{}2\r\nok\r\n0\r\n\r\n", CHUNKED_HEAD).as_bytes());
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().body(), "ok");
+    }
+
+    #[test]
+    fn it_should_reject_invalid_chunks() {
+        let limits = RequestLimits::default();
+
+        let request = format!("{}zz\r\nhello\r\n0\r\n\r\n", CHUNKED_HEAD);
+        assert_eq!(status_of(decode_str(&request, &limits)), 400);
+
+        let request = format!("{}5\r\nhelloXX0\r\n\r\n", CHUNKED_HEAD);
+        assert_eq!(status_of(decode_str(&request, &limits)), 400);
+    }
+
+    #[test]
+    fn it_should_limit_chunked_bodies() {
+        let limits = RequestLimits {
+            max_body_size: 8,
+            ..RequestLimits::default()
+        };
+
+        let request = format!("{}5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n", CHUNKED_HEAD);
+        assert_eq!(status_of(decode_str(&request, &limits)), 413);
+
+        // Before the chunk has arrived
+        let request = format!("{}ffffff\r\n", CHUNKED_HEAD);
+        assert_eq!(status_of(decode_str(&request, &limits)), 413);
+    }
+
+    #[test]
+    fn it_should_limit_chunk_size_lines() {
+        let limits = RequestLimits {
+            max_header_bytes: 256,
+            ..RequestLimits::default()
+        };
+
+        let extension = format!(";{}", "x".repeat(300));
+        let request = format!("{}5{}", CHUNKED_HEAD, extension);
+        assert_eq!(status_of(decode_str(&request, &limits)), 400);
+
+        let request = format!("{}5{}\r\nhello\r\n0\r\n\r\n", CHUNKED_HEAD, extension);
+        assert_eq!(status_of(decode_str(&request, &limits)), 400);
+    }
+
+    #[test]
+    fn it_should_limit_trailers() {
+        let limits = RequestLimits {
+            max_header_bytes: 256,
+            ..RequestLimits::default()
+        };
+
+        let trailer = format!("X-Trailer: {}\r\n", "x".repeat(100));
+        let request = format!("{}0\r\n{}", CHUNKED_HEAD, trailer.repeat(3));
+        assert_eq!(status_of(decode_str(&request, &limits)), 431);
+
+        let request = format!("{}0\r\nX-Trailer: {}", CHUNKED_HEAD, "x".repeat(300));
+        assert_eq!(status_of(decode_str(&request, &limits)), 431);
+    }
+
+    #[test]
+    fn it_should_decode_a_body_by_its_content_length() {
+        let mut buf = BytesMut::from("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET");
+
+        let decoded = decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded.body(), "hello");
+        assert_eq!(&buf[..], b"GET");
+    }
+
+    #[test]
+    fn it_should_reject_ambiguous_content_lengths() {
+        let limits = RequestLimits::default();
+
+        for content_length in &[
+            "Content-Length: 5\r\nContent-Length: 5",
+            "Content-Length: 5\r\ncontent-length: 6",
+            "Content-Length: 5, 5",
+            "Content-Length: +5",
+            "Content-Length: five",
+        ] {
+            let request = format!("POST / HTTP/1.1\r\n{}\r\n\r\nhello", content_length);
+
+            assert_eq!(status_of(decode_str(&request, &limits)), 400);
+        }
+    }
+
+    #[test]
+    fn it_should_reject_transfer_encodings_with_a_content_length() {
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+            "5\r\nhello\r\n0\r\n\r\n"
+        );
+
+        assert_eq!(
+            status_of(decode_str(&request, &RequestLimits::default())),
+            400
+        );
+    }
+
+    #[test]
+    fn it_should_reject_transfer_encodings_that_arent_chunked_last() {
+        let limits = RequestLimits::default();
+
+        for transfer_encoding in &[
+            "Transfer-Encoding: gzip",
+            "Transfer-Encoding: chunked, gzip",
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip",
+        ] {
+            let request = format!("POST / HTTP/1.1\r\n{}\r\n\r\n", transfer_encoding);
+
+            assert_eq!(status_of(decode_str(&request, &limits)), 400);
+        }
+
+        let request = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n0\r\n\r\n";
+
+        assert!(decode_str(request, &limits).unwrap().is_some());
+    }
+
+    #[test]
+    fn it_should_limit_bodies_by_their_content_length() {
+        let limits = RequestLimits {
+            max_body_size: 4,
+            ..RequestLimits::default()
+        };
+
+        // Turned away before the body arrives
+        let request = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n";
+
+        assert_eq!(status_of(decode_str(request, &limits)), 413);
+    }
+
+    #[test]
+    fn it_should_limit_uris() {
+        let limits = RequestLimits {
+            max_uri_length: 16,
+            ..RequestLimits::default()
+        };
+        let uri = format!("/{}", "a".repeat(16));
+
+        assert_eq!(
+            status_of(decode_str(
+                &format!("GET {} HTTP/1.1\r\n\r\n", uri),
+                &limits
+            )),
+            414
+        );
+        // Before the request line has fully arrived too
+        assert_eq!(status_of(decode_str(&format!("GET {}", uri), &limits)), 414);
+        assert!(decode_str("GET /a HTTP/1.1\r\n\r\n", &limits)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn it_should_limit_headers() {
+        let limits = RequestLimits {
+            max_headers: 2,
+            max_header_bytes: 64,
+            ..RequestLimits::default()
+        };
+
+        let request = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert_eq!(status_of(decode_str(request, &limits)), 431);
+
+        let request = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "1".repeat(64));
+        assert_eq!(status_of(decode_str(&request, &limits)), 431);
+
+        // Before the head has fully arrived too
+        let request = format!("GET / HTTP/1.1\r\nA: {}", "1".repeat(64));
+        assert_eq!(status_of(decode_str(&request, &limits)), 431);
+    }
+}
@@ -1,11 +1,87 @@
+use async_trait::async_trait;
 use std::fmt::{self, Write};
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+
+///
+/// A response body that's sent to the client piece by piece as it's produced, rather than all
+/// at once.
+///
+pub struct BodyStream {
+    // Only ever accessed through `get_mut`, so it's never actually locked. It's there so that
+    // responses, and in turn contexts, stay `Sync` without requiring the same of every stream.
+    inner: Mutex<Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>>,
+}
+
+impl BodyStream {
+    pub fn new<S>(stream: S) -> BodyStream
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        BodyStream {
+            inner: Mutex::new(Box::pin(stream)),
+        }
+    }
+}
 
-use bytes::BytesMut;
+impl Stream for BodyStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let inner = match self.get_mut().inner.get_mut() {
+            Ok(inner) => inner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        inner.as_mut().poll_next(cx)
+    }
+}
+
+///
+/// Contexts whose response body can be streamed.
+///
+pub trait HasBodyStream {
+    fn set_body_stream(&mut self, stream: BodyStream);
+}
+
+///
+/// The body of a response that's already been set, as taken by `HasResponseBody`.
+///
+pub enum ResponseBody {
+    Bytes(Bytes),
+    Stream(BodyStream),
+}
+
+///
+/// Contexts whose response can be read back by middleware once the handler has set it, to be
+/// transformed and then set again with `set_body_bytes` or `set_body_stream`.
+///
+#[async_trait]
+pub trait HasResponseBody: HasBodyStream {
+    /// The status code of the response.
+    fn response_status(&self) -> u16;
+
+    /// The values of a response header, joined with commas if it was set more than once.
+    fn response_header(&self, key: &str) -> Option<String>;
+
+    /// The size of the response body in bytes, unless it's streamed and not known up front.
+    fn response_size(&self) -> Option<u64>;
+
+    /// Takes the body of the response, leaving an empty one in its place.
+    async fn take_response_body(&mut self) -> io::Result<ResponseBody>;
+}
 
 pub struct Response {
     pub response: Vec<u8>,
     pub status_message: StatusMessage,
     pub header_raw: BytesMut,
+    /// When set, the body is streamed using chunked transfer-encoding and `response` is ignored.
+    pub body_stream: Option<BodyStream>,
 }
 
 pub enum StatusMessage {
@@ -19,6 +95,7 @@ impl Response {
             response: Vec::new(),
             status_message: StatusMessage::Ok,
             header_raw: BytesMut::new(),
+            body_stream: None,
         }
     }
 
@@ -36,40 +113,90 @@ impl Response {
 
     pub fn body(&mut self, s: &str) -> &mut Response {
         self.response = s.as_bytes().to_vec();
+        self.body_stream = None;
         self
     }
 
     pub fn body_bytes(&mut self, b: &[u8]) -> &mut Response {
         self.response = b.to_vec();
+        self.body_stream = None;
         self
     }
 
     pub fn body_bytes_from_vec(&mut self, b: Vec<u8>) -> &mut Response {
         self.response = b;
+        self.body_stream = None;
         self
     }
+
+    ///
+    /// Streams the body from the given stream, sending each item as a chunk as soon as it's
+    /// ready. Replaces any body that was set before.
+    ///
+    pub fn body_stream<S>(&mut self, stream: S) -> &mut Response
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        self.response = Vec::new();
+        self.body_stream = Some(BodyStream::new(stream));
+        self
+    }
+
+    ///
+    /// Takes the body, leaving an empty one in its place.
+    ///
+    pub fn take_body(&mut self) -> ResponseBody {
+        match self.body_stream.take() {
+            Some(stream) => ResponseBody::Stream(stream),
+            None => ResponseBody::Bytes(Bytes::from(std::mem::take(&mut self.response))),
+        }
+    }
 }
 
 pub fn encode(msg: &Response, buf: &mut BytesMut) {
-    let length = msg.response.len();
+    encode_head(msg, buf, Some(msg.response.len()));
+    buf.extend_from_slice(msg.response.as_slice());
+}
+
+///
+/// Encodes the status line and headers of a response, with the `Content-Length` of its body but
+/// not the body itself, as the answer to a `HEAD` request.
+///
+pub fn encode_without_body(msg: &Response, buf: &mut BytesMut) {
+    encode_head(msg, buf, Some(msg.response.len()));
+}
+
+///
+/// Encodes the status line and headers of a response whose body follows as chunks, written
+/// with `encode_chunk`.
+///
+pub fn encode_chunked_head(msg: &Response, buf: &mut BytesMut) {
+    encode_head(msg, buf, None);
+}
+
+///
+/// Encodes a single chunk of a chunked body. An empty chunk marks the end of the body.
+///
+pub fn encode_chunk(chunk: &[u8], buf: &mut BytesMut) {
+    write!(FastWrite(buf), "{:X}\r\n", chunk.len()).unwrap();
+    buf.extend_from_slice(chunk);
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn encode_head(msg: &Response, buf: &mut BytesMut, length: Option<usize>) {
     let now = crate::core::date::now();
 
-    write!(
-        FastWrite(buf),
-        "\
-         HTTP/1.1 {}\r\n\
-         Content-Length: {}\r\n\
-         Date: {}\r\n\
-         ",
-        msg.status_message,
-        length,
-        now
-    )
-    .unwrap();
+    write!(FastWrite(buf), "HTTP/1.1 {}\r\n", msg.status_message).unwrap();
+
+    match length {
+        Some(length) => write!(FastWrite(buf), "Content-Length: {}\r\n", length).unwrap(),
+        None => buf.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+    };
+
+    write!(FastWrite(buf), "Date: {}\r\n", now).unwrap();
 
     buf.extend_from_slice(&msg.header_raw);
     buf.extend_from_slice(b"\r\n");
-    buf.extend_from_slice(msg.response.as_slice());
 }
 
 impl Default for Response {
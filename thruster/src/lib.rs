@@ -18,10 +18,16 @@ pub mod parser;
 
 pub use crate::core::context::Context;
 pub use crate::core::errors;
+pub use crate::core::extractors;
+pub use crate::core::guard;
 pub use crate::core::http::Http;
 pub use crate::core::middleware::MiddlewareResult;
-pub use crate::core::request::{decode, Request, RequestWithParams};
-pub use crate::core::response::{encode, Response};
+pub use crate::core::request::{
+    decode, decode_with_limits, DecodeError, Request, RequestLimits, RequestWithParams,
+};
+pub use crate::core::response::{
+    encode, BodyStream, HasBodyStream, HasResponseBody, Response, ResponseBody,
+};
 pub use crate::core::{MiddlewareFn, MiddlewareNext, MiddlewareReturnValue};
 pub use app::testing_async as testing;
 pub use app::App;
@@ -0,0 +1,449 @@
+use log::Level;
+use std::fmt;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::core::context::Context;
+use crate::core::extractors::{
+    HasClientIp, HasMatchedRoute, HasRequestHeaders, HasRequestId, HasRequestMethod,
+};
+use crate::core::response::HasResponseBody;
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    ///
+    /// The Common Log Format, i.e. `127.0.0.1 - - [16/Oct/2026:10:49:00 +0000] "GET /users/1"
+    /// 200 512`.
+    ///
+    Common,
+    ///
+    /// The Common Log Format followed by the quoted `Referer` and `User-Agent`.
+    ///
+    Combined,
+    ///
+    /// One JSON object per line, with every field of the entry.
+    ///
+    Json,
+}
+
+///
+/// A request as it's written to the access log.
+///
+#[derive(Clone, Debug)]
+pub struct AccessLogEntry {
+    /// When the request came in.
+    pub time: SystemTime,
+    pub method: String,
+    /// The path of the request, with its query string.
+    pub path: String,
+    /// The route that matched the request, as it was registered, e.g. `/users/:id`.
+    pub route: Option<String>,
+    pub status: u16,
+    /// The size of the response body in bytes, if it's known before the body is sent.
+    pub size: Option<u64>,
+    /// How long the rest of the chain took to produce a response.
+    pub latency: Duration,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    /// The ID of the request, if the `RequestId` middleware gave it one.
+    pub request_id: Option<String>,
+}
+
+impl AccessLogEntry {
+    ///
+    /// Formats the entry as a single line, without a trailing newline.
+    ///
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common => self.common(),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-"))
+            ),
+            LogFormat::Json => serde_json::json!({
+                "time": self.tm().rfc3339().to_string(),
+                "method": self.method,
+                "path": self.path,
+                "route": self.route,
+                "status": self.status,
+                "size": self.size,
+                "latency_ms": self.latency.as_micros() as f64 / 1000.0,
+                "ip": self.ip.map(|ip| ip.to_string()),
+                "user_agent": self.user_agent,
+                "referer": self.referer,
+                "request_id": self.request_id,
+            })
+            .to_string(),
+        }
+    }
+
+    fn common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {}\" {} {}",
+            self.ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            self.tm()
+                .strftime("%d/%b/%Y:%H:%M:%S +0000")
+                .map(|time| time.to_string())
+                .unwrap_or_default(),
+            escape(&self.method),
+            escape(&self.path),
+            self.status,
+            self.size
+                .map(|size| size.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+        )
+    }
+
+    fn tm(&self) -> time::Tm {
+        let since_epoch = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        time::at_utc(time::Timespec::new(
+            since_epoch.as_secs() as i64,
+            since_epoch.subsec_nanos() as i32,
+        ))
+    }
+}
+
+// Keeps whatever the client sent from breaking out of its quotes, or its line
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+type Sink = Box<dyn Fn(&AccessLogEntry, &str) + Send + Sync>;
+
+///
+/// Middleware that writes a line to the access log for every request, with its method, path,
+/// matched route, status, response size, latency, client IP and user agent. Lines go to `log`,
+/// under the `access` target at the info level, unless a sink is set.
+///
+/// Streamed responses are logged once their headers are ready, so their latency doesn't include
+/// the time spent sending the body, and their size is only known if they set a
+/// `Content-Length`.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref ACCESS_LOG: AccessLog = AccessLog::new(LogFormat::Combined);
+/// }
+///
+/// #[middleware_fn]
+/// async fn access_log(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     ACCESS_LOG.handle(context, next).await
+/// }
+///
+/// app.use_middleware("/", m![access_log]);
+/// ```
+///
+pub struct AccessLog {
+    format: LogFormat,
+    target: String,
+    level: Level,
+    sink: Option<Sink>,
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .field("target", &self.target)
+            .field("level", &self.level)
+            .field("sink", &self.sink.is_some())
+            .finish()
+    }
+}
+
+impl AccessLog {
+    pub fn new(format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            target: "access".to_owned(),
+            level: Level::Info,
+            sink: None,
+        }
+    }
+
+    ///
+    /// Sets the `log` target that lines are written under.
+    ///
+    pub fn target(mut self, target: &str) -> AccessLog {
+        self.target = target.to_owned();
+        self
+    }
+
+    ///
+    /// Sets the `log` level that lines are written at.
+    ///
+    pub fn level(mut self, level: Level) -> AccessLog {
+        self.level = level;
+        self
+    }
+
+    ///
+    /// Sends entries to `sink` instead of `log`, along with the line they were formatted as.
+    /// This is called before the response is sent, so it shouldn't block.
+    ///
+    pub fn sink(
+        mut self,
+        sink: impl Fn(&AccessLogEntry, &str) + Send + Sync + 'static,
+    ) -> AccessLog {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    pub async fn handle<T>(&self, context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static
+            + Context
+            + HasClientIp
+            + HasMatchedRoute
+            + HasRequestHeaders
+            + HasRequestId
+            + HasRequestMethod
+            + HasResponseBody
+            + Send,
+    {
+        let time = SystemTime::now();
+        let method = context.request_method().to_owned();
+        let path = context.route().to_owned();
+        let route = context.matched_route().map(|route| route.to_owned());
+        let ip = context.client_ip();
+        let user_agent = context.request_header("user-agent").pop();
+        let referer = context.request_header("referer").pop();
+
+        let start = Instant::now();
+        let result = next(context).await;
+        let latency = start.elapsed();
+
+        let context = match &result {
+            Ok(context) => context,
+            Err(e) => &e.context,
+        };
+
+        let entry = AccessLogEntry {
+            time,
+            method,
+            path,
+            route,
+            status: context.response_status(),
+            size: context.response_size().or_else(|| {
+                context
+                    .response_header("content-length")
+                    .and_then(|length| length.trim().parse().ok())
+            }),
+            latency,
+            ip,
+            user_agent,
+            referer,
+            request_id: context.request_id().map(|id| id.to_owned()),
+        };
+
+        let line = entry.format(self.format);
+        match &self.sink {
+            Some(sink) => sink(&entry, &line),
+            None => log::log!(target: self.target.as_str(), self.level, "{}", line),
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use std::sync::Mutex;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::errors::ThrusterError;
+    use crate::core::request::Request;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    lazy_static! {
+        static ref LINES: Mutex<Vec<(AccessLogEntry, String)>> = Mutex::new(Vec::new());
+        static ref ACCESS_LOG: AccessLog = AccessLog::new(LogFormat::Combined)
+            .sink(|entry, line| { LINES.lock().unwrap().push((entry.clone(), line.to_owned())) });
+    }
+
+    #[middleware_fn(_internal)]
+    async fn access_log(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        ACCESS_LOG.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn user(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body("alice");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn broken(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.status(500);
+        context.body("broken");
+
+        Err(ThrusterError {
+            context,
+            message: "broken".to_owned(),
+            status: 500,
+            cause: None,
+        })
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(access_log));
+        app.get("/users/:id", MiddlewareTuple::A(user));
+        app.get("/broken", MiddlewareTuple::A(broken));
+        app.commit()
+    }
+
+    // The entry and line that were logged for `path`
+    fn logged(path: &str) -> (AccessLogEntry, String) {
+        LINES
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(entry, _)| entry.path == path)
+            .cloned()
+            .unwrap()
+    }
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            time: UNIX_EPOCH + Duration::from_secs(1445412480),
+            method: "GET".to_owned(),
+            path: "/users/1?tab=posts".to_owned(),
+            route: Some("/users/:id".to_owned()),
+            status: 200,
+            size: Some(512),
+            latency: Duration::from_micros(1500),
+            ip: Some("127.0.0.1".parse().unwrap()),
+            user_agent: Some("curl/7.79.1".to_owned()),
+            referer: None,
+            request_id: Some("abc".to_owned()),
+        }
+    }
+
+    #[test]
+    fn it_should_format_entries_in_the_common_log_formats() {
+        assert_eq!(
+            entry().format(LogFormat::Common),
+            "127.0.0.1 - - [21/Oct/2015:07:28:00 +0000] \"GET /users/1?tab=posts\" 200 512"
+        );
+        assert_eq!(
+            entry().format(LogFormat::Combined),
+            "127.0.0.1 - - [21/Oct/2015:07:28:00 +0000] \"GET /users/1?tab=posts\" 200 512 \"-\" \"curl/7.79.1\""
+        );
+
+        let entry = AccessLogEntry {
+            ip: None,
+            size: None,
+            ..entry()
+        };
+        assert!(entry
+            .format(LogFormat::Common)
+            .starts_with("- - - [21/Oct/2015"));
+        assert!(entry.format(LogFormat::Common).ends_with(" 200 -"));
+    }
+
+    #[test]
+    fn it_should_format_entries_as_json() {
+        let line: serde_json::Value =
+            serde_json::from_str(&entry().format(LogFormat::Json)).unwrap();
+
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "time": "2015-10-21T07:28:00Z",
+                "method": "GET",
+                "path": "/users/1?tab=posts",
+                "route": "/users/:id",
+                "status": 200,
+                "size": 512,
+                "latency_ms": 1.5,
+                "ip": "127.0.0.1",
+                "user_agent": "curl/7.79.1",
+                "referer": null,
+                "request_id": "abc",
+            })
+        );
+    }
+
+    #[test]
+    fn it_should_keep_client_values_inside_their_quotes() {
+        let entry = AccessLogEntry {
+            path: "/\"injected\" 200 0\n".to_owned(),
+            user_agent: Some("a\\b".to_owned()),
+            ..entry()
+        };
+
+        assert_eq!(
+            entry.format(LogFormat::Combined),
+            "127.0.0.1 - - [21/Oct/2015:07:28:00 +0000] \"GET /\\\"injected\\\" 200 0\\x0a\" 200 512 \"-\" \"a\\\\b\""
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_log_every_request() {
+        testing::request(
+            &app(),
+            "GET",
+            "/users/7?tab=posts",
+            &[("User-Agent", "tests"), ("Referer", "https://example.com/")],
+            "",
+        )
+        .await;
+
+        let (entry, line) = logged("/users/7?tab=posts");
+
+        assert_eq!(entry.method, "GET");
+        assert_eq!(entry.route.as_deref(), Some("/users/:id"));
+        assert_eq!(entry.status, 200);
+        assert_eq!(entry.size, Some(5));
+        assert_eq!(entry.user_agent.as_deref(), Some("tests"));
+        assert!(
+            line.ends_with("\"GET /users/7?tab=posts\" 200 5 \"https://example.com/\" \"tests\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_log_requests_that_fail() {
+        let response = testing::get(&app(), "/broken").await;
+        assert_eq!(response.status.1, 500);
+
+        let (entry, _) = logged("/broken");
+
+        assert_eq!(entry.status, 500);
+        assert_eq!(entry.size, Some(6));
+    }
+}
@@ -0,0 +1,1221 @@
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet, PublicKeyUse};
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+pub use jsonwebtoken::Algorithm;
+
+use crate::core::context::Context;
+use crate::core::errors::ThrusterError;
+use crate::core::extractors::{ExtractionError, FromContext, HasRequestHeaders};
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+///
+/// A context that can hold whoever the request was authenticated as, e.g. the user found by
+/// `BasicAuth`, or the claims of the token checked by `JwtAuth`.
+///
+pub trait HasPrincipal {
+    fn principal_any(&self) -> Option<&(dyn Any + Send + Sync)>;
+
+    fn set_principal_any(&mut self, principal: Box<dyn Any + Send + Sync>);
+
+    ///
+    /// The principal of the request, if it's been authenticated as a `P`.
+    ///
+    fn principal<P: 'static>(&self) -> Option<&P> {
+        self.principal_any()
+            .and_then(|principal| principal.downcast_ref())
+    }
+
+    fn set_principal<P: Send + Sync + 'static>(&mut self, principal: P) {
+        self.set_principal_any(Box::new(principal));
+    }
+}
+
+///
+/// A principal that has roles, for use with the `has_role` guard.
+///
+pub trait HasRoles {
+    fn has_role(&self, role: &str) -> bool;
+}
+
+///
+/// Extracts the principal that an auth middleware put on the context. Fails with a `401` if the
+/// request wasn't authenticated, or was authenticated as something other than a `P`.
+///
+#[derive(Debug)]
+pub struct Authenticated<P>(pub P);
+
+#[async_trait]
+impl<C: HasPrincipal + Send, P: Clone + Send + 'static> FromContext<C> for Authenticated<P> {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        context
+            .principal::<P>()
+            .cloned()
+            .map(Authenticated)
+            .ok_or_else(|| ExtractionError::new(401, "Unauthorized"))
+    }
+}
+
+fn unauthorized<C: Context>(mut context: C, challenge: &str, message: &str) -> MiddlewareResult<C> {
+    context.status(401);
+    context.remove("WWW-Authenticate");
+    context.set("WWW-Authenticate", challenge);
+    context.set_body(message.as_bytes().to_vec());
+
+    Err(ThrusterError {
+        context,
+        message: message.to_owned(),
+        status: 401,
+        cause: None,
+    })
+}
+
+// The credentials of an `Authorization` header with the given scheme, which is case insensitive
+fn credentials<C: HasRequestHeaders>(context: &C, scheme: &str) -> Option<String> {
+    context
+        .request_header("authorization")
+        .into_iter()
+        .find_map(|header| {
+            let (given, credentials) = header.trim().split_once(' ')?;
+
+            if given.eq_ignore_ascii_case(scheme) {
+                Some(credentials.trim().to_owned())
+            } else {
+                None
+            }
+        })
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+type VerifyPassword<P> = Box<dyn Fn(String, String) -> BoxFuture<'static, Option<P>> + Send + Sync>;
+
+///
+/// Middleware that authenticates requests with HTTP Basic auth. The username and password are
+/// handed to a verifier, which returns the principal they belong to, or `None` if they're wrong,
+/// in which case the request is answered with a `401` and a `WWW-Authenticate` challenge.
+///
+/// Auth middleware can be chained to accept any one of several schemes. A request that's already
+/// been authenticated is let through, and an optional middleware lets through requests that
+/// don't have credentials for it, leaving them to the next one.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref BASIC_AUTH: BasicAuth<User> = BasicAuth::new(|username, password| async move {
+///         User::find_by_login(&username, &password).await
+///     })
+///     .realm("admin");
+/// }
+///
+/// #[middleware_fn]
+/// async fn basic_auth(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     BASIC_AUTH.handle(context, next).await
+/// }
+///
+/// app.use_middleware("/admin", m![basic_auth]);
+/// ```
+///
+pub struct BasicAuth<P> {
+    verify: VerifyPassword<P>,
+    realm: String,
+    optional: bool,
+}
+
+impl<P> fmt::Debug for BasicAuth<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("realm", &self.realm)
+            .field("optional", &self.optional)
+            .finish()
+    }
+}
+
+impl<P: Send + Sync + 'static> BasicAuth<P> {
+    pub fn new<F, Fut>(verify: F) -> BasicAuth<P>
+    where
+        F: Fn(String, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<P>> + Send + 'static,
+    {
+        BasicAuth {
+            verify: Box::new(move |username, password| Box::pin(verify(username, password))),
+            realm: "api".to_owned(),
+            optional: false,
+        }
+    }
+
+    ///
+    /// Sets the realm sent in the challenge, which browsers show when they prompt for a login.
+    /// Defaults to `api`.
+    ///
+    pub fn realm(mut self, realm: &str) -> BasicAuth<P> {
+        self.realm = realm.to_owned();
+        self
+    }
+
+    ///
+    /// Sets whether requests without Basic credentials are let through, unauthenticated.
+    ///
+    pub fn optional(mut self, optional: bool) -> BasicAuth<P> {
+        self.optional = optional;
+        self
+    }
+
+    pub async fn handle<C>(&self, mut context: C, next: MiddlewareNext<C>) -> MiddlewareResult<C>
+    where
+        C: 'static + Context + HasPrincipal + HasRequestHeaders + Send,
+    {
+        if context.principal_any().is_some() {
+            return next(context).await;
+        }
+
+        let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", quote(&self.realm));
+
+        let credentials = match credentials(&context, "basic") {
+            Some(credentials) => credentials,
+            None if self.optional => return next(context).await,
+            None => return unauthorized(context, &challenge, "Unauthorized"),
+        };
+
+        let login = base64::decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded
+                    .split_once(':')
+                    .map(|(username, password)| (username.to_owned(), password.to_owned()))
+            });
+
+        let principal = match login {
+            Some((username, password)) => (self.verify)(username, password).await,
+            None => None,
+        };
+
+        match principal {
+            Some(principal) => {
+                context.set_principal(principal);
+                next(context).await
+            }
+            None => unauthorized(context, &challenge, "Invalid username or password"),
+        }
+    }
+}
+
+type VerifyKey<P> = Box<dyn Fn(String) -> BoxFuture<'static, Option<P>> + Send + Sync>;
+
+enum KeySource {
+    Header(String),
+    Query(String),
+}
+
+///
+/// Middleware that authenticates requests by an API key, sent in a header or the query string.
+/// The key is handed to a verifier, which returns the principal it belongs to, or `None` if it
+/// isn't valid, in which case the request is answered with a `401`. Verifiers should compare
+/// keys in constant time, or look them up by a hash.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref API_KEY_AUTH: ApiKeyAuth<Client> = ApiKeyAuth::header("X-Api-Key", |key| async move {
+///         Client::find_by_api_key(&key).await
+///     });
+/// }
+///
+/// #[middleware_fn]
+/// async fn api_key_auth(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     API_KEY_AUTH.handle(context, next).await
+/// }
+/// ```
+///
+pub struct ApiKeyAuth<P> {
+    source: KeySource,
+    verify: VerifyKey<P>,
+    realm: String,
+    optional: bool,
+}
+
+impl<P> fmt::Debug for ApiKeyAuth<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match &self.source {
+            KeySource::Header(header) => format!("header {}", header),
+            KeySource::Query(param) => format!("query {}", param),
+        };
+
+        f.debug_struct("ApiKeyAuth")
+            .field("source", &source)
+            .field("realm", &self.realm)
+            .field("optional", &self.optional)
+            .finish()
+    }
+}
+
+impl<P: Send + Sync + 'static> ApiKeyAuth<P> {
+    ///
+    /// Reads keys from the given request header.
+    ///
+    pub fn header<F, Fut>(header: &str, verify: F) -> ApiKeyAuth<P>
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<P>> + Send + 'static,
+    {
+        ApiKeyAuth::with_source(KeySource::Header(header.to_owned()), verify)
+    }
+
+    ///
+    /// Reads keys from the given query string param. Query strings end up in access logs, so
+    /// headers are better where clients can send them.
+    ///
+    pub fn query<F, Fut>(param: &str, verify: F) -> ApiKeyAuth<P>
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<P>> + Send + 'static,
+    {
+        ApiKeyAuth::with_source(KeySource::Query(param.to_owned()), verify)
+    }
+
+    fn with_source<F, Fut>(source: KeySource, verify: F) -> ApiKeyAuth<P>
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<P>> + Send + 'static,
+    {
+        ApiKeyAuth {
+            source,
+            verify: Box::new(move |key| Box::pin(verify(key))),
+            realm: "api".to_owned(),
+            optional: false,
+        }
+    }
+
+    ///
+    /// Sets the realm sent in the challenge. Defaults to `api`.
+    ///
+    pub fn realm(mut self, realm: &str) -> ApiKeyAuth<P> {
+        self.realm = realm.to_owned();
+        self
+    }
+
+    ///
+    /// Sets whether requests without a key are let through, unauthenticated.
+    ///
+    pub fn optional(mut self, optional: bool) -> ApiKeyAuth<P> {
+        self.optional = optional;
+        self
+    }
+
+    pub async fn handle<C>(&self, mut context: C, next: MiddlewareNext<C>) -> MiddlewareResult<C>
+    where
+        C: 'static + Context + HasPrincipal + HasRequestHeaders + Send,
+    {
+        if context.principal_any().is_some() {
+            return next(context).await;
+        }
+
+        let key = match &self.source {
+            KeySource::Header(header) => context.request_header(header).pop(),
+            KeySource::Query(param) => context.route().split_once('?').and_then(|(_, query)| {
+                form_urlencoded::parse(query.as_bytes())
+                    .find(|(name, _)| name == param)
+                    .map(|(_, key)| key.into_owned())
+            }),
+        };
+
+        let challenge = format!("ApiKey realm=\"{}\"", quote(&self.realm));
+
+        let key = match key.filter(|key| !key.is_empty()) {
+            Some(key) => key,
+            None if self.optional => return next(context).await,
+            None => return unauthorized(context, &challenge, "Unauthorized"),
+        };
+
+        match (self.verify)(key).await {
+            Some(principal) => {
+                context.set_principal(principal);
+                next(context).await
+            }
+            None => unauthorized(context, &challenge, "Invalid API key"),
+        }
+    }
+}
+
+// How often a JWKS file is checked for new keys, at most, when a token has a key ID that isn't
+// in it
+const JWKS_RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+struct JwksKeys {
+    keys: HashMap<String, (DecodingKey, Algorithm)>,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+}
+
+enum Keys {
+    Static(DecodingKey),
+    Jwks {
+        path: PathBuf,
+        keys: RwLock<JwksKeys>,
+    },
+}
+
+///
+/// Middleware that authenticates requests by a JWT sent as a Bearer token. The token's signature
+/// is checked, along with its `exp` and `nbf` claims and, if they're set, its audience and
+/// issuer, and its claims are put on the context as the principal. Requests without a valid
+/// token are answered with a `401` and a `WWW-Authenticate` challenge that says what was wrong
+/// with it.
+///
+/// Tokens can be checked against a single HMAC secret or RSA public key, or against the keys in
+/// a JWKS file, picked by the token's `kid`. To rotate keys, add the new key to the file before
+/// tokens are signed with it. The file is read again whenever a token comes in with a `kid` that
+/// isn't in it, at most every 10 seconds, if it's changed since it was last read.
+///
+/// ```rust, ignore
+/// #[derive(Clone, Deserialize)]
+/// struct Claims {
+///     sub: String,
+///     exp: u64,
+/// }
+///
+/// lazy_static! {
+///     static ref JWT_AUTH: JwtAuth<Claims> = JwtAuth::jwks_file("/etc/keys/jwks.json")
+///         .unwrap()
+///         .audience(&["orders"]);
+/// }
+///
+/// #[middleware_fn]
+/// async fn jwt_auth(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     JWT_AUTH.handle(context, next).await
+/// }
+///
+/// #[middleware_fn]
+/// async fn get_orders(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     let claims = match Authenticated::<Claims>::from_context(&mut context).await {
+///         Ok(Authenticated(claims)) => claims,
+///         Err(e) => return Err(e.into_thruster_error(context)),
+///     };
+///     ...
+/// }
+/// ```
+///
+pub struct JwtAuth<Claims> {
+    keys: Keys,
+    validation: Validation,
+    realm: String,
+    optional: bool,
+    claims: PhantomData<fn() -> Claims>,
+}
+
+impl<Claims> fmt::Debug for JwtAuth<Claims> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys = match &self.keys {
+            Keys::Static(_) => "static".to_owned(),
+            Keys::Jwks { path, .. } => format!("jwks {}", path.display()),
+        };
+
+        f.debug_struct("JwtAuth")
+            .field("keys", &keys)
+            .field("validation", &self.validation)
+            .field("realm", &self.realm)
+            .field("optional", &self.optional)
+            .finish()
+    }
+}
+
+impl<Claims: DeserializeOwned + Send + Sync + 'static> JwtAuth<Claims> {
+    ///
+    /// Checks tokens signed with HS256, HS384 or HS512 using `secret`.
+    ///
+    pub fn hmac(secret: &[u8]) -> JwtAuth<Claims> {
+        JwtAuth::with_keys(
+            Keys::Static(DecodingKey::from_secret(secret)),
+            &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+        )
+    }
+
+    ///
+    /// Checks tokens signed with RS256, RS384, RS512, PS256, PS384 or PS512 using a PEM encoded
+    /// RSA public key.
+    ///
+    pub fn rsa_pem(pem: &[u8]) -> Result<JwtAuth<Claims>, jsonwebtoken::errors::Error> {
+        Ok(JwtAuth::with_keys(
+            Keys::Static(DecodingKey::from_rsa_pem(pem)?),
+            &[
+                Algorithm::RS256,
+                Algorithm::RS384,
+                Algorithm::RS512,
+                Algorithm::PS256,
+                Algorithm::PS384,
+                Algorithm::PS512,
+            ],
+        ))
+    }
+
+    ///
+    /// Checks tokens using the keys in a JWKS file. Each key is only used with its own
+    /// algorithm, which is taken from its `alg`, or its type and curve if it doesn't have one.
+    ///
+    pub fn jwks_file(path: impl AsRef<Path>) -> io::Result<JwtAuth<Claims>> {
+        let path = path.as_ref().to_path_buf();
+        let modified = std::fs::metadata(&path)?.modified().ok();
+        let keys = parse_jwks(&std::fs::read(&path)?)?;
+
+        Ok(JwtAuth::with_keys(
+            Keys::Jwks {
+                path,
+                keys: RwLock::new(JwksKeys {
+                    keys,
+                    modified,
+                    checked_at: Instant::now(),
+                }),
+            },
+            &[],
+        ))
+    }
+
+    fn with_keys(keys: Keys, algorithms: &[Algorithm]) -> JwtAuth<Claims> {
+        let mut validation = Validation::default();
+        validation.algorithms = algorithms.to_vec();
+        validation.validate_nbf = true;
+
+        JwtAuth {
+            keys,
+            validation,
+            realm: "api".to_owned(),
+            optional: false,
+            claims: PhantomData,
+        }
+    }
+
+    ///
+    /// Limits the algorithms that tokens can be signed with, for keys that aren't from a JWKS
+    /// file. They have to be ones the key can be used with.
+    ///
+    pub fn algorithms(mut self, algorithms: &[Algorithm]) -> JwtAuth<Claims> {
+        self.validation.algorithms = algorithms.to_vec();
+        self
+    }
+
+    ///
+    /// Only accepts tokens whose `aud` claim has one of `audience` in it.
+    ///
+    pub fn audience(mut self, audience: &[&str]) -> JwtAuth<Claims> {
+        self.validation.set_audience(audience);
+        self
+    }
+
+    ///
+    /// Only accepts tokens whose `iss` claim is one of `issuers`.
+    ///
+    pub fn issuer(mut self, issuers: &[&str]) -> JwtAuth<Claims> {
+        self.validation.set_issuer(issuers);
+        self
+    }
+
+    ///
+    /// Sets how much clock skew is allowed for when checking `exp` and `nbf`. Defaults to a
+    /// minute.
+    ///
+    pub fn leeway(mut self, leeway: Duration) -> JwtAuth<Claims> {
+        self.validation.leeway = leeway.as_secs();
+        self
+    }
+
+    ///
+    /// Sets the realm sent in the challenge. Defaults to `api`.
+    ///
+    pub fn realm(mut self, realm: &str) -> JwtAuth<Claims> {
+        self.realm = realm.to_owned();
+        self
+    }
+
+    ///
+    /// Sets whether requests without a Bearer token are let through, unauthenticated. Requests
+    /// with a token that isn't valid are still turned away.
+    ///
+    pub fn optional(mut self, optional: bool) -> JwtAuth<Claims> {
+        self.optional = optional;
+        self
+    }
+
+    ///
+    /// Reads the JWKS file again, if that's where keys come from.
+    ///
+    pub async fn reload(&self) -> io::Result<()> {
+        if let Keys::Jwks { path, keys } = &self.keys {
+            let modified = tokio::fs::metadata(path).await?.modified().ok();
+            let parsed = parse_jwks(&tokio::fs::read(path).await?)?;
+
+            let mut keys = keys.write().unwrap();
+            keys.keys = parsed;
+            keys.modified = modified;
+            keys.checked_at = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    async fn reload_if_modified(&self) {
+        let (path, keys) = match &self.keys {
+            Keys::Jwks { path, keys } => (path, keys),
+            Keys::Static(_) => return,
+        };
+
+        {
+            let mut keys = keys.write().unwrap();
+            if keys.checked_at.elapsed() < JWKS_RECHECK_INTERVAL {
+                return;
+            }
+            keys.checked_at = Instant::now();
+        }
+
+        let modified = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(e) => {
+                warn!("Could not read JWKS file {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        if modified.is_some() && modified == keys.read().unwrap().modified {
+            return;
+        }
+
+        if let Err(e) = self.reload().await {
+            warn!("Could not reload JWKS file {}: {}", path.display(), e);
+        }
+    }
+
+    fn key(&self, kid: Option<&str>) -> Option<(DecodingKey, Validation)> {
+        match &self.keys {
+            Keys::Static(key) => Some((key.clone(), self.validation.clone())),
+            Keys::Jwks { keys, .. } => {
+                let keys = keys.read().unwrap();
+
+                // A token without a key ID can only be checked if there's just the one key
+                let (key, algorithm) = match kid {
+                    Some(kid) => keys.keys.get(kid)?,
+                    None if keys.keys.len() == 1 => keys.keys.values().next()?,
+                    None => return None,
+                };
+
+                let mut validation = self.validation.clone();
+                validation.algorithms = vec![*algorithm];
+
+                Some((key.clone(), validation))
+            }
+        }
+    }
+
+    async fn verify(&self, token: &str) -> Result<Claims, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| describe(e.kind()))?;
+
+        let (key, validation) = match self.key(header.kid.as_deref()) {
+            Some(key) => key,
+            None => {
+                self.reload_if_modified().await;
+                self.key(header.kid.as_deref())
+                    .ok_or_else(|| "The token was signed with an unknown key".to_owned())?
+            }
+        };
+
+        jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| describe(e.kind()))
+    }
+
+    pub async fn handle<C>(&self, mut context: C, next: MiddlewareNext<C>) -> MiddlewareResult<C>
+    where
+        C: 'static + Context + HasPrincipal + HasRequestHeaders + Send,
+    {
+        if context.principal_any().is_some() {
+            return next(context).await;
+        }
+
+        let realm = quote(&self.realm);
+
+        let token = match credentials(&context, "bearer") {
+            Some(token) => token,
+            None if self.optional => return next(context).await,
+            None => {
+                let challenge = format!("Bearer realm=\"{}\"", realm);
+                return unauthorized(context, &challenge, "Unauthorized");
+            }
+        };
+
+        match self.verify(&token).await {
+            Ok(claims) => {
+                context.set_principal(claims);
+                next(context).await
+            }
+            Err(description) => {
+                let challenge = format!(
+                    "Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"",
+                    realm,
+                    quote(&description)
+                );
+                unauthorized(context, &challenge, &description)
+            }
+        }
+    }
+}
+
+fn describe(kind: &ErrorKind) -> String {
+    match kind {
+        ErrorKind::ExpiredSignature => "The token has expired".to_owned(),
+        ErrorKind::ImmatureSignature => "The token isn't valid yet".to_owned(),
+        ErrorKind::InvalidAudience => "The token is for a different audience".to_owned(),
+        ErrorKind::InvalidIssuer => "The token is from an untrusted issuer".to_owned(),
+        ErrorKind::InvalidSignature => "The token's signature is invalid".to_owned(),
+        ErrorKind::InvalidAlgorithm => "The token is signed with the wrong algorithm".to_owned(),
+        ErrorKind::MissingRequiredClaim(claim) => {
+            format!("The token is missing the `{}` claim", claim)
+        }
+        _ => "The token is invalid".to_owned(),
+    }
+}
+
+fn parse_jwks(bytes: &[u8]) -> io::Result<HashMap<String, (DecodingKey, Algorithm)>> {
+    let set: JwkSet =
+        serde_json::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut keys = HashMap::new();
+    for jwk in set.keys {
+        if let Some(PublicKeyUse::Encryption) = jwk.common.public_key_use {
+            continue;
+        }
+
+        let kid = jwk.common.key_id.clone().unwrap_or_default();
+
+        let algorithm = jwk.common.algorithm.or(match &jwk.algorithm {
+            AlgorithmParameters::RSA(_) => Some(Algorithm::RS256),
+            AlgorithmParameters::EllipticCurve(params) => match params.curve {
+                EllipticCurve::P256 => Some(Algorithm::ES256),
+                EllipticCurve::P384 => Some(Algorithm::ES384),
+                _ => None,
+            },
+            AlgorithmParameters::OctetKey(_) => Some(Algorithm::HS256),
+            AlgorithmParameters::OctetKeyPair(_) => Some(Algorithm::EdDSA),
+        });
+
+        // jsonwebtoken reads symmetric keys as standard base64, but JWKs have them as base64url
+        let key = match &jwk.algorithm {
+            AlgorithmParameters::OctetKey(params) => {
+                base64::decode_config(&params.value, base64::URL_SAFE_NO_PAD)
+                    .map(|secret| DecodingKey::from_secret(&secret))
+                    .map_err(|e| e.to_string())
+            }
+            _ => DecodingKey::from_jwk(&jwk).map_err(|e| e.to_string()),
+        };
+
+        match (key, algorithm) {
+            (Ok(key), Some(algorithm)) => {
+                keys.insert(kid, (key, algorithm));
+            }
+            (Err(e), _) => warn!("Skipping JWK {:?}: {}", kid, e),
+            (_, None) => warn!("Skipping JWK {:?}: unsupported algorithm", kid),
+        }
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use lazy_static::lazy_static;
+    use serde_json::{json, Value};
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    const SECRET: &[u8] = b"a secret that's long enough for HS256";
+
+    lazy_static! {
+        static ref BASIC_AUTH: BasicAuth<String> =
+            BasicAuth::new(|username, password| async move {
+                if username == "ada" && password == "hunter2" {
+                    Some(username)
+                } else {
+                    None
+                }
+            })
+            .realm("admin");
+        static ref API_KEY_AUTH: ApiKeyAuth<String> =
+            ApiKeyAuth::header("X-Api-Key", verify_key).optional(true);
+        static ref QUERY_API_KEY_AUTH: ApiKeyAuth<String> =
+            ApiKeyAuth::query("api_key", verify_key);
+        static ref JWT_AUTH: JwtAuth<Value> = JwtAuth::hmac(SECRET)
+            .audience(&["orders"])
+            .leeway(Duration::from_secs(0));
+    }
+
+    async fn verify_key(key: String) -> Option<String> {
+        if key == "key-1" {
+            Some("client-1".to_owned())
+        } else {
+            None
+        }
+    }
+
+    #[middleware_fn(_internal)]
+    async fn basic_auth(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        BASIC_AUTH.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn api_key_auth(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        API_KEY_AUTH.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn query_api_key_auth(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        QUERY_API_KEY_AUTH.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn jwt_auth(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        JWT_AUTH.handle(context, next).await
+    }
+
+    // Answers with who the request was authenticated as
+    #[middleware_fn(_internal)]
+    async fn whoami(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let body = match context.principal::<Value>() {
+            Some(claims) => claims["sub"].as_str().unwrap_or("-").to_owned(),
+            None => context
+                .principal::<String>()
+                .cloned()
+                .unwrap_or_else(|| "anonymous".to_owned()),
+        };
+        context.body(&body);
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn claims(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        match Authenticated::<Value>::from_context(&mut context).await {
+            Ok(Authenticated(claims)) => {
+                context.body(claims["sub"].as_str().unwrap_or("-"));
+                Ok(context)
+            }
+            Err(e) => Err(e.into_thruster_error(context)),
+        }
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/basic", MiddlewareTuple::A(basic_auth));
+        app.use_middleware("/key", MiddlewareTuple::A(api_key_auth));
+        app.use_middleware("/query", MiddlewareTuple::A(query_api_key_auth));
+        app.use_middleware("/jwt", MiddlewareTuple::A(jwt_auth));
+        app.get("/basic", MiddlewareTuple::A(whoami));
+        app.get("/key", MiddlewareTuple::A(whoami));
+        app.get("/query", MiddlewareTuple::A(whoami));
+        app.get("/jwt", MiddlewareTuple::A(whoami));
+        app.get("/claims", MiddlewareTuple::A(claims));
+        app.commit()
+    }
+
+    async fn get(path: &str, headers: &[(&str, &str)]) -> testing::TestResponse {
+        testing::request(&app(), "GET", path, headers, "").await
+    }
+
+    async fn get_with_token(token: &str) -> testing::TestResponse {
+        get("/jwt", &[("Authorization", &format!("Bearer {}", token))]).await
+    }
+
+    fn sign(claims: Value, secret: &[u8]) -> String {
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
+
+    #[tokio::test]
+    async fn it_should_authenticate_basic_credentials() {
+        let credentials = format!("Basic {}", base64::encode("ada:hunter2"));
+        let response = get("/basic", &[("Authorization", &credentials)]).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "ada");
+    }
+
+    #[tokio::test]
+    async fn it_should_read_the_basic_scheme_in_any_case() {
+        let credentials = format!("basic {}", base64::encode("ada:hunter2"));
+        let response = get("/basic", &[("Authorization", &credentials)]).await;
+
+        assert_eq!(response.body, "ada");
+    }
+
+    #[tokio::test]
+    async fn it_should_challenge_requests_without_basic_credentials() {
+        let response = get("/basic", &[]).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(
+            response.headers["WWW-Authenticate"],
+            "Basic realm=\"admin\", charset=\"UTF-8\""
+        );
+        assert_eq!(response.body, "Unauthorized");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_wrong_or_malformed_basic_credentials() {
+        let wrong = format!("Basic {}", base64::encode("ada:letmein"));
+        let response = get("/basic", &[("Authorization", &wrong)]).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.body, "Invalid username or password");
+
+        let response = get("/basic", &[("Authorization", "Basic not base64!")]).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.body, "Invalid username or password");
+
+        let no_colon = format!("Basic {}", base64::encode("ada"));
+        let response = get("/basic", &[("Authorization", &no_colon)]).await;
+
+        assert_eq!(response.status.1, 401);
+    }
+
+    #[tokio::test]
+    async fn it_should_authenticate_api_keys_from_a_header() {
+        let response = get("/key", &[("X-Api-Key", "key-1")]).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "client-1");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_invalid_api_keys() {
+        let response = get("/key", &[("X-Api-Key", "key-2")]).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.headers["WWW-Authenticate"], "ApiKey realm=\"api\"");
+        assert_eq!(response.body, "Invalid API key");
+    }
+
+    #[tokio::test]
+    async fn it_should_let_requests_without_a_key_through_when_optional() {
+        let response = get("/key", &[]).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "anonymous");
+
+        let response = get("/key", &[("X-Api-Key", "")]).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "anonymous");
+    }
+
+    #[tokio::test]
+    async fn it_should_authenticate_api_keys_from_the_query_string() {
+        let response = get("/query?page=2&api_key=key-1", &[]).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "client-1");
+
+        let response = get("/query?api_key=", &[]).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.body, "Unauthorized");
+
+        let response = get("/query?api_key=key-2", &[]).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.body, "Invalid API key");
+    }
+
+    #[tokio::test]
+    async fn it_should_authenticate_valid_tokens() {
+        let token = sign(
+            json!({ "sub": "ada", "aud": "orders", "exp": now() + 60 }),
+            SECRET,
+        );
+        let response = get_with_token(&token).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "ada");
+    }
+
+    #[tokio::test]
+    async fn it_should_challenge_requests_without_a_token() {
+        let response = get("/jwt", &[]).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.headers["WWW-Authenticate"], "Bearer realm=\"api\"");
+        assert_eq!(response.body, "Unauthorized");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_expired_tokens() {
+        let token = sign(
+            json!({ "sub": "ada", "aud": "orders", "exp": now() - 10 }),
+            SECRET,
+        );
+        let response = get_with_token(&token).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(
+            response.headers["WWW-Authenticate"],
+            "Bearer realm=\"api\", error=\"invalid_token\", error_description=\"The token has expired\""
+        );
+        assert_eq!(response.body, "The token has expired");
+    }
+
+    #[tokio::test]
+    async fn it_should_allow_for_clock_skew_with_the_leeway() {
+        let auth = JwtAuth::<Value>::hmac(SECRET).leeway(Duration::from_secs(60));
+        let token = sign(json!({ "sub": "ada", "exp": now() - 10 }), SECRET);
+
+        assert_eq!(auth.verify(&token).await.unwrap()["sub"], "ada");
+
+        let token = sign(json!({ "sub": "ada", "exp": now() - 120 }), SECRET);
+
+        assert_eq!(
+            auth.verify(&token).await.unwrap_err(),
+            "The token has expired"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_tokens_that_arent_valid_yet() {
+        let token = sign(
+            json!({ "sub": "ada", "aud": "orders", "exp": now() + 120, "nbf": now() + 60 }),
+            SECRET,
+        );
+        let response = get_with_token(&token).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.body, "The token isn't valid yet");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_tokens_with_a_bad_signature() {
+        let token = sign(
+            json!({ "sub": "ada", "aud": "orders", "exp": now() + 60 }),
+            b"some other secret",
+        );
+        let response = get_with_token(&token).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.body, "The token's signature is invalid");
+
+        let response = get_with_token("not.a.token").await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.body, "The token is invalid");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_tokens_for_other_audiences() {
+        let token = sign(
+            json!({ "sub": "ada", "aud": "billing", "exp": now() + 60 }),
+            SECRET,
+        );
+        let response = get_with_token(&token).await;
+
+        assert_eq!(response.status.1, 401);
+        assert_eq!(response.body, "The token is for a different audience");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_tokens_signed_with_other_algorithms() {
+        let auth = JwtAuth::<Value>::hmac(SECRET).algorithms(&[Algorithm::HS512]);
+        let token = sign(json!({ "sub": "ada", "exp": now() + 60 }), SECRET);
+
+        assert_eq!(
+            auth.verify(&token).await.unwrap_err(),
+            "The token is signed with the wrong algorithm"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_fail_to_extract_a_principal_from_unauthenticated_requests() {
+        let response = get("/claims", &[]).await;
+
+        assert_eq!(response.status.1, 401);
+    }
+
+    fn oct_key(kid: &str, secret: &[u8]) -> Value {
+        json!({
+            "kty": "oct",
+            "kid": kid,
+            "k": base64::encode_config(secret, base64::URL_SAFE_NO_PAD),
+        })
+    }
+
+    fn token_with_kid(kid: &str, secret: &[u8]) -> String {
+        let header = Header {
+            kid: Some(kid.to_owned()),
+            ..Header::default()
+        };
+
+        jsonwebtoken::encode(
+            &header,
+            &json!({ "sub": "ada", "exp": now() + 60 }),
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_should_parse_symmetric_keys_from_a_jwks() {
+        // Bytes that base64url encodes differently to standard base64
+        let secret = [0xfb, 0xff, 0xfe, 0x01, 0x02, 0x03];
+        let jwks = json!({ "keys": [oct_key("k1", &secret)] });
+
+        let keys = parse_jwks(jwks.to_string().as_bytes()).unwrap();
+        let (key, algorithm) = &keys["k1"];
+
+        assert_eq!(*algorithm, Algorithm::HS256);
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.required_spec_claims.clear();
+        let token = sign(json!({ "sub": "ada", "exp": now() + 60 }), &secret);
+
+        assert!(jsonwebtoken::decode::<Value>(&token, key, &validation).is_ok());
+    }
+
+    #[test]
+    fn it_should_keep_the_algorithm_given_in_a_jwks() {
+        let mut key = oct_key("k1", SECRET);
+        key["alg"] = json!("HS512");
+        let jwks = json!({ "keys": [key] });
+
+        let keys = parse_jwks(jwks.to_string().as_bytes()).unwrap();
+
+        assert_eq!(keys["k1"].1, Algorithm::HS512);
+    }
+
+    #[test]
+    fn it_should_skip_encryption_keys_and_unsupported_curves_in_a_jwks() {
+        let mut encryption = oct_key("enc", SECRET);
+        encryption["use"] = json!("enc");
+        let p521 = json!({
+            "kty": "EC",
+            "kid": "p521",
+            "crv": "P-521",
+            "x": "AekpBQ8ST8a8VcfVOTNl353vSrDCLLJXmPk06wTjxrrjcBpXp5EOnYG_NjFZ6OvLFV1jSfS9tsz4qUxcWceqwQGk",
+            "y": "ADSmRA43Z1DSNx_RvcLI87cdL07l6jQyyBXMoxVg_l2Th-x3S1WDhjDly79ajL4Kkd0AZMaZmh9ubmf63e3kyMj2",
+        });
+        let jwks = json!({ "keys": [encryption, p521, oct_key("sig", SECRET)] });
+
+        let keys = parse_jwks(jwks.to_string().as_bytes()).unwrap();
+
+        assert_eq!(keys.len(), 1);
+        assert!(keys.contains_key("sig"));
+    }
+
+    #[test]
+    fn it_should_fail_to_parse_a_jwks_that_isnt_json() {
+        let e = parse_jwks(b"not json").map(|_| ()).unwrap_err();
+
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn jwks_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "thruster-jwks-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn it_should_pick_keys_from_a_jwks_file_by_the_token_key_id() {
+        let path = jwks_path("kid");
+        let jwks = json!({ "keys": [oct_key("k1", b"first"), oct_key("k2", b"second")] });
+        std::fs::write(&path, jwks.to_string()).unwrap();
+
+        let auth = JwtAuth::<Value>::jwks_file(&path).unwrap();
+
+        assert!(auth.verify(&token_with_kid("k1", b"first")).await.is_ok());
+        assert!(auth.verify(&token_with_kid("k2", b"second")).await.is_ok());
+        assert_eq!(
+            auth.verify(&token_with_kid("k1", b"second"))
+                .await
+                .unwrap_err(),
+            "The token's signature is invalid"
+        );
+        assert_eq!(
+            auth.verify(&token_with_kid("k3", b"third"))
+                .await
+                .unwrap_err(),
+            "The token was signed with an unknown key"
+        );
+
+        // Without a key ID, a token can't be matched to one of several keys
+        let token = sign(json!({ "sub": "ada", "exp": now() + 60 }), b"first");
+
+        assert_eq!(
+            auth.verify(&token).await.unwrap_err(),
+            "The token was signed with an unknown key"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_pick_up_new_keys_when_the_jwks_file_is_reloaded() {
+        let path = jwks_path("reload");
+        let jwks = json!({ "keys": [oct_key("k1", b"first")] });
+        std::fs::write(&path, jwks.to_string()).unwrap();
+
+        let auth = JwtAuth::<Value>::jwks_file(&path).unwrap();
+
+        // The only key is used for tokens without a key ID
+        let token = sign(json!({ "sub": "ada", "exp": now() + 60 }), b"first");
+        assert!(auth.verify(&token).await.is_ok());
+        assert!(auth.verify(&token_with_kid("k2", b"second")).await.is_err());
+
+        let jwks = json!({ "keys": [oct_key("k1", b"first"), oct_key("k2", b"second")] });
+        std::fs::write(&path, jwks.to_string()).unwrap();
+        auth.reload().await.unwrap();
+
+        assert!(auth.verify(&token_with_kid("k2", b"second")).await.is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
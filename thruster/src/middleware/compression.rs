@@ -0,0 +1,817 @@
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder,
+};
+use async_compression::Level;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::core::context::Context;
+use crate::core::errors::ThrusterError;
+use crate::core::extractors::{HasRequestBody, HasRequestHeaders};
+use crate::core::response::{BodyStream, HasResponseBody, ResponseBody};
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    fn encode<R: AsyncBufRead + Send + 'static>(
+        &self,
+        reader: R,
+    ) -> Pin<Box<dyn AsyncRead + Send>> {
+        match self {
+            // Brotli's best quality is far too slow to run on every response
+            Encoding::Brotli => Box::pin(BrotliEncoder::with_quality(reader, Level::Precise(4))),
+            Encoding::Gzip => Box::pin(GzipEncoder::new(reader)),
+            Encoding::Deflate => Box::pin(DeflateEncoder::new(reader)),
+        }
+    }
+
+    fn decode<R: AsyncBufRead + Send + 'static>(
+        &self,
+        reader: R,
+    ) -> Pin<Box<dyn AsyncRead + Send>> {
+        match self {
+            Encoding::Brotli => Box::pin(BrotliDecoder::new(reader)),
+            Encoding::Gzip => Box::pin(GzipDecoder::new(reader)),
+            Encoding::Deflate => Box::pin(DeflateDecoder::new(reader)),
+        }
+    }
+}
+
+///
+/// Middleware that compresses responses with the best encoding the client accepts, per its
+/// `Accept-Encoding`. Bodies smaller than the minimum size are sent as they are, as are bodies
+/// whose content type is already compressed, like images or archives, and partial responses to
+/// `Range` requests. Streamed bodies are compressed as they're sent.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref COMPRESSION: Compression = Compression::new().min_size(512);
+/// }
+///
+/// #[middleware_fn]
+/// async fn compress(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     COMPRESSION.handle(context, next).await
+/// }
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: usize,
+    encodings: Vec<Encoding>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Compression {
+    ///
+    /// Creates a middleware that compresses bodies of at least 1KiB, preferring brotli, then
+    /// gzip, then deflate when the client accepts them equally.
+    ///
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 1024,
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+        }
+    }
+
+    ///
+    /// Sets the smallest body, in bytes, that's worth compressing.
+    ///
+    pub fn min_size(mut self, min_size: usize) -> Compression {
+        self.min_size = min_size;
+        self
+    }
+
+    ///
+    /// Sets the encodings that can be used, in order of preference.
+    ///
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Compression {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    pub async fn handle<T>(&self, context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + HasRequestHeaders + HasResponseBody + Send,
+    {
+        let encoding = negotiate(&context.request_header("accept-encoding"), &self.encodings);
+
+        let mut context = next(context).await?;
+
+        if !is_compressible(&context) {
+            return Ok(context);
+        }
+
+        add_vary(&mut context, "Accept-Encoding");
+
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return Ok(context),
+        };
+
+        let body = match context.take_response_body().await {
+            Ok(body) => body,
+            Err(e) => {
+                return Err(ThrusterError {
+                    context,
+                    message: format!("Could not read response body: {}", e),
+                    status: 500,
+                    cause: Some(Box::new(e)),
+                })
+            }
+        };
+
+        match body {
+            ResponseBody::Bytes(bytes) if bytes.len() < self.min_size => {
+                context.set_body_bytes(bytes);
+                return Ok(context);
+            }
+            ResponseBody::Bytes(bytes) => {
+                let mut compressed = Vec::new();
+                let result = encoding
+                    .encode(Cursor::new(bytes))
+                    .read_to_end(&mut compressed)
+                    .await;
+
+                if let Err(e) = result {
+                    return Err(ThrusterError {
+                        context,
+                        message: format!("Could not compress response body: {}", e),
+                        status: 500,
+                        cause: Some(Box::new(e)),
+                    });
+                }
+
+                context.set_body_bytes(Bytes::from(compressed));
+            }
+            ResponseBody::Stream(stream) => {
+                let reader = StreamReader::new(stream);
+                context
+                    .set_body_stream(BodyStream::new(ReaderStream::new(encoding.encode(reader))));
+            }
+        }
+
+        context.remove("Content-Length");
+        context.set("Content-Encoding", encoding.name());
+
+        // The compressed body isn't byte for byte the same, so a strong ETag no longer fits it
+        if let Some(etag) = context.response_header("etag") {
+            if etag.starts_with('"') {
+                context.remove("ETag");
+                context.set("ETag", &format!("W/{}", etag));
+            }
+        }
+
+        Ok(context)
+    }
+}
+
+///
+/// Picks the encoding with the highest `q` in `Accept-Encoding`, breaking ties by the order of
+/// `supported`. Without an `Accept-Encoding`, nothing is picked.
+///
+fn negotiate(accept_encoding: &[String], supported: &[Encoding]) -> Option<Encoding> {
+    let mut accepted = Vec::new();
+
+    for value in accept_encoding.iter().flat_map(|value| value.split(',')) {
+        let mut params = value.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if !name.is_empty() {
+            accepted.push((name, quality));
+        }
+    }
+
+    let quality_of = |encoding: &Encoding| {
+        accepted
+            .iter()
+            .find(|(name, _)| Encoding::from_name(name) == Some(*encoding))
+            .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in supported {
+        let quality = quality_of(encoding);
+
+        let better = match best {
+            Some((_, best)) => quality > best,
+            None => true,
+        };
+
+        if quality > 0.0 && better {
+            best = Some((*encoding, quality));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+fn is_compressible<T: HasResponseBody>(context: &T) -> bool {
+    if context.response_header("content-encoding").is_some() {
+        return false;
+    }
+
+    // Responses without a body have nothing to compress, and a `Content-Range` describes the
+    // bytes of the body as they are
+    if matches!(context.response_status(), 204 | 206 | 304)
+        || context.response_header("content-range").is_some()
+    {
+        return false;
+    }
+
+    if let Some(cache_control) = context.response_header("cache-control") {
+        if cache_control.to_ascii_lowercase().contains("no-transform") {
+            return false;
+        }
+    }
+
+    let content_type = context
+        .response_header("content-type")
+        .map(|content_type| content_type.to_ascii_lowercase())
+        .unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+
+    let is_compressed = (media_type.starts_with("image/") && media_type != "image/svg+xml")
+        || media_type.starts_with("audio/")
+        || media_type.starts_with("video/")
+        || media_type == "text/event-stream"
+        || matches!(
+            media_type,
+            "application/gzip"
+                | "application/x-gzip"
+                | "application/zip"
+                | "application/zstd"
+                | "application/x-bzip2"
+                | "application/x-7z-compressed"
+                | "application/x-rar-compressed"
+                | "application/pdf"
+                | "font/woff"
+                | "font/woff2"
+        );
+
+    !is_compressed
+}
+
+fn add_vary<T: Context + HasResponseBody>(context: &mut T, header: &str) {
+    match context.response_header("vary") {
+        Some(vary)
+            if vary == "*"
+                || vary
+                    .split(',')
+                    .any(|value| value.trim().eq_ignore_ascii_case(header)) => {}
+        Some(vary) => {
+            context.remove("Vary");
+            context.set("Vary", &format!("{}, {}", vary, header));
+        }
+        None => context.set("Vary", header),
+    }
+}
+
+///
+/// Middleware that decodes request bodies sent with a `Content-Encoding` of `gzip`, `deflate`
+/// or `br`, so that whatever reads the body sees it decoded. Requests with other encodings
+/// fail with a `415`.
+///
+/// The body is decoded as it's read, and reading fails once the decoded body grows past the
+/// maximum size, so small uploads can't expand into huge ones.
+///
+#[derive(Clone, Debug)]
+pub struct Decompression {
+    max_size: u64,
+}
+
+impl Default for Decompression {
+    fn default() -> Self {
+        Decompression::new()
+    }
+}
+
+impl Decompression {
+    ///
+    /// Creates a middleware that allows decoded bodies of up to 10MiB.
+    ///
+    pub fn new() -> Decompression {
+        Decompression {
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+
+    ///
+    /// Sets the largest a decoded body can be, in bytes.
+    ///
+    pub fn max_size(mut self, max_size: u64) -> Decompression {
+        self.max_size = max_size;
+        self
+    }
+
+    pub async fn handle<T>(&self, mut context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + HasRequestBody + HasRequestHeaders + Send,
+    {
+        let mut encodings = Vec::new();
+        for name in context
+            .request_header("content-encoding")
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty() && !name.eq_ignore_ascii_case("identity"))
+        {
+            match Encoding::from_name(name) {
+                Some(encoding) => encodings.push(encoding),
+                None => {
+                    let message = format!("Unsupported Content-Encoding: {}", name);
+                    context.status(415);
+                    context.set_body(message.as_bytes().to_vec());
+
+                    return Err(ThrusterError {
+                        context,
+                        message,
+                        status: 415,
+                        cause: None,
+                    });
+                }
+            }
+        }
+
+        if encodings.is_empty() {
+            return next(context).await;
+        }
+
+        // Encodings are listed in the order they were applied, so they're undone in reverse
+        let mut reader: Pin<Box<dyn AsyncRead + Send>> =
+            Box::pin(StreamReader::new(context.request_body_stream()));
+        for encoding in encodings.iter().rev() {
+            reader = encoding.decode(BufReader::new(reader));
+        }
+
+        let max_size = self.max_size;
+        let mut size = 0;
+        let decoded = ReaderStream::new(reader.take(max_size + 1)).map(move |chunk| {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+
+            if size > max_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Decoded request body is larger than {} bytes", max_size),
+                ));
+            }
+
+            Ok(chunk)
+        });
+
+        context.set_request_body_stream(BodyStream::new(decoded));
+
+        next(context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+    use lazy_static::lazy_static;
+    use std::collections::HashMap;
+
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::{decode, Request};
+    use crate::core::response::{HasBodyStream, StatusMessage};
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    lazy_static! {
+        static ref COMPRESSION: Compression = Compression::new().min_size(16);
+        static ref DECOMPRESSION: Decompression = Decompression::new().max_size(64);
+    }
+
+    const PAGE: &str = "<p>hello, hello, hello, hello, hello, hello, hello</p>";
+
+    #[middleware_fn(_internal)]
+    async fn compress(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        COMPRESSION.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn decompress(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        DECOMPRESSION.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn page(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set("Content-Type", "text/html");
+        context.set("ETag", "\"v1\"");
+        context.body(PAGE);
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn tiny(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body("hi");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn image(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set("Content-Type", "image/png");
+        context.body(PAGE);
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn no_transform(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set("Cache-Control", "public, no-transform");
+        context.body(PAGE);
+        Ok(context)
+    }
+
+    // Sends the first bytes of the page for any `Range`, the way a file server would
+    #[middleware_fn(_internal)]
+    async fn ranged(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set("Content-Type", "text/html");
+        if context.request_header("range").is_empty() {
+            context.body(PAGE);
+        } else {
+            context.status(206);
+            context.set("Content-Range", &format!("bytes 0-31/{}", PAGE.len()));
+            context.body(&PAGE[..32]);
+        }
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn bodiless(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let status = context.params.as_ref().unwrap()["status"].parse().unwrap();
+        context.status(status);
+        context.set("Content-Type", "text/html");
+        context.set("ETag", "\"v1\"");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn streamed(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set_body_stream(BodyStream::new(futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"<p>one</p>")),
+            Ok(Bytes::from_static(b"<p>two</p>")),
+        ])));
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn echo(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        match context.request_body().await {
+            Ok(body) => context.body(std::str::from_utf8(&body).unwrap()),
+            Err(e) => {
+                context.status(413);
+                context.body(&e.to_string());
+            }
+        }
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(compress));
+        app.use_middleware("/echo", MiddlewareTuple::A(decompress));
+        app.get("/page", MiddlewareTuple::A(page));
+        app.get("/tiny", MiddlewareTuple::A(tiny));
+        app.get("/image", MiddlewareTuple::A(image));
+        app.get("/no-transform", MiddlewareTuple::A(no_transform));
+        app.get("/streamed", MiddlewareTuple::A(streamed));
+        app.get("/ranged", MiddlewareTuple::A(ranged));
+        app.get("/bodiless/:status", MiddlewareTuple::A(bodiless));
+        app.post("/echo", MiddlewareTuple::A(echo));
+        app.commit()
+    }
+
+    // Sends a request with a body that may not be UTF-8, returning the status, headers and
+    // whole body of the response
+    async fn send(
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> (u32, HashMap<String, String>, Vec<u8>) {
+        let app = app();
+        let mut bytes = BytesMut::new();
+        bytes.put(format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path).as_bytes());
+        for (name, value) in headers {
+            bytes.put(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        bytes.put(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+        bytes.put(body);
+
+        let request = decode(&mut bytes).unwrap().unwrap();
+        let matched_route = app.resolve_from_method_and_path(method, path.to_owned());
+        let mut response = app.resolve(request, matched_route).await.unwrap();
+
+        let status = match response.status_message {
+            StatusMessage::Ok => 200,
+            StatusMessage::Custom(code, _) => code,
+        };
+
+        let headers = String::from_utf8(response.header_raw.to_vec())
+            .unwrap()
+            .split("\r\n")
+            .filter_map(|header| header.split_once(": "))
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.to_owned()))
+            .collect();
+
+        let body = match response.take_body() {
+            ResponseBody::Bytes(bytes) => bytes.to_vec(),
+            ResponseBody::Stream(stream) => {
+                let mut body = Vec::new();
+                StreamReader::new(stream)
+                    .read_to_end(&mut body)
+                    .await
+                    .unwrap();
+                body
+            }
+        };
+
+        (status, headers, body)
+    }
+
+    async fn encode(encoding: Encoding, body: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        encoding
+            .encode(Cursor::new(body.to_vec()))
+            .read_to_end(&mut encoded)
+            .await
+            .unwrap();
+        encoded
+    }
+
+    async fn decode_body(encoding: Encoding, body: &[u8]) -> String {
+        let mut decoded = String::new();
+        encoding
+            .decode(Cursor::new(body.to_vec()))
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        decoded
+    }
+
+    fn accept(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn it_should_pick_the_encoding_with_the_highest_quality() {
+        let supported = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+        assert_eq!(
+            negotiate(
+                &accept(&["gzip;q=0.5, deflate;q=0.8, br;q=0.1"]),
+                &supported
+            ),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            negotiate(&accept(&["deflate", "GZIP"]), &supported),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate(&accept(&["x-gzip; q=0.9"]), &supported),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn it_should_break_ties_by_the_order_of_preference() {
+        assert_eq!(
+            negotiate(
+                &accept(&["gzip, deflate, br"]),
+                &[Encoding::Brotli, Encoding::Gzip, Encoding::Deflate]
+            ),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate(
+                &accept(&["gzip, deflate, br"]),
+                &[Encoding::Deflate, Encoding::Gzip]
+            ),
+            Some(Encoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn it_should_honour_wildcards_and_refusals() {
+        let supported = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+        assert_eq!(
+            negotiate(&accept(&["*;q=0.5, gzip;q=0.4"]), &supported),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate(&accept(&["*, br;q=0"]), &supported),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(&accept(&["*;q=0"]), &supported), None);
+        assert_eq!(negotiate(&accept(&["identity, zstd"]), &supported), None);
+        assert_eq!(negotiate(&[], &supported), None);
+    }
+
+    #[tokio::test]
+    async fn it_should_compress_bodies_with_the_negotiated_encoding() {
+        for (accept_encoding, encoding) in [
+            ("br", Encoding::Brotli),
+            ("gzip", Encoding::Gzip),
+            ("deflate", Encoding::Deflate),
+        ] {
+            let (status, headers, body) =
+                send("GET", "/page", &[("Accept-Encoding", accept_encoding)], b"").await;
+
+            assert_eq!(status, 200);
+            assert_eq!(headers["content-encoding"], accept_encoding);
+            assert_eq!(headers["vary"], "Accept-Encoding");
+            assert_eq!(headers["etag"], "W/\"v1\"");
+            assert!(!headers.contains_key("content-length"));
+            assert_eq!(decode_body(encoding, &body).await, PAGE);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_compress_streamed_bodies() {
+        let (_, headers, body) =
+            send("GET", "/streamed", &[("Accept-Encoding", "gzip")], b"").await;
+
+        assert_eq!(headers["content-encoding"], "gzip");
+        assert_eq!(
+            decode_body(Encoding::Gzip, &body).await,
+            "<p>one</p><p>two</p>"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_send_some_bodies_as_they_are() {
+        let (_, headers, body) = send("GET", "/page", &[], b"").await;
+        assert!(!headers.contains_key("content-encoding"));
+        assert_eq!(headers["vary"], "Accept-Encoding");
+        assert_eq!(headers["etag"], "\"v1\"");
+        assert_eq!(body, PAGE.as_bytes());
+
+        for path in ["/tiny", "/image", "/no-transform"] {
+            let (_, headers, _) = send("GET", path, &[("Accept-Encoding", "gzip")], b"").await;
+            assert!(!headers.contains_key("content-encoding"), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_send_ranges_as_they_are() {
+        let (status, headers, body) =
+            send("GET", "/ranged", &[("Accept-Encoding", "gzip")], b"").await;
+
+        assert_eq!(status, 200);
+        assert_eq!(headers["content-encoding"], "gzip");
+        assert_eq!(decode_body(Encoding::Gzip, &body).await, PAGE);
+
+        let (status, headers, body) = send(
+            "GET",
+            "/ranged",
+            &[("Accept-Encoding", "gzip"), ("Range", "bytes=0-31")],
+            b"",
+        )
+        .await;
+
+        assert_eq!(status, 206);
+        assert!(!headers.contains_key("content-encoding"));
+        assert_eq!(
+            headers["content-range"],
+            format!("bytes 0-31/{}", PAGE.len())
+        );
+        assert_eq!(body, &PAGE.as_bytes()[..32]);
+    }
+
+    #[tokio::test]
+    async fn it_should_leave_responses_without_a_body_alone() {
+        for status in [204, 304] {
+            let path = format!("/bodiless/{}", status);
+            let (given, headers, body) =
+                send("GET", &path, &[("Accept-Encoding", "gzip")], b"").await;
+
+            assert_eq!(given, status);
+            assert!(!headers.contains_key("content-encoding"));
+            assert_eq!(headers["etag"], "\"v1\"");
+            assert!(body.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_decode_request_bodies() {
+        let body = encode(Encoding::Deflate, &encode(Encoding::Gzip, b"payload").await).await;
+
+        let (status, _, response) = send(
+            "POST",
+            "/echo",
+            &[("Content-Encoding", "gzip, deflate")],
+            &body,
+        )
+        .await;
+
+        assert_eq!(status, 200);
+        assert_eq!(response, b"payload");
+
+        let (_, _, response) = send(
+            "POST",
+            "/echo",
+            &[("Content-Encoding", "identity")],
+            b"plain",
+        )
+        .await;
+        assert_eq!(response, b"plain");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_unsupported_encodings() {
+        let (status, _, response) =
+            send("POST", "/echo", &[("Content-Encoding", "zstd")], b"x").await;
+
+        assert_eq!(status, 415);
+        assert_eq!(response, b"Unsupported Content-Encoding: zstd");
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_reading_bodies_that_decode_past_the_maximum_size() {
+        let body = encode(Encoding::Gzip, &[b'a'; 65]).await;
+        let (status, _, response) =
+            send("POST", "/echo", &[("Content-Encoding", "gzip")], &body).await;
+
+        assert_eq!(status, 413);
+        assert_eq!(
+            std::str::from_utf8(&response).unwrap(),
+            "Decoded request body is larger than 64 bytes"
+        );
+
+        let body = encode(Encoding::Gzip, &[b'a'; 64]).await;
+        let (status, _, _) = send("POST", "/echo", &[("Content-Encoding", "gzip")], &body).await;
+        assert_eq!(status, 200);
+    }
+}
@@ -0,0 +1,507 @@
+use async_trait::async_trait;
+use std::hash::Hasher;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::core::context::Context;
+use crate::core::extractors::{ExtractionError, FromContext, HasRequestHeaders, HasRequestMethod};
+use crate::core::response::{HasResponseBody, ResponseBody};
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+///
+/// Computes an ETag for a body, from a hash of its contents. Handlers can use this for their
+/// own validators, such as a hash of a resource's serialized state.
+///
+pub fn entity_tag(body: &[u8], weak: bool) -> String {
+    let mut hasher = fnv::FnvHasher::default();
+    hasher.write(body);
+
+    format!(
+        "{}\"{:x}-{:x}\"",
+        if weak { "W/" } else { "" },
+        body.len(),
+        hasher.finish()
+    )
+}
+
+///
+/// Middleware that answers conditional `GET` and `HEAD` requests. Responses without an `ETag`
+/// get one computed from their body, unless the body is streamed, and requests whose
+/// `If-None-Match` or `If-Modified-Since` show that the client's copy is still current are
+/// answered with a `304 Not Modified` and no body. `If-Match` and `If-Unmodified-Since` that
+/// don't hold are answered with a `412 Precondition Failed`.
+///
+/// Handlers can set their own `ETag` or `Last-Modified`, which are used in place of a computed
+/// one. Since writes have to check their preconditions before they happen, handlers for
+/// `PUT`, `PATCH` and `DELETE` should use the `Preconditions` extractor instead.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref CONDITIONAL: ConditionalRequests = ConditionalRequests::new();
+/// }
+///
+/// #[middleware_fn]
+/// async fn conditional(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     CONDITIONAL.handle(context, next).await
+/// }
+/// ```
+///
+#[derive(Clone, Debug, Default)]
+pub struct ConditionalRequests {
+    weak: bool,
+}
+
+impl ConditionalRequests {
+    ///
+    /// Creates a middleware that computes strong ETags.
+    ///
+    pub fn new() -> ConditionalRequests {
+        ConditionalRequests::default()
+    }
+
+    ///
+    /// Computes weak ETags, which say two bodies are equivalent rather than byte for byte the
+    /// same. They can't be used for range requests.
+    ///
+    pub fn weak(mut self, weak: bool) -> ConditionalRequests {
+        self.weak = weak;
+        self
+    }
+
+    pub async fn handle<T>(&self, context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + HasRequestHeaders + HasRequestMethod + HasResponseBody + Send,
+    {
+        let preconditions = Preconditions::from_request(&context);
+
+        let mut context = next(context).await?;
+
+        let status = context.response_status();
+        if !preconditions.is_safe() || !(200..300).contains(&status) {
+            return Ok(context);
+        }
+
+        let mut etag = context.response_header("etag");
+        if etag.is_none() {
+            match context.take_response_body().await {
+                Ok(ResponseBody::Bytes(bytes)) => {
+                    let computed = entity_tag(&bytes, self.weak);
+                    context.set("ETag", &computed);
+                    context.set_body_bytes(bytes);
+                    etag = Some(computed);
+                }
+                // Hashing a stream would mean holding all of it back until it ends
+                Ok(ResponseBody::Stream(stream)) => context.set_body_stream(stream),
+                Err(_) => return Ok(context),
+            }
+        }
+
+        if preconditions.is_empty() {
+            return Ok(context);
+        }
+
+        let last_modified = context
+            .response_header("last-modified")
+            .and_then(|last_modified| httpdate::parse_http_date(&last_modified).ok());
+
+        if let Err(e) = preconditions.evaluate(etag.as_deref(), last_modified) {
+            context.status(e.status);
+            context.set_body(Vec::new());
+            context.remove("Content-Length");
+        }
+
+        Ok(context)
+    }
+}
+
+///
+/// The validators sent with a request, for checking them against the current state of a
+/// resource. Writes use this to fail with a `412` when the client's copy is out of date,
+/// rather than overwriting someone else's change.
+///
+/// ```rust, ignore
+/// #[middleware_fn]
+/// async fn update_post(mut context: Ctx, preconditions: Preconditions, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     let post = load_post(&context).await;
+///     let etag = entity_tag(&post.version.to_be_bytes(), false);
+///
+///     if let Err(e) = preconditions.evaluate(Some(&etag), Some(post.updated_at)) {
+///         return Err(e.into_thruster_error(context));
+///     }
+///
+///     // ...
+/// }
+/// ```
+///
+#[derive(Clone, Debug, Default)]
+pub struct Preconditions {
+    method: String,
+    if_match: Option<Vec<String>>,
+    if_none_match: Option<Vec<String>>,
+    if_modified_since: Option<SystemTime>,
+    if_unmodified_since: Option<SystemTime>,
+}
+
+impl Preconditions {
+    fn from_request<C: HasRequestHeaders + HasRequestMethod>(context: &C) -> Preconditions {
+        let date = |header: &str| {
+            context
+                .request_header(header)
+                .pop()
+                .and_then(|date| httpdate::parse_http_date(&date).ok())
+        };
+
+        Preconditions {
+            method: context.request_method().to_ascii_uppercase(),
+            if_match: entity_tags(context.request_header("if-match")),
+            if_none_match: entity_tags(context.request_header("if-none-match")),
+            if_modified_since: date("if-modified-since"),
+            if_unmodified_since: date("if-unmodified-since"),
+        }
+    }
+
+    fn is_safe(&self) -> bool {
+        self.method == "GET" || self.method == "HEAD"
+    }
+
+    fn is_empty(&self) -> bool {
+        self.if_match.is_none()
+            && self.if_none_match.is_none()
+            && self.if_modified_since.is_none()
+            && self.if_unmodified_since.is_none()
+    }
+
+    ///
+    /// Checks the request's validators against the current ETag and modification time of the
+    /// resource, which are `None` if it doesn't have one, or doesn't exist. Fails with a `304`
+    /// for a `GET` or `HEAD` whose cached copy is current, or a `412` for a precondition that
+    /// doesn't hold. The conditions are checked in the order RFC 7232 gives.
+    ///
+    pub fn evaluate(
+        &self,
+        etag: Option<&str>,
+        last_modified: Option<SystemTime>,
+    ) -> Result<(), ExtractionError> {
+        let failed = || ExtractionError::new(412, "Precondition failed");
+
+        if let Some(if_match) = &self.if_match {
+            let matches = match etag {
+                Some(etag) => if_match
+                    .iter()
+                    .any(|tag| tag == "*" || strong_eq(tag, etag)),
+                None => false,
+            };
+
+            if !matches {
+                return Err(failed());
+            }
+        } else if let (Some(since), Some(last_modified)) = (self.if_unmodified_since, last_modified)
+        {
+            if seconds(last_modified) > seconds(since) {
+                return Err(failed());
+            }
+        }
+
+        if let Some(if_none_match) = &self.if_none_match {
+            let matches = if_none_match.iter().any(|tag| match etag {
+                Some(etag) => tag == "*" || weak_eq(tag, etag),
+                None => false,
+            });
+
+            if matches {
+                return Err(if self.is_safe() {
+                    ExtractionError::new(304, "")
+                } else {
+                    failed()
+                });
+            }
+        } else if let (true, Some(since), Some(last_modified)) =
+            (self.is_safe(), self.if_modified_since, last_modified)
+        {
+            if seconds(last_modified) <= seconds(since) {
+                return Err(ExtractionError::new(304, ""));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<C: HasRequestHeaders + HasRequestMethod + Send> FromContext<C> for Preconditions {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        Ok(Preconditions::from_request(context))
+    }
+}
+
+///
+/// Splits a list of entity tags, keeping their `W/` prefixes. Commas can appear inside of a
+/// quoted tag, so they're only split on outside of one.
+///
+fn entity_tags(values: Vec<String>) -> Option<Vec<String>> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut tags = Vec::new();
+    for value in values {
+        let mut tag = String::new();
+        let mut quoted = false;
+
+        for c in value.chars() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    tag.push(c);
+                }
+                ',' if !quoted => tags.push(std::mem::take(&mut tag)),
+                c => tag.push(c),
+            }
+        }
+        tags.push(tag);
+    }
+
+    Some(
+        tags.into_iter()
+            .map(|tag| tag.trim().to_owned())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+    )
+}
+
+fn strong_eq(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+// HTTP dates only go down to the second
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use std::time::Duration;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    lazy_static! {
+        static ref CONDITIONAL: ConditionalRequests = ConditionalRequests::new();
+    }
+
+    const UPDATED_AT: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    #[middleware_fn(_internal)]
+    async fn conditional(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        CONDITIONAL.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn page(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body("hello");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn dated(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.set("ETag", "\"v2\"");
+        context.set("Last-Modified", UPDATED_AT);
+        context.body("dated");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn missing(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.status(404);
+        context.body("missing");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn update(
+        mut context: BasicContext,
+        preconditions: Preconditions,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let updated_at = httpdate::parse_http_date(UPDATED_AT).unwrap();
+
+        if let Err(e) = preconditions.evaluate(Some("\"v2\""), Some(updated_at)) {
+            return Err(e.into_thruster_error(context));
+        }
+
+        context.body("updated");
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(conditional));
+        app.get("/page", MiddlewareTuple::A(page));
+        app.get("/dated", MiddlewareTuple::A(dated));
+        app.get("/missing", MiddlewareTuple::A(missing));
+        app.put("/dated", MiddlewareTuple::A(update));
+        app.commit()
+    }
+
+    async fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> testing::TestResponse {
+        testing::request(&app(), method, path, headers, "").await
+    }
+
+    fn http_date(offset: i64) -> String {
+        let updated_at = httpdate::parse_http_date(UPDATED_AT).unwrap();
+        let date = if offset < 0 {
+            updated_at - Duration::from_secs(offset.unsigned_abs())
+        } else {
+            updated_at + Duration::from_secs(offset as u64)
+        };
+
+        httpdate::fmt_http_date(date)
+    }
+
+    #[test]
+    fn it_should_compute_entity_tags_from_the_body() {
+        assert_eq!(entity_tag(b"hello", false), entity_tag(b"hello", false));
+        assert_ne!(entity_tag(b"hello", false), entity_tag(b"hellp", false));
+        assert!(entity_tag(b"hello", false).starts_with("\"5-"));
+        assert!(entity_tag(b"hello", true).starts_with("W/\"5-"));
+    }
+
+    #[test]
+    fn it_should_split_entity_tags_outside_of_quotes() {
+        assert_eq!(
+            entity_tags(vec!["\"a,b\", W/\"c\"".to_owned(), "*".to_owned()]),
+            Some(vec![
+                "\"a,b\"".to_owned(),
+                "W/\"c\"".to_owned(),
+                "*".to_owned()
+            ])
+        );
+        assert_eq!(entity_tags(Vec::new()), None);
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_requests_with_a_current_etag_with_a_304() {
+        let response = request("GET", "/page", &[]).await;
+        let etag = response.headers["ETag"].clone();
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(etag, entity_tag(b"hello", false));
+
+        for if_none_match in [
+            etag.clone(),
+            format!("W/{}", etag),
+            format!("\"other\", {}", etag),
+            "*".to_owned(),
+        ] {
+            let response = request("GET", "/page", &[("If-None-Match", &if_none_match)]).await;
+
+            assert_eq!(response.status.1, 304, "{}", if_none_match);
+            assert_eq!(response.body, "");
+            assert_eq!(response.headers["ETag"], etag);
+        }
+
+        let response = request("HEAD", "/page", &[("If-None-Match", &etag)]).await;
+        assert_eq!(response.status.1, 304);
+
+        let response = request("GET", "/page", &[("If-None-Match", "\"other\"")]).await;
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "hello");
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_etags_set_by_handlers() {
+        let response = request("GET", "/dated", &[("If-None-Match", "\"v2\"")]).await;
+
+        assert_eq!(response.status.1, 304);
+        assert_eq!(response.headers["ETag"], "\"v2\"");
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_requests_modified_since_a_date_with_a_304() {
+        let response = request("GET", "/dated", &[("If-Modified-Since", UPDATED_AT)]).await;
+        assert_eq!(response.status.1, 304);
+
+        let response = request("GET", "/dated", &[("If-Modified-Since", &http_date(-1))]).await;
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "dated");
+
+        // If-None-Match wins over If-Modified-Since
+        let response = request(
+            "GET",
+            "/dated",
+            &[
+                ("If-None-Match", "\"v1\""),
+                ("If-Modified-Since", &http_date(60)),
+            ],
+        )
+        .await;
+        assert_eq!(response.status.1, 200);
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_preconditions_that_dont_hold_with_a_412() {
+        let response = request("GET", "/dated", &[("If-Match", "\"v1\"")]).await;
+        assert_eq!(response.status.1, 412);
+        assert_eq!(response.body, "");
+
+        // Weak tags never match strongly
+        let response = request("GET", "/dated", &[("If-Match", "W/\"v2\"")]).await;
+        assert_eq!(response.status.1, 412);
+
+        let response = request("GET", "/dated", &[("If-Match", "\"v2\"")]).await;
+        assert_eq!(response.status.1, 200);
+
+        let response = request("GET", "/dated", &[("If-Unmodified-Since", &http_date(-1))]).await;
+        assert_eq!(response.status.1, 412);
+    }
+
+    #[tokio::test]
+    async fn it_should_leave_errors_alone() {
+        let response = request("GET", "/missing", &[("If-None-Match", "*")]).await;
+
+        assert_eq!(response.status.1, 404);
+        assert_eq!(response.body, "missing");
+        assert!(!response.headers.contains_key("ETag"));
+    }
+
+    #[tokio::test]
+    async fn it_should_let_writes_check_their_preconditions() {
+        let response = request("PUT", "/dated", &[("If-Match", "\"v2\"")]).await;
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "updated");
+
+        let response = request("PUT", "/dated", &[("If-Match", "\"v1\"")]).await;
+        assert_eq!(response.status.1, 412);
+        assert_eq!(response.body, "Precondition failed");
+
+        // Writes fail rather than being told their copy is current
+        let response = request("PUT", "/dated", &[("If-None-Match", "*")]).await;
+        assert_eq!(response.status.1, 412);
+
+        let response = request("PUT", "/dated", &[("If-Unmodified-Since", UPDATED_AT)]).await;
+        assert_eq!(response.status.1, 200);
+    }
+}
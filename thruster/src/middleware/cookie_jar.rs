@@ -0,0 +1,563 @@
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::core::context::Context;
+use crate::core::{MiddlewareNext, MiddlewareResult};
+use crate::middleware::cookies::{parse_string, CookieOptions, HasCookies};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 12;
+
+///
+/// A secret used to sign and encrypt cookies. The signing and encryption keys are derived from
+/// the same secret, so one secret is all that needs storing.
+///
+#[derive(Clone)]
+pub struct Key {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl Key {
+    ///
+    /// Derives a key from a secret of at least 32 random bytes.
+    ///
+    /// Panics if the secret is shorter than that.
+    ///
+    pub fn derive_from(secret: &[u8]) -> Key {
+        assert!(
+            secret.len() >= 32,
+            "Cookie secrets must be at least 32 bytes, found {}",
+            secret.len()
+        );
+
+        Key {
+            signing: hmac_sha256(secret, b"thruster-cookie-signing"),
+            encryption: hmac_sha256(secret, b"thruster-cookie-encryption"),
+        }
+    }
+
+    ///
+    /// Generates a random key. Cookies sealed with it can't be opened once the process exits,
+    /// so this is mostly useful for tests and single-process apps.
+    ///
+    pub fn generate() -> Key {
+        let mut secret = [0; 64];
+        rand::thread_rng().fill_bytes(&mut secret);
+
+        Key::derive_from(&secret)
+    }
+
+    pub(crate) fn sign(&self, name: &str, value: &str) -> HmacSha256 {
+        let mut mac =
+            <HmacSha256 as Mac>::new_from_slice(&self.signing).expect("HMAC takes any key size");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&self.encryption).expect("Encryption keys are 32 bytes")
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key { .. }")
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes any key size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+///
+/// A context that holds the `CookieJar` of the request, so that its `cookie` helper can seal
+/// the cookies that are set with `signed`.
+///
+pub trait HasCookieJar {
+    fn cookie_jar(&self) -> Option<&CookieJar>;
+
+    fn set_cookie_jar(&mut self, cookie_jar: CookieJar);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Protection {
+    Signed,
+    Encrypted,
+}
+
+///
+/// Middleware that signs or encrypts the cookies it's told to protect, and verifies them when
+/// they come back. It replaces the `cookies` middleware: incoming cookies are parsed and set on
+/// the context as usual, except that protected cookies are only kept if they verify, and are
+/// then marked as `signed`.
+///
+/// Signed cookies can be read, but not changed, by the client. Encrypted cookies can be
+/// neither read nor changed. Either way, a cookie is bound to its name, so a value can't be
+/// moved from one cookie to another.
+///
+/// Cookies set through the context's `cookie` helper with `signed` in their options are sealed
+/// by the jar, as it was told to protect them, or signed if it wasn't told about them. Only the
+/// cookies it's been told about are verified when they come back though.
+///
+/// New cookies are sealed with the first key. Keys added with `previous_key` are still
+/// accepted when verifying, so secrets can be rotated without logging everyone out.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref JAR: CookieJar = CookieJar::new(Key::derive_from(SECRET))
+///         .previous_key(Key::derive_from(OLD_SECRET))
+///         .encrypted("session");
+/// }
+///
+/// #[middleware_fn]
+/// async fn cookie_jar(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     JAR.handle(context, next).await
+/// }
+///
+/// #[middleware_fn]
+/// async fn login(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     let mut options = CookieOptions::default();
+///     options.signed = true;
+///
+///     context.cookie("session", "user-1", &options);
+///     Ok(context)
+/// }
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct CookieJar {
+    // Shared, as the jar is cloned onto every request's context
+    keys: Arc<Vec<Key>>,
+    protected: Arc<Vec<(String, Protection)>>,
+}
+
+impl CookieJar {
+    pub fn new(key: Key) -> CookieJar {
+        CookieJar {
+            keys: Arc::new(vec![key]),
+            protected: Arc::new(Vec::new()),
+        }
+    }
+
+    ///
+    /// Adds a key that's only used to verify cookies, such as the one being rotated out.
+    ///
+    pub fn previous_key(mut self, key: Key) -> CookieJar {
+        Arc::make_mut(&mut self.keys).push(key);
+        self
+    }
+
+    ///
+    /// Signs the cookie called `name`.
+    ///
+    pub fn signed(mut self, name: &str) -> CookieJar {
+        self.protect(name, Protection::Signed);
+        self
+    }
+
+    ///
+    /// Encrypts the cookie called `name`.
+    ///
+    pub fn encrypted(mut self, name: &str) -> CookieJar {
+        self.protect(name, Protection::Encrypted);
+        self
+    }
+
+    fn protect(&mut self, name: &str, protection: Protection) {
+        let protected = Arc::make_mut(&mut self.protected);
+        protected.retain(|(protected, _)| protected != name);
+        protected.push((name.to_owned(), protection));
+    }
+
+    fn protection(&self, name: &str) -> Option<Protection> {
+        self.protected
+            .iter()
+            .find(|(protected, _)| protected == name)
+            .map(|(_, protection)| *protection)
+    }
+
+    ///
+    /// Signs or encrypts `value` if the cookie called `name` is protected, ready to be set with
+    /// the context's `cookie` helper. Other cookies' values are returned unchanged.
+    ///
+    pub fn seal(&self, name: &str, value: &str) -> String {
+        match self.protection(name) {
+            Some(protection) => self.seal_with(name, value, protection),
+            None => value.to_owned(),
+        }
+    }
+
+    fn seal_with(&self, name: &str, value: &str, protection: Protection) -> String {
+        let key = &self.keys[0];
+
+        match protection {
+            Protection::Signed => {
+                let signature = key.sign(name, value).finalize().into_bytes();
+
+                format!("{}.{}", encode(value.as_bytes()), encode(&signature))
+            }
+            Protection::Encrypted => {
+                let mut nonce = [0; NONCE_LEN];
+                rand::thread_rng().fill_bytes(&mut nonce);
+
+                let payload = Payload {
+                    msg: value.as_bytes(),
+                    aad: name.as_bytes(),
+                };
+                let mut sealed = nonce.to_vec();
+                sealed.extend(
+                    key.cipher()
+                        .encrypt(Nonce::from_slice(&nonce), payload)
+                        .expect("Cookies are far smaller than AES-GCM's limit"),
+                );
+
+                encode(&sealed)
+            }
+        }
+    }
+
+    ///
+    /// Verifies and decodes the value of the cookie called `name`, if it's protected. Returns
+    /// `None` if it doesn't verify under any of the keys.
+    ///
+    pub fn open(&self, name: &str, value: &str) -> Option<String> {
+        match self.protection(name) {
+            Some(Protection::Signed) => {
+                let (value, signature) = value.rsplit_once('.')?;
+                let value = String::from_utf8(decode(value)?).ok()?;
+                let signature = decode(signature)?;
+
+                self.keys
+                    .iter()
+                    .any(|key| key.sign(name, &value).verify_slice(&signature).is_ok())
+                    .then_some(value)
+            }
+            Some(Protection::Encrypted) => {
+                let sealed = decode(value)?;
+                if sealed.len() < NONCE_LEN {
+                    return None;
+                }
+
+                let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+                let opened = self.keys.iter().find_map(|key| {
+                    let payload = Payload {
+                        msg: ciphertext,
+                        aad: name.as_bytes(),
+                    };
+
+                    key.cipher().decrypt(Nonce::from_slice(nonce), payload).ok()
+                })?;
+
+                String::from_utf8(opened).ok()
+            }
+            None => Some(value.to_owned()),
+        }
+    }
+
+    pub async fn handle<T: 'static + Context + HasCookieJar + HasCookies + Send>(
+        &self,
+        mut context: T,
+        next: MiddlewareNext<T>,
+    ) -> MiddlewareResult<T> {
+        let mut cookies = Vec::new();
+
+        for cookie_string in context.get_header("cookie") {
+            for mut cookie in parse_string(&cookie_string) {
+                if self.protection(&cookie.key).is_none() {
+                    cookies.push(cookie);
+                    continue;
+                }
+
+                // Protected cookies that don't verify are dropped, as if they'd never been sent
+                if let Some(value) = self.open(&cookie.key, &cookie.value) {
+                    cookie.value = value;
+                    cookie.options.signed = true;
+                    cookies.push(cookie);
+                }
+            }
+        }
+
+        context.set_cookies(cookies);
+        context.set_cookie_jar(self.clone());
+
+        next(context).await
+    }
+}
+
+///
+/// The value to send for a cookie, sealed by the request's jar if its options say it's
+/// `signed`. Used by the contexts' `cookie` helpers.
+///
+pub(crate) fn seal_if_signed<'a>(
+    cookie_jar: Option<&CookieJar>,
+    name: &str,
+    value: &'a str,
+    options: &CookieOptions,
+) -> Cow<'a, str> {
+    if !options.signed {
+        return Cow::Borrowed(value);
+    }
+
+    match cookie_jar {
+        Some(cookie_jar) => Cow::Owned(cookie_jar.seal_with(
+            name,
+            value,
+            cookie_jar.protection(name).unwrap_or(Protection::Signed),
+        )),
+        None => {
+            warn!(
+                "The cookie `{}` is meant to be signed, but there's no CookieJar to sign it",
+                name
+            );
+            Cow::Borrowed(value)
+        }
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(value: &str) -> Option<Vec<u8>> {
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use lazy_static::lazy_static;
+
+    use super::*;
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    const SECRET: &[u8] = b"a secret that's at least 32 bytes long";
+    const OLD_SECRET: &[u8] = b"the secret that's being rotated out";
+
+    fn jar() -> CookieJar {
+        CookieJar::new(Key::derive_from(SECRET))
+            .signed("user")
+            .encrypted("session")
+    }
+
+    // Flips a character in the middle of a sealed value
+    fn tamper(sealed: &str) -> String {
+        let mut tampered = sealed.to_owned().into_bytes();
+        let middle = tampered.len() / 2;
+        tampered[middle] = if tampered[middle] == b'A' { b'B' } else { b'A' };
+
+        String::from_utf8(tampered).unwrap()
+    }
+
+    #[test]
+    fn it_should_open_what_it_seals() {
+        let jar = jar();
+
+        let signed = jar.seal("user", "jane");
+        assert_ne!(signed, "jane");
+        assert_eq!(jar.open("user", &signed).as_deref(), Some("jane"));
+
+        let encrypted = jar.seal("session", "secret=value");
+        assert!(!encrypted.contains("secret"));
+        assert_eq!(
+            jar.open("session", &encrypted).as_deref(),
+            Some("secret=value")
+        );
+
+        // Encrypting twice gives different values, as the nonce is random
+        assert_ne!(jar.seal("session", "secret=value"), encrypted);
+
+        assert_eq!(jar.seal("theme", "dark"), "dark");
+        assert_eq!(jar.open("theme", "dark").as_deref(), Some("dark"));
+    }
+
+    #[test]
+    fn it_should_reject_tampered_cookies() {
+        let jar = jar();
+
+        let signed = jar.seal("user", "jane");
+        let (value, signature) = signed.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", encode(b"admin"), signature);
+
+        assert_eq!(jar.open("user", &forged), None);
+        assert_eq!(
+            jar.open("user", &format!("{}.{}", value, tamper(signature))),
+            None
+        );
+        assert_eq!(jar.open("user", "jane"), None);
+        assert_eq!(jar.open("user", ""), None);
+
+        let encrypted = jar.seal("session", "secret");
+        assert_eq!(jar.open("session", &tamper(&encrypted)), None);
+        assert_eq!(jar.open("session", "short"), None);
+        assert_eq!(jar.open("session", "not base64!"), None);
+    }
+
+    #[test]
+    fn it_should_bind_cookies_to_their_name() {
+        let jar = jar().signed("admin").encrypted("other_session");
+
+        assert_eq!(jar.open("admin", &jar.seal("user", "jane")), None);
+        assert_eq!(
+            jar.open("other_session", &jar.seal("session", "secret")),
+            None
+        );
+    }
+
+    #[test]
+    fn it_should_accept_cookies_sealed_with_previous_keys() {
+        let old_jar = CookieJar::new(Key::derive_from(OLD_SECRET))
+            .signed("user")
+            .encrypted("session");
+        let signed = old_jar.seal("user", "jane");
+        let encrypted = old_jar.seal("session", "secret");
+
+        // Not without the old key
+        assert_eq!(jar().open("user", &signed), None);
+        assert_eq!(jar().open("session", &encrypted), None);
+
+        let rotated = jar().previous_key(Key::derive_from(OLD_SECRET));
+        assert_eq!(rotated.open("user", &signed).as_deref(), Some("jane"));
+        assert_eq!(
+            rotated.open("session", &encrypted).as_deref(),
+            Some("secret")
+        );
+
+        // New cookies are sealed with the new key
+        let resealed = rotated.seal("user", "jane");
+        assert_eq!(old_jar.open("user", &resealed), None);
+        assert_eq!(jar().open("user", &resealed).as_deref(), Some("jane"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cookie secrets must be at least 32 bytes")]
+    fn it_should_require_long_secrets() {
+        Key::derive_from(b"too short");
+    }
+
+    #[test]
+    fn it_should_seal_cookies_set_as_signed() {
+        let jar = jar();
+        let mut options = CookieOptions::default();
+        options.signed = true;
+
+        let sealed = seal_if_signed(Some(&jar), "session", "secret", &options);
+        assert_eq!(jar.open("session", &sealed).as_deref(), Some("secret"));
+
+        // Cookies the jar wasn't told about are signed
+        let sealed = seal_if_signed(Some(&jar), "theme", "dark", &options);
+        assert_eq!(
+            jar.clone()
+                .signed("theme")
+                .open("theme", &sealed)
+                .as_deref(),
+            Some("dark")
+        );
+
+        assert_eq!(
+            seal_if_signed(None, "session", "secret", &options),
+            "secret"
+        );
+        assert_eq!(
+            seal_if_signed(Some(&jar), "session", "secret", &CookieOptions::default()),
+            "secret"
+        );
+    }
+
+    lazy_static! {
+        static ref JAR: CookieJar = jar();
+    }
+
+    #[middleware_fn(_internal)]
+    async fn cookie_jar(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        JAR.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn echo_cookies(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let mut cookies: Vec<String> = context
+            .cookies
+            .iter()
+            .map(|cookie| {
+                format!(
+                    "{}={} ({})",
+                    cookie.key, cookie.value, cookie.options.signed
+                )
+            })
+            .collect();
+        cookies.sort();
+
+        context.body(&cookies.join(", "));
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn login(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let mut options = CookieOptions::default();
+        options.signed = true;
+
+        context.cookie("user", "jane", &options);
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(cookie_jar));
+        app.get("/cookies", MiddlewareTuple::A(echo_cookies));
+        app.post("/login", MiddlewareTuple::A(login));
+        app.commit()
+    }
+
+    #[tokio::test]
+    async fn it_should_only_keep_protected_cookies_that_verify() {
+        let cookie = format!(
+            "user={}; session={}; theme=dark",
+            JAR.seal("user", "jane"),
+            tamper(&JAR.seal("session", "secret"))
+        );
+
+        let response =
+            testing::request(&app(), "GET", "/cookies", &[("Cookie", &cookie)], "").await;
+
+        assert_eq!(response.body, "theme=dark (false), user=jane (true)");
+    }
+
+    #[tokio::test]
+    async fn it_should_seal_cookies_set_by_handlers() {
+        let response = testing::post(&app(), "/login", "").await;
+
+        let set_cookie = &response.headers["Set-Cookie"];
+        let value = set_cookie
+            .strip_prefix("user=")
+            .and_then(|rest| rest.split(';').next())
+            .unwrap();
+
+        assert_ne!(value, "jane");
+        assert_eq!(JAR.open("user", value).as_deref(), Some("jane"));
+        assert!(set_cookie.ends_with("; SameSite=Strict"));
+    }
+}
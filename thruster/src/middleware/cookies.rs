@@ -3,27 +3,35 @@ use thruster_proc::middleware_fn;
 use crate::core::context::Context;
 use crate::core::{MiddlewareNext, MiddlewareResult};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Cookie {
     pub key: String,
     pub value: String,
     pub options: CookieOptions,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SameSite {
     Strict,
     Lax,
+    ///
+    /// Sends the cookie on cross-site requests too. Browsers only accept this on secure
+    /// cookies, so cookies with it are always marked `Secure`.
+    ///
+    None,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CookieOptions {
     pub domain: String,
     pub path: String,
+    /// When the cookie expires, in seconds since the unix epoch. `0` makes it a session cookie.
     pub expires: u64,
     pub http_only: bool,
     pub max_age: u64,
     pub secure: bool,
+    /// Set on incoming cookies whose signature, or encryption, was verified by a `CookieJar`.
+    /// Setting it on outgoing cookies has the request's `CookieJar` seal them.
     pub signed: bool,
     pub same_site: Option<SameSite>,
 }
@@ -38,7 +46,7 @@ impl CookieOptions {
             max_age: 0,
             secure: false,
             signed: false,
-            same_site: None,
+            same_site: Some(SameSite::Strict),
         }
     }
 }
@@ -49,6 +57,14 @@ pub trait HasCookies {
     fn get_header(&self, key: &str) -> Vec<String>;
 }
 
+///
+/// A context that can set cookies on its response, for middleware that needs to. The contexts
+/// that come with Thruster implement it with their `cookie` helper.
+///
+pub trait HasResponseCookies {
+    fn set_cookie(&mut self, name: &str, value: &str, options: &CookieOptions);
+}
+
 #[middleware_fn(_internal)]
 pub async fn cookies<T: 'static + Context + HasCookies + Send>(
     mut context: T,
@@ -67,21 +83,136 @@ pub async fn cookies<T: 'static + Context + HasCookies + Send>(
     next(context).await
 }
 
-fn parse_string(string: &str) -> Vec<Cookie> {
+///
+/// Renders a cookie as the value of a `Set-Cookie` header.
+///
+pub fn set_cookie_header(name: &str, value: &str, options: &CookieOptions) -> String {
+    let mut pieces = vec![format!("Path={}", options.path)];
+
+    if options.expires > 0 {
+        let expires = time::at_utc(time::Timespec::new(options.expires as i64, 0));
+        pieces.push(format!("Expires={}", expires.rfc822()));
+    }
+
+    if options.max_age > 0 {
+        pieces.push(format!("Max-Age={}", options.max_age));
+    }
+
+    if !options.domain.is_empty() {
+        pieces.push(format!("Domain={}", options.domain));
+    }
+
+    if options.secure || options.same_site == Some(SameSite::None) {
+        pieces.push("Secure".to_owned());
+    }
+
+    if options.http_only {
+        pieces.push("HttpOnly".to_owned());
+    }
+
+    if let Some(ref same_site) = options.same_site {
+        match same_site {
+            SameSite::Strict => pieces.push("SameSite=Strict".to_owned()),
+            SameSite::Lax => pieces.push("SameSite=Lax".to_owned()),
+            SameSite::None => pieces.push("SameSite=None".to_owned()),
+        };
+    }
+
+    format!("{}={}; {}", name, value, pieces.join("; "))
+}
+
+///
+/// Parses the value of a `Cookie` header. Values can contain `=`, and lose the double quotes
+/// RFC 6265 allows around them.
+///
+pub(crate) fn parse_string(string: &str) -> Vec<Cookie> {
     string
         .split(';')
-        .map(|pair| {
-            let mut key_pair = pair.split('=');
-
-            let key = key_pair.next().unwrap_or("").to_owned().trim().to_string();
-            let value = key_pair.next().unwrap_or("").to_owned().trim().to_string();
-            let options = CookieOptions::default();
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = key.trim();
 
-            Cookie {
-                key,
-                value,
-                options,
+            if key.is_empty() {
+                return None;
             }
+
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+
+            Some(Cookie {
+                key: key.to_owned(),
+                value: value.to_owned(),
+                options: CookieOptions::default(),
+            })
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_cookie_headers() {
+        let cookies = parse_string(r#"a=1; b="quoted"; token=abc==; flag;  ; c = 3 "#);
+        let pairs: Vec<(&str, &str)> = cookies
+            .iter()
+            .map(|cookie| (cookie.key.as_str(), cookie.value.as_str()))
+            .collect();
+
+        assert_eq!(
+            pairs,
+            vec![
+                ("a", "1"),
+                ("b", "quoted"),
+                ("token", "abc=="),
+                ("flag", ""),
+                ("c", "3")
+            ]
+        );
+    }
+
+    #[test]
+    fn it_should_default_to_strict_session_cookies() {
+        assert_eq!(
+            set_cookie_header("a", "1", &CookieOptions::default()),
+            "a=1; Path=/; SameSite=Strict"
+        );
+    }
+
+    #[test]
+    fn it_should_render_every_option() {
+        let options = CookieOptions {
+            domain: "example.com".to_owned(),
+            path: "/app".to_owned(),
+            expires: 784_111_777,
+            http_only: true,
+            max_age: 3600,
+            secure: true,
+            signed: false,
+            same_site: Some(SameSite::Lax),
+        };
+
+        assert_eq!(
+            set_cookie_header("a", "1", &options),
+            "a=1; Path=/app; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Max-Age=3600; Domain=example.com; Secure; HttpOnly; SameSite=Lax"
+        );
+    }
+
+    #[test]
+    fn it_should_make_cross_site_cookies_secure() {
+        let mut options = CookieOptions::default();
+        options.same_site = Some(SameSite::None);
+
+        assert_eq!(
+            set_cookie_header("a", "1", &options),
+            "a=1; Path=/; Secure; SameSite=None"
+        );
+
+        options.same_site = None;
+        assert_eq!(set_cookie_header("a", "1", &options), "a=1; Path=/");
+    }
+}
@@ -1,22 +1,437 @@
-use thruster_proc::middleware_fn;
+use std::time::Duration;
 
 use crate::core::context::Context;
-use crate::core::{MiddlewareNext, MiddlewareResult};
 
+enum AllowedOrigins {
+    Any,
+    List(Vec<String>),
+    Predicate(Box<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+///
+/// Configuration for Cross-Origin Resource Sharing. Set it on an app with `App::set_cors`. The
+/// app then adds the CORS headers to every response for a request with an `Origin`, and answers
+/// preflight `OPTIONS` requests itself using the methods that have routes for the requested path.
+/// When the headers depend on the origin, every response gets `Vary: Origin`, including those for
+/// same-origin requests, so that shared caches keep them apart.
+///
+/// Preflights are answered before any middleware runs, since browsers send them without cookies
+/// or other credentials, so middleware like authentication would reject them.
 ///
-/// Middleware to allow CORS.
+/// ```rust, ignore
+/// app.set_cors(
+///     Cors::new()
+///         .allow_origins(&["https://app.example.com"])
+///         .allow_credentials(true)
+///         .expose_headers(&["X-Request-Id"])
+///         .max_age(Duration::from_secs(600)),
+/// );
+/// ```
 ///
-#[middleware_fn(_internal)]
-pub async fn cors<T: 'static + Context + Send>(
-    mut context: T,
-    next: MiddlewareNext<T>,
-) -> MiddlewareResult<T> {
-    context.set("Access-Control-Allow-Origin", "*");
-    context.set("Access-Control-Allow-Headers", "*");
-    context.set(
-        "Access-Control-Allow-Methods",
-        "GET, POST, PUT, DELETE, OPTIONS",
-    );
-
-    next(context).await
+pub struct Cors {
+    origins: AllowedOrigins,
+    allowed_headers: Option<Vec<String>>,
+    exposed_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors::new()
+    }
+}
+
+impl Cors {
+    ///
+    /// Creates a CORS config that allows any origin, allows whatever headers a preflight asks
+    /// for, doesn't allow credentials and doesn't let browsers cache preflight responses.
+    ///
+    pub fn new() -> Cors {
+        Cors {
+            origins: AllowedOrigins::Any,
+            allowed_headers: None,
+            exposed_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    ///
+    /// Only allow requests from the given origins, e.g. `https://example.com`.
+    ///
+    pub fn allow_origins(mut self, origins: &[&str]) -> Cors {
+        self.origins = AllowedOrigins::List(origins.iter().map(|o| (*o).to_owned()).collect());
+        self
+    }
+
+    ///
+    /// Only allow requests from origins for which `predicate` returns true.
+    ///
+    pub fn allow_origin_fn<F: Fn(&str) -> bool + Send + Sync + 'static>(
+        mut self,
+        predicate: F,
+    ) -> Cors {
+        self.origins = AllowedOrigins::Predicate(Box::new(predicate));
+        self
+    }
+
+    ///
+    /// Sets the request headers allowed in preflight responses. If never called, whatever the
+    /// preflight lists in `Access-Control-Request-Headers` is allowed.
+    ///
+    pub fn allow_headers(mut self, headers: &[&str]) -> Cors {
+        self.allowed_headers = Some(headers.iter().map(|h| (*h).to_owned()).collect());
+        self
+    }
+
+    ///
+    /// Sets the response headers that browsers expose to scripts, via
+    /// `Access-Control-Expose-Headers`.
+    ///
+    pub fn expose_headers(mut self, headers: &[&str]) -> Cors {
+        self.exposed_headers = headers.iter().map(|h| (*h).to_owned()).collect();
+        self
+    }
+
+    ///
+    /// Allows cookies and other credentials on cross-origin requests. Since browsers refuse a
+    /// wildcard origin for credentialed requests, the request's origin is echoed back instead.
+    ///
+    pub fn allow_credentials(mut self, credentials: bool) -> Cors {
+        self.credentials = credentials;
+        self
+    }
+
+    ///
+    /// Sets how long browsers may cache a preflight response, via `Access-Control-Max-Age`.
+    ///
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    ///
+    /// The value for `Access-Control-Allow-Origin` for a request from `origin`, if it's allowed.
+    ///
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        let allowed = match &self.origins {
+            AllowedOrigins::Any if !self.credentials => return Some("*".to_owned()),
+            AllowedOrigins::Any => true,
+            AllowedOrigins::List(origins) => origins.iter().any(|o| o == origin),
+            AllowedOrigins::Predicate(predicate) => predicate(origin),
+        };
+
+        if allowed {
+            Some(origin.to_owned())
+        } else {
+            None
+        }
+    }
+
+    fn varies_by_origin(&self) -> bool {
+        self.credentials || !matches!(self.origins, AllowedOrigins::Any)
+    }
+
+    ///
+    /// Adds the CORS headers for a regular (non-preflight) request from `origin`. Responses to
+    /// requests without an `Origin` still get `Vary: Origin` if it matters, so that caches don't
+    /// hand them out for cross-origin requests.
+    ///
+    pub(crate) fn apply<T: Context>(&self, context: &mut T, origin: Option<&str>) {
+        if self.varies_by_origin() {
+            context.set("Vary", "Origin");
+        }
+
+        if let Some(allowed_origin) = origin.and_then(|origin| self.allowed_origin(origin)) {
+            context.set("Access-Control-Allow-Origin", &allowed_origin);
+
+            if self.credentials {
+                context.set("Access-Control-Allow-Credentials", "true");
+            }
+
+            if !self.exposed_headers.is_empty() {
+                context.set(
+                    "Access-Control-Expose-Headers",
+                    &self.exposed_headers.join(", "),
+                );
+            }
+        }
+    }
+
+    ///
+    /// Turns `context` into the response to a preflight request from `origin` for a path
+    /// with routes for the `allowed_methods`.
+    ///
+    pub(crate) fn preflight<T: Context>(
+        &self,
+        context: &mut T,
+        origin: &str,
+        request_headers: &str,
+        allowed_methods: &[String],
+    ) {
+        context.status(204);
+        context.set("Allow", &allowed_methods.join(", "));
+
+        let mut vary = vec!["Access-Control-Request-Method"];
+        if self.varies_by_origin() {
+            vary.insert(0, "Origin");
+        }
+        if self.allowed_headers.is_none() {
+            vary.push("Access-Control-Request-Headers");
+        }
+        context.set("Vary", &vary.join(", "));
+
+        let allowed_origin = match self.allowed_origin(origin) {
+            Some(allowed_origin) => allowed_origin,
+            None => return,
+        };
+
+        context.set("Access-Control-Allow-Origin", &allowed_origin);
+        context.set("Access-Control-Allow-Methods", &allowed_methods.join(", "));
+
+        match (&self.allowed_headers, request_headers) {
+            (Some(allowed_headers), _) if !allowed_headers.is_empty() => {
+                context.set("Access-Control-Allow-Headers", &allowed_headers.join(", "));
+            }
+            (None, request_headers) if !request_headers.trim().is_empty() => {
+                context.set("Access-Control-Allow-Headers", request_headers);
+            }
+            _ => (),
+        }
+
+        if self.credentials {
+            context.set("Access-Control-Allow-Credentials", "true");
+        }
+
+        if let Some(max_age) = self.max_age {
+            context.set("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext as Ctx;
+    use crate::core::request::Request;
+    use crate::core::{MiddlewareNext, MiddlewareResult};
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    #[middleware_fn(_internal)]
+    async fn hello(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+        context.body("hello");
+        Ok(context)
+    }
+
+    fn app(cors: Cors) -> App<Request, Ctx, ()> {
+        let mut app = App::<Request, Ctx, ()>::new_basic();
+        app.get("/posts", MiddlewareTuple::A(hello));
+        app.post("/posts", MiddlewareTuple::A(hello));
+        app.set_cors(cors);
+        app.commit()
+    }
+
+    const ORIGIN: (&str, &str) = ("Origin", "https://app.example.com");
+
+    #[tokio::test]
+    async fn it_should_allow_any_origin_by_default() {
+        let response = testing::request(&app(Cors::new()), "GET", "/posts", &[ORIGIN], "").await;
+
+        assert_eq!(response.body, "hello");
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin").unwrap(),
+            "*"
+        );
+        assert!(!response.headers.contains_key("Vary"));
+        assert!(!response
+            .headers
+            .contains_key("Access-Control-Allow-Credentials"));
+    }
+
+    #[tokio::test]
+    async fn it_should_not_add_headers_to_same_origin_requests() {
+        let response = testing::request(&app(Cors::new()), "GET", "/posts", &[], "").await;
+
+        assert!(!response.headers.contains_key("Access-Control-Allow-Origin"));
+    }
+
+    #[tokio::test]
+    async fn it_should_echo_allowed_origins_and_vary_by_origin() {
+        let app = app(Cors::new().allow_origins(&["https://app.example.com"]));
+
+        let response = testing::request(&app, "GET", "/posts", &[ORIGIN], "").await;
+
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin").unwrap(),
+            "https://app.example.com"
+        );
+        assert_eq!(response.headers.get("Vary").unwrap(), "Origin");
+
+        let response = testing::request(
+            &app,
+            "GET",
+            "/posts",
+            &[("Origin", "https://evil.example.com")],
+            "",
+        )
+        .await;
+
+        assert!(!response.headers.contains_key("Access-Control-Allow-Origin"));
+        assert_eq!(response.headers.get("Vary").unwrap(), "Origin");
+    }
+
+    #[tokio::test]
+    async fn it_should_vary_by_origin_for_same_origin_requests() {
+        let listed = app(Cors::new().allow_origins(&["https://app.example.com"]));
+
+        let response = testing::request(&listed, "GET", "/posts", &[], "").await;
+
+        assert_eq!(response.body, "hello");
+        assert_eq!(response.headers.get("Vary").unwrap(), "Origin");
+        assert!(!response.headers.contains_key("Access-Control-Allow-Origin"));
+
+        let response = testing::request(
+            &app(Cors::new().allow_credentials(true)),
+            "GET",
+            "/posts",
+            &[],
+            "",
+        )
+        .await;
+
+        assert_eq!(response.headers.get("Vary").unwrap(), "Origin");
+
+        let response = testing::request(&app(Cors::new()), "GET", "/posts", &[], "").await;
+
+        assert!(!response.headers.contains_key("Vary"));
+    }
+
+    #[tokio::test]
+    async fn it_should_echo_the_origin_instead_of_a_wildcard_with_credentials() {
+        let app = app(Cors::new()
+            .allow_credentials(true)
+            .expose_headers(&["X-Request-Id", "ETag"]));
+
+        let response = testing::request(&app, "GET", "/posts", &[ORIGIN], "").await;
+
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin").unwrap(),
+            "https://app.example.com"
+        );
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Allow-Credentials")
+                .unwrap(),
+            "true"
+        );
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Expose-Headers")
+                .unwrap(),
+            "X-Request-Id, ETag"
+        );
+        assert_eq!(response.headers.get("Vary").unwrap(), "Origin");
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_preflights_with_the_routes_of_the_path() {
+        let app = app(Cors::new().max_age(Duration::from_secs(600)));
+
+        let response = testing::request(
+            &app,
+            "OPTIONS",
+            "/posts",
+            &[
+                ORIGIN,
+                ("Access-Control-Request-Method", "POST"),
+                ("Access-Control-Request-Headers", "Content-Type, X-Custom"),
+            ],
+            "",
+        )
+        .await;
+
+        assert_eq!(response.status.1, 204);
+        assert_eq!(response.body, "");
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Allow-Methods")
+                .unwrap(),
+            "GET, HEAD, POST, OPTIONS"
+        );
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Allow-Headers")
+                .unwrap(),
+            "Content-Type, X-Custom"
+        );
+        assert_eq!(
+            response.headers.get("Access-Control-Max-Age").unwrap(),
+            "600"
+        );
+        assert_eq!(
+            response.headers.get("Vary").unwrap(),
+            "Access-Control-Request-Method, Access-Control-Request-Headers"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_only_allow_the_configured_headers_in_preflights() {
+        let app = app(Cors::new()
+            .allow_origins(&["https://app.example.com"])
+            .allow_headers(&["Content-Type"]));
+
+        let response = testing::request(
+            &app,
+            "OPTIONS",
+            "/posts",
+            &[
+                ORIGIN,
+                ("Access-Control-Request-Method", "POST"),
+                ("Access-Control-Request-Headers", "X-Custom"),
+            ],
+            "",
+        )
+        .await;
+
+        assert_eq!(
+            response
+                .headers
+                .get("Access-Control-Allow-Headers")
+                .unwrap(),
+            "Content-Type"
+        );
+        assert_eq!(
+            response.headers.get("Vary").unwrap(),
+            "Origin, Access-Control-Request-Method"
+        );
+        assert!(!response.headers.contains_key("Access-Control-Max-Age"));
+    }
+
+    #[tokio::test]
+    async fn it_should_not_allow_anything_in_preflights_from_other_origins() {
+        let app = app(Cors::new().allow_origin_fn(|origin| origin.ends_with(".example.org")));
+
+        let response = testing::request(
+            &app,
+            "OPTIONS",
+            "/posts",
+            &[ORIGIN, ("Access-Control-Request-Method", "POST")],
+            "",
+        )
+        .await;
+
+        assert_eq!(response.status.1, 204);
+        assert!(!response.headers.contains_key("Access-Control-Allow-Origin"));
+        assert!(!response
+            .headers
+            .contains_key("Access-Control-Allow-Methods"));
+    }
 }
@@ -0,0 +1,708 @@
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use hmac::Mac;
+use rand::RngCore;
+use std::fmt;
+use std::io;
+
+use crate::core::context::Context;
+use crate::core::errors::ThrusterError;
+use crate::core::extractors::{HasRequestBody, HasRequestHeaders, HasRequestMethod};
+use crate::core::response::BodyStream;
+use crate::core::{MiddlewareNext, MiddlewareResult};
+use crate::middleware::cookie_jar::Key;
+use crate::middleware::cookies::{parse_string, CookieOptions, HasResponseCookies, SameSite};
+use crate::middleware::form::{has_content_type, URLENCODED};
+
+const NONCE_LEN: usize = 32;
+
+///
+/// A context that holds the CSRF token of the request, for handlers to put in the forms they
+/// render.
+///
+pub trait HasCsrfToken {
+    fn csrf_token(&self) -> Option<&str>;
+
+    fn set_csrf_token(&mut self, token: String);
+}
+
+///
+/// Middleware that protects against cross-site request forgery with signed double-submit
+/// cookies. Every request is given a token in a cookie, which unsafe requests, i.e. anything but
+/// `GET`, `HEAD`, `OPTIONS` and `TRACE`, have to send back in the `X-CSRF-Token` header or, for
+/// urlencoded forms, in the `csrf_token` field. Requests without a matching token are answered
+/// with a `403`.
+///
+/// Tokens are signed with the key, so only tokens handed out by the site are accepted. They
+/// aren't tied to a session, though, so anyone able to set cookies for the site, such as a
+/// compromised subdomain, can plant a token they were given in a victim's browser and then use
+/// it. `check_origin` guards against that, as does rotating the token when a user logs in. The
+/// token of the request is available to handlers through `HasCsrfToken`.
+///
+/// Form fields are read from the request body, which is put back for the handler, so this has to
+/// run before the `form` middleware. Forms larger than `max_form_size` are answered with a `413`.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref CSRF: Csrf = Csrf::new(Key::derive_from(SECRET))
+///         .check_origin()
+///         .exempt("/webhooks");
+/// }
+///
+/// #[middleware_fn]
+/// async fn csrf(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     CSRF.handle(context, next).await
+/// }
+///
+/// #[middleware_fn]
+/// async fn new_post(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     let token = context.csrf_token().unwrap_or_default().to_owned();
+///     context.body(&format!(
+///         r#"<form method="post"><input type="hidden" name="csrf_token" value="{}">...</form>"#,
+///         token
+///     ));
+///     Ok(context)
+/// }
+///
+/// app.use_middleware("/", m![csrf, form]);
+/// ```
+///
+pub struct Csrf {
+    key: Key,
+    cookie: String,
+    cookie_options: CookieOptions,
+    header: String,
+    field: String,
+    exempt: Vec<String>,
+    check_origin: bool,
+    trusted_origins: Vec<String>,
+    max_form_size: u64,
+}
+
+impl fmt::Debug for Csrf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Csrf")
+            .field("cookie", &self.cookie)
+            .field("header", &self.header)
+            .field("field", &self.field)
+            .field("exempt", &self.exempt)
+            .field("check_origin", &self.check_origin)
+            .field("trusted_origins", &self.trusted_origins)
+            .field("max_form_size", &self.max_form_size)
+            .finish()
+    }
+}
+
+impl Csrf {
+    pub fn new(key: Key) -> Csrf {
+        let mut cookie_options = CookieOptions::default();
+        cookie_options.same_site = Some(SameSite::Lax);
+
+        Csrf {
+            key,
+            cookie: "csrf_token".to_owned(),
+            cookie_options,
+            header: "X-CSRF-Token".to_owned(),
+            field: "csrf_token".to_owned(),
+            exempt: Vec::new(),
+            check_origin: false,
+            trusted_origins: Vec::new(),
+            max_form_size: 1024 * 1024,
+        }
+    }
+
+    ///
+    /// Sets the name of the cookie the token is kept in.
+    ///
+    pub fn cookie(mut self, name: &str) -> Csrf {
+        self.cookie = name.to_owned();
+        self
+    }
+
+    ///
+    /// Sets the options of the cookie the token is kept in. It isn't `HttpOnly` by default, so
+    /// that scripts can read it to send it back in the header.
+    ///
+    pub fn cookie_options(mut self, options: CookieOptions) -> Csrf {
+        self.cookie_options = options;
+        self
+    }
+
+    ///
+    /// Sets the header that tokens are read from.
+    ///
+    pub fn header(mut self, header: &str) -> Csrf {
+        self.header = header.to_owned();
+        self
+    }
+
+    ///
+    /// Sets the form field that tokens are read from.
+    ///
+    pub fn field(mut self, field: &str) -> Csrf {
+        self.field = field.to_owned();
+        self
+    }
+
+    ///
+    /// Skips the check for requests to `path` and anything below it, e.g. for webhooks that are
+    /// authenticated some other way.
+    ///
+    pub fn exempt(mut self, path: &str) -> Csrf {
+        self.exempt.push(path.trim_end_matches('/').to_owned());
+        self
+    }
+
+    ///
+    /// Also rejects unsafe requests whose `Origin`, or `Referer` if there's no `Origin`, isn't
+    /// the site itself or one of the trusted origins. Requests with neither are rejected too.
+    ///
+    pub fn check_origin(mut self) -> Csrf {
+        self.check_origin = true;
+        self
+    }
+
+    ///
+    /// Trusts another origin, e.g. `https://admin.example.com`, when checking origins.
+    ///
+    pub fn trusted_origin(mut self, origin: &str) -> Csrf {
+        self.trusted_origins
+            .push(origin.trim_end_matches('/').to_owned());
+        self
+    }
+
+    ///
+    /// Sets the largest a urlencoded form can be, in bytes, for the token to be read from it.
+    /// Defaults to 1MiB.
+    ///
+    pub fn max_form_size(mut self, max_form_size: u64) -> Csrf {
+        self.max_form_size = max_form_size;
+        self
+    }
+
+    ///
+    /// Gives the request a new token, e.g. when a user logs in, so that a token planted before
+    /// then can't be used.
+    ///
+    pub fn rotate<T: HasCsrfToken + HasResponseCookies>(&self, context: &mut T) {
+        let token = self.generate();
+
+        context.set_cookie(&self.cookie, &token, &self.cookie_options);
+        context.set_csrf_token(token);
+    }
+
+    pub async fn handle<T>(&self, mut context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static
+            + Context
+            + HasCsrfToken
+            + HasRequestBody
+            + HasRequestHeaders
+            + HasRequestMethod
+            + HasResponseCookies
+            + Send,
+    {
+        let token = context
+            .request_header("cookie")
+            .iter()
+            .flat_map(|cookies| parse_string(cookies))
+            .find(|cookie| cookie.key == self.cookie)
+            .map(|cookie| cookie.value)
+            .filter(|token| self.verify(token));
+
+        let safe = matches!(
+            context.request_method(),
+            "GET" | "HEAD" | "OPTIONS" | "TRACE"
+        );
+
+        if !safe && !self.is_exempt(context.route()) {
+            if self.check_origin && !self.origin_allowed(&context) {
+                return reject(context, 403, "Cross-origin request rejected");
+            }
+
+            let expected = match &token {
+                Some(token) => token,
+                None => return reject(context, 403, "Missing CSRF token"),
+            };
+
+            let submitted = match context.request_header(&self.header).pop() {
+                Some(submitted) => Some(submitted),
+                None => match self.form_token(&mut context).await {
+                    Ok(submitted) => submitted,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        return reject(context, 413, &e.to_string())
+                    }
+                    Err(_) => None,
+                },
+            };
+
+            match submitted {
+                None => return reject(context, 403, "Missing CSRF token"),
+                Some(submitted) if !constant_time_eq(submitted.as_bytes(), expected.as_bytes()) => {
+                    return reject(context, 403, "Invalid CSRF token")
+                }
+                Some(_) => (),
+            }
+        }
+
+        match token {
+            Some(token) => context.set_csrf_token(token),
+            None => self.rotate(&mut context),
+        }
+
+        next(context).await
+    }
+
+    fn generate(&self) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let nonce = encode(&nonce);
+
+        let signature = self.key.sign(&self.cookie, &nonce).finalize().into_bytes();
+
+        format!("{}.{}", nonce, encode(&signature))
+    }
+
+    fn verify(&self, token: &str) -> bool {
+        let (nonce, signature) = match token.split_once('.') {
+            Some(pieces) => pieces,
+            None => return false,
+        };
+
+        match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(signature) => self
+                .key
+                .sign(&self.cookie, nonce)
+                .verify_slice(&signature)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn is_exempt(&self, route: &str) -> bool {
+        let path = route.split(['?', '#']).next().unwrap_or("");
+
+        self.exempt.iter().any(|exempt| {
+            path.strip_prefix(exempt.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    fn origin_allowed<T: HasRequestHeaders>(&self, context: &T) -> bool {
+        let origin = context
+            .request_header("origin")
+            .pop()
+            .filter(|origin| origin != "null")
+            .or_else(|| {
+                context
+                    .request_header("referer")
+                    .pop()
+                    .and_then(|referer| origin_of(&referer).map(str::to_owned))
+            });
+
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return false,
+        };
+
+        let same_site = match (
+            origin.split_once("://"),
+            context.request_header("host").pop(),
+        ) {
+            (Some((_, host)), Some(expected)) => host.eq_ignore_ascii_case(&expected),
+            _ => false,
+        };
+
+        same_site
+            || self
+                .trusted_origins
+                .iter()
+                .any(|trusted| trusted.eq_ignore_ascii_case(&origin))
+    }
+
+    // Reads the token from a urlencoded form, putting the body back for whatever reads it next.
+    // Fails with `InvalidData` if the form is too large to read.
+    async fn form_token<T: HasRequestBody + HasRequestHeaders>(
+        &self,
+        context: &mut T,
+    ) -> io::Result<Option<String>> {
+        if !has_content_type(context, URLENCODED) {
+            return Ok(None);
+        }
+
+        let too_large = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Form is larger than {} bytes", self.max_form_size),
+            )
+        };
+
+        let content_length = context
+            .request_header("content-length")
+            .pop()
+            .and_then(|length| length.trim().parse::<u64>().ok());
+        if content_length.is_some_and(|length| length > self.max_form_size) {
+            return Err(too_large());
+        }
+
+        let mut stream = context.request_body_stream();
+        let mut body = BytesMut::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            if (body.len() + chunk.len()) as u64 > self.max_form_size {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let body = body.freeze();
+        let token = form_urlencoded::parse(&body)
+            .find(|(key, _)| key == self.field.as_str())
+            .map(|(_, value)| value.into_owned());
+
+        context.set_request_body_stream(BodyStream::new(futures::stream::once(async move {
+            Ok::<Bytes, std::io::Error>(body)
+        })));
+
+        Ok(token)
+    }
+}
+
+fn reject<C: Context>(mut context: C, status: u32, message: &str) -> MiddlewareResult<C> {
+    context.status(status);
+    context.set_body(message.as_bytes().to_vec());
+
+    Err(ThrusterError {
+        context,
+        message: message.to_owned(),
+        status,
+        cause: None,
+    })
+}
+
+// The scheme, host and port of a URL
+fn origin_of(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+
+    Some(&url[..url.len() - rest.len() + end])
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    const SECRET: &[u8] = b"a secret that's used for the tests";
+
+    lazy_static! {
+        static ref CSRF: Csrf = Csrf::new(Key::derive_from(SECRET))
+            .exempt("/forms/webhooks/")
+            .max_form_size(128);
+        static ref ORIGIN_CSRF: Csrf = Csrf::new(Key::derive_from(SECRET))
+            .check_origin()
+            .trusted_origin("https://admin.example.com/");
+    }
+
+    #[middleware_fn(_internal)]
+    async fn csrf(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        CSRF.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn origin_csrf(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        ORIGIN_CSRF.handle(context, next).await
+    }
+
+    // Sends the body in chunks without a `Content-Length`, like a decoded or chunked upload
+    #[middleware_fn(_internal)]
+    async fn chunked_csrf(
+        mut context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let body = context.request_body().await.unwrap();
+        let chunks: Vec<io::Result<Bytes>> = body
+            .chunks(16)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        context.set_request_body_stream(BodyStream::new(futures::stream::iter(chunks)));
+
+        CSRF.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn show_token(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let token = context.csrf_token().unwrap_or("-").to_owned();
+        context.body(&token);
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn echo(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let body = context.request_body().await.unwrap();
+        context.body(std::str::from_utf8(&body).unwrap());
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/forms", MiddlewareTuple::A(csrf));
+        app.use_middleware("/origin", MiddlewareTuple::A(origin_csrf));
+        app.use_middleware("/chunked", MiddlewareTuple::A(chunked_csrf));
+        app.get("/forms", MiddlewareTuple::A(show_token));
+        app.post("/forms", MiddlewareTuple::A(echo));
+        app.post("/forms/webhooks/github", MiddlewareTuple::A(echo));
+        app.post("/origin", MiddlewareTuple::A(echo));
+        app.post("/chunked", MiddlewareTuple::A(echo));
+        app.commit()
+    }
+
+    async fn request(
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> testing::TestResponse {
+        testing::request(&app(), method, path, headers, body).await
+    }
+
+    // A token handed out by the site, along with the `Cookie` header that sends it back
+    async fn token() -> (String, String) {
+        let response = request("GET", "/forms", &[], "").await;
+        let cookie = response.headers["Set-Cookie"]
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+
+        (response.body, cookie)
+    }
+
+    async fn post_form(path: &str, cookie: &str, body: &str) -> testing::TestResponse {
+        let content_length = body.len().to_string();
+
+        request(
+            "POST",
+            path,
+            &[
+                ("Cookie", cookie),
+                ("Content-Type", "application/x-www-form-urlencoded"),
+                ("Content-Length", &content_length),
+            ],
+            body,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn it_should_hand_out_signed_tokens() {
+        let response = request("GET", "/forms", &[], "").await;
+        let token = response.body.clone();
+
+        assert_eq!(response.status.1, 200);
+        assert!(response.headers["Set-Cookie"].starts_with(&format!("csrf_token={};", token)));
+        assert!(response.headers["Set-Cookie"].contains("SameSite=Lax"));
+        assert!(CSRF.verify(&token));
+        assert!(!CSRF.verify(&token.replace('.', "")));
+
+        // The token is kept for as long as the cookie is sent back
+        let cookie = format!("csrf_token={}", token);
+        let response = request("GET", "/forms", &[("Cookie", &cookie)], "").await;
+
+        assert_eq!(response.body, token);
+        assert!(!response.headers.contains_key("Set-Cookie"));
+    }
+
+    #[tokio::test]
+    async fn it_should_accept_tokens_sent_in_the_header() {
+        let (token, cookie) = token().await;
+        let response = request(
+            "POST",
+            "/forms",
+            &[("Cookie", &cookie), ("X-CSRF-Token", &token)],
+            "",
+        )
+        .await;
+
+        assert_eq!(response.status.1, 200);
+    }
+
+    #[tokio::test]
+    async fn it_should_accept_tokens_sent_in_a_form_and_put_the_body_back() {
+        let (token, cookie) = token().await;
+        let body = format!("name=ada&csrf_token={}", token);
+        let response = post_form("/forms", &cookie, &body).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, body);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_requests_without_a_token() {
+        let response = request("POST", "/forms", &[], "").await;
+
+        assert_eq!(response.status.1, 403);
+        assert_eq!(response.body, "Missing CSRF token");
+
+        let (_, cookie) = token().await;
+        let response = request("POST", "/forms", &[("Cookie", &cookie)], "").await;
+
+        assert_eq!(response.status.1, 403);
+        assert_eq!(response.body, "Missing CSRF token");
+
+        let response = post_form("/forms", &cookie, "name=ada").await;
+
+        assert_eq!(response.status.1, 403);
+        assert_eq!(response.body, "Missing CSRF token");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_tokens_that_dont_match_the_cookie() {
+        let (_, cookie) = token().await;
+        let (other, _) = token().await;
+        let response = request(
+            "POST",
+            "/forms",
+            &[("Cookie", &cookie), ("X-CSRF-Token", &other)],
+            "",
+        )
+        .await;
+
+        assert_eq!(response.status.1, 403);
+        assert_eq!(response.body, "Invalid CSRF token");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_tokens_the_site_didnt_sign() {
+        let forged = Csrf::new(Key::derive_from(
+            b"some other secret, as long as the first one",
+        ))
+        .generate();
+
+        for token in &[forged.as_str(), "nonce.signature", "unsigned"] {
+            let cookie = format!("csrf_token={}", token);
+            let response = request(
+                "POST",
+                "/forms",
+                &[("Cookie", &cookie), ("X-CSRF-Token", token)],
+                "",
+            )
+            .await;
+
+            assert_eq!(response.status.1, 403);
+            assert_eq!(response.body, "Missing CSRF token");
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_let_safe_and_exempt_requests_through() {
+        let response = request("GET", "/forms", &[], "").await;
+
+        assert_eq!(response.status.1, 200);
+
+        let response = request("POST", "/forms/webhooks/github", &[], "").await;
+
+        assert_eq!(response.status.1, 200);
+    }
+
+    #[test]
+    fn it_should_only_exempt_paths_at_or_below_the_exempt_path() {
+        assert!(CSRF.is_exempt("/forms/webhooks"));
+        assert!(CSRF.is_exempt("/forms/webhooks?source=github"));
+        assert!(CSRF.is_exempt("/forms/webhooks/github"));
+        assert!(!CSRF.is_exempt("/forms/webhooksx"));
+        assert!(!CSRF.is_exempt("/forms"));
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_forms_that_are_too_large_to_read() {
+        let (token, cookie) = token().await;
+        let body = format!("csrf_token={}&comment={}", token, "a".repeat(128));
+        let response = post_form("/forms", &cookie, &body).await;
+
+        assert_eq!(response.status.1, 413);
+        assert_eq!(response.body, "Form is larger than 128 bytes");
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_reading_forms_without_a_length_once_they_are_too_large() {
+        let (token, cookie) = token().await;
+        let body = format!("csrf_token={}&comment=short", token);
+        let response = post_form("/chunked", &cookie, &body).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, body);
+
+        let body = format!("csrf_token={}&comment={}", token, "a".repeat(128));
+        let response = post_form("/chunked", &cookie, &body).await;
+
+        assert_eq!(response.status.1, 413);
+    }
+
+    async fn post_from(headers: &[(&str, &str)]) -> testing::TestResponse {
+        let (token, cookie) = token().await;
+        let mut headers = headers.to_vec();
+        headers.push(("Cookie", &cookie));
+        headers.push(("X-CSRF-Token", &token));
+
+        request("POST", "/origin", &headers, "").await
+    }
+
+    #[tokio::test]
+    async fn it_should_accept_requests_from_the_site_and_trusted_origins() {
+        let response = post_from(&[("Origin", "http://localhost:8080")]).await;
+
+        assert_eq!(response.status.1, 200);
+
+        let response = post_from(&[("Origin", "https://admin.example.com")]).await;
+
+        assert_eq!(response.status.1, 200);
+
+        let response = post_from(&[("Referer", "http://localhost:8080/posts/new?draft=1")]).await;
+
+        assert_eq!(response.status.1, 200);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_requests_from_other_origins() {
+        for headers in &[
+            vec![("Origin", "https://evil.example.com")],
+            vec![("Origin", "http://localhost:8080.evil.example.com")],
+            vec![("Origin", "null")],
+            vec![("Referer", "https://evil.example.com/localhost:8080")],
+            vec![],
+        ] {
+            let response = post_from(headers).await;
+
+            assert_eq!(response.status.1, 403);
+            assert_eq!(response.body, "Cross-origin request rejected");
+        }
+    }
+}
@@ -2,14 +2,24 @@ use bytes::Bytes;
 use bytes::BytesMut;
 use dashmap::DashMap;
 use lazy_static::*;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
 use std::env;
-use std::fs::File;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::fs::{File, Metadata};
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use thruster_proc::middleware_fn;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
 
 use crate::core::context::Context;
 use crate::core::errors::{ErrorSet, ThrusterError as Error};
+use crate::core::extractors::HasRequestHeaders;
+use crate::core::response::{BodyStream, HasBodyStream};
 use crate::core::{MiddlewareNext, MiddlewareResult};
 use crate::map_try;
 
@@ -40,6 +50,9 @@ lazy_static! {
 /// DashMap. This feature can be turned off by setting the env
 /// var RUST_CACHE=off
 ///
+/// For anything more than a quick prototype, use `StaticFiles`
+/// instead.
+///
 #[middleware_fn(_internal)]
 pub async fn file<T: 'static + Context + Send>(
     mut context: T,
@@ -91,3 +104,841 @@ fn read_file(path: &str) -> Result<Vec<u8>, std::io::Error> {
 
     Ok(contents)
 }
+
+// Files up to this size are read in one go, larger ones are streamed
+const STREAM_THRESHOLD: u64 = 64 * 1024;
+
+// Everything but unreserved characters and `/`, for links in directory listings
+const HREF: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'+')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+///
+/// Serves the files in a directory under a path prefix, so `/static/app.js` is read from
+/// `./public/app.js` when mounted at `/static` with `./public` as the root.
+///
+/// Files are read asynchronously and sent with a `Content-Type` guessed from their extension,
+/// along with an `ETag` and `Last-Modified` so that clients can revalidate them with a `304`.
+/// Single byte ranges are supported, with `206 Partial Content` responses, so media can be
+/// seeked. Paths are resolved to real paths on disk, and only files inside the root are
+/// served, even through symlinks.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref ASSETS: StaticFiles = StaticFiles::new("/", "./dist")
+///         .fallback("index.html")
+///         .precompressed(true)
+///         .cache_control("public, max-age=3600");
+/// }
+///
+/// #[middleware_fn]
+/// async fn assets(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     ASSETS.handle(context, next).await
+/// }
+///
+/// app.get("/*", m![assets]);
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    index: Option<String>,
+    fallback: Option<String>,
+    directory_listing: bool,
+    precompressed: bool,
+    cache_control: Option<String>,
+}
+
+enum Resolved {
+    File(PathBuf, Metadata),
+    Directory(PathBuf),
+    Redirect(String),
+    NotFound,
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl StaticFiles {
+    ///
+    /// Serves the files in `root` for requests under `prefix`. Requests for a directory are
+    /// answered with its `index.html`, if it has one.
+    ///
+    pub fn new(prefix: &str, root: impl AsRef<Path>) -> StaticFiles {
+        let root = root.as_ref();
+
+        StaticFiles {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            root: std::fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf()),
+            index: Some("index.html".to_owned()),
+            fallback: None,
+            directory_listing: false,
+            precompressed: false,
+            cache_control: None,
+        }
+    }
+
+    ///
+    /// Sets the file that's served for requests for a directory, or turns that off with `None`.
+    ///
+    pub fn index(mut self, index: Option<&str>) -> StaticFiles {
+        self.index = index.map(|index| index.to_owned());
+        self
+    }
+
+    ///
+    /// Sets a file, relative to the root, that's served in place of any file that doesn't
+    /// exist. Single page apps use this to serve their `index.html` for every route.
+    ///
+    pub fn fallback(mut self, fallback: &str) -> StaticFiles {
+        self.fallback = Some(fallback.to_owned());
+        self
+    }
+
+    ///
+    /// Lists the contents of directories that don't have an index file. Off by default.
+    ///
+    pub fn directory_listing(mut self, directory_listing: bool) -> StaticFiles {
+        self.directory_listing = directory_listing;
+        self
+    }
+
+    ///
+    /// Serves `file.br` or `file.gz` in place of `file`, when they exist and the client accepts
+    /// that encoding. Off by default.
+    ///
+    pub fn precompressed(mut self, precompressed: bool) -> StaticFiles {
+        self.precompressed = precompressed;
+        self
+    }
+
+    ///
+    /// Sets the `Cache-Control` sent with every file.
+    ///
+    pub fn cache_control(mut self, cache_control: &str) -> StaticFiles {
+        self.cache_control = Some(cache_control.to_owned());
+        self
+    }
+
+    pub async fn handle<T>(&self, mut context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + HasRequestHeaders + HasBodyStream + Send,
+    {
+        let path = context.route();
+        let path = path.split('?').next().unwrap_or(path).to_owned();
+
+        let relative = match path.strip_prefix(&self.prefix) {
+            Some(relative) if relative.is_empty() || relative.starts_with('/') => relative,
+            _ => return next(context).await,
+        };
+
+        let (file, metadata) = match self.resolve(&path, relative).await {
+            Resolved::File(file, metadata) => (file, metadata),
+            Resolved::Directory(directory) => {
+                return match self.list_directory(&path, &directory).await {
+                    Ok(listing) => {
+                        context.set("Content-Type", "text/html; charset=utf-8");
+                        context.set_body_bytes(Bytes::from(listing));
+                        Ok(context)
+                    }
+                    Err(_) => Err(not_found(context)),
+                };
+            }
+            Resolved::Redirect(location) => {
+                context.status(301);
+                context.set("Location", &location);
+                return Ok(context);
+            }
+            Resolved::NotFound => match self.resolve_fallback().await {
+                Some(fallback) => fallback,
+                None => return Err(not_found(context)),
+            },
+        };
+
+        self.send_file(context, file, metadata).await
+    }
+
+    async fn resolve(&self, path: &str, relative: &str) -> Resolved {
+        let mut file = self.root.clone();
+
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            let segment = match percent_decode_str(segment).decode_utf8() {
+                Ok(segment) => segment,
+                Err(_) => return Resolved::NotFound,
+            };
+
+            if segment == "." {
+                continue;
+            }
+
+            if segment == ".." || segment.contains(['/', '\\', '\0']) {
+                return Resolved::NotFound;
+            }
+
+            file.push(segment.as_ref());
+        }
+
+        let (file, metadata) = match self.canonicalize(&file).await {
+            Some(resolved) => resolved,
+            None => return Resolved::NotFound,
+        };
+
+        if !metadata.is_dir() {
+            return Resolved::File(file, metadata);
+        }
+
+        // Relative links in the index, or the listing, only work from behind a trailing slash
+        if !path.ends_with('/') {
+            return Resolved::Redirect(format!("{}/", path));
+        }
+
+        if let Some(index) = &self.index {
+            if let Some((index, metadata)) = self.canonicalize(&file.join(index)).await {
+                if metadata.is_file() {
+                    return Resolved::File(index, metadata);
+                }
+            }
+        }
+
+        if self.directory_listing {
+            Resolved::Directory(file)
+        } else {
+            Resolved::NotFound
+        }
+    }
+
+    async fn resolve_fallback(&self) -> Option<(PathBuf, Metadata)> {
+        let fallback = self.fallback.as_ref()?;
+
+        self.canonicalize(&self.root.join(fallback))
+            .await
+            .filter(|(_, metadata)| metadata.is_file())
+    }
+
+    ///
+    /// Resolves symlinks in `path`, as long as it exists and stays inside the root.
+    ///
+    async fn canonicalize(&self, path: &Path) -> Option<(PathBuf, Metadata)> {
+        let path = tokio::fs::canonicalize(path).await.ok()?;
+
+        if !path.starts_with(&self.root) {
+            return None;
+        }
+
+        let metadata = tokio::fs::metadata(&path).await.ok()?;
+
+        Some((path, metadata))
+    }
+
+    async fn send_file<T>(
+        &self,
+        mut context: T,
+        file: PathBuf,
+        metadata: Metadata,
+    ) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + HasRequestHeaders + HasBodyStream + Send,
+    {
+        let mut content_type = mime_guess::from_path(&file)
+            .first_or_octet_stream()
+            .to_string();
+        if content_type.starts_with("text/")
+            || content_type == "application/javascript"
+            || content_type == "application/json"
+        {
+            content_type.push_str("; charset=utf-8");
+        }
+
+        let (file, metadata, encoding) = match self.precompressed_variant(&context, &file).await {
+            Some((file, metadata, encoding)) => (file, metadata, Some(encoding)),
+            None => (file, metadata, None),
+        };
+
+        let length = metadata.len();
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        let etag = modified.map(|modified| {
+            format!(
+                "\"{:x}-{:x}{}\"",
+                modified.as_secs(),
+                length,
+                encoding
+                    .map(|encoding| format!("-{}", encoding))
+                    .unwrap_or_default()
+            )
+        });
+        let last_modified = modified.map(|modified| httpdate::fmt_http_date(UNIX_EPOCH + modified));
+
+        context.set("Content-Type", &content_type);
+        context.set("Accept-Ranges", "bytes");
+        if self.precompressed {
+            context.set("Vary", "Accept-Encoding");
+        }
+        if let Some(encoding) = encoding {
+            context.set("Content-Encoding", encoding);
+        }
+        if let Some(cache_control) = &self.cache_control {
+            context.set("Cache-Control", cache_control);
+        }
+        if let Some(etag) = &etag {
+            context.set("ETag", etag);
+        }
+        if let Some(last_modified) = &last_modified {
+            context.set("Last-Modified", last_modified);
+        }
+
+        if is_not_modified(&context, etag.as_deref(), modified.map(|m| m.as_secs())) {
+            context.status(304);
+            return Ok(context);
+        }
+
+        let range = match context.request_header("range").pop() {
+            Some(range)
+                if if_range_matches(&context, etag.as_deref(), last_modified.as_deref()) =>
+            {
+                parse_range(&range, length)
+            }
+            _ => ByteRange::Full,
+        };
+
+        let (start, end) = match range {
+            ByteRange::Full => (0, length),
+            ByteRange::Partial(start, end) => {
+                context.status(206);
+                context.set(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", start, end - 1, length),
+                );
+                (start, end)
+            }
+            ByteRange::Unsatisfiable => {
+                context.status(416);
+                context.set("Content-Range", &format!("bytes */{}", length));
+                return Ok(context);
+            }
+        };
+
+        match read_range(&file, start, end).await {
+            Ok(ReadBody::Bytes(bytes)) => context.set_body_bytes(bytes),
+            Ok(ReadBody::Stream(stream)) => context.set_body_stream(stream),
+            Err(e) => {
+                let message = format!("Could not read file: {}", e);
+                context.status(500);
+                context.set_body(message.as_bytes().to_vec());
+
+                return Err(Error {
+                    context,
+                    message,
+                    status: 500,
+                    cause: Some(Box::new(e)),
+                });
+            }
+        }
+
+        Ok(context)
+    }
+
+    async fn precompressed_variant<T: HasRequestHeaders>(
+        &self,
+        context: &T,
+        file: &Path,
+    ) -> Option<(PathBuf, Metadata, &'static str)> {
+        if !self.precompressed {
+            return None;
+        }
+
+        let accept_encoding = context.request_header("accept-encoding");
+
+        for (encoding, extension) in [("br", "br"), ("gzip", "gz")] {
+            if !accepts_encoding(&accept_encoding, encoding) {
+                continue;
+            }
+
+            let mut variant = OsString::from(file.as_os_str());
+            variant.push(".");
+            variant.push(extension);
+
+            if let Some((variant, metadata)) = self.canonicalize(Path::new(&variant)).await {
+                if metadata.is_file() {
+                    return Some((variant, metadata, encoding));
+                }
+            }
+        }
+
+        None
+    }
+
+    async fn list_directory(&self, path: &str, directory: &Path) -> std::io::Result<String> {
+        let mut entries = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(directory).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_dir = entry.file_type().await?.is_dir();
+
+            entries.push((name, is_dir));
+        }
+        entries.sort();
+
+        let title = escape_html(&percent_decode_str(path).decode_utf8_lossy());
+        let mut listing = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+            title
+        );
+
+        if directory != self.root {
+            listing.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+
+        for (name, is_dir) in entries {
+            let slash = if is_dir { "/" } else { "" };
+            let _ = writeln!(
+                listing,
+                "<li><a href=\"{}{}\">{}{}</a></li>",
+                utf8_percent_encode(&name, HREF),
+                slash,
+                escape_html(&name),
+                slash
+            );
+        }
+
+        listing.push_str("</ul>\n</body>\n</html>\n");
+
+        Ok(listing)
+    }
+}
+
+///
+/// A `404` that reads as one even if no error handler picks it up.
+///
+fn not_found<T: Context>(mut context: T) -> Error<T> {
+    context.status(404);
+    context.set_body(b"Not found".to_vec());
+
+    Error::not_found_error(context)
+}
+
+enum ReadBody {
+    Bytes(Bytes),
+    Stream(BodyStream),
+}
+
+async fn read_range(file: &Path, start: u64, end: u64) -> std::io::Result<ReadBody> {
+    let mut file = tokio::fs::File::open(file).await?;
+    if start > 0 {
+        file.seek(SeekFrom::Start(start)).await?;
+    }
+
+    let length = end - start;
+    if length <= STREAM_THRESHOLD {
+        let mut contents = Vec::with_capacity(length as usize);
+        file.take(length).read_to_end(&mut contents).await?;
+
+        return Ok(ReadBody::Bytes(Bytes::from(contents)));
+    }
+
+    Ok(ReadBody::Stream(BodyStream::new(ReaderStream::new(
+        file.take(length),
+    ))))
+}
+
+///
+/// Whether the client's cached copy is still fresh, going by `If-None-Match`, or by
+/// `If-Modified-Since` when there isn't one.
+///
+fn is_not_modified<T: HasRequestHeaders>(
+    context: &T,
+    etag: Option<&str>,
+    modified: Option<u64>,
+) -> bool {
+    let if_none_match = context.request_header("if-none-match");
+    if !if_none_match.is_empty() {
+        let etag = match etag {
+            Some(etag) => etag,
+            None => return false,
+        };
+
+        return if_none_match
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+
+    match (context.request_header("if-modified-since").pop(), modified) {
+        (Some(since), Some(modified)) => match httpdate::parse_http_date(&since) {
+            Ok(since) => {
+                let since = since
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs());
+
+                since.is_ok_and(|since| modified <= since)
+            }
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+///
+/// Whether a `Range` should be honoured, which it is unless `If-Range` names a different
+/// version of the file than the one being served.
+///
+fn if_range_matches<T: HasRequestHeaders>(
+    context: &T,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> bool {
+    match context.request_header("if-range").pop() {
+        None => true,
+        // Only strong validators can be used to combine ranges
+        Some(if_range) if if_range.starts_with('"') => etag == Some(if_range.as_str()),
+        Some(if_range) => match (
+            httpdate::parse_http_date(&if_range),
+            last_modified.map(httpdate::parse_http_date),
+        ) {
+            (Ok(if_range), Some(Ok(last_modified))) => {
+                if_range == last_modified && if_range < SystemTime::now()
+            }
+            _ => false,
+        },
+    }
+}
+
+///
+/// Parses a `Range` of a single span of bytes into a half-open range. Anything else, like
+/// several ranges or another unit, is ignored and the whole file is sent.
+///
+fn parse_range(range: &str, length: u64) -> ByteRange {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+
+    match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if length == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(length.saturating_sub(suffix), length),
+            Err(_) => ByteRange::Full,
+        },
+        (start, end) => {
+            let start = match start.parse::<u64>() {
+                Ok(start) => start,
+                Err(_) => return ByteRange::Full,
+            };
+            let end = match end {
+                "" => u64::MAX,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return ByteRange::Full,
+                },
+            };
+
+            if start >= length {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(start, end.saturating_add(1).min(length))
+            }
+        }
+    }
+}
+
+fn accepts_encoding(accept_encoding: &[String], encoding: &str) -> bool {
+    let mut accepted = None;
+
+    for value in accept_encoding.iter().flat_map(|value| value.split(',')) {
+        let mut params = value.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(encoding) {
+            return quality > 0.0;
+        }
+
+        if name == "*" {
+            accepted = Some(quality > 0.0);
+        }
+    }
+
+    accepted.unwrap_or(false)
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    lazy_static! {
+        // `public` is served, next to a file outside of it that nothing should be able to reach
+        static ref PUBLIC: PathBuf = {
+            let dir = env::temp_dir().join(format!("thruster-static-files-{}", std::process::id()));
+            let public = dir.join("public");
+            std::fs::create_dir_all(public.join("docs")).unwrap();
+            std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+            std::fs::write(public.join("letters.txt"), "abcdefghij").unwrap();
+            std::fs::write(public.join("docs").join("index.html"), "<h1>docs</h1>").unwrap();
+            #[cfg(unix)]
+            {
+                let _ = std::fs::remove_file(public.join("escape.txt"));
+                std::os::unix::fs::symlink(dir.join("secret.txt"), public.join("escape.txt"))
+                    .unwrap();
+            }
+            public
+        };
+        static ref FILES: StaticFiles = StaticFiles::new("/static", &*PUBLIC);
+    }
+
+    #[middleware_fn(_internal)]
+    async fn files(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        FILES.handle(context, next).await
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.get("/static/*", MiddlewareTuple::A(files));
+        app.commit()
+    }
+
+    async fn get(path: &str, headers: &[(&str, &str)]) -> testing::TestResponse {
+        testing::request(&app(), "GET", path, headers, "").await
+    }
+
+    #[test]
+    fn it_should_parse_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-4", 10), ByteRange::Partial(0, 5));
+        assert_eq!(parse_range(" bytes= 2 - 2 ", 10), ByteRange::Partial(2, 3));
+        assert_eq!(parse_range("bytes=5-", 10), ByteRange::Partial(5, 10));
+        assert_eq!(parse_range("bytes=5-100", 10), ByteRange::Partial(5, 10));
+        assert_eq!(parse_range("bytes=-3", 10), ByteRange::Partial(7, 10));
+        assert_eq!(parse_range("bytes=-30", 10), ByteRange::Partial(0, 10));
+        assert_eq!(
+            parse_range(&format!("bytes=0-{}", u64::MAX), 10),
+            ByteRange::Partial(0, 10)
+        );
+    }
+
+    #[test]
+    fn it_should_find_ranges_past_the_end_unsatisfiable() {
+        assert_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=10-20", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn it_should_ignore_ranges_it_cant_serve() {
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 10), ByteRange::Full);
+        assert_eq!(parse_range("bytes=5-2", 10), ByteRange::Full);
+        assert_eq!(parse_range("bytes=a-2", 10), ByteRange::Full);
+        assert_eq!(parse_range("bytes=-", 10), ByteRange::Full);
+        assert_eq!(parse_range("bytes=3", 10), ByteRange::Full);
+    }
+
+    #[tokio::test]
+    async fn it_should_serve_files_under_the_prefix() {
+        let response = get("/static/letters.txt", &[]).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "abcdefghij");
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.headers.get("Accept-Ranges").unwrap(), "bytes");
+        assert!(response.headers.contains_key("ETag"));
+
+        let response = get("/static/docs", &[]).await;
+        assert_eq!(response.status.1, 301);
+        assert_eq!(response.headers.get("Location").unwrap(), "/static/docs/");
+
+        let response = get("/static/docs/", &[]).await;
+        assert_eq!(response.body, "<h1>docs</h1>");
+    }
+
+    #[tokio::test]
+    async fn it_should_serve_byte_ranges() {
+        let response = get("/static/letters.txt", &[("Range", "bytes=2-4")]).await;
+
+        assert_eq!(response.status.1, 206);
+        assert_eq!(response.body, "cde");
+        assert_eq!(
+            response.headers.get("Content-Range").unwrap(),
+            "bytes 2-4/10"
+        );
+
+        let response = get("/static/letters.txt", &[("Range", "bytes=-2")]).await;
+        assert_eq!(response.body, "ij");
+
+        let response = get("/static/letters.txt", &[("Range", "bytes=0-1,4-5")]).await;
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "abcdefghij");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn it_should_send_ranges_uncompressed() {
+        use crate::middleware::compression::Compression;
+
+        lazy_static! {
+            static ref COMPRESSION: Compression = Compression::new().min_size(0);
+        }
+
+        #[middleware_fn(_internal)]
+        async fn compress(
+            context: BasicContext,
+            next: MiddlewareNext<BasicContext>,
+        ) -> MiddlewareResult<BasicContext> {
+            COMPRESSION.handle(context, next).await
+        }
+
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(compress));
+        app.get("/static/*", MiddlewareTuple::A(files));
+        let app = app.commit();
+
+        // A `HEAD`, so that the compressed body isn't read as text
+        let response = testing::request(
+            &app,
+            "HEAD",
+            "/static/letters.txt",
+            &[("Accept-Encoding", "gzip")],
+            "",
+        )
+        .await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.headers.get("Content-Encoding").unwrap(), "gzip");
+
+        let response = testing::request(
+            &app,
+            "GET",
+            "/static/letters.txt",
+            &[("Accept-Encoding", "gzip"), ("Range", "bytes=2-4")],
+            "",
+        )
+        .await;
+
+        assert_eq!(response.status.1, 206);
+        assert!(!response.headers.contains_key("Content-Encoding"));
+        assert_eq!(
+            response.headers.get("Content-Range").unwrap(),
+            "bytes 2-4/10"
+        );
+        assert_eq!(response.body, "cde");
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_unsatisfiable_ranges_with_a_416() {
+        let response = get("/static/letters.txt", &[("Range", "bytes=10-")]).await;
+
+        assert_eq!(response.status.1, 416);
+        assert_eq!(response.body, "");
+        assert_eq!(response.headers.get("Content-Range").unwrap(), "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn it_should_send_the_whole_file_if_the_range_is_for_another_version() {
+        let etag = get("/static/letters.txt", &[]).await.headers["ETag"].clone();
+
+        let response = get(
+            "/static/letters.txt",
+            &[("Range", "bytes=0-0"), ("If-Range", &etag)],
+        )
+        .await;
+        assert_eq!(response.status.1, 206);
+        assert_eq!(response.body, "a");
+
+        let response = get(
+            "/static/letters.txt",
+            &[("Range", "bytes=0-0"), ("If-Range", "\"stale\"")],
+        )
+        .await;
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "abcdefghij");
+    }
+
+    #[tokio::test]
+    async fn it_should_not_serve_files_outside_of_the_root() {
+        for path in [
+            "/static/../secret.txt",
+            "/static/docs/../../secret.txt",
+            "/static/%2e%2e/secret.txt",
+            "/static/..%2fsecret.txt",
+            "/static/..%5csecret.txt",
+            "/static/letters.txt%00",
+            "/static/%ff",
+        ] {
+            let response = get(path, &[]).await;
+
+            assert_eq!(response.status.1, 404, "{}", path);
+            assert_eq!(response.body, "Not found", "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn it_should_not_follow_symlinks_out_of_the_root() {
+        let response = get("/static/escape.txt", &[]).await;
+
+        assert_eq!(response.status.1, 404);
+        assert_eq!(response.body, "Not found");
+    }
+}
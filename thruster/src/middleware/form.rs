@@ -0,0 +1,213 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use thruster_proc::middleware_fn;
+
+use crate::core::context::Context;
+use crate::core::errors::ThrusterError;
+use crate::core::extractors::{ExtractionError, FromContext, HasRequestBody, HasRequestHeaders};
+use crate::core::response::BodyStream;
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+pub(crate) const URLENCODED: &str = "application/x-www-form-urlencoded";
+
+pub trait HasForm {
+    fn set_form(&mut self, form: HashMap<String, String>);
+}
+
+///
+/// Whether the request's `Content-Type` is the given media type, ignoring any parameters.
+///
+pub(crate) fn has_content_type<T: HasRequestHeaders>(context: &T, media_type: &str) -> bool {
+    context
+        .request_header("content-type")
+        .first()
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|content_type| content_type.trim().eq_ignore_ascii_case(media_type))
+        .unwrap_or(false)
+}
+
+///
+/// Middleware that parses `application/x-www-form-urlencoded` request bodies into a map of
+/// fields, set on the context via `HasForm`. When a field is repeated, the last value wins.
+/// The body can still be read afterwards. Requests with other content types are passed on
+/// untouched.
+///
+#[middleware_fn(_internal)]
+pub async fn form<T: 'static + Context + HasForm + HasRequestBody + HasRequestHeaders + Send>(
+    mut context: T,
+    next: MiddlewareNext<T>,
+) -> MiddlewareResult<T> {
+    if !has_content_type(&context, URLENCODED) {
+        return next(context).await;
+    }
+
+    let body = match context.request_body().await {
+        Ok(body) => body,
+        Err(e) => {
+            return Err(ThrusterError {
+                context,
+                message: format!("Could not read request body: {}", e),
+                status: 400,
+                cause: Some(Box::new(e)),
+            })
+        }
+    };
+
+    match serde_urlencoded::from_bytes(&body) {
+        Ok(form) => {
+            context.set_form(form);
+
+            // Put the body back for the extractors and handlers after this
+            context.set_request_body_stream(BodyStream::new(futures::stream::once(async move {
+                Ok::<Bytes, std::io::Error>(body)
+            })));
+        }
+        Err(e) => {
+            return Err(ThrusterError {
+                context,
+                message: format!("Invalid form body: {}", e),
+                status: 400,
+                cause: Some(Box::new(e)),
+            })
+        }
+    }
+
+    next(context).await
+}
+
+///
+/// Extracts an `application/x-www-form-urlencoded` request body as `T`. Fails with a `415` if
+/// the request has another content type, and a `422` if the form can't be deserialized into `T`.
+///
+#[derive(Debug)]
+pub struct Form<T>(pub T);
+
+#[async_trait]
+impl<C: HasRequestBody + HasRequestHeaders + Send, T: DeserializeOwned> FromContext<C> for Form<T> {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        if !has_content_type(context, URLENCODED) {
+            return Err(ExtractionError::new(
+                415,
+                format!("Expected a body of type {}", URLENCODED),
+            ));
+        }
+
+        let body = context.request_body().await.map_err(|e| {
+            ExtractionError::new(400, format!("Could not read request body: {}", e))
+        })?;
+
+        serde_urlencoded::from_bytes(&body)
+            .map(Form)
+            .map_err(|e| ExtractionError::new(422, format!("Invalid form body: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_derive::Deserialize;
+
+    use super::*;
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    #[derive(Deserialize)]
+    struct Comment {
+        author: String,
+        votes: u32,
+    }
+
+    #[middleware_fn(_internal)]
+    async fn echo_form(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let body = match &context.form {
+            Some(form) => {
+                let mut fields: Vec<String> = form
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
+                fields.sort();
+                fields.join(",")
+            }
+            None => "no form".to_owned(),
+        };
+
+        context.body(&body);
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn comment(
+        mut context: BasicContext,
+        Form(comment): Form<Comment>,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body(&format!("{} ({})", comment.author, comment.votes));
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(form));
+        app.post("/echo", MiddlewareTuple::A(echo_form));
+        app.post("/comments", MiddlewareTuple::A(comment));
+        app.commit()
+    }
+
+    async fn post(route: &str, content_type: &str, body: &str) -> testing::TestResponse {
+        let content_length = body.len().to_string();
+
+        testing::request(
+            &app(),
+            "POST",
+            route,
+            &[
+                ("Content-Type", content_type),
+                ("Content-Length", &content_length),
+            ],
+            body,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn it_should_parse_urlencoded_bodies() {
+        let response = post(
+            "/echo",
+            "application/x-www-form-urlencoded; charset=UTF-8",
+            "name=Jane+Doe&city=S%C3%A3o%20Paulo&name=Janet",
+        )
+        .await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "city=São Paulo,name=Janet");
+    }
+
+    #[tokio::test]
+    async fn it_should_leave_other_bodies_alone() {
+        let response = post("/echo", "application/json", r#"{"name":"Jane"}"#).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "no form");
+    }
+
+    #[tokio::test]
+    async fn it_should_extract_typed_forms() {
+        let response = post("/comments", URLENCODED, "author=Jane&votes=3").await;
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "Jane (3)");
+
+        let response = post("/comments", URLENCODED, "author=Jane&votes=many").await;
+        assert_eq!(response.status.1, 422);
+        assert!(response.body.starts_with("Invalid form body: "));
+
+        let response = post("/comments", "application/json", r#"{"author":"Jane"}"#).await;
+        assert_eq!(response.status.1, 415);
+    }
+}
@@ -1,9 +1,34 @@
+pub mod access_log;
+#[cfg(feature = "auth")]
+pub mod auth;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod conditional;
+#[cfg(feature = "cookie_jar")]
+pub mod cookie_jar;
 pub mod cookies;
 pub mod cors;
+#[cfg(feature = "csrf")]
+pub mod csrf;
 #[cfg(feature = "file")]
 pub mod file;
+pub mod form;
 pub mod json;
+#[cfg(feature = "multipart")]
+pub mod multipart;
 #[cfg(feature = "profiling")]
 pub mod profiling;
 pub mod query_params;
+#[cfg(feature = "rate_limit")]
+pub mod rate_limit;
+#[cfg(feature = "request_id")]
+pub mod request_id;
+#[cfg(feature = "security_headers")]
+pub mod security_headers;
 pub mod send;
+#[cfg(feature = "sessions")]
+pub mod sessions;
+pub mod sse;
+pub mod timeout;
+#[cfg(feature = "websocket")]
+pub mod websocket;
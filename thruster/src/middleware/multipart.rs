@@ -0,0 +1,531 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::io::Write;
+use std::path::PathBuf;
+use tempfile::NamedTempFile;
+
+use crate::core::extractors::{ExtractionError, FromContext, HasRequestBody, HasRequestHeaders};
+use crate::middleware::form::has_content_type;
+
+const MULTIPART: &str = "multipart/form-data";
+
+///
+/// Limits and storage options for parsing `multipart/form-data` request bodies.
+///
+/// ```rust, ignore
+/// let config = MultipartConfig::new()
+///     .max_part_size(50 * 1024 * 1024)
+///     .spill_to_disk_over(1024 * 1024);
+///
+/// let form = config.parse(&mut context)?.collect().await?;
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct MultipartConfig {
+    max_part_size: u64,
+    max_parts: usize,
+    spill_threshold: Option<usize>,
+    temp_dir: Option<PathBuf>,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        MultipartConfig::new()
+    }
+}
+
+impl MultipartConfig {
+    ///
+    /// Creates a config allowing up to 100 parts of up to 10MiB each, all of which are kept in
+    /// memory when collected.
+    ///
+    pub fn new() -> MultipartConfig {
+        MultipartConfig {
+            max_part_size: 10 * 1024 * 1024,
+            max_parts: 100,
+            spill_threshold: None,
+            temp_dir: None,
+        }
+    }
+
+    ///
+    /// Sets the largest a single part can be, in bytes. Bigger parts fail with a `413`.
+    ///
+    pub fn max_part_size(mut self, max_part_size: u64) -> MultipartConfig {
+        self.max_part_size = max_part_size;
+        self
+    }
+
+    ///
+    /// Sets the most parts a body can have. Bodies with more fail with a `413`.
+    ///
+    pub fn max_parts(mut self, max_parts: usize) -> MultipartConfig {
+        self.max_parts = max_parts;
+        self
+    }
+
+    ///
+    /// Writes file parts to a temporary file, rather than keeping them in memory, once they
+    /// grow past `threshold` bytes.
+    ///
+    pub fn spill_to_disk_over(mut self, threshold: usize) -> MultipartConfig {
+        self.spill_threshold = Some(threshold);
+        self
+    }
+
+    ///
+    /// Sets the directory that spilled parts are written to. Defaults to the system's temporary
+    /// directory.
+    ///
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> MultipartConfig {
+        self.temp_dir = Some(temp_dir.into());
+        self
+    }
+
+    ///
+    /// Starts parsing the request body as `multipart/form-data`. Fails with a `415` if the
+    /// request has another content type, or a `400` if it doesn't give a boundary.
+    ///
+    pub fn parse<C: HasRequestBody + HasRequestHeaders>(
+        &self,
+        context: &mut C,
+    ) -> Result<Multipart, ExtractionError> {
+        if !has_content_type(context, MULTIPART) {
+            return Err(ExtractionError::new(
+                415,
+                format!("Expected a body of type {}", MULTIPART),
+            ));
+        }
+
+        let content_type = context.request_header("content-type").remove(0);
+        let boundary = multer::parse_boundary(&content_type).map_err(|e| {
+            ExtractionError::new(400, format!("Invalid multipart content type: {}", e))
+        })?;
+
+        let constraints = multer::Constraints::new()
+            .size_limit(multer::SizeLimit::new().per_field(self.max_part_size));
+
+        Ok(Multipart {
+            inner: multer::Multipart::with_constraints(
+                context.request_body_stream(),
+                boundary,
+                constraints,
+            ),
+            config: self.clone(),
+            parts: 0,
+        })
+    }
+}
+
+///
+/// A `multipart/form-data` body, read one part at a time as it streams in.
+///
+/// As an extractor, it uses the default `MultipartConfig`.
+///
+pub struct Multipart {
+    inner: multer::Multipart<'static>,
+    config: MultipartConfig,
+    parts: usize,
+}
+
+impl Multipart {
+    ///
+    /// Waits for the next part of the body. The previous part must have been dropped or read
+    /// to the end first.
+    ///
+    pub async fn next_part(&mut self) -> Result<Option<Part>, ExtractionError> {
+        let field = match self.inner.next_field().await.map_err(multipart_error)? {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+
+        self.parts += 1;
+        if self.parts > self.config.max_parts {
+            return Err(ExtractionError::new(
+                413,
+                format!(
+                    "Multipart body has more than {} parts",
+                    self.config.max_parts
+                ),
+            ));
+        }
+
+        Ok(Some(Part { inner: field }))
+    }
+
+    ///
+    /// Reads the rest of the body, collecting text fields and file parts. File parts go to
+    /// disk if the config says to spill them.
+    ///
+    pub async fn collect(mut self) -> Result<FormData, ExtractionError> {
+        let mut form_data = FormData::default();
+
+        while let Some(part) = self.next_part().await? {
+            if part.file_name().is_some() {
+                let file = part.into_file(&self.config).await?;
+                form_data.files.push(file);
+            } else {
+                let name = part.name().unwrap_or("").to_owned();
+                let value = part.text().await?;
+                form_data.fields.push((name, value));
+            }
+        }
+
+        Ok(form_data)
+    }
+}
+
+#[async_trait]
+impl<C: HasRequestBody + HasRequestHeaders + Send> FromContext<C> for Multipart {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        MultipartConfig::new().parse(context)
+    }
+}
+
+///
+/// A single part of a multipart body.
+///
+pub struct Part {
+    inner: multer::Field<'static>,
+}
+
+impl Part {
+    pub fn name(&self) -> Option<&str> {
+        self.inner.name()
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        self.inner.file_name()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.inner.content_type().map(|mime| mime.as_ref())
+    }
+
+    ///
+    /// Reads the next chunk of the part, or `None` once it's been read to the end.
+    ///
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, ExtractionError> {
+        self.inner.chunk().await.map_err(multipart_error)
+    }
+
+    ///
+    /// Reads the whole part into memory.
+    ///
+    pub async fn bytes(self) -> Result<Bytes, ExtractionError> {
+        self.inner.bytes().await.map_err(multipart_error)
+    }
+
+    ///
+    /// Reads the whole part into memory as text.
+    ///
+    pub async fn text(self) -> Result<String, ExtractionError> {
+        self.inner.text().await.map_err(multipart_error)
+    }
+
+    async fn into_file(mut self, config: &MultipartConfig) -> Result<FilePart, ExtractionError> {
+        let name = self.name().unwrap_or("").to_owned();
+        let file_name = self.file_name().map(|file_name| file_name.to_owned());
+        let content_type = self
+            .content_type()
+            .map(|content_type| content_type.to_owned());
+
+        let mut size = 0;
+        let mut buffer = Vec::new();
+        let mut temp_file: Option<NamedTempFile> = None;
+
+        while let Some(chunk) = self.chunk().await? {
+            size += chunk.len() as u64;
+
+            if let Some(temp_file) = temp_file.as_mut() {
+                temp_file.write_all(&chunk).map_err(storage_error)?;
+                continue;
+            }
+
+            buffer.extend_from_slice(&chunk);
+
+            if matches!(config.spill_threshold, Some(threshold) if buffer.len() > threshold) {
+                let mut file = match &config.temp_dir {
+                    Some(temp_dir) => NamedTempFile::new_in(temp_dir),
+                    None => NamedTempFile::new(),
+                }
+                .map_err(storage_error)?;
+
+                file.write_all(&buffer).map_err(storage_error)?;
+                buffer = Vec::new();
+                temp_file = Some(file);
+            }
+        }
+
+        let contents = match temp_file {
+            Some(mut file) => {
+                file.flush().map_err(storage_error)?;
+                FileContents::Disk(file)
+            }
+            None => FileContents::Memory(Bytes::from(buffer)),
+        };
+
+        Ok(FilePart {
+            name,
+            file_name,
+            content_type,
+            size,
+            contents,
+        })
+    }
+}
+
+///
+/// The fields and files of a multipart body, in the order they were sent.
+///
+#[derive(Debug, Default)]
+pub struct FormData {
+    pub fields: Vec<(String, String)>,
+    pub files: Vec<FilePart>,
+}
+
+impl FormData {
+    ///
+    /// The first value of the text field called `name`.
+    ///
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    ///
+    /// The first file part called `name`.
+    ///
+    pub fn file(&self, name: &str) -> Option<&FilePart> {
+        self.files.iter().find(|file| file.name == name)
+    }
+}
+
+#[derive(Debug)]
+pub struct FilePart {
+    pub name: String,
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub size: u64,
+    pub contents: FileContents,
+}
+
+///
+/// Where the contents of a file part ended up. Spilled files are deleted when dropped, unless
+/// they're persisted with `NamedTempFile::persist`.
+///
+#[derive(Debug)]
+pub enum FileContents {
+    Memory(Bytes),
+    Disk(NamedTempFile),
+}
+
+fn multipart_error(e: multer::Error) -> ExtractionError {
+    let status = match e {
+        multer::Error::FieldSizeExceeded { .. } | multer::Error::StreamSizeExceeded { .. } => 413,
+        _ => 400,
+    };
+
+    ExtractionError::new(status, format!("Invalid multipart body: {}", e))
+}
+
+fn storage_error(e: std::io::Error) -> ExtractionError {
+    ExtractionError::new(500, format!("Could not store multipart file: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::context::basic_context::{generate_context, BasicContext};
+    use crate::core::request::decode;
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    fn request(content_type: &str, body: &str) -> BasicContext {
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        );
+        let request = decode(&mut BytesMut::from(request.as_str()))
+            .unwrap()
+            .unwrap();
+
+        generate_context(request, &(), "/upload")
+    }
+
+    fn multipart(parts: &[(&str, Option<&str>, &str)]) -> BasicContext {
+        let mut body = String::new();
+        for (name, file_name, contents) in parts {
+            body.push_str(&format!("--{}\r\n", BOUNDARY));
+            match file_name {
+                Some(file_name) => body.push_str(&format!(
+                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: text/plain\r\n",
+                    name, file_name
+                )),
+                None => body.push_str(&format!(
+                    "Content-Disposition: form-data; name=\"{}\"\r\n",
+                    name
+                )),
+            }
+            body.push_str(&format!("\r\n{}\r\n", contents));
+        }
+        body.push_str(&format!("--{}--\r\n", BOUNDARY));
+
+        request(
+            &format!("multipart/form-data; boundary={}", BOUNDARY),
+            &body,
+        )
+    }
+
+    async fn collect(
+        config: MultipartConfig,
+        mut context: BasicContext,
+    ) -> Result<FormData, ExtractionError> {
+        config.parse(&mut context)?.collect().await
+    }
+
+    #[tokio::test]
+    async fn it_should_collect_fields_and_files() {
+        let form = collect(
+            MultipartConfig::new(),
+            multipart(&[
+                ("title", None, "Holiday"),
+                ("photo", Some("beach.txt"), "sand and sea"),
+                ("title", None, "Ignored"),
+            ]),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(form.field("title"), Some("Holiday"));
+        assert_eq!(form.fields.len(), 2);
+
+        let photo = form.file("photo").unwrap();
+        assert_eq!(photo.file_name.as_deref(), Some("beach.txt"));
+        assert_eq!(photo.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(photo.size, 12);
+        assert!(
+            matches!(&photo.contents, FileContents::Memory(contents) if contents == "sand and sea")
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_stream_parts() {
+        let mut context = multipart(&[("a", None, "1"), ("b", Some("b.txt"), "2")]);
+        let mut multipart = MultipartConfig::new().parse(&mut context).unwrap();
+
+        let part = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(part.name(), Some("a"));
+        assert_eq!(part.file_name(), None);
+        assert_eq!(part.text().await.unwrap(), "1");
+
+        let mut part = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(part.file_name(), Some("b.txt"));
+        assert_eq!(part.chunk().await.unwrap().unwrap(), "2");
+        assert!(part.chunk().await.unwrap().is_none());
+        drop(part);
+
+        assert!(multipart.next_part().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn it_should_spill_large_files_to_disk() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let contents = "x".repeat(64);
+
+        let form = collect(
+            MultipartConfig::new()
+                .spill_to_disk_over(16)
+                .temp_dir(temp_dir.path()),
+            multipart(&[
+                ("small", Some("small.txt"), "tiny"),
+                ("large", Some("large.txt"), &contents),
+            ]),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            form.file("small").unwrap().contents,
+            FileContents::Memory(_)
+        ));
+
+        let large = form.file("large").unwrap();
+        assert_eq!(large.size, 64);
+        match &large.contents {
+            FileContents::Disk(file) => {
+                assert!(file.path().starts_with(temp_dir.path()));
+                assert_eq!(std::fs::read_to_string(file.path()).unwrap(), contents);
+            }
+            FileContents::Memory(_) => panic!("the large file should have been spilled"),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_limit_the_size_of_parts() {
+        let error = collect(
+            MultipartConfig::new().max_part_size(8),
+            multipart(&[("ok", None, "short"), ("big", None, "much too long")]),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.status, 413);
+    }
+
+    #[tokio::test]
+    async fn it_should_limit_the_number_of_parts() {
+        let error = collect(
+            MultipartConfig::new().max_parts(2),
+            multipart(&[("a", None, "1"), ("b", None, "2"), ("c", None, "3")]),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.status, 413);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_other_content_types() {
+        let mut context = request("application/json", "{}");
+        assert_eq!(
+            MultipartConfig::new()
+                .parse(&mut context)
+                .err()
+                .unwrap()
+                .status,
+            415
+        );
+
+        let mut context = request("multipart/form-data", "");
+        assert_eq!(
+            MultipartConfig::new()
+                .parse(&mut context)
+                .err()
+                .unwrap()
+                .status,
+            400
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_malformed_bodies() {
+        let error = collect(
+            MultipartConfig::new(),
+            request(
+                &format!("multipart/form-data; boundary={}", BOUNDARY),
+                "--X-BOUNDARY\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nunterminated",
+            ),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.status, 400);
+    }
+}
@@ -1,15 +1,16 @@
 use log::info;
-use proc::middleware_fn;
 use std::time::Instant;
+use thruster_proc::middleware_fn;
 
 use crate::core::context::Context;
-use crate::core::{MiddlewareNext, MiddlewareResult, MiddlewareReturnValue};
+use crate::core::{MiddlewareNext, MiddlewareResult};
 
 ///
-/// Middleware to set the request to respond with JSON type.
+/// Middleware that logs how long the rest of the chain took to run, along with the route.
+/// For a full access log, see `AccessLog`.
 ///
 #[middleware_fn(_internal)]
-pub async fn json<T: 'static + Context + Send>(
+pub async fn profile<T: 'static + Context + Send>(
     mut context: T,
     next: MiddlewareNext<T>,
 ) -> MiddlewareResult<T> {
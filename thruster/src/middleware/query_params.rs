@@ -1,11 +1,272 @@
+use serde::de::{self, DeserializeOwned, Visitor};
 use std::collections::HashMap;
 use thruster_proc::middleware_fn;
 
 use crate::core::context::Context;
+use crate::core::extractors::{ParamDeserializer, ParamsError};
 use crate::core::{MiddlewareNext, MiddlewareResult};
 
 pub trait HasQueryParams {
-    fn set_query_params(&mut self, query_params: HashMap<String, String>);
+    fn set_query_params(&mut self, query_params: QueryParams);
+}
+
+///
+/// The params of a query string, decoded as `application/x-www-form-urlencoded`, i.e. with
+/// `+` as a space and percent-encoded bytes as UTF-8. Keys can be repeated, and keep all of their
+/// values in order. A key without a value, as in `?flag`, has the empty string as its value.
+///
+/// Brackets group values, so `?tag[]=a&tag[]=b` is the same as `?tag=a&tag=b`, and
+/// `?filter[name]=bo` can be deserialized into a nested struct.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryParams {
+    pairs: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    ///
+    /// Parses a query string, with or without its leading `?`.
+    ///
+    pub fn parse(query: &str) -> QueryParams {
+        let query = query.strip_prefix('?').unwrap_or(query);
+
+        QueryParams {
+            pairs: form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .map(|(key, value)| (strip_array_suffix(&key).to_owned(), value))
+                .collect(),
+        }
+    }
+
+    ///
+    /// The first value of `key`.
+    ///
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    ///
+    /// All of the values of `key`, in the order they appear in the query.
+    ///
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.pairs
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.pairs.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    ///
+    /// Every key and value, in the order they appear in the query.
+    ///
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    ///
+    /// Deserializes the params into `T`. Fields that are sequences take every value of their
+    /// key, other fields take the last one.
+    ///
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, impl std::error::Error> {
+        let mut root = QueryNode::default();
+
+        for (key, value) in &self.pairs {
+            root.insert(key, value);
+        }
+
+        T::deserialize(&root)
+    }
+}
+
+impl From<QueryParams> for HashMap<String, String> {
+    /// Keeps the first value of each key.
+    fn from(query_params: QueryParams) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+
+        for (key, value) in query_params.pairs {
+            map.entry(key).or_insert(value);
+        }
+
+        map
+    }
+}
+
+/// `tag[]` and `tag[0]` are both values of `tag`.
+fn strip_array_suffix(key: &str) -> &str {
+    match key.strip_suffix(']').and_then(|key| key.rsplit_once('[')) {
+        Some((key, index)) if index.chars().all(|c| c.is_ascii_digit()) => key,
+        _ => key,
+    }
+}
+
+///
+/// The params as a tree, split on the brackets in their keys, for deserializing nested structs.
+///
+#[derive(Debug, Default)]
+struct QueryNode<'a> {
+    values: Vec<&'a str>,
+    children: Vec<(&'a str, QueryNode<'a>)>,
+}
+
+impl<'a> QueryNode<'a> {
+    fn insert(&mut self, key: &'a str, value: &'a str) {
+        let (head, rest) = match key.find('[') {
+            Some(start) if start > 0 && key.ends_with(']') => {
+                (&key[..start], Some(&key[start + 1..key.len() - 1]))
+            }
+            _ => (key, None),
+        };
+
+        let child = match self.children.iter().position(|(k, _)| *k == head) {
+            Some(index) => &mut self.children[index].1,
+            None => {
+                self.children.push((head, QueryNode::default()));
+                &mut self.children.last_mut().unwrap().1
+            }
+        };
+
+        match rest {
+            // `a[b][c]` leaves `b][c`, which is split on the `][`
+            Some(rest) => child.insert_nested(rest, value),
+            None => child.values.push(value),
+        }
+    }
+
+    fn insert_nested(&mut self, key: &'a str, value: &'a str) {
+        match key.find("][") {
+            Some(end) => {
+                let (head, rest) = (&key[..end], &key[end + 2..]);
+                let child = self.child_mut(head);
+                child.insert_nested(rest, value);
+            }
+            None => self.child_mut(key).values.push(value),
+        }
+    }
+
+    fn child_mut(&mut self, key: &'a str) -> &mut QueryNode<'a> {
+        match self.children.iter().position(|(k, _)| *k == key) {
+            Some(index) => &mut self.children[index].1,
+            None => {
+                self.children.push((key, QueryNode::default()));
+                &mut self.children.last_mut().unwrap().1
+            }
+        }
+    }
+
+    fn last_value(&self) -> Result<ParamDeserializer<'a>, ParamsError> {
+        self.values
+            .last()
+            .map(|value| ParamDeserializer(value))
+            .ok_or_else(|| ParamsError("expected a value, found nested params".to_owned()))
+    }
+}
+
+macro_rules! forward_to_last_value {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                de::Deserializer::$method(self.last_value()?, visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a, 'n> de::Deserializer<'de> for &'n QueryNode<'a> {
+    type Error = ParamsError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.values.len() {
+            0 => self.deserialize_map(visitor),
+            1 => visitor.visit_str(self.values[0]),
+            _ => self.deserialize_seq(visitor),
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.values.is_empty() {
+            // `a[x]=1&a[y]=2` is a sequence of the values of `a`'s children
+            visitor.visit_seq(de::value::SeqDeserializer::new(
+                self.children.iter().map(|(_, child)| child),
+            ))
+        } else {
+            visitor.visit_seq(de::value::SeqDeserializer::new(
+                self.values.iter().map(|value| ParamDeserializer(value)),
+            ))
+        }
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(de::value::MapDeserializer::new(
+            self.children.iter().map(|(key, child)| (*key, child)),
+        ))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_enum(self.last_value()?, name, variants, visitor)
+    }
+
+    forward_to_last_value! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32
+        deserialize_f64 deserialize_char deserialize_str deserialize_string
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 bytes byte_buf unit unit_struct tuple_struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a, 'n> de::IntoDeserializer<'de, ParamsError> for &'n QueryNode<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
 }
 
 #[middleware_fn(_internal)]
@@ -13,29 +274,159 @@ pub async fn query_params<T: 'static + Context + HasQueryParams + Send>(
     mut context: T,
     next: MiddlewareNext<T>,
 ) -> MiddlewareResult<T> {
-    let mut query_param_hash = HashMap::new();
+    let query_params = match context.route().split_once('?') {
+        Some((_, query)) => QueryParams::parse(query),
+        None => QueryParams::default(),
+    };
+
+    context.set_query_params(query_params);
+
+    next(context).await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_derive::Deserialize;
 
-    {
-        let route: &str = &context.route();
+    use super::*;
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
 
-        let mut iter = route.split('?');
-        // Get rid of first bit (the non-query string part)
-        let _ = iter.next().unwrap();
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search {
+        q: String,
+        tag: Vec<String>,
+        page: Option<u32>,
+        filter: Filter,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Filter {
+        author: String,
+        year: Vec<u16>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Order {
+        Newest,
+        Oldest,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Sorted {
+        order: Order,
+        limit: u8,
+    }
 
-        if let Some(query_string) = iter.next() {
-            for query_piece in query_string.split('&') {
-                let mut query_iterator = query_piece.split('=');
-                let key = query_iterator.next().unwrap().to_owned();
+    #[test]
+    fn it_should_percent_and_plus_decode() {
+        let query = QueryParams::parse("?q=caf%C3%A9+au+lait&emoji=%F0%9F%9A%80&plus=%2B");
 
-                match query_iterator.next() {
-                    Some(val) => query_param_hash.insert(key, val.to_owned()),
-                    None => query_param_hash.insert(key, "true".to_owned()),
-                };
+        assert_eq!(query.get("q"), Some("café au lait"));
+        assert_eq!(query.get("emoji"), Some("🚀"));
+        assert_eq!(query.get("plus"), Some("+"));
+        assert_eq!(query.get("missing"), None);
+    }
+
+    #[test]
+    fn it_should_keep_every_value_of_repeated_keys() {
+        let query = QueryParams::parse("tag=a&tag[]=b&tag[2]=c&other=d");
+
+        assert_eq!(query.get("tag"), Some("a"));
+        assert_eq!(query.get_all("tag"), vec!["a", "b", "c"]);
+        assert_eq!(
+            query.iter().collect::<Vec<_>>(),
+            vec![("tag", "a"), ("tag", "b"), ("tag", "c"), ("other", "d")]
+        );
+
+        let map: HashMap<String, String> = query.into();
+        assert_eq!(map["tag"], "a");
+    }
+
+    #[test]
+    fn it_should_give_valueless_keys_an_empty_value() {
+        let query = QueryParams::parse("flag&empty=");
+
+        assert!(query.contains_key("flag"));
+        assert_eq!(query.get("flag"), Some(""));
+        assert_eq!(query.get("empty"), Some(""));
+        assert!(QueryParams::parse("").is_empty());
+        assert!(QueryParams::parse("?").is_empty());
+    }
+
+    #[test]
+    fn it_should_deserialize_nested_and_repeated_params() {
+        let query = QueryParams::parse(
+            "q=rust+web&tag[]=async&tag[]=http&filter[author]=J%C3%BCrgen&filter[year]=2020&filter[year]=2021",
+        );
+
+        assert_eq!(
+            query.deserialize::<Search>().unwrap(),
+            Search {
+                q: "rust web".to_owned(),
+                tag: vec!["async".to_owned(), "http".to_owned()],
+                page: None,
+                filter: Filter {
+                    author: "Jürgen".to_owned(),
+                    year: vec![2020, 2021],
+                },
             }
-        }
+        );
     }
 
-    context.set_query_params(query_param_hash);
+    #[test]
+    fn it_should_deserialize_enums_and_take_the_last_value() {
+        let query = QueryParams::parse("order=oldest&limit=10&limit=20");
 
-    next(context).await
+        assert_eq!(
+            query.deserialize::<Sorted>().unwrap(),
+            Sorted {
+                order: Order::Oldest,
+                limit: 20,
+            }
+        );
+
+        assert!(QueryParams::parse("order=sideways&limit=1")
+            .deserialize::<Sorted>()
+            .is_err());
+        assert!(QueryParams::parse("order=newest&limit=300")
+            .deserialize::<Sorted>()
+            .is_err());
+        assert!(QueryParams::parse("order=newest")
+            .deserialize::<Sorted>()
+            .is_err());
+    }
+
+    #[middleware_fn(_internal)]
+    async fn echo_tags(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let tags = context
+            .query_params
+            .as_ref()
+            .map(|query_params| query_params.get_all("tag").join(","))
+            .unwrap_or_default();
+
+        context.body(&tags);
+        Ok(context)
+    }
+
+    #[tokio::test]
+    async fn it_should_set_the_query_params_on_the_context() {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(query_params));
+        app.get("/posts", MiddlewareTuple::A(echo_tags));
+        let app = app.commit();
+
+        let response = testing::get(&app, "/posts?tag=new+york&tag=%C3%BC").await;
+        assert_eq!(response.body, "new york,ü");
+
+        let response = testing::get(&app, "/posts").await;
+        assert_eq!(response.body, "");
+    }
 }
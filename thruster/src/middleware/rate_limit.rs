@@ -0,0 +1,781 @@
+use async_trait::async_trait;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::core::context::Context;
+use crate::core::errors::ThrusterError;
+use crate::core::extractors::{FromContext, HasClientIp, HasRequestHeaders};
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+///
+/// How requests are counted against a limit.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    ///
+    /// A bucket of `capacity` tokens that refills at a steady rate, all of it over `period`.
+    /// Each request takes a token, so bursts of up to `capacity` are allowed, after which
+    /// requests are let through as tokens come back.
+    ///
+    TokenBucket { capacity: u64, period: Duration },
+    ///
+    /// At most `limit` requests in any `window`, estimated from the counts in the current and
+    /// previous fixed windows, weighted by how much of the previous one is still in view.
+    ///
+    SlidingWindow { limit: u64, window: Duration },
+}
+
+///
+/// What's kept for each key between requests.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LimitState {
+    TokenBucket {
+        tokens: f64,
+        /// When the tokens were counted, in milliseconds since the unix epoch.
+        updated_at: u64,
+    },
+    SlidingWindow {
+        /// The start of the current window, in milliseconds since the unix epoch.
+        window_start: u64,
+        count: u64,
+        previous: u64,
+    },
+}
+
+struct Decision {
+    allowed: bool,
+    limit: u64,
+    remaining: u64,
+    reset: Duration,
+    retry_after: Duration,
+}
+
+impl Algorithm {
+    fn period(&self) -> Duration {
+        match self {
+            Algorithm::TokenBucket { period, .. } => *period,
+            Algorithm::SlidingWindow { window, .. } => *window,
+        }
+    }
+
+    fn apply(&self, current: Option<LimitState>, now: u64) -> (LimitState, Decision) {
+        let period = (self.period().as_millis() as u64).max(1);
+
+        match *self {
+            Algorithm::TokenBucket { capacity, .. } => {
+                let capacity = capacity as f64;
+                let rate = capacity / period as f64;
+
+                let (tokens, updated_at) = match current {
+                    Some(LimitState::TokenBucket { tokens, updated_at }) => (tokens, updated_at),
+                    _ => (capacity, now),
+                };
+
+                let mut tokens =
+                    (tokens + now.saturating_sub(updated_at) as f64 * rate).min(capacity);
+                let allowed = tokens >= 1.0;
+                if allowed {
+                    tokens -= 1.0;
+                }
+
+                let millis = |tokens: f64| {
+                    if rate > 0.0 {
+                        Duration::from_millis((tokens / rate).ceil() as u64)
+                    } else {
+                        Duration::from_millis(period)
+                    }
+                };
+
+                let decision = Decision {
+                    allowed,
+                    limit: capacity as u64,
+                    remaining: tokens.floor() as u64,
+                    reset: millis(capacity - tokens),
+                    retry_after: millis((1.0 - tokens).max(0.0)),
+                };
+
+                (
+                    LimitState::TokenBucket {
+                        tokens,
+                        updated_at: now,
+                    },
+                    decision,
+                )
+            }
+            Algorithm::SlidingWindow { limit, .. } => {
+                let window_start = now - now % period;
+
+                let (mut count, previous) = match current {
+                    Some(LimitState::SlidingWindow {
+                        window_start: start,
+                        count,
+                        previous,
+                    }) if start == window_start => (count, previous),
+                    Some(LimitState::SlidingWindow {
+                        window_start: start,
+                        count,
+                        ..
+                    }) if start + period == window_start => (0, count),
+                    _ => (0, 0),
+                };
+
+                let elapsed = now - window_start;
+                let remaining_in_window = period - elapsed;
+                let estimate =
+                    previous as f64 * remaining_in_window as f64 / period as f64 + count as f64;
+
+                let allowed = estimate + 1.0 <= limit as f64;
+                if allowed {
+                    count += 1;
+                }
+
+                let used = estimate + if allowed { 1.0 } else { 0.0 };
+                let under_limit = limit.saturating_sub(1) as f64;
+
+                // The previous window's count fades out over the current one, and the current
+                // one's over the next, until the estimate leaves room for one more request
+                let retry_after = if allowed {
+                    0
+                } else if limit == 0 {
+                    remaining_in_window
+                } else if (count as f64) <= under_limit && previous > 0 {
+                    let fade_to = (under_limit - count as f64) / previous as f64;
+                    ((1.0 - fade_to) * period as f64).ceil() as u64 - elapsed
+                } else {
+                    let fade_to = under_limit / count as f64;
+                    remaining_in_window + ((1.0 - fade_to) * period as f64).ceil() as u64
+                };
+
+                // Nothing counted weighs on the estimate once the next window is over
+                let reset = match (count, previous) {
+                    (0, 0) => 0,
+                    (0, _) => remaining_in_window,
+                    _ => remaining_in_window + period,
+                };
+
+                let decision = Decision {
+                    allowed,
+                    limit,
+                    remaining: (limit as f64 - used).floor().max(0.0) as u64,
+                    reset: Duration::from_millis(reset),
+                    retry_after: Duration::from_millis(retry_after),
+                };
+
+                (
+                    LimitState::SlidingWindow {
+                        window_start,
+                        count,
+                        previous,
+                    },
+                    decision,
+                )
+            }
+        }
+    }
+}
+
+///
+/// Where the state of each limit is kept. A store shared between processes lets them enforce
+/// one limit together.
+///
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    ///
+    /// Replaces the state kept for `key` with what `update` returns when given the current
+    /// state, or `None` if there isn't any. This has to be atomic, so that concurrent requests
+    /// aren't counted against the same state, but `update` can be called more than once. State
+    /// that hasn't been updated for `ttl` can be forgotten.
+    ///
+    async fn update(
+        &self,
+        key: &str,
+        ttl: Duration,
+        update: &(dyn Fn(Option<LimitState>) -> LimitState + Send + Sync),
+    ) -> io::Result<LimitState>;
+}
+
+// How many updates go by between sweeps for expired keys
+const PURGE_INTERVAL: usize = 4096;
+
+///
+/// Keeps limits in memory, so they're lost when the process exits and aren't shared between
+/// processes. Keys that have expired are swept out every so often.
+///
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: DashMap<String, (LimitState, u64)>,
+    updates: AtomicUsize,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    ///
+    /// Removes the state of keys that haven't been seen for their `ttl`.
+    ///
+    pub fn purge_expired(&self) {
+        let now = now();
+        self.entries.retain(|_, (_, expires_at)| *expires_at > now);
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn update(
+        &self,
+        key: &str,
+        ttl: Duration,
+        update: &(dyn Fn(Option<LimitState>) -> LimitState + Send + Sync),
+    ) -> io::Result<LimitState> {
+        let now = now();
+        let expires_at = now + ttl.as_millis() as u64;
+
+        let state = match self.entries.entry(key.to_owned()) {
+            Entry::Occupied(mut entry) => {
+                let (current, current_expires_at) = *entry.get();
+                let state = update(Some(current).filter(|_| current_expires_at > now));
+                entry.insert((state, expires_at));
+                state
+            }
+            Entry::Vacant(entry) => {
+                let state = update(None);
+                entry.insert((state, expires_at));
+                state
+            }
+        };
+
+        if self.updates.fetch_add(1, Ordering::Relaxed) % PURGE_INTERVAL == PURGE_INTERVAL - 1 {
+            self.purge_expired();
+        }
+
+        Ok(state)
+    }
+}
+
+type CustomKey<C> = Box<dyn Fn(&C) -> Option<String> + Send + Sync>;
+type ExtractorKey<C> =
+    Box<dyn for<'a> Fn(&'a mut C) -> BoxFuture<'a, Option<String>> + Send + Sync>;
+
+enum KeyBy<C> {
+    Ip,
+    Header(String),
+    Custom(CustomKey<C>),
+    Extractor(ExtractorKey<C>),
+}
+
+///
+/// Middleware that limits how often each client can make requests, answering with a
+/// `429 Too Many Requests` and a `Retry-After` once they go over. Clients are told where they
+/// stand with `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers.
+///
+/// Clients are told apart by their IP address by default, with IPv6 addresses grouped by their
+/// `/64`, since that's what a single client is usually given. Requests that don't have a key,
+/// like ones without the header that's being keyed by, share a single limit. If the store
+/// fails, requests are let through rather than turned away.
+///
+/// Different routes can have different limits by using different middleware for them.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref API_LIMIT: RateLimit<Ctx, MemoryStore> = RateLimit::new(
+///         MemoryStore::new(),
+///         Algorithm::TokenBucket { capacity: 100, period: Duration::from_secs(60) },
+///     );
+///     static ref LOGIN_LIMIT: RateLimit<Ctx, MemoryStore> = RateLimit::new(
+///         MemoryStore::new(),
+///         Algorithm::SlidingWindow { limit: 5, window: Duration::from_secs(60) },
+///     );
+/// }
+///
+/// #[middleware_fn]
+/// async fn api_limit(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     API_LIMIT.handle(context, next).await
+/// }
+///
+/// #[middleware_fn]
+/// async fn login_limit(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     LOGIN_LIMIT.handle(context, next).await
+/// }
+///
+/// app.use_middleware("/api", m![api_limit]);
+/// app.use_middleware("/login", m![login_limit]);
+/// ```
+///
+pub struct RateLimit<C, S: RateLimitStore> {
+    store: S,
+    algorithm: Algorithm,
+    key: KeyBy<C>,
+    scope: String,
+    headers: bool,
+}
+
+impl<C, S: RateLimitStore> fmt::Debug for RateLimit<C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("algorithm", &self.algorithm)
+            .field("scope", &self.scope)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+impl<C: 'static + Context + HasRequestHeaders + HasClientIp + Send, S: RateLimitStore>
+    RateLimit<C, S>
+{
+    ///
+    /// Creates a limit kept in `store`, keyed by the client's IP address.
+    ///
+    pub fn new(store: S, algorithm: Algorithm) -> RateLimit<C, S> {
+        RateLimit {
+            store,
+            algorithm,
+            key: KeyBy::Ip,
+            scope: "rate-limit".to_owned(),
+            headers: true,
+        }
+    }
+
+    ///
+    /// Keys clients by the value of a request header, like an API key.
+    ///
+    pub fn key_by_header(mut self, header: &str) -> RateLimit<C, S> {
+        self.key = KeyBy::Header(header.to_owned());
+        self
+    }
+
+    ///
+    /// Keys clients by whatever `key` returns for a request.
+    ///
+    pub fn key_by(
+        mut self,
+        key: impl Fn(&C) -> Option<String> + Send + Sync + 'static,
+    ) -> RateLimit<C, S> {
+        self.key = KeyBy::Custom(Box::new(key));
+        self
+    }
+
+    ///
+    /// Keys clients by an extractor, such as the authenticated user. Requests the extractor
+    /// fails for share a single limit.
+    ///
+    pub fn key_by_extractor<E>(mut self) -> RateLimit<C, S>
+    where
+        E: FromContext<C> + fmt::Display + 'static,
+    {
+        self.key = KeyBy::Extractor(Box::new(|context: &mut C| {
+            Box::pin(async move {
+                E::from_context(context)
+                    .await
+                    .ok()
+                    .map(|key| key.to_string())
+            })
+        }));
+        self
+    }
+
+    ///
+    /// Sets the prefix for this limit's keys in the store, so that limits sharing a store don't
+    /// count against each other. Defaults to `rate-limit`.
+    ///
+    pub fn scope(mut self, scope: &str) -> RateLimit<C, S> {
+        self.scope = scope.to_owned();
+        self
+    }
+
+    ///
+    /// Sets whether the `RateLimit-*` headers are sent. They're on by default, and
+    /// `Retry-After` is always sent with a `429`.
+    ///
+    pub fn headers(mut self, headers: bool) -> RateLimit<C, S> {
+        self.headers = headers;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub async fn handle(&self, mut context: C, next: MiddlewareNext<C>) -> MiddlewareResult<C> {
+        let key = match &self.key {
+            KeyBy::Ip => context.client_ip().map(client_key),
+            KeyBy::Header(header) => context.request_header(header).pop(),
+            KeyBy::Custom(key) => key(&context),
+            KeyBy::Extractor(key) => key(&mut context).await,
+        };
+        let key = format!("{}:{}", self.scope, key.unwrap_or_default());
+
+        let now = now();
+        let algorithm = self.algorithm;
+        let decision = Mutex::new(None);
+        let result = self
+            .store
+            .update(&key, algorithm.period(), &|current| {
+                let (state, outcome) = algorithm.apply(current, now);
+                *decision.lock().unwrap() = Some(outcome);
+                state
+            })
+            .await;
+
+        let decision = match (result, decision.into_inner().unwrap()) {
+            (Ok(_), Some(decision)) => decision,
+            (Err(e), _) => {
+                warn!(
+                    "Rate limit store failed, letting the request through: {}",
+                    e
+                );
+                return next(context).await;
+            }
+            (Ok(_), None) => return next(context).await,
+        };
+
+        if self.headers {
+            let headers = [
+                ("RateLimit-Limit", decision.limit),
+                ("RateLimit-Remaining", decision.remaining),
+                ("RateLimit-Reset", seconds(decision.reset)),
+            ];
+
+            // An inner limit replaces the headers of an outer one
+            for (header, value) in headers.iter() {
+                context.remove(header);
+                context.set(header, &value.to_string());
+            }
+        }
+
+        if decision.allowed {
+            return next(context).await;
+        }
+
+        let message = "Too many requests".to_owned();
+        context.status(429);
+        context.set(
+            "Retry-After",
+            &seconds(decision.retry_after).max(1).to_string(),
+        );
+        context.set_body(message.as_bytes().to_vec());
+
+        Err(ThrusterError {
+            context,
+            message,
+            status: 429,
+            cause: None,
+        })
+    }
+}
+
+fn client_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.to_string(),
+            None => {
+                let segments = ip.segments();
+
+                format!(
+                    "{:x}:{:x}:{:x}:{:x}::/64",
+                    segments[0], segments[1], segments[2], segments[3]
+                )
+            }
+        },
+    }
+}
+
+fn seconds(duration: Duration) -> u64 {
+    let seconds = duration.as_secs();
+
+    if duration.subsec_nanos() > 0 {
+        seconds + 1
+    } else {
+        seconds
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    struct FailingStore;
+
+    #[async_trait]
+    impl RateLimitStore for FailingStore {
+        async fn update(
+            &self,
+            _key: &str,
+            _ttl: Duration,
+            _update: &(dyn Fn(Option<LimitState>) -> LimitState + Send + Sync),
+        ) -> io::Result<LimitState> {
+            Err(io::Error::other("store is down"))
+        }
+    }
+
+    lazy_static! {
+        static ref LIMIT: RateLimit<BasicContext, MemoryStore> = RateLimit::new(
+            MemoryStore::new(),
+            Algorithm::TokenBucket {
+                capacity: 2,
+                period: Duration::from_secs(60),
+            },
+        )
+        .key_by_header("x-api-key");
+        static ref QUIET_LIMIT: RateLimit<BasicContext, MemoryStore> = RateLimit::new(
+            MemoryStore::new(),
+            Algorithm::SlidingWindow {
+                limit: 1,
+                window: Duration::from_secs(60),
+            },
+        )
+        .key_by_header("x-api-key")
+        .headers(false);
+        static ref FAILING_LIMIT: RateLimit<BasicContext, FailingStore> = RateLimit::new(
+            FailingStore,
+            Algorithm::TokenBucket {
+                capacity: 0,
+                period: Duration::from_secs(60),
+            },
+        );
+    }
+
+    #[middleware_fn(_internal)]
+    async fn limit(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        LIMIT.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn quiet_limit(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        QUIET_LIMIT.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn failing_limit(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        FAILING_LIMIT.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn hello(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body("hello");
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/limited", MiddlewareTuple::A(limit));
+        app.use_middleware("/quiet", MiddlewareTuple::A(quiet_limit));
+        app.use_middleware("/failing", MiddlewareTuple::A(failing_limit));
+        app.get("/limited", MiddlewareTuple::A(hello));
+        app.get("/quiet", MiddlewareTuple::A(hello));
+        app.get("/failing", MiddlewareTuple::A(hello));
+        app.commit()
+    }
+
+    async fn get(
+        app: &App<Request, BasicContext, ()>,
+        path: &str,
+        key: &str,
+    ) -> testing::TestResponse {
+        testing::request(app, "GET", path, &[("X-Api-Key", key)], "").await
+    }
+
+    // Applies the algorithm at `now`, keeping its state for the next call
+    fn apply(algorithm: &Algorithm, state: &mut Option<LimitState>, now: u64) -> (bool, u64, u64) {
+        let (next, decision) = algorithm.apply(*state, now);
+        *state = Some(next);
+
+        (
+            decision.allowed,
+            decision.remaining,
+            decision.retry_after.as_millis() as u64,
+        )
+    }
+
+    #[test]
+    fn it_should_allow_bursts_up_to_the_capacity_of_a_token_bucket() {
+        let algorithm = Algorithm::TokenBucket {
+            capacity: 3,
+            period: Duration::from_secs(3),
+        };
+        let mut state = None;
+
+        assert_eq!(apply(&algorithm, &mut state, 0), (true, 2, 0));
+        assert_eq!(apply(&algorithm, &mut state, 0), (true, 1, 0));
+        assert_eq!(apply(&algorithm, &mut state, 0), (true, 0, 1000));
+        assert_eq!(apply(&algorithm, &mut state, 0), (false, 0, 1000));
+        assert_eq!(apply(&algorithm, &mut state, 500), (false, 0, 500));
+
+        // Tokens come back at a steady rate, but never past the capacity
+        assert_eq!(apply(&algorithm, &mut state, 1000), (true, 0, 1000));
+        assert_eq!(apply(&algorithm, &mut state, 60_000), (true, 2, 0));
+    }
+
+    #[test]
+    fn it_should_weigh_the_previous_window_of_a_sliding_window() {
+        let algorithm = Algorithm::SlidingWindow {
+            limit: 4,
+            window: Duration::from_secs(1),
+        };
+        let mut state = None;
+
+        for remaining in (0..4).rev() {
+            assert_eq!(apply(&algorithm, &mut state, 1000), (true, remaining, 0));
+        }
+        assert!(!apply(&algorithm, &mut state, 1999).0);
+
+        // Halfway through the next window, half of the previous one's requests still count
+        assert!(apply(&algorithm, &mut state, 2500).0);
+        assert!(apply(&algorithm, &mut state, 2500).0);
+        assert_eq!(apply(&algorithm, &mut state, 2500), (false, 0, 250));
+        assert!(apply(&algorithm, &mut state, 2750).0);
+
+        // Windows from before the previous one don't count at all
+        assert_eq!(apply(&algorithm, &mut state, 10_000), (true, 3, 0));
+    }
+
+    #[test]
+    fn it_should_never_allow_requests_with_a_limit_of_zero() {
+        let algorithm = Algorithm::SlidingWindow {
+            limit: 0,
+            window: Duration::from_secs(1),
+        };
+        let mut state = None;
+
+        assert_eq!(apply(&algorithm, &mut state, 1200), (false, 0, 800));
+    }
+
+    #[test]
+    fn it_should_key_ipv6_clients_by_their_prefix() {
+        assert_eq!(client_key("203.0.113.7".parse().unwrap()), "203.0.113.7");
+        assert_eq!(
+            client_key("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::/64"
+        );
+        assert_eq!(
+            client_key("2001:db8:1:2:ffff::1".parse().unwrap()),
+            "2001:db8:1:2::/64"
+        );
+        assert_eq!(
+            client_key("::ffff:203.0.113.7".parse().unwrap()),
+            "203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn it_should_round_seconds_up() {
+        assert_eq!(seconds(Duration::from_millis(0)), 0);
+        assert_eq!(seconds(Duration::from_millis(1)), 1);
+        assert_eq!(seconds(Duration::from_millis(1000)), 1);
+        assert_eq!(seconds(Duration::from_millis(1001)), 2);
+    }
+
+    #[tokio::test]
+    async fn it_should_forget_state_once_it_expires() {
+        let store = MemoryStore::new();
+        let state = LimitState::SlidingWindow {
+            window_start: 0,
+            count: 1,
+            previous: 0,
+        };
+
+        store
+            .update("a", Duration::from_millis(0), &|_| state)
+            .await
+            .unwrap();
+        let seen = Mutex::new(None);
+        store
+            .update("a", Duration::from_secs(60), &|current| {
+                *seen.lock().unwrap() = Some(current);
+                state
+            })
+            .await
+            .unwrap();
+        assert_eq!(seen.into_inner().unwrap(), Some(None));
+
+        store
+            .update("b", Duration::from_millis(0), &|_| state)
+            .await
+            .unwrap();
+        store.purge_expired();
+        assert!(store.entries.contains_key("a"));
+        assert!(!store.entries.contains_key("b"));
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_clients_over_their_limit_with_a_429() {
+        let app = app();
+
+        let response = get(&app, "/limited", "alice").await;
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.headers["RateLimit-Limit"], "2");
+        assert_eq!(response.headers["RateLimit-Remaining"], "1");
+        assert_eq!(response.headers["RateLimit-Reset"], "30");
+
+        assert_eq!(get(&app, "/limited", "alice").await.status.1, 200);
+
+        let response = get(&app, "/limited", "alice").await;
+        assert_eq!(response.status.1, 429);
+        assert_eq!(response.body, "Too many requests");
+        assert_eq!(response.headers["RateLimit-Remaining"], "0");
+        assert_eq!(response.headers["Retry-After"], "30");
+
+        // Every key has a limit of its own
+        assert_eq!(get(&app, "/limited", "bob").await.status.1, 200);
+    }
+
+    #[tokio::test]
+    async fn it_should_leave_out_the_headers_when_asked_to() {
+        let app = app();
+
+        let response = get(&app, "/quiet", "carol").await;
+        assert_eq!(response.status.1, 200);
+        assert!(!response.headers.contains_key("RateLimit-Limit"));
+
+        let response = get(&app, "/quiet", "carol").await;
+        assert_eq!(response.status.1, 429);
+        assert!(!response.headers.contains_key("RateLimit-Remaining"));
+        assert!(response.headers.contains_key("Retry-After"));
+    }
+
+    #[tokio::test]
+    async fn it_should_let_requests_through_when_the_store_fails() {
+        let response = testing::get(&app(), "/failing").await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "hello");
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+
+use crate::core::context::Context;
+use crate::core::extractors::{HasRequestHeaders, HasRequestId};
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+///
+/// The ID of the request that's being handled, for use where there's no context at hand, like a
+/// logger's format function. It's only set in the task running the request, so work that's been
+/// spawned onto other tasks has to be handed the ID itself.
+///
+/// ```rust, ignore
+/// env_logger::Builder::new()
+///     .format(|buf, record| {
+///         let id = request_id::current().unwrap_or_else(|| "-".to_owned());
+///         writeln!(buf, "{} [{}] {}", record.level(), id, record.args())
+///     })
+///     .init();
+/// ```
+///
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+type Generator = Box<dyn Fn() -> String + Send + Sync>;
+
+///
+/// Middleware that gives every request an ID, so that it can be followed through logs and
+/// across services. The ID is taken from the request's `X-Request-Id` header, or a new UUID is
+/// made if there isn't one, and it's sent back in the same header on the response. While the
+/// rest of the chain runs, it's available from the context through `HasRequestId` and from
+/// `request_id::current()`.
+///
+/// Incoming IDs that are longer than 128 characters, or have anything other than visible ASCII
+/// in them, are replaced, so that they can be logged as they are.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref REQUEST_ID: RequestId = RequestId::new();
+/// }
+///
+/// #[middleware_fn]
+/// async fn request_id(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     REQUEST_ID.handle(context, next).await
+/// }
+///
+/// app.use_middleware("/", m![request_id]);
+/// ```
+///
+pub struct RequestId {
+    header: String,
+    trust_incoming: bool,
+    generator: Option<Generator>,
+}
+
+impl fmt::Debug for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestId")
+            .field("header", &self.header)
+            .field("trust_incoming", &self.trust_incoming)
+            .field("generator", &self.generator.is_some())
+            .finish()
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> RequestId {
+        RequestId::new()
+    }
+}
+
+impl RequestId {
+    pub fn new() -> RequestId {
+        RequestId {
+            header: "X-Request-Id".to_owned(),
+            trust_incoming: true,
+            generator: None,
+        }
+    }
+
+    ///
+    /// Sets the header that IDs are read from and sent back in.
+    ///
+    pub fn header(mut self, header: &str) -> RequestId {
+        self.header = header.to_owned();
+        self
+    }
+
+    ///
+    /// Sets whether IDs sent by clients are used. Turn this off for servers that face the
+    /// internet directly, where the ID isn't coming from a service upstream.
+    ///
+    pub fn trust_incoming(mut self, trust_incoming: bool) -> RequestId {
+        self.trust_incoming = trust_incoming;
+        self
+    }
+
+    ///
+    /// Makes new IDs with `generator` instead of as random UUIDs.
+    ///
+    pub fn generator(
+        mut self,
+        generator: impl Fn() -> String + Send + Sync + 'static,
+    ) -> RequestId {
+        self.generator = Some(Box::new(generator));
+        self
+    }
+
+    pub async fn handle<T>(&self, mut context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + HasRequestHeaders + HasRequestId + Send,
+    {
+        let incoming = if self.trust_incoming {
+            context
+                .request_header(&self.header)
+                .pop()
+                .filter(|id| valid(id))
+        } else {
+            None
+        };
+
+        let id = incoming.unwrap_or_else(|| match &self.generator {
+            Some(generator) => generator(),
+            None => uuid(),
+        });
+
+        context.set_request_id(id.clone());
+        let mut result = REQUEST_ID.scope(id.clone(), next(context)).await;
+
+        // The context that comes back isn't always the one that went down the chain
+        let context = match &mut result {
+            Ok(context) => context,
+            Err(e) => &mut e.context,
+        };
+        context.set_request_id(id.clone());
+        context.remove(&self.header);
+        context.set(&self.header, &id);
+
+        result
+    }
+}
+
+fn valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn uuid() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::errors::ThrusterError;
+    use crate::core::request::Request;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    lazy_static! {
+        static ref REQUEST_ID: RequestId = RequestId::new();
+        static ref EDGE_REQUEST_ID: RequestId = RequestId::new()
+            .header("X-Trace-Id")
+            .trust_incoming(false)
+            .generator(|| "generated".to_owned());
+    }
+
+    #[middleware_fn(_internal)]
+    async fn request_id(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        REQUEST_ID.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn edge_request_id(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        EDGE_REQUEST_ID.handle(context, next).await
+    }
+
+    // Answers with the ID as the context and the task see it
+    #[middleware_fn(_internal)]
+    async fn echo_id(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let body = format!(
+            "{} {}",
+            context.request_id().unwrap_or("-"),
+            current().unwrap_or_else(|| "-".to_owned())
+        );
+        context.body(&body);
+        Ok(context)
+    }
+
+    // Fails with a context other than the one it was given
+    #[middleware_fn(_internal)]
+    async fn broken(
+        _context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let mut context = BasicContext::new();
+        context.status(500);
+
+        Err(ThrusterError {
+            context,
+            message: "broken".to_owned(),
+            status: 500,
+            cause: None,
+        })
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(request_id));
+        app.use_middleware("/edge", MiddlewareTuple::A(edge_request_id));
+        app.get("/", MiddlewareTuple::A(echo_id));
+        app.get("/broken", MiddlewareTuple::A(broken));
+        app.get("/edge", MiddlewareTuple::A(echo_id));
+        app.commit()
+    }
+
+    async fn get(path: &str, headers: &[(&str, &str)]) -> testing::TestResponse {
+        testing::request(&app(), "GET", path, headers, "").await
+    }
+
+    #[test]
+    fn it_should_make_version_4_uuids() {
+        let id = uuid();
+        let pieces: Vec<&str> = id.split('-').collect();
+
+        assert_eq!(
+            pieces.iter().map(|piece| piece.len()).collect::<Vec<_>>(),
+            vec![8, 4, 4, 4, 12]
+        );
+        assert!(pieces[2].starts_with('4'));
+        assert!(matches!(
+            pieces[3].chars().next(),
+            Some('8'..='9' | 'a'..='b')
+        ));
+        assert_ne!(uuid(), id);
+    }
+
+    #[tokio::test]
+    async fn it_should_give_requests_a_new_id() {
+        let response = get("/", &[]).await;
+        let id = response.headers["X-Request-Id"].clone();
+
+        assert_eq!(id.len(), 36);
+        assert_eq!(response.body, format!("{} {}", id, id));
+        assert_eq!(current(), None);
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_the_id_clients_send() {
+        let response = get("/", &[("X-Request-Id", "upstream-1")]).await;
+
+        assert_eq!(response.headers["X-Request-Id"], "upstream-1");
+        assert_eq!(response.body, "upstream-1 upstream-1");
+    }
+
+    #[tokio::test]
+    async fn it_should_replace_ids_that_cant_be_logged_as_they_are() {
+        for id in ["has space", &"a".repeat(129), "caf\u{e9}"] {
+            let response = get("/", &[("X-Request-Id", id)]).await;
+
+            assert_ne!(response.headers["X-Request-Id"], id);
+            assert_eq!(response.headers["X-Request-Id"].len(), 36);
+        }
+
+        let id = "a".repeat(128);
+        let response = get("/", &[("X-Request-Id", &id)]).await;
+        assert_eq!(response.headers["X-Request-Id"], id);
+    }
+
+    #[tokio::test]
+    async fn it_should_send_the_id_back_on_errors() {
+        let response = get("/broken", &[("X-Request-Id", "upstream-2")]).await;
+
+        assert_eq!(response.status.1, 500);
+        assert_eq!(response.headers["X-Request-Id"], "upstream-2");
+    }
+
+    #[tokio::test]
+    async fn it_should_ignore_incoming_ids_unless_they_are_trusted() {
+        let response = get("/edge", &[("X-Trace-Id", "spoofed")]).await;
+
+        assert_eq!(response.headers["X-Trace-Id"], "generated");
+        assert_eq!(response.body, "generated generated");
+    }
+}
@@ -0,0 +1,572 @@
+use rand::RngCore;
+
+use crate::core::context::Context;
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+///
+/// A context that holds the nonce of the request's `Content-Security-Policy`, for handlers to put
+/// on the inline scripts and styles they render.
+///
+pub trait HasCspNonce {
+    fn csp_nonce(&self) -> Option<&str>;
+
+    fn set_csp_nonce(&mut self, nonce: String);
+}
+
+///
+/// A `Content-Security-Policy`, built up from its directives. Directives that are given a nonce
+/// have a new one added to their sources on every request.
+///
+/// ```rust, ignore
+/// let csp = ContentSecurityPolicy::default()
+///     .directive("img-src", "'self' https://images.example.com")
+///     .directive("report-uri", "/csp-reports");
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct ContentSecurityPolicy {
+    directives: Vec<(String, String)>,
+    nonce_directives: Vec<String>,
+    report_only: bool,
+}
+
+impl Default for ContentSecurityPolicy {
+    ///
+    /// A policy that only allows resources from the site itself, except for images, fonts and
+    /// styles, which can also be inlined as data or come from any HTTPS source. Scripts are
+    /// given a nonce.
+    ///
+    fn default() -> ContentSecurityPolicy {
+        ContentSecurityPolicy::new()
+            .directive("default-src", "'self'")
+            .directive("base-uri", "'self'")
+            .directive("font-src", "'self' https: data:")
+            .directive("form-action", "'self'")
+            .directive("frame-ancestors", "'self'")
+            .directive("img-src", "'self' data:")
+            .directive("object-src", "'none'")
+            .directive("script-src", "'self'")
+            .directive("script-src-attr", "'none'")
+            .directive("style-src", "'self' https: 'unsafe-inline'")
+            .directive("upgrade-insecure-requests", "")
+            .nonce("script-src")
+    }
+}
+
+impl ContentSecurityPolicy {
+    ///
+    /// An empty policy, which allows everything.
+    ///
+    pub fn new() -> ContentSecurityPolicy {
+        ContentSecurityPolicy {
+            directives: Vec::new(),
+            nonce_directives: Vec::new(),
+            report_only: false,
+        }
+    }
+
+    ///
+    /// Sets a directive, replacing any sources it already had. Directives without sources, like
+    /// `upgrade-insecure-requests`, take an empty string.
+    ///
+    pub fn directive(mut self, name: &str, sources: &str) -> ContentSecurityPolicy {
+        match self.directives.iter_mut().find(|(given, _)| given == name) {
+            Some((_, given)) => *given = sources.to_owned(),
+            None => self.directives.push((name.to_owned(), sources.to_owned())),
+        }
+        self
+    }
+
+    ///
+    /// Removes a directive, along with its nonce.
+    ///
+    pub fn remove(mut self, name: &str) -> ContentSecurityPolicy {
+        self.directives.retain(|(given, _)| given != name);
+        self.nonce_directives.retain(|given| given != name);
+        self
+    }
+
+    ///
+    /// Adds the request's nonce to a directive's sources, e.g. `style-src`. Browsers ignore
+    /// `'unsafe-inline'` in directives with a nonce.
+    ///
+    pub fn nonce(mut self, name: &str) -> ContentSecurityPolicy {
+        if !self.nonce_directives.iter().any(|given| given == name) {
+            self.nonce_directives.push(name.to_owned());
+        }
+        self
+    }
+
+    ///
+    /// Sends the policy as `Content-Security-Policy-Report-Only`, so that violations are only
+    /// reported, for trying out a policy before enforcing it.
+    ///
+    pub fn report_only(mut self, report_only: bool) -> ContentSecurityPolicy {
+        self.report_only = report_only;
+        self
+    }
+
+    fn header_name(&self) -> &'static str {
+        if self.report_only {
+            "Content-Security-Policy-Report-Only"
+        } else {
+            "Content-Security-Policy"
+        }
+    }
+
+    fn render(&self, nonce: Option<&str>) -> String {
+        let nonce_source = nonce.map(|nonce| format!("'nonce-{}'", nonce));
+        let mut directives: Vec<String> = self
+            .directives
+            .iter()
+            .map(|(name, sources)| {
+                let sources = match &nonce_source {
+                    Some(nonce_source) if self.nonce_directives.contains(name) => {
+                        format!("{} {}", sources, nonce_source)
+                    }
+                    _ => sources.to_owned(),
+                };
+
+                format!("{} {}", name, sources).trim().to_owned()
+            })
+            .collect();
+
+        if let Some(nonce_source) = &nonce_source {
+            for name in &self.nonce_directives {
+                if !self.directives.iter().any(|(given, _)| given == name) {
+                    directives.push(format!("{} {}", name, nonce_source));
+                }
+            }
+        }
+
+        directives.join("; ")
+    }
+}
+
+///
+/// Middleware that sets the headers that tell browsers to turn on their security features, with
+/// defaults that suit most sites:
+///
+/// - `Strict-Transport-Security: max-age=15552000; includeSubDomains`
+/// - `Content-Security-Policy`, as `ContentSecurityPolicy::default()`
+/// - `X-Content-Type-Options: nosniff`
+/// - `X-Frame-Options: SAMEORIGIN`
+/// - `Referrer-Policy: no-referrer`
+/// - `Permissions-Policy: camera=(), geolocation=(), microphone=(), payment=(), usb=()`
+/// - `Cross-Origin-Opener-Policy: same-origin`
+/// - `Cross-Origin-Resource-Policy: same-origin`
+///
+/// Each of them can be changed, or turned off with `None`. The `Server` header is removed too,
+/// so that responses don't give away what they're served by.
+///
+/// The headers are set once the rest of the chain has run, replacing any that it set. The nonce
+/// of the `Content-Security-Policy` is available to handlers through `HasCspNonce`.
+///
+/// Only responses that go through middleware get the headers. That includes `405`s and the
+/// `OPTIONS` responses the app makes for paths without an `OPTIONS` route, but not CORS
+/// preflights, which are answered before any middleware runs when the app has `set_cors`. Those
+/// are sent without these headers and with the `Server` header, so a proxy in front of the app
+/// has to add or remove headers that need to be on every response.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref SECURITY_HEADERS: SecurityHeaders = SecurityHeaders::new()
+///         .x_frame_options(Some("DENY"))
+///         .cross_origin_embedder_policy(Some("require-corp"));
+/// }
+///
+/// #[middleware_fn]
+/// async fn security_headers(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     SECURITY_HEADERS.handle(context, next).await
+/// }
+///
+/// #[middleware_fn]
+/// async fn page(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     let nonce = context.csp_nonce().unwrap_or_default().to_owned();
+///     context.body(&format!(r#"<script nonce="{}">...</script>"#, nonce));
+///     Ok(context)
+/// }
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    headers: Vec<(&'static str, String)>,
+    content_security_policy: Option<ContentSecurityPolicy>,
+    remove_server_header: bool,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+impl SecurityHeaders {
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders {
+            headers: Vec::new(),
+            content_security_policy: Some(ContentSecurityPolicy::default()),
+            remove_server_header: true,
+        }
+        .strict_transport_security(Some("max-age=15552000; includeSubDomains"))
+        .x_content_type_options(Some("nosniff"))
+        .x_frame_options(Some("SAMEORIGIN"))
+        .referrer_policy(Some("no-referrer"))
+        .permissions_policy(Some(
+            "camera=(), geolocation=(), microphone=(), payment=(), usb=()",
+        ))
+        .cross_origin_opener_policy(Some("same-origin"))
+        .cross_origin_resource_policy(Some("same-origin"))
+    }
+
+    fn header(mut self, name: &'static str, value: Option<&str>) -> SecurityHeaders {
+        self.headers.retain(|(given, _)| *given != name);
+        if let Some(value) = value {
+            self.headers.push((name, value.to_owned()));
+        }
+        self
+    }
+
+    ///
+    /// Sets `Strict-Transport-Security`. Browsers only take it from responses sent over HTTPS,
+    /// after which they won't use plain HTTP for the site until it expires.
+    ///
+    pub fn strict_transport_security(self, value: Option<&str>) -> SecurityHeaders {
+        self.header("Strict-Transport-Security", value)
+    }
+
+    pub fn content_security_policy(
+        mut self,
+        policy: Option<ContentSecurityPolicy>,
+    ) -> SecurityHeaders {
+        self.content_security_policy = policy;
+        self
+    }
+
+    pub fn x_content_type_options(self, value: Option<&str>) -> SecurityHeaders {
+        self.header("X-Content-Type-Options", value)
+    }
+
+    pub fn x_frame_options(self, value: Option<&str>) -> SecurityHeaders {
+        self.header("X-Frame-Options", value)
+    }
+
+    pub fn referrer_policy(self, value: Option<&str>) -> SecurityHeaders {
+        self.header("Referrer-Policy", value)
+    }
+
+    pub fn permissions_policy(self, value: Option<&str>) -> SecurityHeaders {
+        self.header("Permissions-Policy", value)
+    }
+
+    pub fn cross_origin_opener_policy(self, value: Option<&str>) -> SecurityHeaders {
+        self.header("Cross-Origin-Opener-Policy", value)
+    }
+
+    pub fn cross_origin_resource_policy(self, value: Option<&str>) -> SecurityHeaders {
+        self.header("Cross-Origin-Resource-Policy", value)
+    }
+
+    ///
+    /// Sets `Cross-Origin-Embedder-Policy`, which isn't set by default as `require-corp` stops
+    /// pages from loading cross-origin resources that don't opt in to it.
+    ///
+    pub fn cross_origin_embedder_policy(self, value: Option<&str>) -> SecurityHeaders {
+        self.header("Cross-Origin-Embedder-Policy", value)
+    }
+
+    ///
+    /// Sets whether the `Server` header is removed.
+    ///
+    pub fn remove_server_header(mut self, remove: bool) -> SecurityHeaders {
+        self.remove_server_header = remove;
+        self
+    }
+
+    pub async fn handle<T>(&self, mut context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + HasCspNonce + Send,
+    {
+        let nonce = self
+            .content_security_policy
+            .as_ref()
+            .filter(|policy| !policy.nonce_directives.is_empty())
+            .map(|_| nonce());
+
+        if let Some(nonce) = &nonce {
+            context.set_csp_nonce(nonce.clone());
+        }
+
+        let mut result = next(context).await;
+
+        // The context that comes back isn't always the one that went down the chain
+        let context = match &mut result {
+            Ok(context) => context,
+            Err(e) => &mut e.context,
+        };
+
+        for (name, value) in &self.headers {
+            context.remove(name);
+            context.set(name, value);
+        }
+
+        if let Some(policy) = &self.content_security_policy {
+            context.remove(policy.header_name());
+            context.set(policy.header_name(), &policy.render(nonce.as_deref()));
+        }
+
+        if self.remove_server_header {
+            context.remove("Server");
+        }
+
+        result
+    }
+}
+
+fn nonce() -> String {
+    let mut bytes = [0; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    base64::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::errors::ThrusterError;
+    use crate::core::request::Request;
+    use crate::middleware::cors::Cors;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    lazy_static! {
+        static ref SECURITY_HEADERS: SecurityHeaders = SecurityHeaders::new();
+        static ref CUSTOM_SECURITY_HEADERS: SecurityHeaders = SecurityHeaders::new()
+            .x_frame_options(Some("DENY"))
+            .referrer_policy(None)
+            .cross_origin_embedder_policy(Some("require-corp"))
+            .content_security_policy(Some(
+                ContentSecurityPolicy::new()
+                    .directive("default-src", "'self'")
+                    .report_only(true),
+            ))
+            .remove_server_header(false);
+    }
+
+    #[middleware_fn(_internal)]
+    async fn security_headers(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        SECURITY_HEADERS.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn custom_security_headers(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        CUSTOM_SECURITY_HEADERS.handle(context, next).await
+    }
+
+    // Renders the nonce, and sets a header that the middleware replaces
+    #[middleware_fn(_internal)]
+    async fn page(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let nonce = context.csp_nonce().unwrap_or("-").to_owned();
+        context.set("X-Frame-Options", "ALLOWALL");
+        context.body(&nonce);
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn broken(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.status(500);
+
+        Err(ThrusterError {
+            context,
+            message: "broken".to_owned(),
+            status: 500,
+            cause: None,
+        })
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/pages", MiddlewareTuple::A(security_headers));
+        app.use_middleware("/custom", MiddlewareTuple::A(custom_security_headers));
+        app.get("/pages", MiddlewareTuple::A(page));
+        app.get("/pages/broken", MiddlewareTuple::A(broken));
+        app.get("/custom", MiddlewareTuple::A(page));
+        app.set_cors(Cors::new());
+        app.commit()
+    }
+
+    async fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> testing::TestResponse {
+        testing::request(&app(), method, path, headers, "").await
+    }
+
+    // Checks that the default headers are there and the `Server` header isn't
+    fn assert_secured(response: &testing::TestResponse) {
+        assert_eq!(
+            response.headers["Strict-Transport-Security"],
+            "max-age=15552000; includeSubDomains"
+        );
+        assert_eq!(response.headers["X-Content-Type-Options"], "nosniff");
+        assert_eq!(response.headers["X-Frame-Options"], "SAMEORIGIN");
+        assert_eq!(response.headers["Referrer-Policy"], "no-referrer");
+        assert_eq!(
+            response.headers["Permissions-Policy"],
+            "camera=(), geolocation=(), microphone=(), payment=(), usb=()"
+        );
+        assert_eq!(
+            response.headers["Cross-Origin-Opener-Policy"],
+            "same-origin"
+        );
+        assert_eq!(
+            response.headers["Cross-Origin-Resource-Policy"],
+            "same-origin"
+        );
+        assert!(response.headers.contains_key("Content-Security-Policy"));
+        assert!(!response
+            .headers
+            .contains_key("Cross-Origin-Embedder-Policy"));
+        assert!(!response.headers.contains_key("Server"));
+    }
+
+    #[test]
+    fn it_should_render_the_default_policy_with_a_nonce() {
+        assert_eq!(
+            ContentSecurityPolicy::default().render(Some("abc")),
+            "default-src 'self'; base-uri 'self'; font-src 'self' https: data:; \
+             form-action 'self'; frame-ancestors 'self'; img-src 'self' data:; \
+             object-src 'none'; script-src 'self' 'nonce-abc'; script-src-attr 'none'; \
+             style-src 'self' https: 'unsafe-inline'; upgrade-insecure-requests"
+        );
+    }
+
+    #[test]
+    fn it_should_replace_and_remove_directives() {
+        let policy = ContentSecurityPolicy::new()
+            .directive("default-src", "'self'")
+            .directive("img-src", "'self'")
+            .nonce("img-src")
+            .directive("img-src", "https://images.example.com")
+            .remove("default-src");
+
+        assert_eq!(
+            policy.render(Some("abc")),
+            "img-src https://images.example.com 'nonce-abc'"
+        );
+
+        let policy = policy.remove("img-src");
+
+        assert_eq!(policy.render(Some("abc")), "");
+    }
+
+    #[test]
+    fn it_should_add_directives_that_only_have_a_nonce() {
+        let policy = ContentSecurityPolicy::new()
+            .directive("default-src", "'self'")
+            .nonce("style-src")
+            .nonce("style-src");
+
+        assert_eq!(
+            policy.render(Some("abc")),
+            "default-src 'self'; style-src 'nonce-abc'"
+        );
+        assert_eq!(policy.render(None), "default-src 'self'");
+    }
+
+    #[tokio::test]
+    async fn it_should_set_the_default_headers() {
+        let response = request("GET", "/pages", &[]).await;
+
+        assert_eq!(response.status.1, 200);
+        assert_secured(&response);
+    }
+
+    #[tokio::test]
+    async fn it_should_give_each_request_a_new_nonce() {
+        let first = request("GET", "/pages", &[]).await;
+        let second = request("GET", "/pages", &[]).await;
+
+        assert_eq!(first.body.len(), 24);
+        assert_ne!(first.body, second.body);
+        assert!(first.headers["Content-Security-Policy"]
+            .contains(&format!("script-src 'self' 'nonce-{}';", first.body)));
+    }
+
+    #[tokio::test]
+    async fn it_should_use_the_configured_headers() {
+        let response = request("GET", "/custom", &[]).await;
+
+        assert_eq!(response.headers["X-Frame-Options"], "DENY");
+        assert_eq!(
+            response.headers["Cross-Origin-Embedder-Policy"],
+            "require-corp"
+        );
+        assert_eq!(
+            response.headers["Content-Security-Policy-Report-Only"],
+            "default-src 'self'"
+        );
+        assert_eq!(response.headers["Server"], "Thruster");
+        assert!(!response.headers.contains_key("Referrer-Policy"));
+        assert!(!response.headers.contains_key("Content-Security-Policy"));
+
+        // Policies without a nonce don't give requests one
+        assert_eq!(response.body, "-");
+    }
+
+    #[tokio::test]
+    async fn it_should_set_the_headers_on_errors() {
+        let response = request("GET", "/pages/broken", &[]).await;
+
+        assert_eq!(response.status.1, 500);
+        assert_secured(&response);
+    }
+
+    #[tokio::test]
+    async fn it_should_set_the_headers_on_405s_and_automatic_options() {
+        let response = request("POST", "/pages", &[]).await;
+
+        assert_eq!(response.status.1, 405);
+        assert_secured(&response);
+
+        let response = request("OPTIONS", "/pages", &[]).await;
+
+        assert_eq!(response.status.1, 204);
+        assert_secured(&response);
+    }
+
+    #[tokio::test]
+    async fn it_should_leave_cors_preflights_alone() {
+        let response = request(
+            "OPTIONS",
+            "/pages",
+            &[
+                ("Origin", "https://app.example.com"),
+                ("Access-Control-Request-Method", "GET"),
+            ],
+        )
+        .await;
+
+        assert_eq!(response.status.1, 204);
+        assert!(response
+            .headers
+            .contains_key("Access-Control-Allow-Methods"));
+        assert!(!response.headers.contains_key("Strict-Transport-Security"));
+        assert!(!response.headers.contains_key("Content-Security-Policy"));
+        assert_eq!(response.headers["Server"], "Thruster");
+    }
+}
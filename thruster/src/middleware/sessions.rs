@@ -0,0 +1,764 @@
+use async_trait::async_trait;
+use dashmap::DashMap;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::core::context::Context;
+use crate::core::errors::ThrusterError;
+use crate::core::extractors::HasRequestHeaders;
+use crate::core::{MiddlewareNext, MiddlewareResult};
+use crate::middleware::cookie_jar::CookieJar;
+use crate::middleware::cookies::{parse_string, CookieOptions, HasResponseCookies, SameSite};
+
+pub trait HasSession {
+    fn set_session(&mut self, session: Session);
+    fn take_session(&mut self) -> Option<Session>;
+}
+
+///
+/// What a store keeps for each session. Times are in seconds since the unix epoch.
+///
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SessionRecord {
+    pub data: HashMap<String, Value>,
+    pub created_at: u64,
+    /// When the session expires, if it has an idle or absolute timeout.
+    pub expires_at: Option<u64>,
+}
+
+impl SessionRecord {
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now())
+    }
+}
+
+///
+/// Where sessions are kept between requests.
+///
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn load(&self, id: &str) -> io::Result<Option<SessionRecord>>;
+    async fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()>;
+    async fn destroy(&self, id: &str) -> io::Result<()>;
+}
+
+///
+/// Keeps sessions in memory, so they're lost when the process exits and aren't shared between
+/// processes.
+///
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: DashMap<String, SessionRecord>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    ///
+    /// Removes expired sessions. Expired sessions are never loaded, but they're only removed
+    /// by this, or when a client sends their ID again.
+    ///
+    pub fn purge_expired(&self) {
+        self.sessions.retain(|_, record| !record.is_expired());
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        Ok(self.sessions.get(id).map(|record| record.clone()))
+    }
+
+    async fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        self.sessions.insert(id.to_owned(), record.clone());
+        Ok(())
+    }
+
+    async fn destroy(&self, id: &str) -> io::Result<()> {
+        self.sessions.remove(id);
+        Ok(())
+    }
+}
+
+///
+/// Keeps each session as a JSON file in a directory.
+///
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    ///
+    /// Creates a store in `dir`, which must already exist.
+    ///
+    pub fn new(dir: impl Into<PathBuf>) -> FileStore {
+        FileStore { dir: dir.into() }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    ///
+    /// Removes the files of expired sessions.
+    ///
+    pub async fn purge_expired(&self) -> io::Result<()> {
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+
+            let expired = match tokio::fs::read(&path).await {
+                Ok(contents) => serde_json::from_slice::<SessionRecord>(&contents)
+                    .map(|record| record.is_expired())
+                    .unwrap_or(false),
+                Err(_) => false,
+            };
+
+            if expired {
+                remove_file(&path).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for FileStore {
+    async fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        match tokio::fs::read(self.path(id)).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let contents = serde_json::to_vec(record)?;
+
+        // Written to the side and renamed into place, so a session is never read half-written
+        let temp_path = self.dir.join(format!(".{}.tmp", id));
+        tokio::fs::write(&temp_path, contents).await?;
+        tokio::fs::rename(&temp_path, self.path(id)).await
+    }
+
+    async fn destroy(&self, id: &str) -> io::Result<()> {
+        remove_file(&self.path(id)).await
+    }
+}
+
+async fn remove_file(path: &std::path::Path) -> io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+///
+/// The session of the current request. Changes are saved once the rest of the middleware
+/// chain has returned successfully.
+///
+#[derive(Debug)]
+pub struct Session {
+    id: Option<String>,
+    record: SessionRecord,
+    changed: bool,
+    regenerate: bool,
+    destroyed: bool,
+}
+
+impl Session {
+    fn new() -> Session {
+        Session {
+            id: None,
+            record: SessionRecord {
+                created_at: now(),
+                ..SessionRecord::default()
+            },
+            changed: false,
+            regenerate: false,
+            destroyed: false,
+        }
+    }
+
+    ///
+    /// The ID of the session, or `None` if it's new and hasn't been saved yet.
+    ///
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.record
+            .data
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    pub fn insert<T: serde::Serialize>(&mut self, key: &str, value: T) -> serde_json::Result<()> {
+        self.record
+            .data
+            .insert(key.to_owned(), serde_json::to_value(value)?);
+        self.changed = true;
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.changed = true;
+        self.record.data.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.changed = true;
+        self.record.data.clear();
+    }
+
+    ///
+    /// Moves the session to a new ID when it's saved, keeping its data but restarting its
+    /// absolute timeout. Call this whenever a user logs in, so that an ID planted before they
+    /// did can't be used to hijack their session.
+    ///
+    pub fn regenerate(&mut self) {
+        self.regenerate = true;
+        self.changed = true;
+    }
+
+    ///
+    /// Removes the session from the store and the client, as on logout.
+    ///
+    pub fn destroy(&mut self) {
+        self.destroyed = true;
+    }
+}
+
+///
+/// Middleware that loads the session named by a cookie into the context, and saves it once the
+/// rest of the chain has returned. New sessions are only stored, and their cookie only set,
+/// once something is put in them.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref SESSIONS: Sessions<MemoryStore> = Sessions::new(MemoryStore::new())
+///         .idle_timeout(Duration::from_secs(30 * 60))
+///         .absolute_timeout(Duration::from_secs(12 * 60 * 60));
+/// }
+///
+/// #[middleware_fn]
+/// async fn sessions(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     SESSIONS.handle(context, next).await
+/// }
+/// ```
+///
+pub struct Sessions<S: SessionStore> {
+    store: S,
+    cookie_name: String,
+    cookie_options: CookieOptions,
+    idle_timeout: Option<Duration>,
+    absolute_timeout: Option<Duration>,
+    cookie_jar: Option<CookieJar>,
+}
+
+impl<S: SessionStore> Sessions<S> {
+    ///
+    /// Creates sessions kept in `store`, with their IDs in an `HttpOnly`, `SameSite=Lax` cookie
+    /// called `sid`, and no timeouts.
+    ///
+    pub fn new(store: S) -> Sessions<S> {
+        let mut cookie_options = CookieOptions::default();
+        cookie_options.http_only = true;
+        cookie_options.same_site = Some(SameSite::Lax);
+
+        Sessions {
+            store,
+            cookie_name: "sid".to_owned(),
+            cookie_options,
+            idle_timeout: None,
+            absolute_timeout: None,
+            cookie_jar: None,
+        }
+    }
+
+    pub fn cookie_name(mut self, cookie_name: &str) -> Sessions<S> {
+        self.cookie_name = cookie_name.to_owned();
+        self
+    }
+
+    ///
+    /// Sets the options of the session cookie. Its `Expires` and `Max-Age` are always set from
+    /// the timeouts.
+    ///
+    pub fn cookie_options(mut self, cookie_options: CookieOptions) -> Sessions<S> {
+        self.cookie_options = cookie_options;
+        self
+    }
+
+    ///
+    /// Expires sessions that haven't been used for `timeout`.
+    ///
+    pub fn idle_timeout(mut self, timeout: Duration) -> Sessions<S> {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    ///
+    /// Expires sessions `timeout` after they were created, however much they're used.
+    ///
+    pub fn absolute_timeout(mut self, timeout: Duration) -> Sessions<S> {
+        self.absolute_timeout = Some(timeout);
+        self
+    }
+
+    ///
+    /// Seals the session cookie with `cookie_jar`, which should sign or encrypt the cookie
+    /// name. Session IDs are random enough not to need it, but it means forged IDs are
+    /// rejected before the store is asked for them.
+    ///
+    pub fn cookie_jar(mut self, cookie_jar: CookieJar) -> Sessions<S> {
+        self.cookie_jar = Some(cookie_jar);
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub async fn handle<T>(&self, mut context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + HasSession + HasRequestHeaders + HasResponseCookies + Send,
+    {
+        let session = match self.load(&context).await {
+            Ok(session) => session,
+            Err(e) => return Err(session_error(context, e)),
+        };
+
+        context.set_session(session);
+
+        let mut context = next(context).await?;
+
+        if let Some(session) = context.take_session() {
+            if let Err(e) = self.save(&mut context, session).await {
+                return Err(session_error(context, e));
+            }
+        }
+
+        Ok(context)
+    }
+
+    async fn load<T: HasRequestHeaders>(&self, context: &T) -> io::Result<Session> {
+        let id = match self.session_id(context) {
+            Some(id) => id,
+            None => return Ok(Session::new()),
+        };
+
+        match self.store.load(&id).await? {
+            Some(record) if !record.is_expired() => Ok(Session {
+                id: Some(id),
+                record,
+                changed: false,
+                regenerate: false,
+                destroyed: false,
+            }),
+            Some(_) => {
+                self.store.destroy(&id).await?;
+                Ok(Session::new())
+            }
+            None => Ok(Session::new()),
+        }
+    }
+
+    fn session_id<T: HasRequestHeaders>(&self, context: &T) -> Option<String> {
+        let cookie = context
+            .request_header("cookie")
+            .iter()
+            .flat_map(|cookie_string| parse_string(cookie_string))
+            .find(|cookie| cookie.key == self.cookie_name)?;
+
+        let id = match &self.cookie_jar {
+            Some(cookie_jar) => cookie_jar.open(&cookie.key, &cookie.value)?,
+            None => cookie.value,
+        };
+
+        // IDs are also file names and store keys, so anything that we couldn't have generated
+        // is ignored rather than passed on
+        if is_valid_id(&id) {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    async fn save<T: HasResponseCookies>(
+        &self,
+        context: &mut T,
+        mut session: Session,
+    ) -> io::Result<()> {
+        if session.destroyed {
+            if let Some(id) = session.id {
+                self.store.destroy(&id).await?;
+
+                let mut options = self.cookie_options.clone();
+                options.expires = 1;
+                options.max_age = 0;
+                context.set_cookie(&self.cookie_name, "", &options);
+            }
+
+            return Ok(());
+        }
+
+        let now = now();
+
+        if session.regenerate {
+            if let Some(id) = session.id.take() {
+                self.store.destroy(&id).await?;
+            }
+            session.record.created_at = now;
+        }
+
+        let Session {
+            id,
+            mut record,
+            changed,
+            ..
+        } = session;
+        let (id, is_new) = match id {
+            Some(id) => (id, false),
+            // Sessions that were never written to aren't worth storing
+            None if !changed => return Ok(()),
+            None => (generate_id(), true),
+        };
+
+        if !changed && !is_new && self.idle_timeout.is_none() {
+            return Ok(());
+        }
+
+        let idle_expiry = self.idle_timeout.map(|timeout| now + timeout.as_secs());
+        let absolute_expiry = self
+            .absolute_timeout
+            .map(|timeout| record.created_at + timeout.as_secs());
+        record.expires_at = match (idle_expiry, absolute_expiry) {
+            (Some(idle), Some(absolute)) => Some(idle.min(absolute)),
+            (idle, absolute) => idle.or(absolute),
+        };
+
+        self.store.save(&id, &record).await?;
+
+        // The cookie's only resent when its ID or expiry changes
+        if is_new || record.expires_at.is_some() {
+            let mut options = self.cookie_options.clone();
+            if let Some(expires_at) = record.expires_at {
+                options.expires = expires_at;
+                options.max_age = expires_at.saturating_sub(now);
+            }
+
+            let value = match &self.cookie_jar {
+                Some(cookie_jar) => cookie_jar.seal(&self.cookie_name, &id),
+                None => id,
+            };
+
+            context.set_cookie(&self.cookie_name, &value, &options);
+        }
+
+        Ok(())
+    }
+}
+
+fn session_error<T>(context: T, e: io::Error) -> ThrusterError<T> {
+    ThrusterError {
+        context,
+        message: format!("Could not access session: {}", e),
+        status: 500,
+        cause: Some(Box::new(e)),
+    }
+}
+
+const ID_LEN: usize = 43;
+
+fn generate_id() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn is_valid_id(id: &str) -> bool {
+    id.len() == ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use lazy_static::lazy_static;
+
+    use super::*;
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::middleware::cookie_jar::Key;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    lazy_static! {
+        static ref SESSIONS: Sessions<MemoryStore> = Sessions::new(MemoryStore::new())
+            .idle_timeout(Duration::from_secs(60))
+            .absolute_timeout(Duration::from_secs(3600));
+        static ref SEALED_SESSIONS: Sessions<MemoryStore> = Sessions::new(MemoryStore::new())
+            .cookie_jar(CookieJar::new(Key::derive_from(&[7; 32])).signed("sid"));
+    }
+
+    #[middleware_fn(_internal)]
+    async fn sessions(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        SESSIONS.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn sealed_sessions(
+        context: BasicContext,
+        next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        SEALED_SESSIONS.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn login(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let session = context.session.as_mut().unwrap();
+        session.insert("user", "jane").unwrap();
+        session.regenerate();
+
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn whoami(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        let user = context
+            .session
+            .as_ref()
+            .and_then(|session| session.get::<String>("user"))
+            .unwrap_or_else(|| "nobody".to_owned());
+
+        context.body(&user);
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn logout(
+        mut context: BasicContext,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.session.as_mut().unwrap().destroy();
+
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(sessions));
+        app.post("/login", MiddlewareTuple::A(login));
+        app.get("/whoami", MiddlewareTuple::A(whoami));
+        app.post("/logout", MiddlewareTuple::A(logout));
+        app.commit()
+    }
+
+    fn sealed_app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(sealed_sessions));
+        app.post("/login", MiddlewareTuple::A(login));
+        app.get("/whoami", MiddlewareTuple::A(whoami));
+        app.commit()
+    }
+
+    // The value of the session cookie set by a response
+    fn session_cookie(response: &testing::TestResponse) -> Option<String> {
+        response
+            .headers
+            .get("Set-Cookie")
+            .and_then(|cookie| cookie.strip_prefix("sid="))
+            .and_then(|cookie| cookie.split(';').next())
+            .map(str::to_owned)
+    }
+
+    async fn get(app: &App<Request, BasicContext, ()>, path: &str, sid: &str) -> String {
+        let cookie = format!("sid={}", sid);
+
+        testing::request(app, "GET", path, &[("Cookie", &cookie)], "")
+            .await
+            .body
+    }
+
+    #[tokio::test]
+    async fn it_should_only_store_sessions_that_are_written_to() {
+        let response = testing::get(&app(), "/whoami").await;
+
+        assert_eq!(response.body, "nobody");
+        assert!(!response.headers.contains_key("Set-Cookie"));
+    }
+
+    #[tokio::test]
+    async fn it_should_load_stored_sessions() {
+        let app = app();
+
+        let response = testing::post(&app, "/login", "").await;
+        let sid = session_cookie(&response).unwrap();
+        let set_cookie = &response.headers["Set-Cookie"];
+
+        assert!(is_valid_id(&sid));
+        assert!(set_cookie.contains("; Max-Age=60;"));
+        assert!(set_cookie.ends_with("; HttpOnly; SameSite=Lax"));
+        assert_eq!(get(&app, "/whoami", &sid).await, "jane");
+        assert_eq!(get(&app, "/whoami", &generate_id()).await, "nobody");
+        assert_eq!(get(&app, "/whoami", "../../etc/passwd").await, "nobody");
+    }
+
+    #[tokio::test]
+    async fn it_should_move_regenerated_sessions_to_a_new_id() {
+        let app = app();
+
+        let sid = session_cookie(&testing::post(&app, "/login", "").await).unwrap();
+
+        let cookie = format!("sid={}", sid);
+        let response = testing::request(&app, "POST", "/login", &[("Cookie", &cookie)], "").await;
+        let new_sid = session_cookie(&response).unwrap();
+
+        assert_ne!(new_sid, sid);
+        assert_eq!(get(&app, "/whoami", &new_sid).await, "jane");
+        assert_eq!(get(&app, "/whoami", &sid).await, "nobody");
+        assert!(SESSIONS.store().load(&sid).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn it_should_destroy_sessions() {
+        let app = app();
+
+        let sid = session_cookie(&testing::post(&app, "/login", "").await).unwrap();
+
+        let cookie = format!("sid={}", sid);
+        let response = testing::request(&app, "POST", "/logout", &[("Cookie", &cookie)], "").await;
+
+        assert_eq!(session_cookie(&response).as_deref(), Some(""));
+        assert!(response.headers["Set-Cookie"].contains("; Expires=Thu, 01 Jan 1970 00:00:01 GMT;"));
+        assert_eq!(get(&app, "/whoami", &sid).await, "nobody");
+    }
+
+    #[tokio::test]
+    async fn it_should_expire_sessions() {
+        let app = app();
+
+        let sid = session_cookie(&testing::post(&app, "/login", "").await).unwrap();
+
+        let mut record = SESSIONS.store().load(&sid).await.unwrap().unwrap();
+        let expires_at = record.expires_at.unwrap();
+        assert!(expires_at > now() && expires_at <= now() + 60);
+
+        record.expires_at = Some(now() - 1);
+        SESSIONS.store().save(&sid, &record).await.unwrap();
+
+        assert_eq!(get(&app, "/whoami", &sid).await, "nobody");
+        assert!(SESSIONS.store().load(&sid).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn it_should_cap_idle_expiry_at_the_absolute_timeout() {
+        let app = app();
+
+        let sid = session_cookie(&testing::post(&app, "/login", "").await).unwrap();
+
+        // A session that's nearly reached its absolute timeout
+        let mut record = SESSIONS.store().load(&sid).await.unwrap().unwrap();
+        record.created_at = now() - 3590;
+        SESSIONS.store().save(&sid, &record).await.unwrap();
+
+        let cookie = format!("sid={}", sid);
+        let response = testing::request(&app, "GET", "/whoami", &[("Cookie", &cookie)], "").await;
+        let record = SESSIONS.store().load(&sid).await.unwrap().unwrap();
+
+        assert_eq!(response.body, "jane");
+        assert_eq!(record.expires_at, Some(record.created_at + 3600));
+        assert!(!response.headers["Set-Cookie"].contains("Max-Age=60"));
+    }
+
+    #[tokio::test]
+    async fn it_should_seal_session_cookies_with_the_jar() {
+        let app = sealed_app();
+
+        let response = testing::post(&app, "/login", "").await;
+        let sealed = session_cookie(&response).unwrap();
+        let (sid, _) = sealed.rsplit_once('.').unwrap();
+
+        assert_eq!(get(&app, "/whoami", &sealed).await, "jane");
+        assert_eq!(get(&app, "/whoami", sid).await, "nobody");
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_sessions_in_files() {
+        let dir = std::env::temp_dir().join(format!("thruster-sessions-{}", generate_id()));
+        std::fs::create_dir(&dir).unwrap();
+        let store = FileStore::new(&dir);
+
+        let live = SessionRecord {
+            created_at: now(),
+            expires_at: Some(now() + 60),
+            ..SessionRecord::default()
+        };
+        let expired = SessionRecord {
+            expires_at: Some(now() - 1),
+            ..live.clone()
+        };
+
+        store.save("live", &live).await.unwrap();
+        store.save("expired", &expired).await.unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a session").unwrap();
+
+        assert_eq!(
+            store.load("live").await.unwrap().unwrap().expires_at,
+            live.expires_at
+        );
+        assert!(store.load("missing").await.unwrap().is_none());
+
+        store.purge_expired().await.unwrap();
+
+        assert!(store.load("live").await.unwrap().is_some());
+        assert!(store.load("expired").await.unwrap().is_none());
+        assert!(dir.join("notes.txt").exists());
+
+        store.destroy("live").await.unwrap();
+        store.destroy("live").await.unwrap();
+        assert!(store.load("live").await.unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,375 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use serde::Serialize;
+use std::fmt::Write;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::time::{sleep, Instant, Sleep};
+
+use crate::core::context::Context;
+use crate::core::extractors::{ExtractionError, FromContext, HasRequestHeaders};
+use crate::core::response::{BodyStream, HasBodyStream};
+
+///
+/// A single server-sent event. Data with newlines in it is sent over several `data` lines,
+/// which the browser joins back together.
+///
+/// ```rust, ignore
+/// let event = Event::data("50%").event("progress").id("7");
+/// ```
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    pub fn data(data: impl Into<String>) -> Event {
+        Event {
+            data: Some(data.into()),
+            ..Event::default()
+        }
+    }
+
+    ///
+    /// An event whose data is `data` as JSON.
+    ///
+    pub fn json<T: Serialize>(data: &T) -> serde_json::Result<Event> {
+        Ok(Event::data(serde_json::to_string(data)?))
+    }
+
+    ///
+    /// An event that's only a comment, which the browser ignores.
+    ///
+    pub fn comment(comment: impl Into<String>) -> Event {
+        Event {
+            comment: Some(comment.into()),
+            ..Event::default()
+        }
+    }
+
+    ///
+    /// Sets the ID, which the browser sends back as `Last-Event-ID` when it reconnects.
+    ///
+    pub fn id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    ///
+    /// Sets the event type, which is what listeners are added for in the browser. Events
+    /// without one are `message`s.
+    ///
+    pub fn event(mut self, event: impl Into<String>) -> Event {
+        self.event = Some(event.into());
+        self
+    }
+
+    ///
+    /// Sets how long the browser waits before reconnecting, if the connection drops.
+    ///
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut encoded = String::new();
+
+        if let Some(comment) = &self.comment {
+            for line in comment.lines() {
+                let _ = writeln!(encoded, ": {}", line);
+            }
+        }
+
+        // Newlines would end the field early, so they're dropped from single line fields
+        if let Some(event) = &self.event {
+            let _ = writeln!(encoded, "event: {}", single_line(event));
+        }
+
+        if let Some(id) = &self.id {
+            let _ = writeln!(encoded, "id: {}", single_line(id));
+        }
+
+        if let Some(retry) = self.retry {
+            let _ = writeln!(encoded, "retry: {}", retry.as_millis());
+        }
+
+        if let Some(data) = &self.data {
+            // `lines` would drop a trailing empty line, which is part of the data
+            for line in data.split('\n') {
+                let _ = writeln!(encoded, "data: {}", line.strip_suffix('\r').unwrap_or(line));
+            }
+        }
+
+        encoded.push('\n');
+
+        Bytes::from(encoded)
+    }
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+///
+/// Sends a stream of events as a `text/event-stream` response. A keep-alive comment is sent
+/// whenever the stream has been quiet for a while, so that proxies don't close the
+/// connection.
+///
+/// ```rust, ignore
+/// #[middleware_fn]
+/// async fn progress(mut context: Ctx, last_event_id: LastEventId, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     let (sender, receiver) = tokio::sync::mpsc::channel(16);
+///     tokio::spawn(run_job(last_event_id.0, sender));
+///
+///     Sse::new().respond(&mut context, ReceiverStream::new(receiver));
+///     Ok(context)
+/// }
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct Sse {
+    keep_alive: Option<Duration>,
+}
+
+impl Default for Sse {
+    fn default() -> Self {
+        Sse::new()
+    }
+}
+
+impl Sse {
+    ///
+    /// Creates a response that sends a keep-alive comment after 15 seconds without events.
+    ///
+    pub fn new() -> Sse {
+        Sse {
+            keep_alive: Some(Duration::from_secs(15)),
+        }
+    }
+
+    ///
+    /// Sets how long the stream can be quiet before a keep-alive comment is sent, or turns
+    /// keep-alives off with `None`.
+    ///
+    pub fn keep_alive(mut self, keep_alive: Option<Duration>) -> Sse {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    ///
+    /// Makes `events` the body of the response, ending the response when the stream ends.
+    ///
+    pub fn respond<C, S>(self, context: &mut C, events: S)
+    where
+        C: Context + HasBodyStream,
+        S: Stream<Item = Event> + Send + 'static,
+    {
+        context.set("Content-Type", "text/event-stream");
+        context.set("Cache-Control", "no-cache");
+
+        context.set_body_stream(BodyStream::new(EventStream {
+            events: Box::pin(events),
+            keep_alive: self
+                .keep_alive
+                .map(|keep_alive| (keep_alive, Box::pin(sleep(keep_alive)))),
+        }));
+    }
+}
+
+struct EventStream {
+    events: Pin<Box<dyn Stream<Item = Event> + Send>>,
+    keep_alive: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl Stream for EventStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if let Poll::Ready(event) = this.events.as_mut().poll_next(cx) {
+            if let Some((keep_alive, timer)) = &mut this.keep_alive {
+                timer.as_mut().reset(Instant::now() + *keep_alive);
+            }
+
+            return Poll::Ready(event.map(|event| Ok(event.encode())));
+        }
+
+        if let Some((keep_alive, timer)) = &mut this.keep_alive {
+            if timer.as_mut().poll(cx).is_ready() {
+                timer.as_mut().reset(Instant::now() + *keep_alive);
+
+                return Poll::Ready(Some(Ok(Bytes::from_static(b":\n\n"))));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+///
+/// Extracts the `Last-Event-ID` a browser sends when reconnecting to an event stream, so the
+/// stream can pick up where it left off.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LastEventId(pub Option<String>);
+
+#[async_trait]
+impl<C: HasRequestHeaders + Send> FromContext<C> for LastEventId {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        Ok(LastEventId(context.request_header("last-event-id").pop()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::core::{MiddlewareNext, MiddlewareResult};
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    fn encoded(event: Event) -> String {
+        String::from_utf8(event.encode().to_vec()).unwrap()
+    }
+
+    // Collects the chunks of the body an event stream was sent as
+    async fn chunks(sse: Sse, events: impl Stream<Item = Event> + Send + 'static) -> Vec<String> {
+        let mut context = BasicContext::new();
+        sse.respond(&mut context, events);
+
+        let mut response = context.get_response();
+        response
+            .body_stream
+            .take()
+            .unwrap()
+            .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+            .collect()
+            .await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn resume(
+        mut context: BasicContext,
+        last_event_id: LastEventId,
+        _next: MiddlewareNext<BasicContext>,
+    ) -> MiddlewareResult<BasicContext> {
+        context.body(last_event_id.0.as_deref().unwrap_or("none"));
+        Ok(context)
+    }
+
+    #[test]
+    fn it_should_encode_every_field_of_an_event() {
+        let event = Event::data("50%")
+            .event("progress")
+            .id("7")
+            .retry(Duration::from_secs(3));
+
+        assert_eq!(
+            encoded(event),
+            "event: progress\nid: 7\nretry: 3000\ndata: 50%\n\n"
+        );
+    }
+
+    #[test]
+    fn it_should_split_data_over_several_lines() {
+        assert_eq!(
+            encoded(Event::data("one\r\ntwo\nthree")),
+            "data: one\ndata: two\ndata: three\n\n"
+        );
+        assert_eq!(encoded(Event::data("one\n")), "data: one\ndata: \n\n");
+        assert_eq!(encoded(Event::data("")), "data: \n\n");
+    }
+
+    #[test]
+    fn it_should_keep_newlines_out_of_single_line_fields() {
+        let event = Event::data("x").event("a\nevent: b").id("1\r\n2");
+
+        assert_eq!(encoded(event), "event: aevent: b\nid: 12\ndata: x\n\n");
+    }
+
+    #[test]
+    fn it_should_encode_comments_and_json() {
+        assert_eq!(encoded(Event::comment("hi\nthere")), ": hi\n: there\n\n");
+        assert_eq!(
+            encoded(Event::json(&vec![1, 2]).unwrap()),
+            "data: [1,2]\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_send_events_as_an_event_stream() {
+        let mut context = BasicContext::new();
+        Sse::new().respond(&mut context, futures::stream::empty());
+
+        assert_eq!(
+            context.headers.get("Content-Type").unwrap(),
+            "text/event-stream"
+        );
+        assert_eq!(context.headers.get("Cache-Control").unwrap(), "no-cache");
+
+        let events = futures::stream::iter(vec![Event::data("a"), Event::data("b").id("2")]);
+
+        assert_eq!(
+            chunks(Sse::new(), events).await,
+            vec!["data: a\n\n", "id: 2\ndata: b\n\n"]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_send_keep_alives_while_the_stream_is_quiet() {
+        let events = futures::stream::once(async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Event::data("late")
+        });
+
+        let chunks = chunks(
+            Sse::new().keep_alive(Some(Duration::from_millis(200))),
+            events,
+        )
+        .await;
+
+        assert_eq!(chunks, vec![":\n\n", ":\n\n", "data: late\n\n"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_not_send_keep_alives_when_turned_off() {
+        let events = futures::stream::once(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Event::data("late")
+        });
+
+        let chunks = chunks(Sse::new().keep_alive(None), events).await;
+
+        assert_eq!(chunks, vec!["data: late\n\n"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_extract_the_last_event_id() {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.get("/events", MiddlewareTuple::A(resume));
+        let app = app.commit();
+
+        let response =
+            testing::request(&app, "GET", "/events", &[("Last-Event-ID", "41")], "").await;
+        assert_eq!(response.body, "41");
+
+        let response = testing::get(&app, "/events").await;
+        assert_eq!(response.body, "none");
+    }
+}
@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+use crate::core::context::Context;
+use crate::core::errors::ThrusterError;
+use crate::core::{MiddlewareNext, MiddlewareResult};
+
+///
+/// A context that can be copied before it's handed down the chain, so that the request can
+/// still be answered if the chain never hands it back.
+///
+pub trait Detach {
+    ///
+    /// A copy of the context with the request's method, path and headers and whatever's been set
+    /// on the response so far, but neither body.
+    ///
+    fn detach(&self) -> Self;
+}
+
+///
+/// Middleware that gives the rest of the chain a deadline. Once it passes, whatever's still
+/// running is dropped at its next `.await`, and the request fails with a `504 Gateway Timeout`,
+/// or whichever status is set.
+///
+/// The context that was handed down the chain goes with it, so the error carries a copy of it
+/// that's detached before the rest of the chain runs. Headers set by the middleware before
+/// this one are kept, while anything the rest of the chain set on the response is lost.
+///
+/// Different routes or subapps can have different deadlines by using different middleware for
+/// them.
+///
+/// ```rust, ignore
+/// lazy_static! {
+///     static ref TIMEOUT: Timeout = Timeout::new(Duration::from_secs(10));
+///     static ref REPORT_TIMEOUT: Timeout = Timeout::new(Duration::from_secs(60)).status(503);
+/// }
+///
+/// #[middleware_fn]
+/// async fn timeout(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     TIMEOUT.handle(context, next).await
+/// }
+///
+/// #[middleware_fn]
+/// async fn report_timeout(context: Ctx, next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     REPORT_TIMEOUT.handle(context, next).await
+/// }
+///
+/// app.use_middleware("/", m![timeout]);
+/// app.get("/reports", m![report_timeout, generate_report]);
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct Timeout {
+    duration: Duration,
+    status: u16,
+}
+
+impl Timeout {
+    ///
+    /// Creates a middleware that fails requests with a `504` once `duration` has passed.
+    ///
+    pub fn new(duration: Duration) -> Timeout {
+        Timeout {
+            duration,
+            status: 504,
+        }
+    }
+
+    ///
+    /// Sets the status that requests fail with once the deadline passes, usually a `503` or
+    /// `504`.
+    ///
+    pub fn status(mut self, status: u16) -> Timeout {
+        self.status = status;
+        self
+    }
+
+    pub async fn handle<T>(&self, context: T, next: MiddlewareNext<T>) -> MiddlewareResult<T>
+    where
+        T: 'static + Context + Detach + Send,
+    {
+        let detached = context.detach();
+
+        match tokio::time::timeout(self.duration, next(context)).await {
+            Ok(result) => result,
+            Err(_) => {
+                let message = format!("Request timed out after {:?}", self.duration);
+
+                let mut context = detached;
+                context.status(self.status as u32);
+                context.set_body(message.as_bytes().to_vec());
+
+                Err(ThrusterError {
+                    context,
+                    message,
+                    status: self.status as u32,
+                    cause: None,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+
+    use crate::app::testing_async as testing;
+    use crate::context::basic_context::BasicContext;
+    use crate::core::request::Request;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::App;
+
+    lazy_static! {
+        static ref TIMEOUT: Timeout = Timeout::new(Duration::from_millis(50));
+        static ref UNAVAILABLE: Timeout = Timeout::new(Duration::from_millis(50)).status(503);
+    }
+
+    #[middleware_fn(_internal)]
+    async fn outer<T: 'static + Context + Send>(
+        mut context: T,
+        next: MiddlewareNext<T>,
+    ) -> MiddlewareResult<T> {
+        context.set("X-Outer", "kept");
+
+        match next(context).await {
+            Ok(context) => Ok(context),
+            Err(mut e) => {
+                let route = e.context.route().to_owned();
+                e.context.set("X-Route", &route);
+                Err(e)
+            }
+        }
+    }
+
+    #[middleware_fn(_internal)]
+    async fn timeout<T: 'static + Context + Detach + Send>(
+        context: T,
+        next: MiddlewareNext<T>,
+    ) -> MiddlewareResult<T> {
+        TIMEOUT.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn unavailable<T: 'static + Context + Detach + Send>(
+        context: T,
+        next: MiddlewareNext<T>,
+    ) -> MiddlewareResult<T> {
+        UNAVAILABLE.handle(context, next).await
+    }
+
+    #[middleware_fn(_internal)]
+    async fn slow<T: 'static + Context + Send>(
+        mut context: T,
+        _next: MiddlewareNext<T>,
+    ) -> MiddlewareResult<T> {
+        context.set("X-Inner", "lost");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        context.set_body(b"done".to_vec());
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn fast<T: 'static + Context + Send>(
+        mut context: T,
+        _next: MiddlewareNext<T>,
+    ) -> MiddlewareResult<T> {
+        context.set_body(b"done".to_vec());
+        Ok(context)
+    }
+
+    fn app() -> App<Request, BasicContext, ()> {
+        let mut app = App::<Request, BasicContext, ()>::new_basic();
+        app.use_middleware("/", MiddlewareTuple::A(outer));
+        app.use_middleware("/slow", MiddlewareTuple::A(timeout));
+        app.use_middleware("/fast", MiddlewareTuple::A(timeout));
+        app.use_middleware("/unavailable", MiddlewareTuple::A(unavailable));
+        app.get("/slow", MiddlewareTuple::A(slow));
+        app.get("/fast", MiddlewareTuple::A(fast));
+        app.get("/unavailable", MiddlewareTuple::A(slow));
+        app.commit()
+    }
+
+    #[tokio::test]
+    async fn it_should_let_requests_that_finish_in_time_through() {
+        let response = testing::get(&app(), "/fast").await;
+
+        assert_eq!(response.status.1, 200);
+        assert_eq!(response.body, "done");
+    }
+
+    #[tokio::test]
+    async fn it_should_fail_requests_that_run_past_the_deadline() {
+        let response = testing::get(&app(), "/slow?page=2").await;
+
+        assert_eq!(response.status.1, 504);
+        assert_eq!(response.body, "Request timed out after 50ms");
+        assert_eq!(response.headers["X-Outer"], "kept");
+        assert_eq!(response.headers["X-Route"], "/slow?page=2");
+        assert!(!response.headers.contains_key("X-Inner"));
+
+        let response = testing::get(&app(), "/unavailable").await;
+
+        assert_eq!(response.status.1, 503);
+        assert_eq!(response.headers["X-Outer"], "kept");
+    }
+
+    #[cfg(feature = "hyper_server")]
+    #[tokio::test]
+    async fn it_should_fail_requests_to_hyper_contexts_that_run_past_the_deadline() {
+        use crate::app::testing_hyper_async;
+        use crate::context::basic_hyper_context::{
+            generate_context, BasicHyperContext, HyperRequest,
+        };
+        use hyper::Body;
+
+        let mut app = App::<HyperRequest, BasicHyperContext, ()>::create(generate_context, ());
+        app.use_middleware("/", MiddlewareTuple::A(outer));
+        app.use_middleware("/slow", MiddlewareTuple::A(timeout));
+        app.post("/slow", MiddlewareTuple::A(slow));
+        let app = app.commit();
+
+        let request = hyper::Request::post("/slow?page=2")
+            .body(Body::from("a body"))
+            .unwrap();
+        let response = testing_hyper_async::request(&app, request).await;
+
+        assert_eq!(response.status, 504);
+        assert_eq!(response.body_string(), "Request timed out after 50ms");
+        assert_eq!(response.headers["x-outer"], "kept");
+        assert_eq!(response.headers["x-route"], "/slow?page=2");
+        assert_eq!(response.headers["server"], "thruster");
+        assert!(!response.headers.contains_key("x-inner"));
+    }
+}
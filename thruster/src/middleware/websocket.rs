@@ -0,0 +1,351 @@
+use async_trait::async_trait;
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use std::future::Future;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::core::context::Context;
+use crate::core::extractors::{ExtractionError, FromContext, HasRequestHeaders};
+
+pub use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+pub use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+pub use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+
+///
+/// An open websocket connection. It's a `Stream` of incoming messages and a `Sink` of outgoing
+/// ones. Pings are answered, and closes acknowledged, while the stream is being read; once the
+/// client has closed the connection, the stream ends.
+///
+pub type WebSocket = WebSocketStream<Upgraded>;
+
+pub trait HasUpgrade {
+    ///
+    /// Takes the handle that resolves to the raw connection once the response has been sent,
+    /// if the request can be upgraded.
+    ///
+    fn take_upgrade(&mut self) -> Option<OnUpgrade>;
+}
+
+///
+/// Extracts a websocket handshake, which is accepted by `on_upgrade`. Fails with a `400` if the
+/// request isn't a websocket handshake, or a `426` if it's for an unsupported version of the
+/// protocol.
+///
+/// ```rust, ignore
+/// #[middleware_fn]
+/// async fn socket(mut context: Ctx, ws: WebSocketUpgrade, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+///     ws.on_upgrade(&mut context, |mut socket| async move {
+///         while let Some(Ok(message)) = socket.next().await {
+///             if message.is_text() && socket.send(message).await.is_err() {
+///                 break;
+///             }
+///         }
+///     });
+///
+///     Ok(context)
+/// }
+/// ```
+///
+pub struct WebSocketUpgrade {
+    key: String,
+    requested_protocols: Vec<String>,
+    protocol: Option<String>,
+    config: WebSocketConfig,
+    on_upgrade: OnUpgrade,
+}
+
+impl WebSocketUpgrade {
+    ///
+    /// Sets the largest message that will be read, in bytes. Defaults to 64MiB.
+    ///
+    pub fn max_message_size(mut self, max_message_size: usize) -> WebSocketUpgrade {
+        self.config.max_message_size = Some(max_message_size);
+        self
+    }
+
+    ///
+    /// Sets the largest frame that will be read, in bytes. Defaults to 16MiB.
+    ///
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> WebSocketUpgrade {
+        self.config.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    ///
+    /// Picks the first of the client's subprotocols that's in `supported`. If there's none in
+    /// common, no subprotocol is chosen and it's up to the client whether to carry on.
+    ///
+    pub fn protocols(mut self, supported: &[&str]) -> WebSocketUpgrade {
+        self.protocol = self
+            .requested_protocols
+            .iter()
+            .find(|requested| supported.contains(&requested.as_str()))
+            .cloned();
+        self
+    }
+
+    ///
+    /// The subprotocol that was picked by `protocols`.
+    ///
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    ///
+    /// Accepts the handshake, making the response a `101 Switching Protocols`, and calls
+    /// `handler` with the connection once the response has been sent. The handler runs on its
+    /// own task, so the connection outlives the middleware chain.
+    ///
+    pub fn on_upgrade<C, F, Fut>(self, context: &mut C, handler: F)
+    where
+        C: Context,
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        context.status(101);
+        context.set("Connection", "Upgrade");
+        context.set("Upgrade", "websocket");
+        context.set(
+            "Sec-WebSocket-Accept",
+            &derive_accept_key(self.key.as_bytes()),
+        );
+
+        if let Some(protocol) = &self.protocol {
+            context.set("Sec-WebSocket-Protocol", protocol);
+        }
+
+        let on_upgrade = self.on_upgrade;
+        let config = self.config;
+        tokio::spawn(async move {
+            match on_upgrade.await {
+                Ok(upgraded) => {
+                    let socket =
+                        WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config))
+                            .await;
+
+                    handler(socket).await;
+                }
+                Err(e) => error!("Websocket upgrade failed: {}", e),
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl<C: HasRequestHeaders + HasUpgrade + Send> FromContext<C> for WebSocketUpgrade {
+    async fn from_context(context: &mut C) -> Result<Self, ExtractionError> {
+        let has_token = |header: &str, token: &str| {
+            context.request_header(header).iter().any(|value| {
+                value
+                    .split(',')
+                    .any(|value| value.trim().eq_ignore_ascii_case(token))
+            })
+        };
+
+        if !has_token("connection", "upgrade") || !has_token("upgrade", "websocket") {
+            return Err(ExtractionError::new(
+                400,
+                "Expected a websocket upgrade".to_owned(),
+            ));
+        }
+
+        if !has_token("sec-websocket-version", "13") {
+            return Err(ExtractionError::new(
+                426,
+                "Only version 13 of the websocket protocol is supported".to_owned(),
+            ));
+        }
+
+        let key = match context.request_header("sec-websocket-key").pop() {
+            Some(key) => key,
+            None => {
+                return Err(ExtractionError::new(
+                    400,
+                    "Missing Sec-WebSocket-Key header".to_owned(),
+                ))
+            }
+        };
+
+        let requested_protocols = context
+            .request_header("sec-websocket-protocol")
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|protocol| protocol.trim().to_owned())
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+
+        let on_upgrade = context
+            .take_upgrade()
+            .ok_or_else(|| ExtractionError::new(400, "Connection can't be upgraded".to_owned()))?;
+
+        Ok(WebSocketUpgrade {
+            key,
+            requested_protocols,
+            protocol: None,
+            config: WebSocketConfig::default(),
+            on_upgrade,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{FutureExt, SinkExt, StreamExt};
+    use hyper::{Body, Request};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    use crate::app::testing_hyper_async as testing;
+    use crate::context::basic_hyper_context::{
+        generate_context, BasicHyperContext as Ctx, HyperRequest,
+    };
+    use crate::core::{MiddlewareNext, MiddlewareResult};
+    use crate::hyper_server::HyperServer;
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+    use crate::{App, ThrusterServer};
+
+    #[middleware_fn(_internal)]
+    async fn echo(
+        mut context: Ctx,
+        ws: WebSocketUpgrade,
+        _next: MiddlewareNext<Ctx>,
+    ) -> MiddlewareResult<Ctx> {
+        ws.protocols(&["chat"])
+            .on_upgrade(&mut context, |mut socket| async move {
+                while let Some(Ok(message)) = socket.next().await {
+                    if message.is_text() && socket.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+        Ok(context)
+    }
+
+    fn app() -> App<HyperRequest, Ctx, ()> {
+        let mut app = App::<HyperRequest, Ctx, ()>::create(generate_context, ());
+        app.get("/echo", MiddlewareTuple::A(echo));
+        app
+    }
+
+    async fn handshake(headers: &[(&str, &str)]) -> testing::TestResponse {
+        let mut request = Request::get("/echo");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+
+        testing::request(&app().commit(), request.body(Body::empty()).unwrap()).await
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    async fn connect(port: u16) -> TcpStream {
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_accept_a_handshake_pick_a_subprotocol_and_echo_messages() {
+        let port = free_port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(HyperServer::new(app()).build_with_shutdown(
+            "127.0.0.1",
+            port,
+            stopped.map(|_| ()),
+        ));
+
+        let mut request = format!("ws://127.0.0.1:{}/echo", port)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", "mqtt, chat".parse().unwrap());
+
+        let (mut socket, response) = tokio_tungstenite::client_async(request, connect(port).await)
+            .await
+            .unwrap();
+
+        // The client has already checked the accept key against the one it sent
+        assert_eq!(response.status(), 101);
+        assert_eq!(response.headers()["sec-websocket-protocol"], "chat");
+
+        socket.send(Message::text("hello")).await.unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::text("hello")
+        );
+
+        socket.close(None).await.unwrap();
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_handshakes_on_connections_that_cant_be_upgraded() {
+        let response = handshake(&[
+            ("Connection", "keep-alive, Upgrade"),
+            ("Upgrade", "websocket"),
+            ("Sec-WebSocket-Version", "13"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ])
+        .await;
+
+        // Requests that don't come from a server have no connection to upgrade
+        assert_eq!(response.status, 400);
+        assert_eq!(response.body_string(), "Connection can't be upgraded");
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_requests_that_arent_handshakes() {
+        let response = handshake(&[]).await;
+        assert_eq!(response.status, 400);
+        assert_eq!(response.body_string(), "Expected a websocket upgrade");
+
+        let response = handshake(&[("Connection", "Upgrade"), ("Upgrade", "h2c")]).await;
+        assert_eq!(response.status, 400);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_unsupported_versions_with_a_426() {
+        let response = handshake(&[
+            ("Connection", "Upgrade"),
+            ("Upgrade", "websocket"),
+            ("Sec-WebSocket-Version", "8"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ])
+        .await;
+
+        assert_eq!(response.status, 426);
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_handshakes_without_a_key() {
+        let response = handshake(&[
+            ("Connection", "Upgrade"),
+            ("Upgrade", "websocket"),
+            ("Sec-WebSocket-Version", "13"),
+        ])
+        .await;
+
+        assert_eq!(response.status, 400);
+        assert_eq!(response.body_string(), "Missing Sec-WebSocket-Key header");
+    }
+}
@@ -2,6 +2,7 @@ use crate::core::errors::ThrusterError;
 use crate::ReusableBoxFuture;
 use paste::paste;
 use std::boxed::Box;
+use std::sync::Arc;
 use thruster_proc::generate_tuples;
 
 pub type NextFn<T> =
@@ -100,3 +101,66 @@ macro_rules! expand_combine {
 type M<T> = MiddlewareFnPointer<T>;
 expand_combine!(P, O, N, M, L, K, J, I, H, G, F, E, D, C, B, A);
 generate_tuples!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
+macro_rules! impl_into_vec {
+    ($(($variant:ident, $($field:ident),*)),*) => {
+        impl<T> MiddlewareTuple<T> {
+            /// The middleware in the tuple, in the order they run.
+            pub(crate) fn into_vec(self) -> Vec<M<T>> {
+                match self {
+                    $(MiddlewareTuple::$variant($($field),*) => vec![$($field),*],)*
+                }
+            }
+        }
+    };
+}
+
+impl_into_vec!(
+    (A, a),
+    (B, a, b),
+    (C, a, b, c),
+    (D, a, b, c, d),
+    (E, a, b, c, d, e),
+    (F, a, b, c, d, e, f),
+    (G, a, b, c, d, e, f, g),
+    (H, a, b, c, d, e, f, g, h),
+    (I, a, b, c, d, e, f, g, h, i),
+    (J, a, b, c, d, e, f, g, h, i, j),
+    (K, a, b, c, d, e, f, g, h, i, j, k),
+    (L, a, b, c, d, e, f, g, h, i, j, k, l),
+    (M, a, b, c, d, e, f, g, h, i, j, k, l, m),
+    (N, a, b, c, d, e, f, g, h, i, j, k, l, m, n),
+    (O, a, b, c, d, e, f, g, h, i, j, k, l, m, n, o),
+    (P, a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p)
+);
+
+type Terminal<T> = Arc<dyn Fn(T) -> ReusableBoxFuture<Result<T, ThrusterError<T>>> + Send + Sync>;
+
+///
+/// Chains middleware in front of a function that isn't a middleware itself, and so can't be put
+/// in a tuple, e.g. one that captures state. `terminal` is called as the `next` of the last of
+/// the middleware.
+///
+pub(crate) fn chain<T: 'static + Send>(
+    middleware: Vec<M<T>>,
+    terminal: Terminal<T>,
+) -> Box<dyn Fn(T) -> ReusableBoxFuture<Result<T, ThrusterError<T>>> + Send + Sync> {
+    let middleware: Arc<[M<T>]> = middleware.into();
+
+    Box::new(move |context| run(middleware.clone(), 0, terminal.clone(), context))
+}
+
+fn run<T: 'static + Send>(
+    middleware: Arc<[M<T>]>,
+    index: usize,
+    terminal: Terminal<T>,
+    context: T,
+) -> ReusableBoxFuture<Result<T, ThrusterError<T>>> {
+    match middleware.get(index).copied() {
+        Some(current) => current(
+            context,
+            Box::new(move |context| run(middleware, index + 1, terminal, context)),
+        ),
+        None => terminal(context),
+    }
+}
@@ -1,18 +1,97 @@
 use crate::ReusableBoxFuture;
 use fnv::FnvHashMap;
+use regex::Regex;
 
-
+use std::collections::HashMap;
 use std::str::Split;
+use std::sync::Arc;
 use std::{fmt, fmt::Debug};
 
 use crate::core::context::Context;
 use crate::core::errors::ThrusterError;
+use crate::core::guard::Guard;
 use crate::parser::middleware_traits::*;
 
 const ROOT_ROUTE_ID: &str = "__root__";
 const WILDCARD_ROUTE_ID: char = '*';
 const PARAM_ROUTE_LEADING_CHAR: char = ':';
 
+/// A restriction on the path pieces that a param node will match, parsed from the route.
+#[derive(Clone, Debug)]
+enum Constraint {
+    /// `:name`, matches any single piece.
+    Any,
+    /// `:name(regex)`, matches pieces that the whole regex matches.
+    Regex(Regex),
+    /// `:name<type>`, matches pieces that parse as the given type.
+    Typed(fn(&str) -> bool),
+    /// `:name*`, matches the rest of the path, however many pieces are left.
+    CatchAll,
+}
+
+impl Constraint {
+    /// Parses a param path piece, such as `:id<u64>`, into the param's name and its constraint.
+    fn parse(path_piece: &str) -> (String, Constraint) {
+        let param = &path_piece[1..];
+
+        if let Some(name) = param.strip_suffix(WILDCARD_ROUTE_ID) {
+            return (name.to_owned(), Constraint::CatchAll);
+        }
+
+        if let (Some(start), true) = (param.find('('), param.ends_with(')')) {
+            let pattern = format!("^(?:{})$", &param[start + 1..param.len() - 1]);
+            let regex = Regex::new(&pattern)
+                .unwrap_or_else(|e| panic!("Invalid regex in route param `{}`: {}", path_piece, e));
+
+            return (param[..start].to_owned(), Constraint::Regex(regex));
+        }
+
+        if let (Some(start), true) = (param.find('<'), param.ends_with('>')) {
+            let matches: fn(&str) -> bool = match &param[start + 1..param.len() - 1] {
+                "u8" => |v| v.parse::<u8>().is_ok(),
+                "u16" => |v| v.parse::<u16>().is_ok(),
+                "u32" => |v| v.parse::<u32>().is_ok(),
+                "u64" => |v| v.parse::<u64>().is_ok(),
+                "usize" => |v| v.parse::<usize>().is_ok(),
+                "i8" => |v| v.parse::<i8>().is_ok(),
+                "i16" => |v| v.parse::<i16>().is_ok(),
+                "i32" => |v| v.parse::<i32>().is_ok(),
+                "i64" => |v| v.parse::<i64>().is_ok(),
+                "isize" => |v| v.parse::<isize>().is_ok(),
+                "f32" => |v| v.parse::<f32>().is_ok(),
+                "f64" => |v| v.parse::<f64>().is_ok(),
+                "bool" => |v| v.parse::<bool>().is_ok(),
+                other => panic!(
+                    "Unsupported type `{}` in route param `{}`",
+                    other, path_piece
+                ),
+            };
+
+            return (param[..start].to_owned(), Constraint::Typed(matches));
+        }
+
+        (param.to_owned(), Constraint::Any)
+    }
+
+    /// The order in which params are tried, lowest first. Constrained params are tried before
+    /// unconstrained ones, and catch-alls last of all.
+    fn rank(&self) -> u8 {
+        match self {
+            Constraint::Regex(_) | Constraint::Typed(_) => 0,
+            Constraint::Any => 1,
+            Constraint::CatchAll => 2,
+        }
+    }
+
+    fn matches(&self, path_piece: &str) -> bool {
+        match self {
+            Constraint::Any | Constraint::CatchAll => true,
+            Constraint::Regex(regex) => regex.is_match(path_piece),
+            Constraint::Typed(matches) => matches(path_piece),
+        }
+    }
+}
+
 /// A single node in the route parse tree.
 pub struct Node<T: Clone + Send> {
     /// The value of the node. Not every node has a value, for example in the path /a/b, two nodes are created;
@@ -25,13 +104,22 @@ pub struct Node<T: Clone + Send> {
     /// The path piece of the param that this node matches against.
     path_piece: String,
 
-    /// The param name that will be named with this node. If a param name is set, then this node will match for
-    /// all input paths.
+    /// The route, as it was registered, that ends at this node, e.g. `/users/:id`. Set on commit.
+    route: String,
+
+    /// The name of the param that this node captures, if it's a param node.
     param_name: Option<String>,
 
+    /// The constraint on the path pieces this node matches, if it's a param node.
+    constraint: Option<Constraint>,
+
     /// The nodes which are children to this node. Empty if node is a leaf.
     children: Vec<Node<T>>,
 
+    /// The param nodes which are children to this node, ordered by the rank of their constraint.
+    /// They're tried after the exact children, and until one of them leads to a match.
+    param_nodes: Vec<Node<T>>,
+
     /// The committed middleware, only usable once node has been consumed and replaced.
     committed_middleware:
         Box<dyn Fn(T) -> ReusableBoxFuture<Result<T, ThrusterError<T>>> + Send + Sync>,
@@ -49,6 +137,10 @@ pub struct Node<T: Clone + Send> {
     /// and should be pushed down the tree on commit.
     non_leaf_value: Option<MiddlewareTuple<T>>,
 
+    /// The guards that have to pass for any route at or below this node, checked once the
+    /// non-leaf values above it have run. They're pushed down the tree on commit.
+    guards: Vec<Guard<T>>,
+
     /// A shortcut for fast matching so that we don't have to traverse the tree for trivial matches
     fastmatch_map: FnvHashMap<
         String,
@@ -83,16 +175,41 @@ impl Params {
     }
 }
 
+impl From<Params> for HashMap<String, String> {
+    fn from(params: Params) -> HashMap<String, String> {
+        params
+            .inner
+            .into_iter()
+            .map(|param| (param.key, param.param))
+            .collect()
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Param {
     key: String,
     param: String,
 }
 
+impl Param {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn param(&self) -> &str {
+        &self.param
+    }
+}
+
 pub struct NodeOutput<'m, T> {
     pub value: &'m Box<dyn Fn(T) -> ReusableBoxFuture<Result<T, ThrusterError<T>>> + Send + Sync>,
     pub params: Params,
     pub path: String,
+    /// Whether the whole path was matched by a route, as opposed to falling back to a shorter
+    /// prefix or a root level `*` catch-all (such as the 404 handler).
+    pub exact_match: bool,
+    /// Whether `value` belongs to a route, rather than being the default not found middleware.
+    is_route: bool,
 }
 
 pub struct OwnedNodeOutput<'m, T> {
@@ -117,14 +234,17 @@ impl<T: 'static + Context + Clone + Send> Default for Node<T> {
             value: None,
             wildcard_node: None,
             path_piece: ROOT_ROUTE_ID.to_string(),
+            route: String::new(),
             param_name: None,
+            constraint: None,
             children: vec![],
+            param_nodes: vec![],
             committed_middleware: Box::new(|c| {
                 ReusableBoxFuture::new(async move {
                     Err(ThrusterError {
                         context: c,
                         message: "Not found".to_string(),
-                        status: 1,
+                        status: 404,
                         cause: None,
                     })
                 })
@@ -133,6 +253,7 @@ impl<T: 'static + Context + Clone + Send> Default for Node<T> {
             has_committed_middleware: false,
             is_leaf: false,
             non_leaf_value: None,
+            guards: vec![],
             fastmatch_map: FnvHashMap::default(),
         }
     }
@@ -152,13 +273,19 @@ impl<T: 'static + Context + Clone + Send> Default for Node<T> {
 // }
 
 impl<T: 'static + Context + Clone + Send> Node<T> {
-    /// Gets the value at the end of the path.
-    pub fn get_value_at_path<'m, 'k: 'm>(&'k self, path: String) -> NodeOutput<'m, T> {
+    /// Gets the value at the end of the path. Any query string or fragment is ignored.
+    pub fn get_value_at_path<'m, 'k: 'm>(&'k self, mut path: String) -> NodeOutput<'m, T> {
+        if let Some(end) = path.find(['?', '#']) {
+            path.truncate(end);
+        }
+
         if let Some(value) = self.fastmatch_map.get(&path) {
             return NodeOutput {
                 value,
                 params: Params::default(),
                 path,
+                exact_match: true,
+                is_route: true,
             };
         }
 
@@ -194,6 +321,19 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
         self.add_value_at_split_path(split, value, false)
     }
 
+    /// Adds a guard for every route at or below the given path.
+    pub fn add_guard_at_path(&mut self, path: &str, guard: Guard<T>) {
+        let mut split = path.split('/');
+
+        // Strip any initial '/' chars
+        let mut chars = path.chars();
+        while chars.next() == Some('/') {
+            let _ = split.next();
+        }
+
+        self.node_at_split_path_or_insert(split).guards.push(guard);
+    }
+
     /// Merges this node with another node, attempting to combine values at matching paths.
     pub fn add_node_at_path(&mut self, path: &str, mut added_node: Node<T>) {
         let mut split = path.split('/');
@@ -217,6 +357,7 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
                 }
                 None => added_node.value,
             };
+            node.guards.append(&mut added_node.guards);
 
             // Also consider wildcards here
             let wildcard_node = node.wildcard_node.take();
@@ -244,6 +385,7 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
                             _ => None,
                         };
                     wildcard_node.value = middleware;
+                    wildcard_node.guards.append(&mut added_wildcard_node.guards);
 
                     Some(wildcard_node)
                 }
@@ -252,7 +394,11 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
                 _ => None,
             };
 
-            for child in added_node.children {
+            for child in added_node
+                .children
+                .into_iter()
+                .chain(added_node.param_nodes)
+            {
                 self.add_node_at_path(&format!("{}/{}", path, child.path_piece), child);
             }
         } else {
@@ -261,25 +407,97 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
 
             for i in 0..split_vec.len() - 1 {
                 let piece = split_vec.get(i).unwrap().to_owned();
-                let children: &mut Vec<Node<T>> = &mut last_node.children;
 
-                if let Some(index) = children.iter().position(|n| n.path_piece == piece) {
-                    last_node = children.get_mut(index).unwrap();
-                } else {
-                    let next_node = Node::<T> {
+                last_node = match last_node.child_position(piece) {
+                    Some(index) => last_node.child_at_mut(index, piece),
+                    None => last_node.insert_child(Node::<T> {
                         path_piece: piece.to_owned(),
                         ..Default::default()
-                    };
-
-                    children.push(next_node);
-
-                    last_node = children.get_mut(0).unwrap();
-                }
+                    }),
+                };
             }
 
             added_node.path_piece = split_vec.last().unwrap().to_string();
-            last_node.children.push(added_node);
+            last_node.insert_child(added_node);
+        }
+    }
+
+    /// Finds the index of the child, or param node, with the given path piece.
+    fn child_position(&self, path_piece: &str) -> Option<usize> {
+        match path_piece.chars().next() {
+            Some(PARAM_ROUTE_LEADING_CHAR) => self
+                .param_nodes
+                .iter()
+                .position(|n| n.path_piece == path_piece),
+            _ => self
+                .children
+                .iter()
+                .position(|n| n.path_piece == path_piece),
+        }
+    }
+
+    fn child_at_mut(&mut self, index: usize, path_piece: &str) -> &mut Node<T> {
+        match path_piece.chars().next() {
+            Some(PARAM_ROUTE_LEADING_CHAR) => &mut self.param_nodes[index],
+            _ => &mut self.children[index],
+        }
+    }
+
+    /// Inserts a node as a child, or a param node if its path piece is a param, and returns it.
+    fn insert_child(&mut self, mut node: Node<T>) -> &mut Node<T> {
+        if !node.path_piece.starts_with(PARAM_ROUTE_LEADING_CHAR) {
+            self.children.push(node);
+
+            return self.children.last_mut().unwrap();
+        }
+
+        let (param_name, constraint) = Constraint::parse(&node.path_piece);
+        let rank = constraint.rank();
+        node.param_name = Some(param_name);
+        node.constraint = Some(constraint);
+
+        let index = self
+            .param_nodes
+            .iter()
+            .position(|n| n.constraint.as_ref().map(|c| c.rank()).unwrap_or(0) > rank)
+            .unwrap_or(self.param_nodes.len());
+        self.param_nodes.insert(index, node);
+
+        &mut self.param_nodes[index]
+    }
+
+    /// Finds the node at the end of the path, adding any nodes on the way that don't exist yet.
+    fn node_at_split_path_or_insert(&mut self, mut split: Split<char>) -> &mut Node<T> {
+        let path_piece = match split.next() {
+            Some(path_piece) if !path_piece.is_empty() => path_piece,
+            _ => return self,
+        };
+
+        if path_piece.starts_with(WILDCARD_ROUTE_ID) {
+            return self
+                .wildcard_node
+                .get_or_insert_with(|| {
+                    Box::new(Node::<T> {
+                        path_piece: WILDCARD_ROUTE_ID.to_string(),
+                        ..Default::default()
+                    })
+                })
+                .node_at_split_path_or_insert(split);
         }
+
+        let child = match self.child_position(path_piece) {
+            Some(index) => self.child_at_mut(index, path_piece),
+            None => self.insert_child(Node::<T> {
+                path_piece: path_piece.to_owned(),
+                ..Default::default()
+            }),
+        };
+
+        child.node_at_split_path_or_insert(split)
+    }
+
+    fn is_catch_all(&self) -> bool {
+        matches!(self.constraint, Some(Constraint::CatchAll))
     }
 
     pub(crate) fn get_node_at_split_path(
@@ -292,9 +510,10 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
             None => Some(self),
             Some(path_piece) => match path_piece.chars().next() {
                 Some(PARAM_ROUTE_LEADING_CHAR) => self
-                    .wildcard_node
-                    .as_mut()
-                    .and_then(|w| w.get_node_at_split_path(split)),
+                    .param_nodes
+                    .iter_mut()
+                    .find(|n| n.path_piece == path_piece)
+                    .and_then(|n| n.get_node_at_split_path(split)),
                 Some(WILDCARD_ROUTE_ID) => self
                     .wildcard_node
                     .as_mut()
@@ -321,16 +540,18 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
             Some(node_value) => v.combine(node_value),
             None => v,
         });
+        self.guards.append(&mut node.guards);
 
         let mut missing_nodes = vec![];
 
         // Merge child nodes, yes n^2, but this is on build so it's only done on init.
-        for incoming_child in node.children.into_iter() {
+        for incoming_child in node.children.into_iter().chain(node.param_nodes) {
             let path_piece = incoming_child.path_piece.clone();
 
             let child = self
                 .children
                 .iter_mut()
+                .chain(self.param_nodes.iter_mut())
                 .find(|child| child.path_piece == path_piece);
 
             match child {
@@ -339,53 +560,115 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
             };
         }
 
-        self.children.append(&mut missing_nodes);
+        for missing_node in missing_nodes {
+            self.insert_child(missing_node);
+        }
+    }
+
+    /// The output for a path that ends at this node.
+    fn output<'m, 'k: 'm>(&'k self) -> NodeOutput<'m, T> {
+        let path = match self.route.as_str() {
+            _ if !self.has_committed_middleware => String::new(),
+            "" => "/".to_owned(),
+            route => route.to_owned(),
+        };
+
+        NodeOutput {
+            value: &self.committed_middleware,
+            params: Params::default(),
+            path,
+            exact_match: self.has_committed_middleware,
+            is_route: self.has_committed_middleware,
+        }
+    }
+
+    /// Matches this node, as a candidate child of its parent, against the next path piece.
+    /// Returns `None` if the piece doesn't meet the node's constraint.
+    fn match_piece<'m, 'k: 'm, 'p>(
+        &'k self,
+        path_piece: &'p str,
+        path: Split<'p, char>,
+    ) -> Option<NodeOutput<'m, T>> {
+        let constraint = match &self.constraint {
+            Some(constraint) => constraint,
+            None => return Some(self.get_value_at_split_path(path)),
+        };
+
+        if !constraint.matches(path_piece) {
+            return None;
+        }
+
+        let param_name = self.param_name.as_deref().unwrap_or_default();
+
+        if let Constraint::CatchAll = constraint {
+            let rest = std::iter::once(path_piece)
+                .chain(path)
+                .collect::<Vec<&str>>()
+                .join("/");
+            let mut res = self.output();
+            res.params.add(param_name, &rest);
+
+            return Some(res);
+        }
+
+        let mut res = self.get_value_at_split_path(path);
+        res.params.add(param_name, path_piece);
+
+        Some(res)
     }
 
     pub(crate) fn get_value_at_split_path<'m, 'k: 'm>(
         &'k self,
-        mut path: Split<char>,
+        path: Split<char>,
     ) -> NodeOutput<'m, T> {
-        let path_piece = path.next();
+        let mut rest = path.clone();
 
-        match path_piece {
-            None => NodeOutput {
-                value: &self.committed_middleware,
-                params: Params::default(),
-                path: "".to_owned(),
-            },
-            Some(path_piece) => {
-                // Check exact children
-                for child in self.children.iter() {
-                    if child.path_piece == path_piece {
-                        let mut res = child.get_value_at_split_path(path);
+        let path_piece = match rest.next() {
+            // A trailing slash matches the same as the path without one
+            Some("") if rest.clone().next().is_none() => return self.output(),
+            Some(path_piece) => path_piece,
+            None => return self.output(),
+        };
 
-                        if let Some(param) = &self.param_name {
-                            res.params.add(param, path_piece);
-                        }
+        // Candidates in order of precedence: the exact child, constrained params, unconstrained
+        // params, the wildcard, and finally catch-all params. The first to lead to a route that
+        // matches the whole path wins.
+        let candidates = self
+            .children
+            .iter()
+            .find(|child| child.path_piece == path_piece)
+            .into_iter()
+            .chain(self.param_nodes.iter().filter(|n| !n.is_catch_all()))
+            .chain(self.wildcard_node.as_deref())
+            .chain(self.param_nodes.iter().filter(|n| n.is_catch_all()));
+
+        let mut fallback = None;
+        for candidate in candidates {
+            let mut res = match candidate.match_piece(path_piece, rest.clone()) {
+                Some(res) => res,
+                None => continue,
+            };
 
-                        return res;
-                    }
-                }
+            // A bare `*` at the root is a catch-all rather than a route
+            if self.path_piece == ROOT_ROUTE_ID
+                && candidate.path_piece.starts_with(WILDCARD_ROUTE_ID)
+            {
+                res.exact_match = false;
+            }
 
-                // Check wildcard child
-                match self.wildcard_node.as_ref() {
-                    Some(wildcard_node) => {
-                        let mut res = wildcard_node.get_value_at_split_path(path);
-                        if let Some(param) = &self.param_name {
-                            res.params.add(param, path_piece);
-                        }
+            if res.exact_match {
+                return res;
+            }
 
-                        res
-                    }
-                    None => NodeOutput {
-                        value: &self.committed_middleware,
-                        params: Params::default(),
-                        path: "".to_owned(),
-                    },
-                }
+            if fallback.is_none() && res.is_route {
+                fallback = Some(res);
             }
         }
+
+        fallback.unwrap_or_else(|| NodeOutput {
+            exact_match: false,
+            ..self.output()
+        })
     }
 
     pub(crate) fn add_value_at_split_path(
@@ -399,22 +682,17 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
         match path_piece {
             Some(path_piece) => {
                 match path_piece.chars().next() {
-                    Some(PARAM_ROUTE_LEADING_CHAR) => match self.wildcard_node.as_mut() {
-                        Some(wildcard_node) => {
-                            wildcard_node.add_value_at_split_path(path, value, is_leaf);
-                            self.param_name = Some(path_piece[1..].to_string());
-                        }
-                        None => {
-                            let mut wildcard_node = Node::<T> {
-                                path_piece: WILDCARD_ROUTE_ID.to_string(),
+                    Some(PARAM_ROUTE_LEADING_CHAR) => {
+                        let param_node = match self.child_position(path_piece) {
+                            Some(index) => self.child_at_mut(index, path_piece),
+                            None => self.insert_child(Node::<T> {
+                                path_piece: path_piece.to_owned(),
                                 ..Default::default()
-                            };
-                            wildcard_node.add_value_at_split_path(path, value, is_leaf);
+                            }),
+                        };
 
-                            self.param_name = Some(path_piece[1..].to_string());
-                            self.wildcard_node = Some(Box::new(wildcard_node));
-                        }
-                    },
+                        param_node.add_value_at_split_path(path, value, is_leaf);
+                    }
                     Some(WILDCARD_ROUTE_ID) => match self.wildcard_node.as_mut() {
                         Some(wildcard_node) => {
                             wildcard_node.add_value_at_split_path(path, value, is_leaf);
@@ -492,12 +770,20 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
     }
 
     pub(crate) fn commit(self) -> Self {
-        let mut committed = self.commit_inner(None);
+        let mut committed = self.commit_inner(None, vec![], String::new());
 
         let enumerations = committed.enumerate("");
+        let root_prefix = format!("/{}", committed.path_piece);
 
         for (path, committed_value, _value) in enumerations {
             if let Some(value) = committed_value {
+                // Enumerated paths start with the root, which incoming paths don't
+                let path = match path.strip_prefix(&root_prefix) {
+                    Some("") => "/".to_owned(),
+                    Some(path) => path.to_owned(),
+                    None => path,
+                };
+
                 committed.fastmatch_map.insert(path, value.middleware());
             }
         }
@@ -505,7 +791,12 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
         committed
     }
 
-    fn commit_inner(mut self, collected_middleware: Option<MiddlewareTuple<T>>) -> Self {
+    fn commit_inner(
+        mut self,
+        collected_middleware: Option<MiddlewareTuple<T>>,
+        mut collected_guards: Vec<Guard<T>>,
+        route: String,
+    ) -> Self {
         let updated_collected_middleware = match self.non_leaf_value {
             Some(non_leaf_value) => match collected_middleware {
                 Some(collected_middleware) => Some(collected_middleware.combine(non_leaf_value)),
@@ -513,14 +804,31 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
             },
             None => collected_middleware,
         };
+        collected_guards.append(&mut self.guards);
 
-        let children = self
-            .children
-            .into_iter()
-            .map(|c| c.commit_inner(updated_collected_middleware.clone()))
-            .collect();
+        let commit_child = |child: Node<T>| {
+            let route = format!("{}/{}", route, child.path_piece);
+
+            child.commit_inner(
+                updated_collected_middleware.clone(),
+                collected_guards.clone(),
+                route,
+            )
+        };
+
+        let children = self.children.into_iter().map(commit_child).collect();
+        let param_nodes = self.param_nodes.into_iter().map(commit_child).collect();
         let has_committed_middleware = self.value.is_some();
         let (committed, committed_tuple) = match self.value.take() {
+            // Guarded routes can't be a tuple, so they're left out of the fastmatch map
+            Some(v) if !collected_guards.is_empty() => (
+                guarded(
+                    updated_collected_middleware.clone(),
+                    collected_guards.clone(),
+                    v,
+                ),
+                None,
+            ),
             Some(v) => match updated_collected_middleware.clone() {
                 Some(updated_collected_middleware) => {
                     let combined = updated_collected_middleware.combine(v);
@@ -534,22 +842,57 @@ impl<T: 'static + Context + Clone + Send> Node<T> {
 
         Node {
             value: None,
-            wildcard_node: self
-                .wildcard_node
-                .map(|n| Box::new(n.commit_inner(updated_collected_middleware.clone()))),
+            wildcard_node: self.wildcard_node.map(|n| Box::new(commit_child(*n))),
             path_piece: self.path_piece,
+            route,
             param_name: self.param_name,
+            constraint: self.constraint,
             children,
+            param_nodes,
             committed_middleware: committed,
             committed_value: committed_tuple,
             has_committed_middleware,
             is_leaf: self.is_leaf,
             non_leaf_value: None,
+            guards: vec![],
             fastmatch_map: FnvHashMap::default(),
         }
     }
 }
 
+///
+/// Runs the collected middleware, then the guards, and then the route's own middleware if they
+/// all pass. Otherwise the request is answered with a `403`.
+///
+fn guarded<T: 'static + Context + Clone + Send>(
+    collected_middleware: Option<MiddlewareTuple<T>>,
+    guards: Vec<Guard<T>>,
+    value: MiddlewareTuple<T>,
+) -> Box<dyn Fn(T) -> ReusableBoxFuture<Result<T, ThrusterError<T>>> + Send + Sync> {
+    let route = value.middleware();
+    let middleware = collected_middleware.map_or_else(Vec::new, MiddlewareTuple::into_vec);
+
+    let terminal = Arc::new(move |mut context: T| {
+        if guards.iter().all(|guard| guard.check(&context)) {
+            return route(context);
+        }
+
+        context.status(403);
+        context.set_body(b"Forbidden".to_vec());
+
+        ReusableBoxFuture::new(async move {
+            Err(ThrusterError {
+                context,
+                message: "Forbidden".to_string(),
+                status: 403,
+                cause: None,
+            })
+        })
+    });
+
+    chain(middleware, terminal)
+}
+
 // impl<T: Debug> Node<T> {
 //     /// Prints the tree at the current level and all levels beneath with appropraite indentation starting
 //     /// with zero indentation.
@@ -605,7 +948,7 @@ impl<T: Clone + Send> Node<T> {
             }
         );
 
-        for child in self.children.iter() {
+        for child in self.children.iter().chain(self.param_nodes.iter()) {
             val = format!(
                 "{}\n{}",
                 val,
@@ -671,6 +1014,11 @@ pub mod test {
         fn remove(&mut self, _key: &str) {
             panic!("Don't set a header...");
         }
+
+        /// status is used to set the status code of the outgoing response.
+        fn status(&mut self, _code: u32) {
+            panic!("Don't set a status...");
+        }
     }
 
     #[test]
@@ -819,6 +1167,221 @@ pub mod test {
             });
     }
 
+    #[test]
+    fn it_should_only_flag_full_matches_as_exact() {
+        async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 1)
+        }
+        async fn f2(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 2)
+        }
+
+        let mut root: Node<i32> = Node::default();
+
+        root.add_value_at_path("/a/b", MiddlewareTuple::A(pinbox!(i32, f1)));
+        root.add_value_at_path("/*", MiddlewareTuple::A(pinbox!(i32, f2)));
+
+        let committed = root.commit();
+
+        assert!(committed.get_value_at_path("/a/b".to_owned()).exact_match);
+        assert!(!committed.get_value_at_path("/a/b/c".to_owned()).exact_match);
+        assert!(!committed.get_value_at_path("/d".to_owned()).exact_match);
+    }
+
+    #[test]
+    fn it_should_fall_through_params_whose_constraints_dont_match() {
+        async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 1)
+        }
+        async fn f2(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 2)
+        }
+        async fn f3(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 3)
+        }
+
+        let _ = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let mut root: Node<i32> = Node::default();
+
+                root.add_value_at_path("/a/:slug", MiddlewareTuple::A(pinbox!(i32, f2)));
+                root.add_value_at_path("/a/:id<u64>", MiddlewareTuple::A(pinbox!(i32, f1)));
+                root.add_value_at_path("/b/:rest*", MiddlewareTuple::A(pinbox!(i32, f3)));
+
+                let committed = root.commit();
+
+                let node = committed.get_value_at_path("/a/42".to_owned());
+                assert!(node.params.get("id").unwrap().param == "42");
+                assert!((node.value)(0).await.unwrap() == 1);
+
+                let node = committed.get_value_at_path("/a/forty-two".to_owned());
+                assert!(node.params.get("slug").unwrap().param == "forty-two");
+                assert!((node.value)(0).await.unwrap() == 2);
+
+                let node = committed.get_value_at_path("/b/c/d.txt".to_owned());
+                assert!(node.params.get("rest").unwrap().param == "c/d.txt");
+                assert!((node.value)(0).await.unwrap() == 3);
+            });
+    }
+
+    #[test]
+    fn it_should_match_params_against_their_regex() {
+        async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 1)
+        }
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let mut root: Node<i32> = Node::default();
+
+                root.add_value_at_path(
+                    "/files/:name([a-z]+\\.txt)",
+                    MiddlewareTuple::A(pinbox!(i32, f1)),
+                );
+
+                let committed = root.commit();
+
+                let node = committed.get_value_at_path("/files/notes.txt".to_owned());
+                assert!(node.exact_match);
+                assert!(node.params.get("name").unwrap().param == "notes.txt");
+                assert!((node.value)(0).await.unwrap() == 1);
+
+                // The regex has to match the whole piece
+                let node = committed.get_value_at_path("/files/notes.txt.exe".to_owned());
+                assert!(!node.exact_match);
+                assert!(node.params.get("name").is_none());
+
+                let node = committed.get_value_at_path("/files/Notes.txt".to_owned());
+                assert!(!node.exact_match);
+            });
+    }
+
+    #[test]
+    fn it_should_not_match_typed_params_that_dont_parse() {
+        async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 1)
+        }
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let mut root: Node<i32> = Node::default();
+
+                root.add_value_at_path("/a/:id<u8>", MiddlewareTuple::A(pinbox!(i32, f1)));
+                root.add_value_at_path("/b/:flag<bool>", MiddlewareTuple::A(pinbox!(i32, f1)));
+
+                let committed = root.commit();
+
+                assert!(committed.get_value_at_path("/a/255".to_owned()).exact_match);
+                assert!(!committed.get_value_at_path("/a/256".to_owned()).exact_match);
+                assert!(!committed.get_value_at_path("/a/-1".to_owned()).exact_match);
+                assert!(
+                    committed
+                        .get_value_at_path("/b/true".to_owned())
+                        .exact_match
+                );
+                assert!(!committed.get_value_at_path("/b/yes".to_owned()).exact_match);
+
+                // Not found, rather than the route with an unchecked param
+                let node = committed.get_value_at_path("/a/abc".to_owned());
+                assert!((node.value)(0).await.unwrap_err().status == 404);
+            });
+    }
+
+    #[test]
+    fn it_should_fall_back_to_the_next_param_when_the_rest_of_the_path_doesnt_match() {
+        async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 1)
+        }
+        async fn f2(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 2)
+        }
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let mut root: Node<i32> = Node::default();
+
+                root.add_value_at_path("/a/:id<u64>/edit", MiddlewareTuple::A(pinbox!(i32, f1)));
+                root.add_value_at_path("/a/:slug/show", MiddlewareTuple::A(pinbox!(i32, f2)));
+
+                let committed = root.commit();
+
+                let node = committed.get_value_at_path("/a/42/edit".to_owned());
+                assert!(node.params.get("id").unwrap().param == "42");
+                assert!(node.params.get("slug").is_none());
+                assert!((node.value)(0).await.unwrap() == 1);
+
+                // `42` meets the constraint, but only the unconstrained route goes on to `show`
+                let node = committed.get_value_at_path("/a/42/show".to_owned());
+                assert!(node.params.get("slug").unwrap().param == "42");
+                assert!(node.params.get("id").is_none());
+                assert!((node.value)(0).await.unwrap() == 2);
+            });
+    }
+
+    #[test]
+    fn it_should_try_catch_alls_after_everything_else() {
+        async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 1)
+        }
+        async fn f2(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 2)
+        }
+        async fn f3(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 3)
+        }
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let mut root: Node<i32> = Node::default();
+
+                root.add_value_at_path("/a/:rest*", MiddlewareTuple::A(pinbox!(i32, f3)));
+                root.add_value_at_path("/a/:b", MiddlewareTuple::A(pinbox!(i32, f2)));
+                root.add_value_at_path("/a/b/c", MiddlewareTuple::A(pinbox!(i32, f1)));
+
+                let committed = root.commit();
+
+                let node = committed.get_value_at_path("/a/b/c".to_owned());
+                assert!((node.value)(0).await.unwrap() == 1);
+
+                let node = committed.get_value_at_path("/a/x".to_owned());
+                assert!((node.value)(0).await.unwrap() == 2);
+
+                let node = committed.get_value_at_path("/a/b/d".to_owned());
+                assert!(node.exact_match);
+                assert!(node.params.get("rest").unwrap().param == "b/d");
+                assert!((node.value)(0).await.unwrap() == 3);
+            });
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid regex in route param")]
+    fn it_should_panic_on_an_invalid_regex() {
+        async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 1)
+        }
+
+        let mut root: Node<i32> = Node::default();
+
+        root.add_value_at_path("/a/:id([0-9)", MiddlewareTuple::A(pinbox!(i32, f1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported type `uuid`")]
+    fn it_should_panic_on_an_unsupported_type() {
+        async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
+            Ok(a + 1)
+        }
+
+        let mut root: Node<i32> = Node::default();
+
+        root.add_value_at_path("/a/:id<uuid>", MiddlewareTuple::A(pinbox!(i32, f1)));
+    }
+
     #[test]
     fn it_should_return_the_param_for_a_route() {
         async fn f1(a: i32, _b: NextFn<i32>) -> Result<i32, ThrusterError<i32>> {
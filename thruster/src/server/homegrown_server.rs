@@ -1,17 +1,18 @@
 use crate::ReusableBoxFuture;
 use async_trait::async_trait;
-use futures::{FutureExt, SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt};
 use socket2::{Domain, Socket, Type};
+use std::future::Future;
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::codec::Framed;
 
 use crate::app::App;
 use crate::core::context::Context;
-use crate::core::http::Http;
-use crate::core::request::Request;
+use crate::core::http::{decode_error_response, next_request, send_response, Http};
+use crate::core::request::{Request, RequestLimits};
 use crate::core::response::Response;
 
 // use std::thread;
@@ -20,6 +21,8 @@ use crate::core::response::Response;
 // #[cfg(not(windows))]
 // use net2::unix::UnixTcpBuilderExt;
 
+use crate::server::shutdown::{self, Watch};
+use crate::server::timeouts::Timeouts;
 use crate::server::ThrusterServer;
 
 pub struct Server<
@@ -27,11 +30,46 @@ pub struct Server<
     S: 'static + Send + Sync,
 > {
     app: Arc<App<Request, T, S>>,
+    drain_timeout: Duration,
+    request_limits: RequestLimits,
+    timeouts: Timeouts,
 }
 
 impl<T: 'static + Context<Response = Response> + Clone + Send + Sync, S: 'static + Send + Sync>
     Server<T, S>
 {
+    ///
+    /// Sets how long in-flight requests are given to finish after a shutdown signal
+    /// before their connections are dropped. Defaults to 30 seconds.
+    ///
+    pub fn drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    ///
+    /// Sets how long an idle keep-alive connection is kept open, waiting for another request,
+    /// before it's closed. Defaults to 75 seconds.
+    ///
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) {
+        self.timeouts.keep_alive = timeout;
+    }
+
+    ///
+    /// Sets how long a client has to send the head of a request once it's started, after which
+    /// it's answered with a `408` and its connection is closed. Defaults to 30 seconds.
+    ///
+    pub fn header_read_timeout(&mut self, timeout: Duration) {
+        self.timeouts.header_read = timeout;
+    }
+
+    ///
+    /// Sets the limits on the size of incoming requests. Requests going over them are answered
+    /// with a `414`, `431` or `413` and their connection is closed.
+    ///
+    pub fn request_limits(&mut self, limits: RequestLimits) {
+        self.request_limits = limits;
+    }
+
     ///
     /// Starts the app with the default tokio runtime execution model
     ///
@@ -51,18 +89,32 @@ impl<T: 'static + Context<Response = Response> + Clone + Send + Sync, S: 'static
     /// https://users.rust-lang.org/t/getting-tokio-to-match-actix-web-performance/18659/7
     ///
     pub fn start_small_load_optimized(self, host: &str, port: u16) {
-        // panic!("Nope!");
+        self.start_small_load_optimized_with_shutdown(host, port, futures::future::pending())
+    }
 
+    ///
+    /// Same as `start_small_load_optimized`, but returns once `shutdown` has resolved and every
+    /// thread has finished draining.
+    ///
+    pub fn start_small_load_optimized_with_shutdown<F>(self, host: &str, port: u16, shutdown: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
         let addr = (host, port).to_socket_addrs().unwrap().next().unwrap();
         let mut threads = Vec::new();
 
-        let arc_app = Arc::new(self.app);
+        let arc_app = self.app;
+        let drain_timeout = self.drain_timeout;
+        let request_limits = self.request_limits;
+        let timeouts = self.timeouts;
+        let shutdown = shutdown.shared();
 
         for _ in 0..num_cpus::get() {
             let arc_app = arc_app.clone();
+            let shutdown = shutdown.clone();
             threads.push(std::thread::spawn(move || {
                 let runtime = tokio::runtime::Builder::new_current_thread()
-                    .enable_io()
+                    .enable_all()
                     .build()
                     .unwrap();
 
@@ -82,12 +134,15 @@ impl<T: 'static + Context<Response = Response> + Clone + Send + Sync, S: 'static
                         tokio::net::TcpListener::from_std(listener).unwrap()
                     };
 
-                    TcpListenerStream::new(listener)
-                        .for_each(move |socket| {
-                            process(Arc::clone(&arc_app), socket.unwrap());
-                            async {}
-                        })
-                        .await;
+                    serve(
+                        arc_app,
+                        listener,
+                        shutdown,
+                        drain_timeout,
+                        request_limits,
+                        timeouts,
+                    )
+                    .await;
                 };
 
                 runtime.block_on(server);
@@ -112,30 +167,87 @@ impl<T: Context<Response = Response> + Clone + Send + Sync, S: 'static + Send +
     fn new(mut app: App<Self::Request, T, S>) -> Self {
         app = app.commit();
 
-        Server { app: Arc::new(app) }
+        Server {
+            app: Arc::new(app),
+            drain_timeout: shutdown::DEFAULT_DRAIN_TIMEOUT,
+            request_limits: RequestLimits::default(),
+            timeouts: Timeouts::default(),
+        }
     }
 
     fn build(self, host: &str, port: u16) -> ReusableBoxFuture<()> {
+        self.build_with_shutdown(host, port, futures::future::pending())
+    }
+
+    fn build_with_shutdown<F>(self, host: &str, port: u16, shutdown: F) -> ReusableBoxFuture<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
         let addr = (host, port).to_socket_addrs().unwrap().next().unwrap();
 
         // self.app._route_parser.optimize();
 
         let arc_app = self.app;
-        let listener_fut = TcpListener::bind(addr).then(move |listener| {
-            TcpListenerStream::new(listener.unwrap()).for_each(move |res| {
-                if let Ok(stream) = res {
-                    let cloned = arc_app.clone();
-                    tokio::spawn(process(cloned, stream));
-                }
+        let drain_timeout = self.drain_timeout;
+        let request_limits = self.request_limits;
+        let timeouts = self.timeouts;
+        let listener_fut = async move {
+            let listener = TcpListener::bind(addr).await.unwrap();
 
-                async {}
-            })
-        });
+            serve(
+                arc_app,
+                listener,
+                shutdown,
+                drain_timeout,
+                request_limits,
+                timeouts,
+            )
+            .await;
+        };
 
         ReusableBoxFuture::new(listener_fut)
     }
 }
 
+// Accepts connections until `shutdown` resolves, then closes the listener and drains the
+// connections that are still open.
+async fn serve<
+    T: Context<Response = Response> + Clone + Send + Sync,
+    S: 'static + Send + Sync,
+    F: Future<Output = ()>,
+>(
+    app: Arc<App<Request, T, S>>,
+    listener: TcpListener,
+    shutdown: F,
+    drain_timeout: Duration,
+    request_limits: RequestLimits,
+    timeouts: Timeouts,
+) {
+    let (signal, watch) = shutdown::channel();
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                if let Ok((stream, _)) = res {
+                    tokio::spawn(watch.clone().abortable(process(
+                        app.clone(),
+                        stream,
+                        request_limits,
+                        timeouts,
+                        watch.clone(),
+                    )));
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(listener);
+    drop(watch);
+    signal.drain(drain_timeout).await;
+}
+
 struct _Error {
     _message: String,
 }
@@ -143,27 +255,72 @@ struct _Error {
 fn process<T: Context<Response = Response> + Clone + Send + Sync, S: 'static + Send + Sync>(
     app: Arc<App<Request, T, S>>,
     socket: TcpStream,
+    request_limits: RequestLimits,
+    timeouts: Timeouts,
+    mut watch: Watch,
 ) -> ReusableBoxFuture<Result<(), _Error>> {
     ReusableBoxFuture::new(async move {
-        let mut framed = Framed::new(socket, Http);
+        let ip = socket.peer_addr().map(|v| v.ip()).ok();
+        let mut framed = Framed::new(socket, Http::new(request_limits));
+
+        loop {
+            let request = tokio::select! {
+                request = next_request(
+                    &mut framed,
+                    timeouts.keep_alive,
+                    timeouts.header_read,
+                ) => Some(request),
+                _ = watch.signaled() => None,
+            };
+
+            let request = match request {
+                Some(request) => request,
+                // A request that has started to come in is still answered while draining
+                None if !framed.read_buffer().is_empty() => {
+                    next_request(&mut framed, timeouts.keep_alive, timeouts.header_read).await
+                }
+                None => break,
+            };
+
+            let request = match request {
+                Some(request) => request,
+                None => break,
+            };
 
-        while let Some(request) = framed.next().await {
             match request {
-                Ok(request) => {
+                Ok(mut request) => {
+                    request.ip = ip;
                     let path = request.path().to_owned();
                     let method = &request.method().to_owned();
                     let matched = app.resolve_from_method_and_path(method, path);
-                    let response = app.resolve(request, matched).await.map_err(|e| _Error {
-                        _message: e.to_string(),
-                    })?;
-                    framed.send(response).await.map_err(|e| _Error {
+                    let mut response = app.resolve(request, matched).await.map_err(|e| _Error {
                         _message: e.to_string(),
                     })?;
+
+                    // Don't keep the connection alive past the current request while draining
+                    let draining = watch.is_signaled();
+                    if draining {
+                        response.header("Connection", "close");
+                    }
+
+                    send_response(&mut framed, response, method == "HEAD")
+                        .await
+                        .map_err(|e| _Error {
+                            _message: e.to_string(),
+                        })?;
+
+                    if draining {
+                        break;
+                    }
                 }
                 Err(e) => {
+                    if let Some(response) = decode_error_response(&e) {
+                        let _ = framed.send(response).await;
+                    }
+
                     return Err(_Error {
                         _message: e.to_string(),
-                    })
+                    });
                 }
             }
         }
@@ -171,3 +328,199 @@ fn process<T: Context<Response = Response> + Clone + Send + Sync, S: 'static + S
         Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::context::basic_context::BasicContext as Ctx;
+    use crate::core::{MiddlewareNext, MiddlewareResult};
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+
+    #[middleware_fn(_internal)]
+    async fn slow(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        context.body("done");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn stuck(context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn hello(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+        context.body("hello");
+        Ok(context)
+    }
+
+    fn server(drain_timeout: Duration) -> Server<Ctx, ()> {
+        let mut app = App::<Request, Ctx, ()>::new_basic();
+        app.get("/hello", MiddlewareTuple::A(hello));
+        app.get("/slow", MiddlewareTuple::A(slow));
+        app.get("/stuck", MiddlewareTuple::A(stuck));
+
+        let mut server = Server::new(app);
+        server.drain_timeout(drain_timeout);
+        server
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    // Sends a request once the server is listening, then signals shutdown while it may still be
+    // in flight
+    async fn request_then_shut_down(
+        port: u16,
+        method: &str,
+        path: &str,
+        stop: oneshot::Sender<()>,
+    ) -> String {
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        stream
+            .write_all(
+                format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).as_bytes(),
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        response
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_head_requests_with_the_length_of_the_get_body() {
+        let port = free_port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(server(Duration::from_secs(5)).build_with_shutdown(
+            "127.0.0.1",
+            port,
+            stopped.map(|_| ()),
+        ));
+
+        let response = request_then_shut_down(port, "HEAD", "/hello", stop).await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("Content-Length: 5\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+        running.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_finish_in_flight_requests_then_close_the_listener() {
+        let port = free_port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(server(Duration::from_secs(5)).build_with_shutdown(
+            "127.0.0.1",
+            port,
+            stopped.map(|_| ()),
+        ));
+
+        let response = request_then_shut_down(port, "GET", "/slow", stop).await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(response.ends_with("done"), "{}", response);
+
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("the server should have drained")
+            .unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_a_request_that_started_coming_in_before_shutdown() {
+        let port = free_port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(server(Duration::from_secs(5)).build_with_shutdown(
+            "127.0.0.1",
+            port,
+            stopped.map(|_| ()),
+        ));
+
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        stream.write_all(b"GET /hello HTTP/1.1\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream.write_all(b"Host: localhost\r\n\r\n").await.unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(response.ends_with("hello"), "{}", response);
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("the server should have drained")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_abort_connections_left_when_the_drain_times_out() {
+        let port = free_port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(server(Duration::from_millis(100)).build_with_shutdown(
+            "127.0.0.1",
+            port,
+            stopped.map(|_| ()),
+        ));
+
+        let response = request_then_shut_down(port, "GET", "/stuck", stop).await;
+
+        assert_eq!(response, "");
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("the server should have given up draining")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_shut_down_every_thread_when_small_load_optimized() {
+        let port = free_port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::task::spawn_blocking(move || {
+            server(Duration::from_secs(5)).start_small_load_optimized_with_shutdown(
+                "127.0.0.1",
+                port,
+                stopped.map(|_| ()),
+            )
+        });
+
+        let response = request_then_shut_down(port, "GET", "/slow", stop).await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("every thread should have drained")
+            .unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+}
@@ -1,19 +1,28 @@
 use crate::ReusableBoxFuture;
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt};
 use hyper::server::conn::Http;
 use hyper::service::make_service_fn;
 use hyper::service::Service;
 use hyper::{Body, Request, Response};
 use socket2::{Domain, Socket, Type};
+use std::future::Future;
+use std::io;
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::task::{Context as TaskContext, Poll};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
+use tokio::time::{Instant, Sleep};
 use tokio_stream::wrappers::TcpListenerStream;
 
 use crate::context::hyper_request::HyperRequest;
 use crate::core::context::Context;
+use crate::server::shutdown;
+use crate::server::timeouts::Timeouts;
 use crate::server::ThrusterServer;
 use crate::{app::App, core::request::ThrusterRequest};
 
@@ -25,18 +34,59 @@ impl ThrusterRequest for HyperRequest {
     fn path(&self) -> String {
         self.request.uri().to_string()
     }
+
+    fn get_header(&self, key: &str) -> Vec<String> {
+        self.request
+            .headers()
+            .get_all(key)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+            .collect()
+    }
 }
 
 pub struct HyperServer<T: 'static + Context + Clone + Send + Sync, S: 'static + Send> {
     app: App<HyperRequest, T, S>,
+    drain_timeout: Duration,
+    timeouts: Timeouts,
 }
 
 impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + Send + Sync>
     HyperServer<T, S>
 {
-    async fn process(
+    ///
+    /// Sets how long in-flight requests are given to finish after a shutdown signal
+    /// before their connections are dropped. Defaults to 30 seconds.
+    ///
+    pub fn drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    ///
+    /// Sets how long an idle keep-alive connection is kept open, waiting for another request,
+    /// before it's closed. Defaults to 75 seconds. hyper times this together with the header
+    /// read timeout, so a connection gets the two combined to go from idle to a full request
+    /// head.
+    ///
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) {
+        self.timeouts.keep_alive = timeout;
+    }
+
+    ///
+    /// Sets how long a client has to send the head of a request, after which its connection is
+    /// closed. Defaults to 30 seconds.
+    ///
+    pub fn header_read_timeout(&mut self, timeout: Duration) {
+        self.timeouts.header_read = timeout;
+    }
+
+    async fn process<F: Future<Output = ()> + Send + 'static>(
         app: Arc<App<HyperRequest, T, S>>,
         addr: SocketAddr,
+        shutdown: F,
+        drain_timeout: Duration,
+        timeouts: Timeouts,
     ) -> Result<(), hyper::Error> {
         let listener = TcpListenerStream::new({
             let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
@@ -54,8 +104,12 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
             tokio::net::TcpListener::from_std(listener).unwrap()
         });
 
-        let service = make_service_fn(|stream: &tokio::net::TcpStream| {
-            let ip = stream.peer_addr().map(|v| v.ip()).ok();
+        let keep_alive = timeouts.keep_alive;
+        let listener =
+            listener.map(move |stream| stream.map(|stream| IdleTimeout::new(stream, keep_alive)));
+
+        let service = make_service_fn(|stream: &IdleTimeout<tokio::net::TcpStream>| {
+            let ip = stream.get_ref().peer_addr().map(|v| v.ip()).ok();
             let arc_app = app.clone();
 
             async move { Ok::<_, hyper::Error>(HyperService::<T, S> { ip, app: arc_app }) }
@@ -63,43 +117,75 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
 
         let mut http = Http::new();
         http.http1_only(true);
+        http.http1_header_read_timeout(timeouts.hyper_header_read());
 
+        let (signal, deadline) = shutdown::with_deadline(shutdown, drain_timeout);
         let server =
             hyper::server::Builder::new(hyper::server::accept::from_stream(listener), http)
-                .serve(service);
+                .serve(service)
+                .with_graceful_shutdown(signal);
 
-        server.await?;
+        tokio::select! {
+            res = server => res?,
+            _ = deadline => (),
+        };
 
         Ok::<_, hyper::Error>(())
     }
 
     #[allow(dead_code)]
     pub async fn build_per_thread(self, host: &str, port: u16) {
+        self.build_per_thread_with_shutdown(host, port, futures::future::pending())
+            .await
+    }
+
+    ///
+    /// Same as `build_per_thread`, but resolves once `shutdown` has resolved and every thread
+    /// has finished draining.
+    ///
+    pub async fn build_per_thread_with_shutdown<F>(self, host: &str, port: u16, shutdown: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
         // self.app._route_parser.optimize();
 
+        let drain_timeout = self.drain_timeout;
+        let timeouts = self.timeouts;
         let arc_app = Arc::new(self.app);
         let addr = (host, port).to_socket_addrs().unwrap().next().unwrap();
+        let shutdown = shutdown.shared();
+        let mut threads = Vec::new();
 
         for _ in 0..num_cpus::get() - 1 {
             let arc_app = arc_app.clone();
+            let shutdown = shutdown.clone();
+            let (finished, thread) = oneshot::channel::<()>();
 
             std::thread::spawn(move || {
                 let rt = tokio::runtime::Builder::new_current_thread()
-                    .enable_io()
+                    .enable_all()
                     .build()
                     .unwrap();
 
-                rt.spawn(async move {
-                    Self::process(arc_app, addr)
+                rt.block_on(async move {
+                    Self::process(arc_app, addr, shutdown, drain_timeout, timeouts)
                         .await
                         .expect("Unable to spawn hyper server thread.");
                 });
+
+                let _ = finished.send(());
             });
+
+            threads.push(thread);
         }
 
-        Self::process(arc_app, addr)
+        Self::process(arc_app, addr, shutdown, drain_timeout, timeouts)
             .await
             .expect("Unable to spawn hyper server thread.");
+
+        for thread in threads {
+            let _ = thread.await;
+        }
     }
 }
 
@@ -114,16 +200,31 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
     fn new(mut app: App<Self::Request, T, Self::State>) -> Self {
         app = app.commit();
 
-        HyperServer { app }
+        HyperServer {
+            app,
+            drain_timeout: shutdown::DEFAULT_DRAIN_TIMEOUT,
+            timeouts: Timeouts::default(),
+        }
     }
 
     fn build(self, host: &str, port: u16) -> ReusableBoxFuture<()> {
+        self.build_with_shutdown(host, port, futures::future::pending())
+    }
+
+    fn build_with_shutdown<F>(self, host: &str, port: u16, shutdown: F) -> ReusableBoxFuture<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
         // self.app._route_parser.optimize();
 
+        let drain_timeout = self.drain_timeout;
+        let timeouts = self.timeouts;
         let arc_app = Arc::new(self.app);
 
         let addr = (host, port).to_socket_addrs().unwrap().next().unwrap();
-        ReusableBoxFuture::new(Self::process(arc_app, addr).map(|_| ()))
+        ReusableBoxFuture::new(
+            Self::process(arc_app, addr, shutdown, drain_timeout, timeouts).map(|_| ()),
+        )
         // .await
         // .expect("hyper server failed");
     }
@@ -152,3 +253,203 @@ impl<T: 'static + Context + Clone + Send + Sync, S: 'static + Send + Sync> Servi
         self.app.clone().match_and_resolve(req)
     }
 }
+
+///
+/// Wraps a connection for hyper, which doesn't time out idle keep-alive connections on its own.
+/// Once a response has started going out, the connection is idle until the next request comes
+/// in, and if nothing is sent either way for `timeout`, reading it ends as if the client had
+/// closed it. Handlers that take a while to respond don't count, since nothing's been sent yet.
+///
+pub(crate) struct IdleTimeout<S> {
+    io: S,
+    timeout: Duration,
+    timer: Pin<Box<Sleep>>,
+    idle: bool,
+    read_waker: Option<Waker>,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(io: S, timeout: Duration) -> IdleTimeout<S> {
+        IdleTimeout {
+            io,
+            timeout,
+            timer: Box::pin(tokio::time::sleep(timeout)),
+            idle: false,
+            read_waker: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+
+        match Pin::new(&mut this.io).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    this.idle = false;
+                }
+
+                Poll::Ready(result)
+            }
+            Poll::Pending if this.idle && this.timer.as_mut().poll(cx).is_ready() => {
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => {
+                this.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.io).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = result {
+            if written > 0 {
+                this.timer.as_mut().reset(Instant::now() + this.timeout);
+
+                // A read that's already waiting has to be polled again to start on the timer
+                if !this.idle {
+                    this.idle = true;
+                    if let Some(waker) = this.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::context::basic_hyper_context::{generate_context, BasicHyperContext as Ctx};
+    use crate::core::{MiddlewareNext, MiddlewareResult};
+    use crate::middleware_fn;
+    use crate::parser::middleware_traits::MiddlewareTuple;
+
+    #[middleware_fn(_internal)]
+    async fn hello(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+        context.body("hello");
+        Ok(context)
+    }
+
+    #[middleware_fn(_internal)]
+    async fn slow(mut context: Ctx, _next: MiddlewareNext<Ctx>) -> MiddlewareResult<Ctx> {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        context.body("done");
+        Ok(context)
+    }
+
+    fn server() -> HyperServer<Ctx, ()> {
+        let mut app = App::<HyperRequest, Ctx, ()>::create(generate_context, ());
+        app.get("/hello", MiddlewareTuple::A(hello));
+        app.get("/slow", MiddlewareTuple::A(slow));
+
+        HyperServer::new(app)
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    // Sends a request once the server is listening, then signals shutdown while it may still be
+    // in flight
+    async fn request_then_shut_down(
+        port: u16,
+        method: &str,
+        path: &str,
+        stop: oneshot::Sender<()>,
+    ) -> String {
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        stream
+            .write_all(
+                format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).as_bytes(),
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        response
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_head_requests_with_the_length_of_the_get_body() {
+        let port = free_port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running =
+            tokio::spawn(server().build_with_shutdown("127.0.0.1", port, stopped.map(|_| ())));
+
+        let response = request_then_shut_down(port, "HEAD", "/hello", stop).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("content-length: 5\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+        running.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_shut_down_every_thread_when_built_per_thread() {
+        let port = free_port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(server().build_per_thread_with_shutdown(
+            "127.0.0.1",
+            port,
+            stopped.map(|_| ()),
+        ));
+
+        let response = request_then_shut_down(port, "GET", "/slow", stop).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("done"), "{}", response);
+
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("every thread should have drained")
+            .unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+}
@@ -13,7 +13,9 @@ pub mod unix_hyper_server;
 #[cfg(all(feature = "hyper_server", feature = "tls"))]
 pub mod ssl_hyper_server;
 
+mod shutdown;
 mod thruster_server;
+mod timeouts;
 
 pub use thruster_server::ThrusterServer;
 
@@ -0,0 +1,140 @@
+use std::future::Future;
+use std::time::Duration;
+#[cfg(feature = "hyper_server")]
+use tokio::sync::oneshot;
+use tokio::sync::{mpsc, watch};
+
+/// The amount of time servers give in-flight requests to finish once a shutdown
+/// signal has been received, unless told otherwise.
+pub(crate) const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+///
+/// The half of a shutdown channel held by the accepting loop of a server. Firing
+/// it tells every connection to finish up, then waits for them to do so.
+///
+pub(crate) struct Signal {
+    tx: watch::Sender<State>,
+    drained: mpsc::Receiver<()>,
+}
+
+///
+/// The half of a shutdown channel held by each connection. As long as a `Watch`
+/// is alive, the matching `Signal` considers the server to still be draining.
+///
+#[derive(Clone)]
+pub(crate) struct Watch {
+    rx: watch::Receiver<State>,
+    _drain: mpsc::Sender<()>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Serving,
+    Draining,
+    Aborted,
+}
+
+pub(crate) fn channel() -> (Signal, Watch) {
+    let (tx, rx) = watch::channel(State::Serving);
+    let (drain_tx, drained) = mpsc::channel(1);
+
+    (
+        Signal { tx, drained },
+        Watch {
+            rx,
+            _drain: drain_tx,
+        },
+    )
+}
+
+impl Signal {
+    ///
+    /// Tells all connections to close once their current request is done, and waits
+    /// until either they have all done so or `timeout` has passed, in which case the
+    /// connections that are left are aborted.
+    ///
+    pub(crate) async fn drain(mut self, timeout: Duration) {
+        let _ = self.tx.send(State::Draining);
+
+        let drained = &mut self.drained;
+        let drained = async move { while drained.recv().await.is_some() {} };
+
+        if tokio::time::timeout(timeout, drained).await.is_err() {
+            warn!(
+                "Shutdown drain timed out after {:?}, dropping remaining connections.",
+                timeout
+            );
+
+            let _ = self.tx.send(State::Aborted);
+            while self.drained.recv().await.is_some() {}
+        }
+    }
+}
+
+impl Watch {
+    /// Whether or not shutdown has been signaled.
+    pub(crate) fn is_signaled(&self) -> bool {
+        *self.rx.borrow() != State::Serving
+    }
+
+    /// Resolves once shutdown has been signaled. Never resolves if the `Signal` was
+    /// dropped without firing.
+    pub(crate) async fn signaled(&mut self) {
+        self.reached(State::Draining).await
+    }
+
+    ///
+    /// Runs a connection until it's done, or until the drain times out and the
+    /// connection is aborted, in which case `None` is returned.
+    ///
+    pub(crate) async fn abortable<F: Future>(mut self, connection: F) -> Option<F::Output> {
+        tokio::select! {
+            output = connection => Some(output),
+            _ = self.reached(State::Aborted) => None,
+        }
+    }
+
+    async fn reached(&mut self, state: State) {
+        while *self.rx.borrow() != state && *self.rx.borrow() != State::Aborted {
+            if self.rx.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "hyper_server")]
+///
+/// Splits a user supplied shutdown future into the signal handed to hyper's
+/// `with_graceful_shutdown` and a deadline that resolves `timeout` after the
+/// signal has fired. Racing the server against the deadline bounds the drain.
+///
+pub(crate) fn with_deadline<F: Future<Output = ()> + Send + 'static>(
+    shutdown: F,
+    timeout: Duration,
+) -> (
+    impl Future<Output = ()> + Send + 'static,
+    impl Future<Output = ()> + Send + 'static,
+) {
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let signal = async move {
+        shutdown.await;
+        let _ = tx.send(());
+    };
+
+    let deadline = async move {
+        match rx.await {
+            Ok(_) => {
+                tokio::time::sleep(timeout).await;
+                warn!(
+                    "Shutdown drain timed out after {:?}, dropping remaining connections.",
+                    timeout
+                );
+            }
+            Err(_) => futures::future::pending::<()>().await,
+        }
+    };
+
+    (signal, deadline)
+}
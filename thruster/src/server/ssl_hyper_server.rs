@@ -1,8 +1,10 @@
 use futures::future;
 use futures::stream::StreamExt;
 use futures::FutureExt;
+use std::future::Future;
 use std::io::{self, BufReader};
 use std::net::ToSocketAddrs;
+use std::time::Duration;
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::sync::ReusableBoxFuture;
 
@@ -22,7 +24,9 @@ use crate::app::App;
 use crate::context::basic_hyper_context::HyperRequest;
 use crate::core::context::Context;
 
-use crate::hyper_server::HyperService;
+use crate::hyper_server::{HyperService, IdleTimeout};
+use crate::server::shutdown;
+use crate::server::timeouts::Timeouts;
 use crate::server::ThrusterServer;
 
 /// Fake certs generated using
@@ -32,6 +36,8 @@ pub struct SSLHyperServer<T: 'static + Context + Clone + Send + Sync, S: Send> {
     cert: Option<Vec<u8>>,
     key: Option<Vec<u8>>,
     tls_acceptor: Option<Arc<TlsAcceptor>>,
+    drain_timeout: Duration,
+    timeouts: Timeouts,
 }
 
 impl<T: 'static + Context + Clone + Send + Sync, S: Send> SSLHyperServer<T, S> {
@@ -46,6 +52,32 @@ impl<T: 'static + Context + Clone + Send + Sync, S: Send> SSLHyperServer<T, S> {
     pub fn key(&mut self, key: Vec<u8>) {
         self.key = Some(key);
     }
+
+    ///
+    /// Sets how long in-flight requests are given to finish after a shutdown signal
+    /// before their connections are dropped. Defaults to 30 seconds.
+    ///
+    pub fn drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    ///
+    /// Sets how long an idle keep-alive connection is kept open, waiting for another request,
+    /// before it's closed. Defaults to 75 seconds. hyper times this together with the header
+    /// read timeout, so a connection gets the two combined to go from idle to a full request
+    /// head.
+    ///
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) {
+        self.timeouts.keep_alive = timeout;
+    }
+
+    ///
+    /// Sets how long a client has to send the head of a request, after which its connection is
+    /// closed. Defaults to 30 seconds.
+    ///
+    pub fn header_read_timeout(&mut self, timeout: Duration) {
+        self.timeouts.header_read = timeout;
+    }
 }
 
 #[async_trait]
@@ -65,10 +97,19 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
             cert: None,
             key: None,
             tls_acceptor: None,
+            drain_timeout: shutdown::DEFAULT_DRAIN_TIMEOUT,
+            timeouts: Timeouts::default(),
         }
     }
 
-    fn build(mut self, host: &str, port: u16) -> ReusableBoxFuture<()> {
+    fn build(self, host: &str, port: u16) -> ReusableBoxFuture<()> {
+        self.build_with_shutdown(host, port, futures::future::pending())
+    }
+
+    fn build_with_shutdown<F>(mut self, host: &str, port: u16, shutdown: F) -> ReusableBoxFuture<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
         let addr = (host, port).to_socket_addrs().unwrap().next().unwrap();
 
         let arc_app = Arc::new(self.app);
@@ -90,8 +131,14 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
         self.tls_acceptor = Some(Arc::new(TlsAcceptor::from(Arc::new(config))));
 
         let service = make_service_fn(
-            move |_: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>| {
-                let ip = None;
+            move |stream: &IdleTimeout<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>| {
+                let ip = stream
+                    .get_ref()
+                    .get_ref()
+                    .0
+                    .peer_addr()
+                    .map(|v| v.ip())
+                    .ok();
                 let arc_app = arc_app.clone();
 
                 async move { Ok::<_, hyper::Error>(HyperService::<T, S> { ip, app: arc_app }) }
@@ -99,6 +146,9 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
         );
 
         let arc_acceptor = self.tls_acceptor.as_ref().unwrap().clone();
+        let header_read_timeout = self.timeouts.hyper_header_read();
+        let keep_alive = self.timeouts.keep_alive;
+        let (signal, deadline) = shutdown::with_deadline(shutdown, self.drain_timeout);
         let listener_fut = TcpListener::bind(addr)
             .then(move |listener| {
                 let arc_acceptor = arc_acceptor.clone();
@@ -110,13 +160,17 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
                             let acceptor = arc_acceptor.clone();
 
                             let timed_out_fut =
-                                acceptor.accept(stream).map(|timed_out| match timed_out {
-                                    Ok(val) => Some(Ok::<_, std::io::Error>(val)),
-                                    Err(e) => {
-                                        error!("TLS error: {}", e);
-                                        None
-                                    }
-                                });
+                                acceptor
+                                    .accept(stream)
+                                    .map(move |timed_out| match timed_out {
+                                        Ok(val) => Some(Ok::<_, std::io::Error>(IdleTimeout::new(
+                                            val, keep_alive,
+                                        ))),
+                                        Err(e) => {
+                                            error!("TLS error: {}", e);
+                                            None
+                                        }
+                                    });
 
                             ReusableBoxFuture::new(timed_out_fut)
                         }
@@ -127,13 +181,23 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
                     },
                 ));
 
-                Builder::new(hyper_stream, Http::new()).serve(service)
+                let mut http = Http::new();
+                http.http1_header_read_timeout(header_read_timeout);
+
+                Builder::new(hyper_stream, http)
+                    .serve(service)
+                    .with_graceful_shutdown(signal)
             })
             .map(|v| match v {
                 Ok(_) => (),
                 Err(e) => panic!("Hyper server error occurred: {:#?}", e),
             });
 
-        ReusableBoxFuture::new(listener_fut)
+        ReusableBoxFuture::new(async move {
+            tokio::select! {
+                _ = listener_fut => (),
+                _ = deadline => (),
+            }
+        })
     }
 }
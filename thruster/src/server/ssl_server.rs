@@ -1,29 +1,33 @@
 use std::error::Error;
+use std::future::Future;
 use std::net::ToSocketAddrs;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
-use futures::FutureExt;
 use native_tls::Identity;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::codec::Framed;
 use tokio_util::sync::ReusableBoxFuture;
 
 use crate::app::App;
 use crate::core::context::Context;
-use crate::core::http::Http;
-use crate::core::request::Request;
+use crate::core::http::{decode_error_response, next_request, send_response, Http};
+use crate::core::request::{Request, RequestLimits};
 use crate::core::response::Response;
 
+use crate::server::shutdown::{self, Watch};
+use crate::server::timeouts::Timeouts;
 use crate::server::ThrusterServer;
 
 pub struct SSLServer<T: 'static + Context<Response = Response> + Clone + Send + Sync, S: Send> {
     app: App<Request, T, S>,
     cert: Option<Vec<u8>>,
     cert_pass: &'static str,
+    drain_timeout: Duration,
+    request_limits: RequestLimits,
+    timeouts: Timeouts,
 }
 
 impl<T: 'static + Context<Response = Response> + Clone + Send + Sync, S: Send> SSLServer<T, S> {
@@ -37,6 +41,38 @@ impl<T: 'static + Context<Response = Response> + Clone + Send + Sync, S: Send> S
     pub fn cert_pass(&mut self, cert_pass: &'static str) {
         self.cert_pass = cert_pass;
     }
+
+    ///
+    /// Sets how long in-flight requests are given to finish after a shutdown signal
+    /// before their connections are dropped. Defaults to 30 seconds.
+    ///
+    pub fn drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    ///
+    /// Sets how long an idle keep-alive connection is kept open, waiting for another request,
+    /// before it's closed. Defaults to 75 seconds.
+    ///
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) {
+        self.timeouts.keep_alive = timeout;
+    }
+
+    ///
+    /// Sets how long a client has to send the head of a request once it's started, after which
+    /// it's answered with a `408` and its connection is closed. Defaults to 30 seconds.
+    ///
+    pub fn header_read_timeout(&mut self, timeout: Duration) {
+        self.timeouts.header_read = timeout;
+    }
+
+    ///
+    /// Sets the limits on the size of incoming requests. Requests going over them are answered
+    /// with a `414`, `431` or `413` and their connection is closed.
+    ///
+    pub fn request_limits(&mut self, limits: RequestLimits) {
+        self.request_limits = limits;
+    }
 }
 
 #[async_trait]
@@ -55,13 +91,20 @@ impl<T: Context<Response = Response> + Clone + Send + Sync, S: 'static + Send +
             app,
             cert: None,
             cert_pass: "",
+            drain_timeout: shutdown::DEFAULT_DRAIN_TIMEOUT,
+            request_limits: RequestLimits::default(),
+            timeouts: Timeouts::default(),
         }
     }
 
-    ///
-    /// Alias for start_work_stealing_optimized
-    ///
     fn build(self, host: &str, port: u16) -> ReusableBoxFuture<()> {
+        self.build_with_shutdown(host, port, futures::future::pending())
+    }
+
+    fn build_with_shutdown<F>(self, host: &str, port: u16, shutdown: F) -> ReusableBoxFuture<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
         if self.cert.is_none() {
             panic!("Cert is required to be set via SSLServer::cert() before starting the server");
         }
@@ -78,22 +121,44 @@ impl<T: Context<Response = Response> + Clone + Send + Sync, S: 'static + Send +
         );
         let arc_app = Arc::new(self.app);
         let arc_acceptor = Arc::new(tls_acceptor);
-
-        let listener_fut = TcpListener::bind(addr).then(move |listener| {
-            TcpListenerStream::new(listener.unwrap()).for_each(move |res| {
-                if let Ok(stream) = res {
-                    let cloned_app = arc_app.clone();
-                    let cloned_tls_acceptor = arc_acceptor.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = process(cloned_app, cloned_tls_acceptor, stream).await {
-                            println!("failed to process connection; error = {}", e);
+        let drain_timeout = self.drain_timeout;
+        let request_limits = self.request_limits;
+        let timeouts = self.timeouts;
+
+        let listener_fut = async move {
+            let listener = TcpListener::bind(addr).await.unwrap();
+            let (signal, watch) = shutdown::channel();
+
+            tokio::pin!(shutdown);
+            loop {
+                tokio::select! {
+                    res = listener.accept() => {
+                        if let Ok((stream, _)) = res {
+                            let cloned_app = arc_app.clone();
+                            let cloned_tls_acceptor = arc_acceptor.clone();
+                            let connection = process(
+                                cloned_app,
+                                cloned_tls_acceptor,
+                                stream,
+                                request_limits,
+                                timeouts,
+                                watch.clone(),
+                            );
+                            tokio::spawn(watch.clone().abortable(async move {
+                                if let Err(e) = connection.await {
+                                    println!("failed to process connection; error = {}", e);
+                                }
+                            }));
                         }
-                    });
+                    }
+                    _ = &mut shutdown => break,
                 }
+            }
 
-                async {}
-            })
-        });
+            drop(listener);
+            drop(watch);
+            signal.drain(drain_timeout).await;
+        };
 
         ReusableBoxFuture::new(listener_fut)
     }
@@ -103,19 +168,48 @@ async fn process<T: Context<Response = Response> + Clone + Send + Sync, S: 'stat
     app: Arc<App<Request, T, S>>,
     tls_acceptor: Arc<tokio_native_tls::TlsAcceptor>,
     socket: TcpStream,
+    request_limits: RequestLimits,
+    timeouts: Timeouts,
+    mut watch: Watch,
 ) -> Result<(), Box<dyn Error>> {
+    let ip = socket.peer_addr().map(|v| v.ip()).ok();
     let tls = tls_acceptor.accept(socket).await?;
-    let mut framed = Framed::new(tls, Http);
+    let mut framed = Framed::new(tls, Http::new(request_limits));
+
+    loop {
+        let request = tokio::select! {
+            request = next_request(
+                &mut framed,
+                timeouts.keep_alive,
+                timeouts.header_read,
+            ) => match request {
+                Some(request) => request,
+                None => break,
+            },
+            _ = watch.signaled() => break,
+        };
 
-    while let Some(request) = framed.next().await {
         match request {
-            Ok(request) => {
+            Ok(mut request) => {
+                request.ip = ip;
+                let head = request.method() == "HEAD";
                 let matched =
                     app.resolve_from_method_and_path(request.method(), request.path().to_owned());
                 let response = app.resolve(request, matched).await?;
-                framed.send(response).await?;
+                send_response(&mut framed, response, head).await?;
+            }
+            Err(e) => {
+                if let Some(response) = decode_error_response(&e) {
+                    let _ = framed.send(response).await;
+                }
+
+                return Err(e.into());
             }
-            Err(e) => return Err(e.into()),
+        }
+
+        // Don't keep the connection alive past the current request while draining
+        if watch.is_signaled() {
+            break;
         }
     }
 
@@ -1,3 +1,5 @@
+use std::future::Future;
+
 use crate::core::context::Context;
 use crate::ReusableBoxFuture;
 use crate::{app::App, core::request::ThrusterRequest};
@@ -9,7 +11,30 @@ pub trait ThrusterServer {
     type State: Send;
 
     fn new(_: App<Self::Request, Self::Context, Self::State>) -> Self;
+
     fn build(self, host: &str, port: u16) -> ReusableBoxFuture<()>;
+
+    ///
+    /// Builds a future that serves the app until `shutdown` resolves. Servers that
+    /// support it then stop accepting new connections, let in-flight requests finish
+    /// within their drain timeout, and close idle keep-alive connections. Otherwise,
+    /// the server from `build` is dropped as soon as `shutdown` resolves.
+    ///
+    fn build_with_shutdown<F>(self, host: &str, port: u16, shutdown: F) -> ReusableBoxFuture<()>
+    where
+        Self: Sized,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let server = self.build(host, port);
+
+        ReusableBoxFuture::new(async move {
+            tokio::select! {
+                _ = server => (),
+                _ = shutdown => (),
+            }
+        })
+    }
+
     fn start(self, host: &str, port: u16)
     where
         Self: Sized,
@@ -18,4 +43,18 @@ pub trait ThrusterServer {
             .unwrap()
             .block_on(self.build(host, port))
     }
+
+    ///
+    /// Same as `start`, but returns once `shutdown` has resolved and the server has
+    /// finished draining.
+    ///
+    fn start_with_shutdown<F>(self, host: &str, port: u16, shutdown: F)
+    where
+        Self: Sized,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(self.build_with_shutdown(host, port, shutdown))
+    }
 }
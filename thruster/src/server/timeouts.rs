@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+///
+/// How long servers wait on a client before giving up on it, unless told otherwise.
+///
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timeouts {
+    /// How long an idle keep-alive connection is kept open for another request.
+    pub keep_alive: Duration,
+    /// How long a client has to send the head of a request once it's started.
+    pub header_read: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            keep_alive: Duration::from_secs(75),
+            header_read: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    ///
+    /// hyper starts timing the head of a request as soon as it starts waiting for one, so the
+    /// idle time and the time spent reading the head share a single timeout.
+    ///
+    #[cfg(feature = "hyper_server")]
+    pub fn hyper_header_read(&self) -> Duration {
+        self.keep_alive + self.header_read
+    }
+}
@@ -1,19 +1,55 @@
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt};
+use hyper::server::accept;
 use hyper::service::make_service_fn;
 use hyper::{Body, Response, Server};
-use hyperlocal::UnixServerExt;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, path::Path};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::wrappers::UnixListenerStream;
 use tokio_util::sync::ReusableBoxFuture;
 
 use crate::app::App;
 use crate::context::basic_hyper_context::HyperRequest;
 use crate::core::context::Context;
-use crate::server::hyper_server::HyperService;
+use crate::server::hyper_server::{HyperService, IdleTimeout};
+use crate::server::shutdown;
+use crate::server::timeouts::Timeouts;
 use crate::server::ThrusterServer;
 
 pub struct UnixHyperServer<T: 'static + Context + Clone + Send + Sync, S: Send> {
     app: Arc<App<HyperRequest, T, S>>,
+    drain_timeout: Duration,
+    timeouts: Timeouts,
+}
+
+impl<T: 'static + Context + Clone + Send + Sync, S: Send> UnixHyperServer<T, S> {
+    ///
+    /// Sets how long in-flight requests are given to finish after a shutdown signal
+    /// before their connections are dropped. Defaults to 30 seconds.
+    ///
+    pub fn drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    ///
+    /// Sets how long an idle keep-alive connection is kept open, waiting for another request,
+    /// before it's closed. Defaults to 75 seconds. hyper times this together with the header
+    /// read timeout, so a connection gets the two combined to go from idle to a full request
+    /// head.
+    ///
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) {
+        self.timeouts.keep_alive = timeout;
+    }
+
+    ///
+    /// Sets how long a client has to send the head of a request, after which its connection is
+    /// closed. Defaults to 30 seconds.
+    ///
+    pub fn header_read_timeout(&mut self, timeout: Duration) {
+        self.timeouts.header_read = timeout;
+    }
 }
 
 impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + Send + Sync>
@@ -27,10 +63,26 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
     fn new(mut app: App<Self::Request, T, Self::State>) -> Self {
         app = app.commit();
 
-        UnixHyperServer { app: Arc::new(app) }
+        UnixHyperServer {
+            app: Arc::new(app),
+            drain_timeout: shutdown::DEFAULT_DRAIN_TIMEOUT,
+            timeouts: Timeouts::default(),
+        }
     }
 
     fn build(self, socket_path: &str, _unused_port: u16) -> ReusableBoxFuture<()> {
+        self.build_with_shutdown(socket_path, _unused_port, futures::future::pending())
+    }
+
+    fn build_with_shutdown<F>(
+        self,
+        socket_path: &str,
+        _unused_port: u16,
+        shutdown: F,
+    ) -> ReusableBoxFuture<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
         let app = self.app;
         let path = Path::new(socket_path);
 
@@ -39,7 +91,7 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
                 .unwrap_or_else(|_| panic!("Could not remove file: {}", socket_path));
         }
 
-        let service = make_service_fn(move |_: &tokio::net::UnixStream| {
+        let service = make_service_fn(move |_: &IdleTimeout<UnixStream>| {
             let arc_app = app.clone();
 
             async move {
@@ -50,14 +102,25 @@ impl<T: Context<Response = Response<Body>> + Clone + Send + Sync, S: 'static + S
             }
         });
 
-        let listener_fut = Server::bind_unix(path)
-            .unwrap()
+        let (signal, deadline) = shutdown::with_deadline(shutdown, self.drain_timeout);
+        let keep_alive = self.timeouts.keep_alive;
+        let listener = UnixListenerStream::new(UnixListener::bind(path).unwrap())
+            .map(move |stream| stream.map(|stream| IdleTimeout::new(stream, keep_alive)));
+
+        let listener_fut = Server::builder(accept::from_stream(listener))
+            .http1_header_read_timeout(self.timeouts.hyper_header_read())
             .serve(service)
+            .with_graceful_shutdown(signal)
             .map(|v| match v {
                 Ok(_) => (),
                 Err(e) => panic!("Hyper server error occurred: {:#?}", e),
             });
 
-        ReusableBoxFuture::new(listener_fut)
+        ReusableBoxFuture::new(async move {
+            tokio::select! {
+                _ = listener_fut => (),
+                _ = deadline => (),
+            }
+        })
     }
 }